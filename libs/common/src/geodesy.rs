@@ -0,0 +1,347 @@
+//! Distances, in meters, and bearings, in degrees clockwise from north,
+//! between coordinates. The spherical functions use the mean earth radius,
+//! the Vincenty ones the WGS84 ellipsoid.
+
+use crate::types::{Coordinate, Latitude, Longitude};
+use err_derive::Error;
+
+/// IUGG mean earth radius
+pub const MEAN_EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// WGS84 ellipsoid
+pub const WGS84_SEMI_MAJOR_AXIS_METERS: f64 = 6_378_137.0;
+pub const WGS84_FLATTENING: f64 = 1.0 / 298.257_223_563;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "Vincenty's formula failed to converge, the points are nearly antipodal")]
+    NoConvergence,
+}
+
+/// Great-circle distance on a sphere
+pub fn haversine_distance(from: &Coordinate, to: &Coordinate) -> f64 {
+    MEAN_EARTH_RADIUS_METERS * angular_distance(from, to)
+}
+
+/// Bearing to set off from `from` towards `to` on the great circle between them
+pub fn initial_bearing(from: &Coordinate, to: &Coordinate) -> f64 {
+    let (lat1, lon1) = radians(from);
+    let (lat2, lon2) = radians(to);
+    let d_lon = lon2 - lon1;
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    normalize_bearing(y.atan2(x).to_degrees())
+}
+
+/// Bearing arriving at `to` from `from` on the great circle between them
+pub fn final_bearing(from: &Coordinate, to: &Coordinate) -> f64 {
+    normalize_bearing(initial_bearing(to, from) + 180.0)
+}
+
+/// Where travelling `distance` along the great circle starting at `bearing` ends up
+pub fn destination(from: &Coordinate, bearing: f64, distance: f64) -> Coordinate {
+    let (lat1, lon1) = radians(from);
+    let delta = distance / MEAN_EARTH_RADIUS_METERS;
+    let theta = bearing.to_radians();
+    let lat2 = (lat1.sin() * delta.cos() + lat1.cos() * delta.sin() * theta.cos()).asin();
+    let lon2 = lon1
+        + (theta.sin() * delta.sin() * lat1.cos()).atan2(delta.cos() - lat1.sin() * lat2.sin());
+    coordinate(lat2, lon2)
+}
+
+/// Half way along the great circle between the two
+pub fn midpoint(from: &Coordinate, to: &Coordinate) -> Coordinate {
+    let (lat1, lon1) = radians(from);
+    let (lat2, lon2) = radians(to);
+    let d_lon = lon2 - lon1;
+    let bx = lat2.cos() * d_lon.cos();
+    let by = lat2.cos() * d_lon.sin();
+    let lat = (lat1.sin() + lat2.sin()).atan2(((lat1.cos() + bx).powi(2) + by.powi(2)).sqrt());
+    let lon = lon1 + by.atan2(lat1.cos() + bx);
+    coordinate(lat, lon)
+}
+
+/// Distance from `point` to the great circle through `path_start` and
+/// `path_end`, negative to the left of it, positive to the right
+pub fn cross_track_distance(
+    point: &Coordinate,
+    path_start: &Coordinate,
+    path_end: &Coordinate,
+) -> f64 {
+    let delta13 = angular_distance(path_start, point);
+    let theta13 = initial_bearing(path_start, point).to_radians();
+    let theta12 = initial_bearing(path_start, path_end).to_radians();
+    MEAN_EARTH_RADIUS_METERS * (delta13.sin() * (theta13 - theta12).sin()).asin()
+}
+
+/// Distance from `path_start` to the point on the great circle through
+/// `path_start` and `path_end` closest to `point`, negative behind `path_start`
+pub fn along_track_distance(
+    point: &Coordinate,
+    path_start: &Coordinate,
+    path_end: &Coordinate,
+) -> f64 {
+    let delta13 = angular_distance(path_start, point);
+    let theta13 = initial_bearing(path_start, point).to_radians();
+    let theta12 = initial_bearing(path_start, path_end).to_radians();
+    let delta_xt = (delta13.sin() * (theta13 - theta12).sin()).asin();
+    let delta_at = (delta13.cos() / delta_xt.cos()).clamp(-1.0, 1.0).acos();
+    MEAN_EARTH_RADIUS_METERS * delta_at * (theta12 - theta13).cos().signum()
+}
+
+/// Result of Vincenty's inverse formula
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Geodesic {
+    /// Meters along the ellipsoid
+    pub distance: f64,
+    pub initial_bearing: f64,
+    pub final_bearing: f64,
+}
+
+/// Distance along the WGS84 ellipsoid, accurate to within a millimeter
+pub fn vincenty_distance(from: &Coordinate, to: &Coordinate) -> Result<f64, Error> {
+    Ok(vincenty_inverse(from, to)?.distance)
+}
+
+/// Vincenty's inverse formula on the WGS84 ellipsoid, fails for nearly
+/// antipodal points
+pub fn vincenty_inverse(from: &Coordinate, to: &Coordinate) -> Result<Geodesic, Error> {
+    const MAX_ITERATIONS: usize = 200;
+    let a = WGS84_SEMI_MAJOR_AXIS_METERS;
+    let f = WGS84_FLATTENING;
+    let b = a * (1.0 - f);
+
+    let (lat1, lon1) = radians(from);
+    let (lat2, lon2) = radians(to);
+    let l = lon2 - lon1;
+    // Reduced latitudes
+    let u1 = ((1.0 - f) * lat1.tan()).atan();
+    let u2 = ((1.0 - f) * lat2.tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..MAX_ITERATIONS {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            // Same point
+            return Ok(Geodesic {
+                distance: 0.0,
+                initial_bearing: 0.0,
+                final_bearing: 0.0,
+            });
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos2_alpha = 1.0 - sin_alpha.powi(2);
+        // Both points on the equator
+        let cos_2sigma_m = if cos2_alpha == 0.0 {
+            0.0
+        } else {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos2_alpha
+        };
+        let c = f / 16.0 * cos2_alpha * (4.0 + f * (4.0 - 3.0 * cos2_alpha));
+        let previous = lambda;
+        lambda = l
+            + (1.0 - c)
+                * f
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))));
+        if (lambda - previous).abs() > 1e-12 {
+            continue;
+        }
+
+        let u_sq = cos2_alpha * (a * a - b * b) / (b * b);
+        let big_a =
+            1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+        let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+        let delta_sigma = big_b
+            * sin_sigma
+            * (cos_2sigma_m
+                + big_b / 4.0
+                    * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))
+                        - big_b / 6.0
+                            * cos_2sigma_m
+                            * (-3.0 + 4.0 * sin_sigma.powi(2))
+                            * (-3.0 + 4.0 * cos_2sigma_m.powi(2))));
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let alpha1 = (cos_u2 * sin_lambda).atan2(cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda);
+        let alpha2 = (cos_u1 * sin_lambda).atan2(-sin_u1 * cos_u2 + cos_u1 * sin_u2 * cos_lambda);
+        return Ok(Geodesic {
+            distance: b * big_a * (sigma - delta_sigma),
+            initial_bearing: normalize_bearing(alpha1.to_degrees()),
+            final_bearing: normalize_bearing(alpha2.to_degrees()),
+        });
+    }
+    Err(Error::NoConvergence)
+}
+
+/// Wraps `degrees` into [0, 360)
+pub fn normalize_bearing(degrees: f64) -> f64 {
+    let b = degrees.rem_euclid(360.0);
+    // rem_euclid can round up to the modulus for tiny negative values
+    if b >= 360.0 {
+        0.0
+    } else {
+        b
+    }
+}
+
+/// Angle subtended at the earth's center, haversine formula
+fn angular_distance(from: &Coordinate, to: &Coordinate) -> f64 {
+    let (lat1, lon1) = radians(from);
+    let (lat2, lon2) = radians(to);
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin()
+}
+
+fn radians(c: &Coordinate) -> (f64, f64) {
+    (
+        c.latitude.get().to_radians(),
+        c.longitude.get().to_radians(),
+    )
+}
+
+/// From radians, with the longitude wrapped into [-180, 180)
+fn coordinate(lat: f64, lon: f64) -> Coordinate {
+    Coordinate {
+        latitude: Latitude::new_clamped(lat.to_degrees()),
+        longitude: Longitude::new_wrapped(lon.to_degrees()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dms(degrees: f64, minutes: f64, seconds: f64) -> f64 {
+        degrees.signum() * (degrees.abs() + minutes / 60.0 + seconds / 3600.0)
+    }
+
+    // Aviation Formulary example, LAX to JFK
+    fn lax() -> Coordinate {
+        Coordinate::new(dms(33.0, 57.0, 0.0), -dms(118.0, 24.0, 0.0)).unwrap()
+    }
+
+    fn jfk() -> Coordinate {
+        Coordinate::new(dms(40.0, 38.0, 0.0), -dms(73.0, 47.0, 0.0)).unwrap()
+    }
+
+    #[test]
+    fn great_circle() {
+        // 0.623585 radians, 2144 nautical miles
+        let d = angular_distance(&lax(), &jfk());
+        assert!((d - 0.623585).abs() < 1e-6, "{}", d);
+        let nm = haversine_distance(&lax(), &jfk()) / MEAN_EARTH_RADIUS_METERS * 180.0 * 60.0
+            / std::f64::consts::PI;
+        assert!((nm - 2144.0).abs() < 1.0, "{}", nm);
+        // True course 1.150035 radians, 66 degrees
+        let b = initial_bearing(&lax(), &jfk());
+        assert!((b.to_radians() - 1.150035).abs() < 1e-6, "{}", b);
+        assert_eq!(haversine_distance(&jfk(), &jfk()), 0.0);
+
+        // Due east along the equator doesn't change course
+        let a = Coordinate::new(0.0, 10.0).unwrap();
+        let b = Coordinate::new(0.0, 20.0).unwrap();
+        assert!((initial_bearing(&a, &b) - 90.0).abs() < 1e-9);
+        assert!((final_bearing(&a, &b) - 90.0).abs() < 1e-9);
+        assert!((initial_bearing(&b, &a) - 270.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn destination_point() {
+        // Movable Type Scripts example, 124.8 km from 53°19′14″N 001°43′47″W
+        // at 096°01′18″ ends up at 53°11′18″N 000°08′00″E, arriving at 097°30′52″
+        let from = Coordinate::new(dms(53.0, 19.0, 14.0), -dms(1.0, 43.0, 47.0)).unwrap();
+        let to = destination(&from, dms(96.0, 1.0, 18.0), 124_800.0);
+        let arc_second = 1.0 / 3600.0;
+        assert!(
+            (to.latitude.get() - dms(53.0, 11.0, 18.0)).abs() < arc_second,
+            "{}",
+            to
+        );
+        assert!(
+            (to.longitude.get() - dms(0.0, 8.0, 0.0)).abs() < arc_second,
+            "{}",
+            to
+        );
+        let b = final_bearing(&from, &to);
+        assert!((b - dms(97.0, 30.0, 52.0)).abs() < arc_second, "{}", b);
+
+        // Wraps across the antimeridian
+        let east = destination(&Coordinate::new(0.0, 179.5).unwrap(), 90.0, 111_195.0);
+        assert!((east.longitude.get() - -179.5).abs() < 1e-3, "{}", east);
+    }
+
+    #[test]
+    fn midpoint_is_half_way() {
+        let mid = midpoint(&lax(), &jfk());
+        let to_lax = haversine_distance(&mid, &lax());
+        let to_jfk = haversine_distance(&mid, &jfk());
+        assert!((to_lax - to_jfk).abs() < 1e-6);
+        assert!((to_lax * 2.0 - haversine_distance(&lax(), &jfk())).abs() < 1e-6);
+        // And on the great circle, north of the rhumb line
+        assert!(cross_track_distance(&mid, &lax(), &jfk()).abs() < 1e-6);
+        assert!(mid.latitude.get() > (lax().latitude.get() + jfk().latitude.get()) / 2.0);
+    }
+
+    #[test]
+    fn cross_and_along_track() {
+        // 100 km along the LAX to JFK course, then 5 km off to either side
+        let course = initial_bearing(&lax(), &jfk());
+        let along = destination(&lax(), course, 100_000.0);
+        let across = initial_bearing(&along, &jfk()) + 90.0;
+        let right = destination(&along, across, 5_000.0);
+        let left = destination(&along, across + 180.0, 5_000.0);
+        let xtd = cross_track_distance(&right, &lax(), &jfk());
+        assert!((xtd - 5_000.0).abs() < 0.1, "{}", xtd);
+        let xtd = cross_track_distance(&left, &lax(), &jfk());
+        assert!((xtd + 5_000.0).abs() < 0.1, "{}", xtd);
+        let atd = along_track_distance(&right, &lax(), &jfk());
+        assert!((atd - 100_000.0).abs() < 1.0, "{}", atd);
+
+        let behind = destination(&lax(), course + 180.0, 20_000.0);
+        let atd = along_track_distance(&behind, &lax(), &jfk());
+        assert!((atd + 20_000.0).abs() < 1.0, "{}", atd);
+    }
+
+    #[test]
+    fn vincenty() {
+        // Vincenty's example, Flinders Peak to Buninyong, 54972.271 m,
+        // azimuth 306°52′05.37″, reverse azimuth 127°10′25.07″
+        let flinders =
+            Coordinate::new(-dms(37.0, 57.0, 3.72030), dms(144.0, 25.0, 29.52440)).unwrap();
+        let buninyong =
+            Coordinate::new(-dms(37.0, 39.0, 10.15610), dms(143.0, 55.0, 35.38390)).unwrap();
+        let g = vincenty_inverse(&flinders, &buninyong).unwrap();
+        assert!((g.distance - 54_972.271).abs() < 1e-3, "{}", g.distance);
+        let hundredth = 0.01 / 3600.0;
+        assert!((g.initial_bearing - dms(306.0, 52.0, 5.37)).abs() < hundredth);
+        assert!((g.final_bearing - dms(307.0, 10.0, 25.07)).abs() < hundredth);
+
+        // Close to the spherical distance
+        let s = haversine_distance(&flinders, &buninyong);
+        assert!((s - g.distance).abs() / g.distance < 0.005);
+
+        assert_eq!(vincenty_distance(&flinders, &flinders).unwrap(), 0.0);
+        // One degree of longitude on the equator
+        let d = vincenty_distance(
+            &Coordinate::new(0.0, 0.0).unwrap(),
+            &Coordinate::new(0.0, 1.0).unwrap(),
+        )
+        .unwrap();
+        assert!((d - 111_319.491).abs() < 1e-3, "{}", d);
+        assert!(vincenty_distance(
+            &Coordinate::new(0.0, 0.0).unwrap(),
+            &Coordinate::new(0.5, 179.7).unwrap()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::transform::util::{lat_to_y, lon_to_x};
+use crate::types::{Coordinate, FractionalZoom, TileNumber, Zoom};
+use std::ops::Range;
+
+/// Size of an image made of square tiles
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Viewport {
+    pub width: u32,
+    pub height: u32,
+    pub tile_size: u32,
+}
+
+impl Viewport {
+    pub fn new(width: u32, height: u32, tile_size: u32) -> Self {
+        Viewport {
+            width,
+            height,
+            tile_size,
+        }
+    }
+
+    /// Pixel position of the middle of the image
+    pub fn middle(&self) -> (f64, f64) {
+        (f64::from(self.width) / 2.0, f64::from(self.height) / 2.0)
+    }
+
+    /// The image drawn at the nearest whole level to `zoom`, which is then
+    /// scaled to this one by `FractionalZoom::tile_scale`. Has a pixel to
+    /// spare on every side for the filtering.
+    pub fn tile_level(&self, zoom: FractionalZoom) -> Viewport {
+        if zoom.is_whole() {
+            return *self;
+        }
+        let size = |len: u32| (f64::from(len) / zoom.tile_scale()).ceil() as u32 + 2;
+        Viewport::new(size(self.width), size(self.height), self.tile_size)
+    }
+}
+
+/// A tile in a `TileGrid`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct GridTile {
+    /// Tile number, wrapped around the world
+    pub tile_x: TileNumber,
+    pub tile_y: TileNumber,
+    /// Position in the grid, tiles to the right of and below tile (0, 0).
+    /// Not wrapped, so it keeps growing past the antimeridian.
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Where the tiles of a whole zoom level go in a viewport.
+/// Tiles are drawn on whole pixels, every tile is a whole number of tiles
+/// away from tile (0, 0), which is rounded to the nearest pixel.
+/// Anything placed on top of the tiles should go through the same origin.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct TileGrid {
+    viewport: Viewport,
+    zoom: Zoom,
+    origin: (i64, i64),
+}
+
+impl TileGrid {
+    /// Places `center`, in tiles at `zoom`, at the `anchor` image pixel
+    pub fn new(viewport: Viewport, zoom: Zoom, center: (f64, f64), anchor: (f64, f64)) -> Self {
+        let tile_size = f64::from(viewport.tile_size);
+        TileGrid {
+            viewport,
+            zoom,
+            origin: (
+                (anchor.0 - center.0 * tile_size).round() as i64,
+                (anchor.1 - center.1 * tile_size).round() as i64,
+            ),
+        }
+    }
+
+    /// Places `center`, in tiles at `zoom`, in the middle of the image
+    pub fn centered(viewport: Viewport, zoom: Zoom, center: (f64, f64)) -> Self {
+        Self::new(viewport, zoom, center, viewport.middle())
+    }
+
+    /// Where the tiles go in an image of `viewport` showing `center` at
+    /// `zoom` on the `anchor` pixel. Between whole zoom levels the image is
+    /// drawn at the nearest one, see `Viewport::tile_level`, around the
+    /// `image_center`. Maps and anything drawn over them share this grid.
+    pub fn for_view(
+        viewport: Viewport,
+        zoom: FractionalZoom,
+        center: &Coordinate,
+        anchor: (f64, f64),
+    ) -> Self {
+        let tile_zoom = zoom.tile_zoom();
+        if zoom.is_whole() {
+            let center = (
+                lon_to_x(center.longitude, tile_zoom),
+                lat_to_y(center.latitude, tile_zoom),
+            );
+            return Self::new(viewport, tile_zoom, center, anchor);
+        }
+        Self::centered(
+            viewport.tile_level(zoom),
+            tile_zoom,
+            Self::image_center(viewport, zoom, center, anchor),
+        )
+    }
+
+    /// Position of the middle of an image of `viewport`, showing `center`
+    /// at `zoom` on the `anchor` pixel, in tiles at the nearest whole level
+    pub fn image_center(
+        viewport: Viewport,
+        zoom: FractionalZoom,
+        center: &Coordinate,
+        anchor: (f64, f64),
+    ) -> (f64, f64) {
+        let tile_zoom = zoom.tile_zoom();
+        let tile_px = f64::from(viewport.tile_size) * zoom.tile_scale();
+        let (middle_x, middle_y) = viewport.middle();
+        (
+            lon_to_x(center.longitude, tile_zoom) + (middle_x - anchor.0) / tile_px,
+            lat_to_y(center.latitude, tile_zoom) + (middle_y - anchor.1) / tile_px,
+        )
+    }
+
+    pub fn viewport(&self) -> Viewport {
+        self.viewport
+    }
+
+    pub fn zoom(&self) -> Zoom {
+        self.zoom
+    }
+
+    /// Image pixel offset of tile (0, 0)
+    pub fn origin(&self) -> (i64, i64) {
+        self.origin
+    }
+
+    /// Image pixel offset of the top left corner of the tile at (`x`, `y`)
+    /// in the grid, negative for tiles partially outside the image
+    pub fn tile_offset(&self, x: i32, y: i32) -> (i32, i32) {
+        let tile_size = i64::from(self.viewport.tile_size);
+        (
+            (self.origin.0 + i64::from(x) * tile_size) as i32,
+            (self.origin.1 + i64::from(y) * tile_size) as i32,
+        )
+    }
+
+    /// Image pixel position of a point at (`x`, `y`) in tiles, not rounded
+    pub fn tile_to_px(&self, x: f64, y: f64) -> (f64, f64) {
+        let tile_size = f64::from(self.viewport.tile_size);
+        (
+            self.origin.0 as f64 + x * tile_size,
+            self.origin.1 as f64 + y * tile_size,
+        )
+    }
+
+    /// Position in tiles of image pixel (`x`, `y`), the inverse of `tile_to_px`
+    pub fn px_to_tile(&self, x: f64, y: f64) -> (f64, f64) {
+        let tile_size = f64::from(self.viewport.tile_size);
+        (
+            (x - self.origin.0 as f64) / tile_size,
+            (y - self.origin.1 as f64) / tile_size,
+        )
+    }
+
+    /// Grid x and y positions of the tiles that are at least partially in the image
+    pub fn tile_range(&self) -> (Range<i32>, Range<i32>) {
+        let tile_size = i64::from(self.viewport.tile_size);
+        let range = |origin: i64, len: u32| {
+            let start = (-origin).div_euclid(tile_size);
+            let end = (i64::from(len) - origin + tile_size - 1).div_euclid(tile_size);
+            start as i32..end as i32
+        };
+        (
+            range(self.origin.0, self.viewport.width),
+            range(self.origin.1, self.viewport.height),
+        )
+    }
+
+    /// The tiles that are at least partially in the image, column by column
+    pub fn tiles(&self) -> impl Iterator<Item = GridTile> {
+        let (x_range, y_range) = self.tile_range();
+        let max_tile = 1_i64 << self.zoom.get();
+        x_range.flat_map(move |x| {
+            y_range.clone().map(move |y| GridTile {
+                tile_x: TileNumber(i64::from(x).rem_euclid(max_tile) as u32),
+                tile_y: TileNumber(i64::from(y).rem_euclid(max_tile) as u32),
+                x,
+                y,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placement() {
+        let viewport = Viewport::new(30, 20, 16);
+        let zoom = Zoom::new_clamped(3);
+        let grid = TileGrid::centered(viewport, zoom, (4.2, 4.0));
+        // 15 - 4.2 * 16 = -52.2
+        assert_eq!(grid.origin(), (-52, -54));
+        assert_eq!(grid.tile_offset(4, 4), (12, 10));
+        let (x, y) = grid.tile_to_px(4.2, 4.0);
+        assert!((x - 15.2).abs() < 1e-9 && y == 10.0);
+        let (x, y) = grid.px_to_tile(x, y);
+        assert!((x - 4.2).abs() < 1e-9 && y == 4.0);
+
+        // Same center at the anchor
+        let anchored = TileGrid::new(viewport, zoom, (4.2, 4.0), (15.0, 15.0));
+        assert_eq!(anchored.origin(), (-52, -49));
+
+        let (x_range, y_range) = grid.tile_range();
+        assert_eq!((x_range, y_range), (3..6, 3..5));
+        let tiles: Vec<GridTile> = grid.tiles().collect();
+        assert_eq!(tiles.len(), 6);
+        for tile in tiles.iter() {
+            let (x, y) = grid.tile_offset(tile.x, tile.y);
+            assert!(x < 30 && x + 16 > 0 && y < 20 && y + 16 > 0, "{:?}", tile);
+        }
+
+        // Exactly on a tile edge, nothing past it
+        let aligned = TileGrid::new(viewport, zoom, (4.0, 4.0), (0.0, 0.0));
+        assert_eq!(aligned.tile_range(), (4..6, 4..6));
+    }
+
+    #[test]
+    fn view_grids() {
+        let viewport = Viewport::new(30, 20, 16);
+        let center = Coordinate::new(0.0, 0.0).unwrap();
+        // At whole levels the center is at the anchor
+        let whole = TileGrid::for_view(
+            viewport,
+            FractionalZoom::new_clamped(3.0),
+            &center,
+            (10.0, 5.0),
+        );
+        assert_eq!(whole.viewport(), viewport);
+        assert_eq!(whole.origin(), (10 - 64, 5 - 64));
+
+        // In between at the nearest level, the anchor a scaled distance
+        // from the middle
+        let zoom = FractionalZoom::new_clamped(3.4);
+        assert_eq!(zoom.tile_zoom(), Zoom::new_clamped(3));
+        let grid = TileGrid::for_view(viewport, zoom, &center, (10.0, 5.0));
+        let scale = zoom.tile_scale();
+        let tile_level = viewport.tile_level(zoom);
+        assert_eq!(tile_level.width, (30.0 / scale).ceil() as u32 + 2);
+        assert_eq!(grid.viewport(), tile_level);
+        let (x, y) = grid.tile_to_px(4.0, 4.0);
+        let (middle_x, middle_y) = tile_level.middle();
+        assert!((x - (middle_x - 5.0 / scale)).abs() <= 0.5, "x={}", x);
+        assert!((y - (middle_y - 5.0 / scale)).abs() <= 0.5, "y={}", y);
+    }
+
+    #[test]
+    fn wraps_tile_numbers() {
+        let zoom = Zoom::new_clamped(2);
+        let grid = TileGrid::centered(Viewport::new(64, 16, 16), zoom, (0.0, 0.5));
+        let tiles: Vec<(i32, u32)> = grid.tiles().map(|t| (t.x, t.tile_x.0)).collect();
+        assert_eq!(tiles, vec![(-2, 2), (-1, 3), (0, 0), (1, 1)]);
+    }
+}
@@ -1,7 +1,17 @@
 #![deny(warnings)]
 
+pub use crate::grid::*;
+pub use crate::notation::*;
+pub use crate::tile::*;
 pub use crate::transform::*;
 pub use crate::types::*;
+pub use crate::units::*;
 
+pub mod geodesy;
+pub mod grid;
+pub mod notation;
+pub mod tile;
 pub mod transform;
 pub mod types;
+pub mod units;
+pub mod utm;
@@ -0,0 +1,434 @@
+//! Textual forms of a coordinate, decimal degrees, degrees minutes and
+//! seconds, degrees and decimal minutes, UTM and MGRS
+
+use crate::types::Coordinate;
+use crate::utm::{Mgrs, Utm};
+use err_derive::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Deserialize, Serialize)]
+pub enum CoordinateFormat {
+    /// `47.453551, -116.788118`
+    #[default]
+    DecimalDegrees,
+    /// `47°27'12.8"N 116°47'17.2"W`
+    DegreesMinutesSeconds,
+    /// `47°27.213'N 116°47.287'W`
+    DegreesDecimalMinutes,
+    /// `11T 515972 5255589`
+    Utm,
+    /// `11T NN 15971 55589`
+    Mgrs,
+}
+
+impl CoordinateFormat {
+    /// Decimal places, or MGRS digits per axis, used unless configured
+    pub fn default_precision(&self) -> usize {
+        match self {
+            CoordinateFormat::DecimalDegrees => 6,
+            CoordinateFormat::DegreesMinutesSeconds => 1,
+            CoordinateFormat::DegreesDecimalMinutes => 3,
+            CoordinateFormat::Utm => 0,
+            CoordinateFormat::Mgrs => 5,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse coordinate format, expected DD, DMS, DDM, UTM or MGRS")]
+pub struct CoordinateFormatParseError;
+
+impl FromStr for CoordinateFormat {
+    type Err = CoordinateFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "dd" | "decimaldegrees" => Ok(CoordinateFormat::DecimalDegrees),
+            "dms" | "degreesminutesseconds" => Ok(CoordinateFormat::DegreesMinutesSeconds),
+            "ddm" | "degreesdecimalminutes" => Ok(CoordinateFormat::DegreesDecimalMinutes),
+            "utm" => Ok(CoordinateFormat::Utm),
+            "mgrs" => Ok(CoordinateFormat::Mgrs),
+            _ => Err(CoordinateFormatParseError),
+        }
+    }
+}
+
+impl fmt::Display for CoordinateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CoordinateFormat::DecimalDegrees => "DD",
+            CoordinateFormat::DegreesMinutesSeconds => "DMS",
+            CoordinateFormat::DegreesDecimalMinutes => "DDM",
+            CoordinateFormat::Utm => "UTM",
+            CoordinateFormat::Mgrs => "MGRS",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Formats coordinates in one of the `CoordinateFormat`s. Everything it
+/// produces parses back with `Coordinate::from_str`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub struct CoordinateFormatter {
+    format: CoordinateFormat,
+    precision: Option<usize>,
+}
+
+impl CoordinateFormatter {
+    pub fn new(format: CoordinateFormat) -> Self {
+        CoordinateFormatter {
+            format,
+            precision: None,
+        }
+    }
+
+    /// Decimal places of the last component, degrees, minutes, seconds or
+    /// meters. For MGRS the digits per axis, 1 (10 km) to 5 (1 m).
+    pub fn with_precision(mut self, precision: usize) -> Self {
+        self.precision = Some(precision);
+        self
+    }
+
+    pub fn format(&self) -> CoordinateFormat {
+        self.format
+    }
+
+    pub fn precision(&self) -> usize {
+        self.precision
+            .unwrap_or_else(|| self.format.default_precision())
+    }
+
+    /// Falls back to decimal degrees for UTM and MGRS outside of their
+    /// range, near the poles
+    pub fn to_string(&self, coord: &Coordinate) -> String {
+        let precision = self.precision();
+        match self.format {
+            CoordinateFormat::DecimalDegrees => decimal_degrees(coord, precision),
+            CoordinateFormat::DegreesMinutesSeconds => format!(
+                "{} {}",
+                sexagesimal(coord.latitude.get(), ('N', 'S'), 2, precision),
+                sexagesimal(coord.longitude.get(), ('E', 'W'), 2, precision)
+            ),
+            CoordinateFormat::DegreesDecimalMinutes => format!(
+                "{} {}",
+                sexagesimal(coord.latitude.get(), ('N', 'S'), 1, precision),
+                sexagesimal(coord.longitude.get(), ('E', 'W'), 1, precision)
+            ),
+            CoordinateFormat::Utm => match Utm::from_coordinate(coord) {
+                Ok(utm) => format!("{:.*}", precision, utm),
+                Err(_) => {
+                    decimal_degrees(coord, CoordinateFormat::DecimalDegrees.default_precision())
+                }
+            },
+            CoordinateFormat::Mgrs => match Mgrs::from_coordinate(coord) {
+                Ok(mgrs) => format!("{:.*}", precision, mgrs),
+                Err(_) => {
+                    decimal_degrees(coord, CoordinateFormat::DecimalDegrees.default_precision())
+                }
+            },
+        }
+    }
+}
+
+fn decimal_degrees(coord: &Coordinate, precision: usize) -> String {
+    format!(
+        "{:.*}, {:.*}",
+        precision,
+        coord.latitude.get(),
+        precision,
+        coord.longitude.get()
+    )
+}
+
+/// `degrees` with `parts` of minutes and seconds, the last one with
+/// `precision` decimal places, and a hemisphere letter
+fn sexagesimal(degrees: f64, hemispheres: (char, char), parts: u32, precision: usize) -> String {
+    let hemisphere = if degrees < 0.0 {
+        hemispheres.1
+    } else {
+        hemispheres.0
+    };
+    // Round once in units of the last part so 59.99 seconds carries into the minutes
+    let scale = 10_f64.powi(precision as i32);
+    let units = (degrees.abs() * 60_f64.powi(parts as i32) * scale).round() as u64;
+    let unit_scale = scale as u64;
+    let last = units % (60 * unit_scale);
+    let whole = units / (60 * unit_scale);
+    let last = last as f64 / scale;
+    let width = if precision == 0 { 2 } else { precision + 3 };
+    match parts {
+        1 => format!(
+            "{}°{:0w$.p$}'{}",
+            whole,
+            last,
+            hemisphere,
+            w = width,
+            p = precision
+        ),
+        _ => format!(
+            "{}°{:02}'{:0w$.p$}\"{}",
+            whole / 60,
+            whole % 60,
+            last,
+            hemisphere,
+            w = width,
+            p = precision
+        ),
+    }
+}
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse coordinate, expected decimal degrees, DMS, DDM, UTM or MGRS")]
+pub struct CoordinateParseError;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Hemisphere(char),
+}
+
+/// Accepts what `CoordinateFormatter` produces, along with the usual
+/// variations: signed degrees or hemisphere letters before or after, degree,
+/// minute and second marks or just spaces, and latitude and longitude
+/// swapped when both have hemisphere letters
+pub(crate) fn parse_coordinate(s: &str) -> Result<Coordinate, CoordinateParseError> {
+    if let Ok(mgrs) = Mgrs::from_str(s) {
+        return mgrs.to_coordinate().map_err(|_| CoordinateParseError);
+    }
+    if let Ok(utm) = Utm::from_str(s) {
+        return utm.to_coordinate().map_err(|_| CoordinateParseError);
+    }
+
+    let tokens = tokenize(s)?;
+    let hemispheres = tokens
+        .iter()
+        .filter(|t| matches!(t, Token::Hemisphere(_)))
+        .count();
+    let (first, second) = match hemispheres {
+        0 => {
+            if tokens.len() % 2 == 1 {
+                return Err(CoordinateParseError);
+            }
+            tokens.split_at(tokens.len() / 2)
+        }
+        2 => {
+            // Letters either lead or trail both halves
+            let split = if let Some(Token::Hemisphere(_)) = tokens.first() {
+                tokens[1..]
+                    .iter()
+                    .position(|t| matches!(t, Token::Hemisphere(_)))
+                    .map(|i| i + 1)
+            } else {
+                tokens
+                    .iter()
+                    .position(|t| matches!(t, Token::Hemisphere(_)))
+                    .map(|i| i + 1)
+            }
+            .ok_or(CoordinateParseError)?;
+            tokens.split_at(split)
+        }
+        _ => return Err(CoordinateParseError),
+    };
+    let (first_deg, first_hemi) = angle(first)?;
+    let (second_deg, second_hemi) = angle(second)?;
+    let (lat, lon) = match (first_hemi, second_hemi) {
+        (None, None) | (Some('N'), Some('E')) => (first_deg, second_deg),
+        (Some('E'), Some('N')) => (second_deg, first_deg),
+        _ => return Err(CoordinateParseError),
+    };
+    Coordinate::new(lat, lon).map_err(|_| CoordinateParseError)
+}
+
+/// Numbers and hemisphere letters, marks, commas and whitespace separate them
+fn tokenize(s: &str) -> Result<Vec<Token>, CoordinateParseError> {
+    let mut tokens = Vec::new();
+    let mut number = String::new();
+    for c in s.chars() {
+        match c {
+            '0'..='9' | '.' => number.push(c),
+            '-' | '+' if number.is_empty() => number.push(c),
+            'N' | 'S' | 'E' | 'W' | 'n' | 's' | 'e' | 'w' => {
+                end_number(&mut number, &mut tokens)?;
+                tokens.push(Token::Hemisphere(c.to_ascii_uppercase()));
+            }
+            '°' | '\'' | '"' | '′' | '″' | '’' | '”' | ',' | ';' | 'º' => {
+                end_number(&mut number, &mut tokens)?
+            }
+            c if c.is_whitespace() => end_number(&mut number, &mut tokens)?,
+            _ => return Err(CoordinateParseError),
+        }
+    }
+    end_number(&mut number, &mut tokens)?;
+    Ok(tokens)
+}
+
+fn end_number(number: &mut String, tokens: &mut Vec<Token>) -> Result<(), CoordinateParseError> {
+    if !number.is_empty() {
+        let val = number.parse().map_err(|_| CoordinateParseError)?;
+        tokens.push(Token::Number(val));
+        number.clear();
+    }
+    Ok(())
+}
+
+/// Signed degrees and the axis, `N` or `E`, if there was a hemisphere letter
+fn angle(tokens: &[Token]) -> Result<(f64, Option<char>), CoordinateParseError> {
+    let mut hemisphere = None;
+    let mut parts = Vec::with_capacity(3);
+    for t in tokens.iter() {
+        match t {
+            Token::Number(n) => parts.push(*n),
+            Token::Hemisphere(h) => hemisphere = Some(*h),
+        }
+    }
+    let (degrees, minutes, seconds) = match parts.as_slice() {
+        [d] => (*d, 0.0, 0.0),
+        [d, m] => (*d, *m, 0.0),
+        [d, m, s] => (*d, *m, *s),
+        _ => return Err(CoordinateParseError),
+    };
+    let in_range = |v: f64| (0.0..60.0).contains(&v);
+    if parts.len() > 1 && (degrees.fract() != 0.0 || !in_range(minutes) || !in_range(seconds))
+        || parts.len() > 2 && minutes.fract() != 0.0
+    {
+        return Err(CoordinateParseError);
+    }
+    let magnitude = degrees.abs() + minutes / 60.0 + seconds / 3600.0;
+    let negative = degrees.is_sign_negative();
+    match hemisphere {
+        None => Ok((if negative { -magnitude } else { magnitude }, None)),
+        // Either a sign or a letter, not both
+        Some(_) if negative => Err(CoordinateParseError),
+        Some('N') => Ok((magnitude, Some('N'))),
+        Some('S') => Ok((-magnitude, Some('N'))),
+        Some('E') => Ok((magnitude, Some('E'))),
+        Some(_) => Ok((-magnitude, Some('E'))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Coordinate, b: &Coordinate, tolerance: f64) {
+        assert!(
+            (a.latitude.get() - b.latitude.get()).abs() < tolerance
+                && (a.longitude.get() - b.longitude.get()).abs() < tolerance,
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let c = Coordinate::new(47.453551, -116.788118).unwrap();
+        let format = |f: CoordinateFormat| CoordinateFormatter::new(f).to_string(&c);
+        assert_eq!(
+            format(CoordinateFormat::DecimalDegrees),
+            "47.453551, -116.788118"
+        );
+        assert_eq!(
+            format(CoordinateFormat::DegreesMinutesSeconds),
+            "47°27'12.8\"N 116°47'17.2\"W"
+        );
+        assert_eq!(
+            format(CoordinateFormat::DegreesDecimalMinutes),
+            "47°27.213'N 116°47.287'W"
+        );
+        assert_eq!(format(CoordinateFormat::Utm), "11T 515972 5255589");
+        assert_eq!(format(CoordinateFormat::Mgrs), "11T NN 15971 55589");
+
+        let dms = CoordinateFormatter::new(CoordinateFormat::DegreesMinutesSeconds);
+        // Rounding carries into the minutes and degrees
+        assert_eq!(
+            dms.to_string(&Coordinate::new(-33.999999, 151.0).unwrap()),
+            "34°00'00.0\"S 151°00'00.0\"E"
+        );
+        assert_eq!(
+            dms.with_precision(0)
+                .to_string(&Coordinate::new(0.5, -0.25).unwrap()),
+            "0°30'00\"N 0°15'00\"W"
+        );
+        let mgrs = CoordinateFormatter::new(CoordinateFormat::Mgrs).with_precision(3);
+        assert_eq!(mgrs.to_string(&c), "11T NN 159 555");
+        // No UTM at the poles
+        assert_eq!(
+            CoordinateFormatter::new(CoordinateFormat::Utm)
+                .to_string(&Coordinate::new(89.0, 0.0).unwrap()),
+            "89.000000, 0.000000"
+        );
+    }
+
+    #[test]
+    fn parse_forms() {
+        let c = Coordinate::new(47.453551, -116.788118).unwrap();
+        for s in [
+            "47.453551, -116.788118",
+            "47.453551 -116.788118",
+            "47.453551N 116.788118W",
+            "N47.453551 W116.788118",
+            "116.788118W, 47.453551N",
+            "47° 27' 12.7836\" N, 116° 47' 17.2248\" W",
+            "47 27 12.7836 -116 47 17.2248",
+            "47°27.21306'N 116°47.28708'W",
+            "N 47 27.21306 W 116 47.28708",
+        ]
+        .iter()
+        {
+            assert_close(&s.parse().unwrap(), &c, 1e-8);
+        }
+        assert_close(&"11T 515972 5255589".parse().unwrap(), &c, 1e-5);
+        assert_close(&"11TNN1597155589".parse().unwrap(), &c, 2e-5);
+
+        for s in [
+            "",
+            "47.45",
+            "47.45N 116.78N",
+            "-47.45S 116.78W",
+            "47 60 0 N 116 0 0 W",
+            "47.5 30 N 116 W",
+            "91, 0",
+            "47.45 x 116.78",
+        ]
+        .iter()
+        {
+            assert!(s.parse::<Coordinate>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let coords = [
+            Coordinate::new(47.453551, -116.788118).unwrap(),
+            Coordinate::new(-33.856784, 151.215297).unwrap(),
+            Coordinate::new(0.0, 0.0).unwrap(),
+            Coordinate::new(-0.000001, 179.999999).unwrap(),
+            Coordinate::new(78.22, 15.65).unwrap(),
+        ];
+        let formats = [
+            (CoordinateFormat::DecimalDegrees, 1e-6),
+            (CoordinateFormat::DegreesMinutesSeconds, 0.05 / 3600.0),
+            (CoordinateFormat::DegreesDecimalMinutes, 0.0005 / 60.0),
+            (CoordinateFormat::Utm, 1e-5),
+            (CoordinateFormat::Mgrs, 3e-5),
+        ];
+        for c in coords.iter() {
+            for (format, tolerance) in formats.iter() {
+                let s = CoordinateFormatter::new(*format).to_string(c);
+                let parsed: Coordinate = s.parse().unwrap();
+                // MGRS truncates, a degree of longitude is short near the pole
+                let tolerance = tolerance / c.latitude.get().to_radians().cos();
+                assert_close(&parsed, c, tolerance);
+            }
+        }
+        for format in formats.iter().map(|(f, _)| f) {
+            assert_eq!(
+                format.to_string().parse::<CoordinateFormat>().unwrap(),
+                *format
+            );
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use crate::transform::util::{lat_to_y, lon_to_x, x_to_lon, y_to_lat};
+use crate::types::{BoundingBox, Coordinate, TileNumber, Zoom};
+use err_derive::Error;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A Web Mercator tile in the XYZ (slippy map) scheme, where tile (0, 0) is
+/// the north west corner and y increases southward
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+pub struct TileCoord {
+    pub zoom: Zoom,
+    pub x: TileNumber,
+    pub y: TileNumber,
+}
+
+impl TileCoord {
+    /// `None` if `x` or `y` is past the edge of the world at `zoom`
+    pub fn new<T: Into<TileNumber>>(zoom: Zoom, x: T, y: T) -> Option<Self> {
+        let (x, y) = (x.into(), y.into());
+        if x.0 >= tiles_across(zoom) || y.0 >= tiles_across(zoom) {
+            return None;
+        }
+        Some(TileCoord { zoom, x, y })
+    }
+
+    /// The tile containing `coord`, longitude wraps around the antimeridian and
+    /// latitude is clamped to the Web Mercator range
+    pub fn from_coordinate(coord: &Coordinate, zoom: Zoom) -> Self {
+        let lat = coord.latitude.clamped_to_mercator();
+        TileCoord {
+            zoom,
+            x: TileNumber(tile_number(lon_to_x(coord.longitude, zoom), zoom)),
+            y: TileNumber(tile_number(lat_to_y(lat, zoom), zoom)),
+        }
+    }
+
+    /// The tile one zoom level out that covers this one, `None` at `Zoom::MIN`
+    pub fn parent(&self) -> Option<Self> {
+        self.ancestor(1)
+    }
+
+    /// The tile `levels` zoom levels out that covers this one
+    pub fn ancestor(&self, levels: u8) -> Option<Self> {
+        let zoom = self.zoom.get().checked_sub(levels)?;
+        if zoom < Zoom::MIN.get() {
+            return None;
+        }
+        Some(TileCoord {
+            zoom: Zoom::new_clamped(zoom),
+            x: TileNumber(self.x.0 >> levels),
+            y: TileNumber(self.y.0 >> levels),
+        })
+    }
+
+    /// The four tiles one zoom level in that cover this one, in the order
+    /// top left, top right, bottom left, bottom right. `None` at `Zoom::MAX`.
+    pub fn children(&self) -> Option<[Self; 4]> {
+        if self.zoom >= Zoom::MAX {
+            return None;
+        }
+        let zoom = Zoom::new_clamped(self.zoom.get() + 1);
+        let child = |dx: u32, dy: u32| TileCoord {
+            zoom,
+            x: TileNumber(2 * self.x.0 + dx),
+            y: TileNumber(2 * self.y.0 + dy),
+        };
+        Some([child(0, 0), child(1, 0), child(0, 1), child(1, 1)])
+    }
+
+    /// The tile `dx` columns right and `dy` rows down. Wraps around the
+    /// antimeridian, `None` past the top or bottom of the world.
+    pub fn neighbor(&self, dx: i64, dy: i64) -> Option<Self> {
+        let max_tile = i64::from(tiles_across(self.zoom));
+        let y = i64::from(self.y.0) + dy;
+        if !(0..max_tile).contains(&y) {
+            return None;
+        }
+        Some(TileCoord {
+            zoom: self.zoom,
+            x: TileNumber((i64::from(self.x.0) + dx).rem_euclid(max_tile) as u32),
+            y: TileNumber(y as u32),
+        })
+    }
+
+    /// The distinct tiles surrounding this one, row by row from the top left.
+    /// Fewer than eight next to the poles or when the world is only a couple
+    /// of tiles wide.
+    pub fn neighbors(&self) -> Vec<Self> {
+        let mut neighbors = Vec::with_capacity(8);
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if let Some(tile) = self.neighbor(dx, dy) {
+                    if tile != *self && !neighbors.contains(&tile) {
+                        neighbors.push(tile);
+                    }
+                }
+            }
+        }
+        neighbors
+    }
+
+    /// Geographic area covered by the tile
+    pub fn bounds(&self) -> BoundingBox {
+        let (x, y) = (f64::from(self.x.0), f64::from(self.y.0));
+        BoundingBox {
+            west: x_to_lon(x, self.zoom),
+            south: y_to_lat(y + 1.0, self.zoom),
+            east: x_to_lon(x + 1.0, self.zoom),
+            north: y_to_lat(y, self.zoom),
+        }
+    }
+
+    /// Bing Maps quadkey, one digit per zoom level
+    pub fn quadkey(&self) -> String {
+        (1..=self.zoom.get())
+            .rev()
+            .map(|level| {
+                let mask = 1 << (level - 1);
+                let mut digit = b'0';
+                if self.x.0 & mask != 0 {
+                    digit += 1;
+                }
+                if self.y.0 & mask != 0 {
+                    digit += 2;
+                }
+                char::from(digit)
+            })
+            .collect()
+    }
+
+    pub fn from_quadkey(quadkey: &str) -> Result<Self, QuadkeyParseError> {
+        if quadkey.len() < usize::from(Zoom::MIN.get())
+            || quadkey.len() > usize::from(Zoom::MAX.get())
+        {
+            return Err(QuadkeyParseError);
+        }
+        let (mut x, mut y) = (0, 0);
+        for digit in quadkey.bytes() {
+            let digit = match digit {
+                b'0'..=b'3' => u32::from(digit - b'0'),
+                _ => return Err(QuadkeyParseError),
+            };
+            x = (x << 1) | (digit & 1);
+            y = (y << 1) | (digit >> 1);
+        }
+        Ok(TileCoord {
+            zoom: Zoom::new_clamped(quadkey.len() as u8),
+            x: TileNumber(x),
+            y: TileNumber(y),
+        })
+    }
+
+    /// Row number in the TMS scheme, which counts from the south
+    pub fn tms_y(&self) -> TileNumber {
+        TileNumber(tiles_across(self.zoom) - 1 - self.y.0)
+    }
+
+    /// `None` if `x` or `tms_y` is past the edge of the world at `zoom`
+    pub fn from_tms<T: Into<TileNumber>>(zoom: Zoom, x: T, tms_y: T) -> Option<Self> {
+        let tms_y = tms_y.into();
+        let y = tiles_across(zoom).checked_sub(tms_y.0 + 1)?;
+        TileCoord::new(zoom, x.into(), TileNumber(y))
+    }
+}
+
+/// Number of tiles across the world at `zoom`
+fn tiles_across(zoom: Zoom) -> u32 {
+    1 << zoom.get()
+}
+
+/// Tile number containing `v`, in tiles, clamped to the world at `zoom`
+pub(crate) fn tile_number(v: f64, zoom: Zoom) -> u32 {
+    (v.floor().max(0.0) as u32).min(tiles_across(zoom) - 1)
+}
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse quadkey, expected 1 to 18 digits from 0 to 3")]
+pub struct QuadkeyParseError;
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse tile coordinate, expected zoom/x/y")]
+pub struct TileCoordParseError;
+
+/// Parses `zoom/x/y`, the order used in tile URLs and cache paths
+impl FromStr for TileCoord {
+    type Err = TileCoordParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s
+            .split('/')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| TileCoordParseError)?;
+        match vals.as_slice() {
+            [zoom, x, y] => {
+                if *zoom < u32::from(Zoom::MIN.get()) || *zoom > u32::from(Zoom::MAX.get()) {
+                    return Err(TileCoordParseError);
+                }
+                TileCoord::new(Zoom::new_clamped(*zoom as u8), *x, *y).ok_or(TileCoordParseError)
+            }
+            _ => Err(TileCoordParseError),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TileCoord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            zoom: Zoom,
+            x: TileNumber,
+            y: TileNumber,
+        }
+        let Fields { zoom, x, y } = Fields::deserialize(deserializer)?;
+        TileCoord::new(zoom, x, y).ok_or_else(|| {
+            de::Error::custom(format!(
+                "Tile {}/{}/{} is past the edge of the world",
+                zoom, x, y
+            ))
+        })
+    }
+}
+
+impl fmt::Display for TileCoord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.zoom, self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(zoom: u8, x: u32, y: u32) -> TileCoord {
+        TileCoord::new(Zoom::new_clamped(zoom), x, y).unwrap()
+    }
+
+    #[test]
+    fn from_coordinate_and_bounds() {
+        // Coeur d'Alene, ID
+        let coord = Coordinate::new(47.453551, -116.788118).unwrap();
+        let t = TileCoord::from_coordinate(&coord, Zoom::new_clamped(14));
+        assert_eq!(t, tile(14, 2876, 5732));
+        let b = t.bounds();
+        assert!(b.contains(&coord));
+        assert!(b.west.get() < b.east.get() && b.south.get() < b.north.get());
+        assert!((b.east.get() - b.west.get() - 360.0 / 16384.0).abs() < 1e-9);
+
+        assert_eq!(
+            TileCoord::from_coordinate(
+                &Coordinate::new(90.0, 180.0).unwrap(),
+                Zoom::new_clamped(2)
+            ),
+            tile(2, 0, 0)
+        );
+        assert_eq!(
+            TileCoord::from_coordinate(
+                &Coordinate::new(-90.0, 179.99).unwrap(),
+                Zoom::new_clamped(2)
+            ),
+            tile(2, 3, 3)
+        );
+        assert_eq!(TileCoord::new(Zoom::new_clamped(2), 4, 0), None);
+    }
+
+    #[test]
+    fn parent_and_children() {
+        let t = tile(5, 13, 6);
+        assert_eq!(t.parent(), Some(tile(4, 6, 3)));
+        assert_eq!(t.ancestor(3), Some(tile(2, 1, 0)));
+        assert_eq!(tile(1, 1, 1).parent(), None);
+        let children = tile(4, 6, 3).children().unwrap();
+        assert_eq!(
+            children,
+            [
+                tile(5, 12, 6),
+                tile(5, 13, 6),
+                tile(5, 12, 7),
+                tile(5, 13, 7)
+            ]
+        );
+        assert!(children.iter().all(|c| c.parent() == Some(tile(4, 6, 3))));
+        assert_eq!(tile(18, 0, 0).children(), None);
+    }
+
+    #[test]
+    fn neighbors() {
+        assert_eq!(tile(3, 4, 4).neighbors().len(), 8);
+        assert_eq!(
+            tile(3, 0, 0).neighbors(),
+            vec![
+                tile(3, 7, 0),
+                tile(3, 1, 0),
+                tile(3, 7, 1),
+                tile(3, 0, 1),
+                tile(3, 1, 1)
+            ]
+        );
+        assert_eq!(tile(3, 7, 7).neighbor(1, 0), Some(tile(3, 0, 7)));
+        assert_eq!(tile(3, 7, 7).neighbor(0, 1), None);
+        assert_eq!(
+            tile(1, 0, 0).neighbors(),
+            vec![tile(1, 1, 0), tile(1, 1, 1), tile(1, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn quadkey() {
+        // Example from the Bing Maps tile system documentation
+        let t = tile(3, 3, 5);
+        assert_eq!(t.quadkey(), "213");
+        assert_eq!(TileCoord::from_quadkey("213").unwrap(), t);
+        let deep = tile(18, 46_104, 91_697);
+        assert_eq!(TileCoord::from_quadkey(&deep.quadkey()).unwrap(), deep);
+        assert!(TileCoord::from_quadkey("").is_err());
+        assert!(TileCoord::from_quadkey("124").is_err());
+        assert!(TileCoord::from_quadkey(&"0".repeat(19)).is_err());
+    }
+
+    #[test]
+    fn tms() {
+        let t = tile(3, 3, 5);
+        assert_eq!(t.tms_y(), TileNumber(2));
+        assert_eq!(TileCoord::from_tms(Zoom::new_clamped(3), 3, 2), Some(t));
+        assert_eq!(TileCoord::from_tms(Zoom::new_clamped(3), 3, 8), None);
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let t = tile(14, 2876, 5731);
+        assert_eq!(t.to_string(), "14/2876/5731");
+        assert_eq!(TileCoord::from_str("14/2876/5731").unwrap(), t);
+        assert!(TileCoord::from_str("0/0/0").is_err());
+        assert!(TileCoord::from_str("2/4/0").is_err());
+        assert!(TileCoord::from_str("2/1").is_err());
+    }
+
+    #[test]
+    fn deserialize_checks_range() {
+        let t: TileCoord = toml::from_str("zoom = 2\nx = 3\ny = 1").unwrap();
+        assert_eq!(t, tile(2, 3, 1));
+        assert_eq!(
+            toml::from_str::<TileCoord>(&toml::to_string(&t).unwrap()).unwrap(),
+            t
+        );
+        let err = toml::from_str::<TileCoord>("zoom = 2\nx = 4\ny = 1").unwrap_err();
+        assert!(err.to_string().contains("past the edge"), "{}", err);
+    }
+}
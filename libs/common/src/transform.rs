@@ -1,81 +1,246 @@
-use crate::types::{Coordinate, Scale, Zoom};
+use crate::grid::{TileGrid, Viewport};
+use crate::types::{BoundingBox, Coordinate, FractionalZoom, Longitude, Scale, ScreenAnchor};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// The center coordinate is placed at the anchor, the image center unless
+/// set otherwise. Between whole zoom levels the tiles are scaled, so a tile
+/// spans `tile_size` scaled by `FractionalZoom::tile_scale` pixels.
+/// At whole zoom levels pixels line up with the tiles of `tile_grid`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct CoordinateTransform {
-    tile_size: u32,
-    zoom: Zoom,
+    viewport: Viewport,
+    zoom: FractionalZoom,
     x_center: f64,
     y_center: f64,
-    image_width: u32,
-    image_height: u32,
+    anchor: ScreenAnchor,
+    /// Degrees clockwise the map is rotated by around the anchor
+    rotation: f64,
 }
 
 impl CoordinateTransform {
     pub fn new(
         center: &Coordinate,
         scale: Scale,
-        zoom: Zoom,
+        zoom: impl Into<FractionalZoom>,
         image_width: u32,
         image_height: u32,
     ) -> Self {
+        let viewport = Viewport::new(image_width, image_height, scale.tile_size());
+        Self::from_viewport(center, viewport, zoom)
+    }
+
+    /// For tiles whose size doesn't follow from a `Scale`
+    pub fn from_viewport(
+        center: &Coordinate,
+        viewport: Viewport,
+        zoom: impl Into<FractionalZoom>,
+    ) -> Self {
+        let zoom = zoom.into();
         CoordinateTransform {
-            tile_size: scale.tile_size(),
+            viewport,
             zoom,
             x_center: util::lon_to_x(center.longitude, zoom),
             y_center: util::lat_to_y(center.latitude, zoom),
-            image_width,
-            image_height,
+            anchor: ScreenAnchor::CENTER,
+            rotation: 0.0,
         }
     }
 
-    pub fn update(&mut self, center: &Coordinate, zoom: Zoom) {
+    /// Places the center coordinate at `anchor` instead of the image center
+    pub fn with_anchor(mut self, anchor: ScreenAnchor) -> Self {
+        self.set_anchor(anchor);
+        self
+    }
+
+    pub fn set_anchor(&mut self, anchor: ScreenAnchor) {
+        self.anchor = anchor;
+    }
+
+    pub fn anchor(&self) -> ScreenAnchor {
+        self.anchor
+    }
+
+    /// Rotates the map clockwise by `degrees` around the anchor
+    pub fn with_rotation(mut self, degrees: f64) -> Self {
+        self.set_rotation(degrees);
+        self
+    }
+
+    pub fn set_rotation(&mut self, degrees: f64) {
+        self.rotation = degrees;
+    }
+
+    pub fn rotation(&self) -> f64 {
+        self.rotation
+    }
+
+    pub fn update(&mut self, center: &Coordinate, zoom: impl Into<FractionalZoom>) {
+        let zoom = zoom.into();
         self.zoom = zoom;
         self.x_center = util::lon_to_x(center.longitude, zoom);
         self.y_center = util::lat_to_y(center.latitude, zoom);
     }
 
+    pub fn viewport(&self) -> Viewport {
+        self.viewport
+    }
+
+    /// Where the tiles go in the unrotated image, `None` between whole zoom
+    /// levels where they're scaled
+    pub fn tile_grid(&self) -> Option<TileGrid> {
+        if !self.zoom.is_whole() {
+            return None;
+        }
+        Some(TileGrid::new(
+            self.viewport,
+            self.zoom.tile_zoom(),
+            (self.x_center, self.y_center),
+            self.anchor_px(),
+        ))
+    }
+
     pub fn coordinate_to_pixel(&self, coord: &Coordinate) -> (f64, f64) {
-        let x = self.x_to_px(util::lon_to_x(coord.longitude, self.zoom));
-        let y = self.y_to_px(util::lat_to_y(coord.latitude, self.zoom));
-        (x, y)
+        let x = util::lon_to_x(coord.longitude, self.zoom);
+        let y = util::lat_to_y(coord.latitude, self.zoom);
+        let (x, y) = self.rotate_pixel(self.tile_to_px(x, y));
+        (x.round(), y.round())
+    }
+
+    /// The coordinate under image pixel `px`, the inverse of `coordinate_to_pixel`
+    /// without the rounding. Longitudes wrap around the antimeridian.
+    pub fn pixel_to_coordinate(&self, px: (f64, f64)) -> Coordinate {
+        let (x, y) = self.unrotate_pixel(px);
+        let (x, y) = self.px_to_tile(x, y);
+        Coordinate::from((util::y_to_lat(y, self.zoom), util::x_to_lon(x, self.zoom)))
     }
 
-    fn x_to_px(&self, x: f64) -> f64 {
-        let px = (x - self.x_center) * self.tile_size as f64 + self.image_width as f64 / 2f64;
-        px.round()
+    /// Smallest bounding box containing the whole image, rotated or not.
+    /// Crosses the antimeridian when the image does, covers every longitude
+    /// when the image is wider than the world and stops at the north and
+    /// south edges of the map.
+    pub fn visible_bounds(&self) -> BoundingBox {
+        let (width, height) = (
+            f64::from(self.viewport.width),
+            f64::from(self.viewport.height),
+        );
+        let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)];
+        let (mut x_min, mut x_max) = (f64::INFINITY, f64::NEG_INFINITY);
+        let (mut y_min, mut y_max) = (f64::INFINITY, f64::NEG_INFINITY);
+        for px in corners.iter() {
+            let (x, y) = self.unrotate_pixel(*px);
+            let (x, y) = self.px_to_tile(x, y);
+            x_min = x_min.min(x);
+            x_max = x_max.max(x);
+            y_min = y_min.min(y);
+            y_max = y_max.max(y);
+        }
+        let world = util::tiles_across(self.zoom);
+        let (west, east) = if x_max - x_min >= world {
+            (Longitude::MIN, Longitude::MAX)
+        } else {
+            // Wrapped separately, a west edge east of the east one crosses the antimeridian
+            (
+                util::x_to_lon(x_min, self.zoom),
+                util::x_to_lon(x_max, self.zoom),
+            )
+        };
+        let lat = |y: f64| util::y_to_lat(y.clamp(0.0, world), self.zoom).clamped_to_mercator();
+        BoundingBox {
+            west,
+            south: lat(y_max),
+            east,
+            north: lat(y_min),
+        }
     }
 
-    fn y_to_px(&self, y: f64) -> f64 {
-        let px = (y - self.y_center) * self.tile_size as f64 + self.image_height as f64 / 2f64;
-        px.round()
+    pub fn center(&self) -> Coordinate {
+        Coordinate::from((
+            util::y_to_lat(self.y_center, self.zoom),
+            util::x_to_lon(self.x_center, self.zoom),
+        ))
+    }
+
+    /// Ground distance covered by a pixel at the center latitude
+    pub fn meters_per_pixel(&self) -> f64 {
+        util::meters_per_pixel(self.center().latitude, self.zoom, self.viewport.tile_size)
+    }
+
+    /// From an unrotated, north-up, image pixel to where it is on the rotated image
+    pub fn rotate_pixel(&self, px: (f64, f64)) -> (f64, f64) {
+        util::rotate(px, self.anchor_px(), self.rotation)
+    }
+
+    /// From a rotated image pixel back to where it is on the unrotated,
+    /// north-up, image
+    pub fn unrotate_pixel(&self, px: (f64, f64)) -> (f64, f64) {
+        util::rotate(px, self.anchor_px(), -self.rotation)
+    }
+
+    fn anchor_px(&self) -> (f64, f64) {
+        self.anchor
+            .to_pixels(self.viewport.width, self.viewport.height)
+    }
+
+    /// Unrotated image pixel position of (`x`, `y`) in tiles
+    fn tile_to_px(&self, x: f64, y: f64) -> (f64, f64) {
+        if let Some(grid) = self.tile_grid() {
+            return grid.tile_to_px(x, y);
+        }
+        let tile_size = f64::from(self.viewport.tile_size);
+        let (anchor_x, anchor_y) = self.anchor_px();
+        (
+            (x - self.x_center) * tile_size + anchor_x,
+            (y - self.y_center) * tile_size + anchor_y,
+        )
+    }
+
+    fn px_to_tile(&self, x: f64, y: f64) -> (f64, f64) {
+        if let Some(grid) = self.tile_grid() {
+            return grid.px_to_tile(x, y);
+        }
+        let tile_size = f64::from(self.viewport.tile_size);
+        let (anchor_x, anchor_y) = self.anchor_px();
+        (
+            (x - anchor_x) / tile_size + self.x_center,
+            (y - anchor_y) / tile_size + self.y_center,
+        )
     }
 }
 
 pub mod util {
-    use crate::types::{Latitude, Longitude, Zoom};
+    use crate::types::{BoundingBox, Coordinate, FractionalZoom, Latitude, Longitude, Zoom};
     use std::f64::consts::PI;
 
-    pub fn lon_to_x(mut lon: Longitude, zoom: Zoom) -> f64 {
-        if !(-180_f64..180_f64).contains(&lon.0) {
-            lon.0 = (lon.0 + 180_f64) % 360_f64 - 180_f64;
+    /// WGS84 equatorial radius, the sphere Web Mercator projects
+    pub const EARTH_RADIUS_METERS: f64 = 6_378_137.0;
+
+    /// Number of tiles across the world at `zoom`, not a whole number between levels
+    pub(crate) fn tiles_across(zoom: impl Into<FractionalZoom>) -> f64 {
+        2_f64.powf(zoom.into().get())
+    }
+
+    pub fn lon_to_x(lon: Longitude, zoom: impl Into<FractionalZoom>) -> f64 {
+        let mut lon = lon.get();
+        if !(-180_f64..180_f64).contains(&lon) {
+            lon = (lon + 180_f64) % 360_f64 - 180_f64;
         }
 
-        ((lon.0 + 180_f64) / 360_f64) * 2_f64.powi(zoom.get().into())
+        ((lon + 180_f64) / 360_f64) * tiles_across(zoom)
     }
 
-    pub fn lat_to_y(mut lat: Latitude, zoom: Zoom) -> f64 {
-        if !(-90_f64..90_f64).contains(&lat.0) {
-            lat.0 = (lat.0 + 90_f64) % 180_f64 - 90_f64;
+    pub fn lat_to_y(lat: Latitude, zoom: impl Into<FractionalZoom>) -> f64 {
+        let mut lat = lat.get();
+        if !(-90_f64..90_f64).contains(&lat) {
+            lat = (lat + 90_f64) % 180_f64 - 90_f64;
         }
 
-        (1_f64 - ((lat.0 * PI / 180_f64).tan() + 1_f64 / (lat.0 * PI / 180_f64).cos()).ln() / PI)
+        (1_f64 - ((lat * PI / 180_f64).tan() + 1_f64 / (lat * PI / 180_f64).cos()).ln() / PI)
             / 2_f64
-            * 2_f64.powi(zoom.get().into())
+            * tiles_across(zoom)
     }
 
-    pub fn y_to_lat(y: f64, zoom: Zoom) -> Latitude {
-        Latitude(
-            (PI * (1_f64 - 2_f64 * y / 2_f64.powi(zoom.get().into())))
+    pub fn y_to_lat(y: f64, zoom: impl Into<FractionalZoom>) -> Latitude {
+        Latitude::new_clamped(
+            (PI * (1_f64 - 2_f64 * y / tiles_across(zoom)))
                 .sinh()
                 .atan()
                 / PI
@@ -83,7 +248,347 @@ pub mod util {
         )
     }
 
-    pub fn x_to_lon(x: f64, zoom: Zoom) -> Longitude {
-        Longitude(x / 2_f64.powi(zoom.get().into()) * 360_f64 - 180_f64)
+    /// Wraps around the antimeridian when `x` is past the edge of the world
+    pub fn x_to_lon(x: f64, zoom: impl Into<FractionalZoom>) -> Longitude {
+        let lon = x / tiles_across(zoom) * 360_f64 - 180_f64;
+        Longitude::new(lon).unwrap_or_else(|_| Longitude::new_wrapped(lon))
+    }
+
+    /// Ground distance covered by a pixel at latitude `lat`, with tiles of
+    /// `tile_size` pixels
+    pub fn meters_per_pixel(lat: Latitude, zoom: impl Into<FractionalZoom>, tile_size: u32) -> f64 {
+        2_f64 * PI * EARTH_RADIUS_METERS * lat.get().to_radians().cos()
+            / (tiles_across(zoom) * tile_size as f64)
+    }
+
+    /// `center` moved by (`dx`, `dy`) pixels, positive being right and down,
+    /// on a north-up map with tiles of `tile_size` pixels. Wraps around the
+    /// antimeridian and stops at the north and south edges of the map.
+    pub fn pan(
+        center: &Coordinate,
+        dx: f64,
+        dy: f64,
+        zoom: impl Into<FractionalZoom>,
+        tile_size: u32,
+    ) -> Coordinate {
+        let zoom = zoom.into();
+        let lat = center.latitude.clamped_to_mercator();
+        let x = lon_to_x(center.longitude, zoom) + dx / tile_size as f64;
+        let y = (lat_to_y(lat, zoom) + dy / tile_size as f64).clamp(0.0, tiles_across(zoom));
+        Coordinate {
+            latitude: y_to_lat(y, zoom).clamped_to_mercator(),
+            longitude: Longitude::new_wrapped(x_to_lon(x, zoom).get()),
+        }
+    }
+
+    /// Rotates `point` clockwise by `degrees` around `pivot`, in image
+    /// coordinates where y grows downwards
+    pub fn rotate(point: (f64, f64), pivot: (f64, f64), degrees: f64) -> (f64, f64) {
+        if degrees == 0.0 {
+            return point;
+        }
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (dx, dy) = (point.0 - pivot.0, point.1 - pivot.1);
+        (pivot.0 + dx * cos - dy * sin, pivot.1 + dx * sin + dy * cos)
+    }
+
+    /// Center and the largest zoom level at which the bounding box fits
+    /// in an image of `width` x `height` pixels
+    pub fn fit_bounds(
+        bbox: &BoundingBox,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+    ) -> (Coordinate, Zoom) {
+        let mut zoom = Zoom::MAX;
+        while zoom > Zoom::MIN {
+            let w = (lon_to_x(bbox.east, zoom) - lon_to_x(bbox.west, zoom)) * tile_size as f64;
+            let h = (lat_to_y(bbox.south, zoom) - lat_to_y(bbox.north, zoom)) * tile_size as f64;
+            if w <= width as f64 && h <= height as f64 {
+                break;
+            }
+            zoom.decrement();
+        }
+        // Center in projected space, not the average latitude
+        let x = (lon_to_x(bbox.west, zoom) + lon_to_x(bbox.east, zoom)) / 2.0;
+        let y = (lat_to_y(bbox.north, zoom) + lat_to_y(bbox.south, zoom)) / 2.0;
+        (
+            Coordinate::from((y_to_lat(y, zoom), x_to_lon(x, zoom))),
+            zoom,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Latitude, Zoom};
+
+    #[test]
+    fn fit_bounds() {
+        let bbox = BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap();
+        let (center, zoom) = util::fit_bounds(&bbox, 800, 600, 256);
+        assert_eq!(zoom, Zoom::new_clamped(15));
+        assert!((center.longitude.get() - -116.785).abs() < 1e-9);
+        assert!(center.latitude.get() > 47.45 && center.latitude.get() < 47.46);
+
+        let t = CoordinateTransform::new(&center, Scale::One, zoom, 800, 600);
+        for c in [
+            Coordinate::new(47.45, -116.80).unwrap(),
+            Coordinate::new(47.46, -116.77).unwrap(),
+        ]
+        .iter()
+        {
+            let (x, y) = t.coordinate_to_pixel(c);
+            assert!((0.0..=800.0).contains(&x), "x={}", x);
+            assert!((0.0..=600.0).contains(&y), "y={}", y);
+        }
+
+        // Doesn't fit at any zoom level
+        let world = BoundingBox::new(-180.0, -85.0, 179.9, 85.0).unwrap();
+        assert_eq!(util::fit_bounds(&world, 64, 64, 256).1, Zoom::MIN);
+    }
+
+    #[test]
+    fn fractional_zoom_scales_pixels() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let east = Coordinate::new(47.453551, -116.78).unwrap();
+        let zoom = FractionalZoom::new_clamped(15.5);
+        assert_eq!(zoom.tile_zoom(), Zoom::new_clamped(16));
+        assert!((zoom.tile_scale() - 0.5_f64.sqrt()).abs() < 1e-12);
+
+        let whole = CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(15), 800, 600);
+        let half = CoordinateTransform::new(&center, Scale::One, zoom, 800, 600);
+        let (x_whole, _) = whole.coordinate_to_pixel(&east);
+        let (x_half, _) = half.coordinate_to_pixel(&east);
+        let ratio = (x_half - 400.0) / (x_whole - 400.0);
+        assert!((ratio - 2_f64.sqrt()).abs() < 0.01, "ratio={}", ratio);
+
+        let x = util::lon_to_x(east.longitude, zoom);
+        assert!((util::x_to_lon(x, zoom).get() - east.longitude.get()).abs() < 1e-9);
+        let y = util::lat_to_y(east.latitude, zoom);
+        assert!((util::y_to_lat(y, zoom).get() - east.latitude.get()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rotated_pixels() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let east = Coordinate::new(47.453551, -116.78).unwrap();
+        let north = Coordinate::new(47.46, -116.788118).unwrap();
+        let north_up =
+            CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(15), 800, 600);
+        let (ex, ey) = north_up.coordinate_to_pixel(&east);
+        assert!(ex > 400.0 && ey == 300.0);
+
+        // Heading east, east is up and north is to the left
+        let heading_up = north_up.clone().with_rotation(-90.0);
+        let (x, y) = heading_up.coordinate_to_pixel(&east);
+        assert_eq!((x, y), (400.0, 300.0 - (ex - 400.0)));
+        let (x, y) = heading_up.coordinate_to_pixel(&north);
+        assert!(x < 400.0 && (y - 300.0).abs() <= 1.0, "{}, {}", x, y);
+        assert_eq!(heading_up.coordinate_to_pixel(&center), (400.0, 300.0));
+
+        // Both ways and past a full turn
+        for degrees in [-270.0, -45.0, 30.0, 135.0, 400.0].iter() {
+            let t = north_up.clone().with_rotation(*degrees);
+            let (x, y) = t.unrotate_pixel(t.rotate_pixel((123.0, 456.0)));
+            assert!((x - 123.0).abs() < 1e-9 && (y - 456.0).abs() < 1e-9);
+        }
+        let quarter = north_up.with_rotation(90.0);
+        let (x, y) = quarter.rotate_pixel((500.0, 300.0));
+        assert!((x - 400.0).abs() < 1e-9 && (y - 400.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pixel_to_coordinate() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let t = CoordinateTransform::new(
+            &center,
+            Scale::One,
+            FractionalZoom::new_clamped(14.5),
+            800,
+            600,
+        )
+        .with_anchor(ScreenAnchor::new_clamped(0.5, 0.75))
+        .with_rotation(-30.0);
+        let c = t.pixel_to_coordinate((400.0, 450.0));
+        assert!((c.latitude.get() - center.latitude.get()).abs() < 1e-9);
+        assert!((c.longitude.get() - center.longitude.get()).abs() < 1e-9);
+        assert!((t.center().latitude.get() - center.latitude.get()).abs() < 1e-9);
+
+        for px in [(0.0, 0.0), (123.0, 456.0), (800.0, 600.0)].iter() {
+            let c = t.pixel_to_coordinate(*px);
+            let (x, y) = t.coordinate_to_pixel(&c);
+            assert!(
+                (x - px.0).abs() <= 0.5 && (y - px.1).abs() <= 0.5,
+                "{:?}",
+                px
+            );
+        }
+    }
+
+    #[test]
+    fn visible_bounds() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let t = CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(15), 800, 600);
+        let bounds = t.visible_bounds();
+        let top_left = t.pixel_to_coordinate((0.0, 0.0));
+        let bottom_right = t.pixel_to_coordinate((800.0, 600.0));
+        assert_eq!(bounds.west, top_left.longitude);
+        assert_eq!(bounds.north, top_left.latitude);
+        assert_eq!(bounds.east, bottom_right.longitude);
+        assert_eq!(bounds.south, bottom_right.latitude);
+
+        // A quarter turn swaps the extents
+        let rotated = t.with_rotation(90.0).visible_bounds();
+        let ratio = rotated.width() / bounds.width();
+        assert!((ratio - 600.0 / 800.0).abs() < 1e-6, "ratio={}", ratio);
+    }
+
+    #[test]
+    fn visible_bounds_at_the_edges() {
+        // 800 pixels is 800 / 256 / 2^5 * 360 = 35.2 degrees at zoom 5
+        let center = Coordinate::new(0.0, 179.0).unwrap();
+        let t = CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(5), 800, 600);
+        let bounds = t.visible_bounds();
+        assert!(bounds.crosses_antimeridian(), "{}", bounds);
+        assert!((bounds.width() - 35.15625).abs() < 1e-6, "{}", bounds);
+        // Within a pixel, the tile grid is placed on whole pixels
+        let pixel = 35.15625 / 800.0;
+        assert!((bounds.west.get() - (179.0 - 35.15625 / 2.0)).abs() < pixel);
+        assert!((bounds.east.get() - (179.0 + 35.15625 / 2.0 - 360.0)).abs() < pixel);
+        assert!(bounds.contains(&Coordinate::new(0.0, -179.0).unwrap()));
+        assert!(!bounds.contains(&Coordinate::new(0.0, 0.0).unwrap()));
+
+        // Wider and taller than the whole world at zoom 1
+        let t = CoordinateTransform::new(&center, Scale::One, Zoom::MIN, 800, 600);
+        let bounds = t.visible_bounds();
+        assert_eq!((bounds.west, bounds.east), (Longitude::MIN, Longitude::MAX));
+        assert_eq!(
+            (bounds.south, bounds.north),
+            (Latitude::MERCATOR_MIN, Latitude::MERCATOR_MAX)
+        );
+    }
+
+    #[test]
+    fn meters_per_pixel() {
+        // 156543.03 m/px at zoom 0 on the equator for 256 pixel tiles
+        let zoom = FractionalZoom::new_clamped(1.0);
+        let equator = util::meters_per_pixel(Latitude::new(0.0).unwrap(), zoom, 256);
+        assert!((equator - 156_543.034 / 2.0).abs() < 1e-3, "{}", equator);
+        let sixty = util::meters_per_pixel(Latitude::new(60.0).unwrap(), zoom, 256);
+        assert!((sixty - equator / 2.0).abs() < 1e-6);
+        let big_tiles = util::meters_per_pixel(Latitude::new(0.0).unwrap(), zoom, 512);
+        assert!((big_tiles - equator / 2.0).abs() < 1e-6);
+
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let t = CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(15), 800, 600);
+        let expected = util::meters_per_pixel(center.latitude, Zoom::new_clamped(15), 256);
+        assert!((t.meters_per_pixel() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pixels_line_up_with_tiles() {
+        // The tile grid origin gets rounded from -623.6 to -624, a point 10.3
+        // pixels into tile (4, 4) is drawn 10 pixels into it, not 11
+        let zoom = Zoom::new_clamped(3);
+        let at =
+            |x: f64, y: f64| Coordinate::from((util::y_to_lat(y, zoom), util::x_to_lon(x, zoom)));
+        let center = at(4.0 + 0.1 / 256.0, 4.25);
+        let t = CoordinateTransform::new(&center, Scale::One, zoom, 801, 600);
+        let grid = t.tile_grid().unwrap();
+        assert_eq!(grid.origin().0, -624);
+        let (x, y) = grid.tile_offset(4, 4);
+        let (px, py) = t.coordinate_to_pixel(&at(4.0 + 10.3 / 256.0, 4.5));
+        assert_eq!((px, py), (f64::from(x + 10), f64::from(y + 128)));
+        assert_eq!(t.coordinate_to_pixel(&at(5.0, 5.0)), {
+            let (x, y) = grid.tile_offset(5, 5);
+            (f64::from(x), f64::from(y))
+        });
+
+        assert!(CoordinateTransform::new(
+            &center,
+            Scale::One,
+            FractionalZoom::new_clamped(3.5),
+            800,
+            600
+        )
+        .tile_grid()
+        .is_none());
+    }
+
+    #[test]
+    fn pan() {
+        let zoom = Zoom::new_clamped(10);
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let t = CoordinateTransform::new(&center, Scale::One, zoom, 800, 600);
+
+        // The same number of pixels on screen, at any zoom
+        let moved = util::pan(&center, 100.0, -50.0, zoom, 256);
+        let (x, y) = t.coordinate_to_pixel(&moved);
+        assert!(
+            (x - 500.0).abs() <= 1.0 && (y - 250.0).abs() <= 1.0,
+            "{}, {}",
+            x,
+            y
+        );
+        let back = util::pan(&moved, -100.0, 50.0, zoom, 256);
+        assert!((back.latitude.get() - center.latitude.get()).abs() < 1e-9);
+        assert!((back.longitude.get() - center.longitude.get()).abs() < 1e-9);
+        let zoomed = util::pan(&center, 100.0, 0.0, Zoom::new_clamped(11), 256);
+        let d_lon = |c: &Coordinate| c.longitude.get() - center.longitude.get();
+        assert!((d_lon(&moved) / d_lon(&zoomed) - 2.0).abs() < 1e-9);
+
+        // Across the antimeridian, a quarter of the world at zoom 1
+        let east = Coordinate::new(0.0, 179.0).unwrap();
+        let wrapped = util::pan(&east, 128.0, 0.0, Zoom::new_clamped(1), 256);
+        assert!(
+            (wrapped.longitude.get() - -91.0).abs() < 1e-9,
+            "{}",
+            wrapped
+        );
+        let wrapped = util::pan(
+            &Coordinate::new(0.0, -179.0).unwrap(),
+            -128.0,
+            0.0,
+            Zoom::new_clamped(1),
+            256,
+        );
+        assert!((wrapped.longitude.get() - 91.0).abs() < 1e-9, "{}", wrapped);
+
+        // Stops at the top and bottom edges
+        let north = util::pan(&center, 0.0, -1e9, zoom, 256);
+        assert_eq!(north.latitude, Latitude::MERCATOR_MAX);
+        let south = util::pan(&center, 0.0, 1e9, zoom, 256);
+        assert_eq!(south.latitude, Latitude::MERCATOR_MIN);
+        let pole = util::pan(&Coordinate::new(90.0, 0.0).unwrap(), 0.0, 10.0, zoom, 256);
+        assert!(pole.latitude < Latitude::MERCATOR_MAX);
+    }
+
+    #[test]
+    fn anchored_pixels() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let east = Coordinate::new(47.453551, -116.78).unwrap();
+        let centered =
+            CoordinateTransform::new(&center, Scale::One, Zoom::new_clamped(15), 800, 600);
+        let (ex, _) = centered.coordinate_to_pixel(&east);
+
+        // Lower third, the rest of the map moves along with the center
+        let anchor = ScreenAnchor::new_clamped(0.5, 2.0 / 3.0);
+        let anchored = centered.clone().with_anchor(anchor);
+        assert_eq!(anchored.coordinate_to_pixel(&center), (400.0, 400.0));
+        assert_eq!(anchored.coordinate_to_pixel(&east), (ex, 400.0));
+
+        // Rotates around the anchor
+        let rotated = anchored.with_rotation(-90.0);
+        assert_eq!(rotated.coordinate_to_pixel(&center), (400.0, 400.0));
+        assert_eq!(
+            rotated.coordinate_to_pixel(&east),
+            (400.0, 400.0 - (ex - 400.0))
+        );
+        assert_eq!(
+            ScreenAnchor::new_clamped(f64::NAN, 3.0),
+            ScreenAnchor { x: 0.5, y: 1.0 }
+        );
     }
 }
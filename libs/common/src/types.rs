@@ -1,22 +1,44 @@
+use crate::notation::{parse_coordinate, CoordinateParseError};
+use crate::tile::{tile_number, TileCoord};
+use crate::transform::util::lat_to_y;
 use err_derive::Error;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 use std::{fmt, num};
 
 // TODO - write up some newtype helper macros to do trait impls
 // impl approx::RelEqual+ traits for lat/lon/coord
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
-pub struct Latitude(pub f64);
+/// Degrees north of the equator, from -90 to 90
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Serialize)]
+pub struct Latitude(f64);
 
 impl Latitude {
     pub const MIN: Latitude = Latitude(-90.0);
     pub const MAX: Latitude = Latitude(90.0);
+    /// Web Mercator only goes this far north and south, the map is square
+    pub const MERCATOR_MIN: Latitude = Latitude(-85.051_128_779_806_59);
+    pub const MERCATOR_MAX: Latitude = Latitude(85.051_128_779_806_59);
+
+    /// Error if `val` is NaN or outside of [-90, 90]
+    pub fn new(val: f64) -> Result<Self, LatitudeRangeError> {
+        if (Self::MIN.0..=Self::MAX.0).contains(&val) {
+            Ok(Self(val))
+        } else {
+            Err(LatitudeRangeError(val))
+        }
+    }
 
     pub fn new_clamped(val: f64) -> Self {
         Self(val.clamp(Self::MIN.0, Self::MAX.0))
     }
 
+    /// Clamped to the Web Mercator range, closer to the poles doesn't project
+    pub fn clamped_to_mercator(&self) -> Self {
+        Self(self.0.clamp(Self::MERCATOR_MIN.0, Self::MERCATOR_MAX.0))
+    }
+
     pub const fn get(&self) -> f64 {
         self.0
     }
@@ -30,35 +52,54 @@ impl Latitude {
     }
 }
 
-impl From<f64> for Latitude {
-    fn from(l: f64) -> Self {
-        Latitude(l)
-    }
-}
-
 impl From<Latitude> for f64 {
     fn from(l: Latitude) -> Self {
         l.0
     }
 }
 
+impl<'de> Deserialize<'de> for Latitude {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Latitude::new(f64::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error(display = "Latitude {} is not between -90 and 90 degrees", _0)]
+pub struct LatitudeRangeError(pub f64);
+
 impl fmt::Display for Latitude {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
-pub struct Longitude(pub f64);
+/// Degrees east of the prime meridian, from -180 to 180
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Serialize)]
+pub struct Longitude(f64);
 
 impl Longitude {
     pub const MIN: Longitude = Longitude(-180.0);
     pub const MAX: Longitude = Longitude(180.0);
 
+    /// Error if `val` is NaN or outside of [-180, 180]
+    pub fn new(val: f64) -> Result<Self, LongitudeRangeError> {
+        if (Self::MIN.0..=Self::MAX.0).contains(&val) {
+            Ok(Self(val))
+        } else {
+            Err(LongitudeRangeError(val))
+        }
+    }
+
     pub fn new_clamped(val: f64) -> Self {
         Self(val.clamp(Self::MIN.0, Self::MAX.0))
     }
 
+    /// Wraps around the antimeridian into [-180, 180)
+    pub fn new_wrapped(val: f64) -> Self {
+        Self((val + 180.0).rem_euclid(360.0) - 180.0)
+    }
+
     pub const fn get(&self) -> f64 {
         self.0
     }
@@ -72,24 +113,37 @@ impl Longitude {
     }
 }
 
-impl From<f64> for Longitude {
-    fn from(l: f64) -> Self {
-        Longitude(l)
-    }
-}
-
 impl From<Longitude> for f64 {
     fn from(l: Longitude) -> Self {
         l.0
     }
 }
 
+impl<'de> Deserialize<'de> for Longitude {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Longitude::new(f64::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error(display = "Longitude {} is not between -180 and 180 degrees", _0)]
+pub struct LongitudeRangeError(pub f64);
+
 impl fmt::Display for Longitude {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+pub enum CoordinateRangeError {
+    #[error(display = "{}", _0)]
+    Latitude(#[error(source)] LatitudeRangeError),
+
+    #[error(display = "{}", _0)]
+    Longitude(#[error(source)] LongitudeRangeError),
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct Coordinate {
     pub latitude: Latitude,
@@ -97,11 +151,12 @@ pub struct Coordinate {
 }
 
 impl Coordinate {
-    pub fn new<Lat: Into<Latitude>, Lon: Into<Longitude>>(latitude: Lat, longitude: Lon) -> Self {
-        Coordinate {
-            latitude: latitude.into(),
-            longitude: longitude.into(),
-        }
+    /// Error if either is NaN or out of range, see `Latitude::new` and `Longitude::new`
+    pub fn new(latitude: f64, longitude: f64) -> Result<Self, CoordinateRangeError> {
+        Ok(Coordinate {
+            latitude: Latitude::new(latitude)?,
+            longitude: Longitude::new(longitude)?,
+        })
     }
 }
 
@@ -114,12 +169,244 @@ impl From<(Latitude, Longitude)> for Coordinate {
     }
 }
 
+/// Decimal degrees, DMS, DDM, UTM or MGRS, see `CoordinateFormatter`
+impl FromStr for Coordinate {
+    type Err = CoordinateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_coordinate(s)
+    }
+}
+
 impl fmt::Display for Coordinate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}, {}", self.latitude, self.longitude)
     }
 }
 
+/// Geographic bounding box. One with its west edge east of its east edge
+/// crosses the antimeridian, covering west to 180 and -180 to east.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct BoundingBox {
+    pub west: Longitude,
+    pub south: Latitude,
+    pub east: Longitude,
+    pub north: Latitude,
+}
+
+impl BoundingBox {
+    /// Error if any edge is NaN or out of range
+    pub fn new(west: f64, south: f64, east: f64, north: f64) -> Result<Self, CoordinateRangeError> {
+        Ok(BoundingBox {
+            west: Longitude::new(west)?,
+            south: Latitude::new(south)?,
+            east: Longitude::new(east)?,
+            north: Latitude::new(north)?,
+        })
+    }
+
+    /// Smallest bounding box containing all of the coordinates, `None` if
+    /// empty. Never crosses the antimeridian.
+    pub fn from_coordinates<I: IntoIterator<Item = Coordinate>>(coords: I) -> Option<Self> {
+        coords.into_iter().fold(None, |bbox, c| {
+            Some(match bbox {
+                None => BoundingBox {
+                    west: c.longitude,
+                    south: c.latitude,
+                    east: c.longitude,
+                    north: c.latitude,
+                },
+                Some(b) => BoundingBox {
+                    west: Longitude(b.west.0.min(c.longitude.0)),
+                    south: Latitude(b.south.0.min(c.latitude.0)),
+                    east: Longitude(b.east.0.max(c.longitude.0)),
+                    north: Latitude(b.north.0.max(c.latitude.0)),
+                },
+            })
+        })
+    }
+
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west.0 > self.east.0
+    }
+
+    /// Degrees of longitude from the west edge east to the east edge
+    pub fn width(&self) -> f64 {
+        if self.crosses_antimeridian() {
+            self.east.0 - self.west.0 + 360.0
+        } else {
+            self.east.0 - self.west.0
+        }
+    }
+
+    pub fn center(&self) -> Coordinate {
+        Coordinate {
+            latitude: Latitude((self.south.0 + self.north.0) / 2.0),
+            longitude: Longitude(wrap_longitude(self.west.0 + self.width() / 2.0)),
+        }
+    }
+
+    /// Grows the box by roughly `meters` on every side, using a spherical
+    /// earth approximation. Latitudes are clamped to the valid range, while
+    /// longitudes wrap around the antimeridian and cover the whole range once
+    /// the box is wider than the world.
+    pub fn expand_by_meters(&self, meters: f64) -> Self {
+        const METERS_PER_DEGREE: f64 = 111_320.0;
+        let d_lat = meters / METERS_PER_DEGREE;
+        // Longitude degrees shrink with latitude, use the widest edge
+        let max_lat = self.south.0.abs().max(self.north.0.abs()) + d_lat;
+        let cos_lat = max_lat.min(89.0).to_radians().cos();
+        let d_lon = meters / (METERS_PER_DEGREE * cos_lat);
+        // Edges still in range aren't wrapped, an east edge of 180 stays there
+        let wrap = |lon: f64| Longitude::new(lon).unwrap_or_else(|_| Longitude::new_wrapped(lon));
+        let (west, east) = if self.width() + 2.0 * d_lon >= 360.0 {
+            (Longitude::MIN, Longitude::MAX)
+        } else {
+            (wrap(self.west.0 - d_lon), wrap(self.east.0 + d_lon))
+        };
+        BoundingBox {
+            west,
+            south: Latitude::new_clamped(self.south.0 - d_lat),
+            east,
+            north: Latitude::new_clamped(self.north.0 + d_lat),
+        }
+    }
+
+    /// Whether `coord` is inside the box or on its edge
+    pub fn contains(&self, coord: &Coordinate) -> bool {
+        self.longitude_ranges()
+            .iter()
+            .any(|lons| lons.contains(&coord.longitude.0))
+            && (self.south.0..=self.north.0).contains(&coord.latitude.0)
+    }
+
+    /// Whether the boxes overlap, touching edges count
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        let lons = other.longitude_ranges();
+        self.longitude_ranges().iter().any(|a| {
+            lons.iter()
+                .any(|b| a.start() <= b.end() && b.start() <= a.end())
+        }) && self.south.0 <= other.north.0
+            && other.south.0 <= self.north.0
+    }
+
+    /// Smallest bounding box containing both boxes, which crosses the
+    /// antimeridian when that's narrower than going around the other way
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        // Going east from either west edge, to the farther east edge
+        let span = |from: &BoundingBox, to: &BoundingBox| {
+            let to_width = (to.west.0 - from.west.0).rem_euclid(360.0) + to.width();
+            if from.width() >= to_width {
+                (from.width(), from.west.0, from.east.0)
+            } else {
+                (to_width, from.west.0, to.east.0)
+            }
+        };
+        let (a, b) = (span(self, other), span(other, self));
+        let (width, west, east) = if a.0 <= b.0 { a } else { b };
+        let (west, east) = if width >= 360.0 {
+            (Longitude::MIN.0, Longitude::MAX.0)
+        } else {
+            (west, east)
+        };
+        BoundingBox {
+            west: Longitude(west),
+            south: Latitude(self.south.0.min(other.south.0)),
+            east: Longitude(east),
+            north: Latitude(self.north.0.max(other.north.0)),
+        }
+    }
+
+    /// Ranges of the tile numbers (x, y) covering the box at `zoom`, two x
+    /// ranges when the box crosses the antimeridian
+    pub fn tile_ranges(&self, zoom: Zoom) -> (Vec<RangeInclusive<u32>>, RangeInclusive<u32>) {
+        // Not lon_to_x, which wraps an east edge of 180 around to the first column
+        let lon_to_x = |lon: Longitude| (lon.0 + 180.0) / 360.0 * f64::from(1_u32 << zoom.get());
+        let lat_to_y = |lat: Latitude| tile_number(lat_to_y(lat.clamped_to_mercator(), zoom), zoom);
+        let x_ranges = self
+            .longitude_ranges()
+            .into_iter()
+            .map(|lons| {
+                tile_number(lon_to_x(Longitude(*lons.start())), zoom)
+                    ..=tile_number(lon_to_x(Longitude(*lons.end())), zoom)
+            })
+            .collect();
+        // Tile y numbers increase southward
+        let y_range = lat_to_y(self.north)..=lat_to_y(self.south);
+        (x_ranges, y_range)
+    }
+
+    /// Tiles covering the box at `zoom`, column by column from the north
+    /// west, or from the antimeridian westward for boxes crossing it
+    pub fn tiles(&self, zoom: Zoom) -> impl Iterator<Item = TileCoord> {
+        let (x_ranges, y_range) = self.tile_ranges(zoom);
+        x_ranges.into_iter().flatten().flat_map(move |x| {
+            y_range.clone().map(move |y| TileCoord {
+                zoom,
+                x: TileNumber(x),
+                y: TileNumber(y),
+            })
+        })
+    }
+
+    /// Inclusive ranges of the longitudes in the box, split at the antimeridian
+    fn longitude_ranges(&self) -> Vec<RangeInclusive<f64>> {
+        if self.crosses_antimeridian() {
+            vec![
+                self.west.0..=Longitude::MAX.0,
+                Longitude::MIN.0..=self.east.0,
+            ]
+        } else {
+            vec![self.west.0..=self.east.0]
+        }
+    }
+}
+
+/// Into -180..=180, 180 itself isn't wrapped
+fn wrap_longitude(lon: f64) -> f64 {
+    if lon > 180.0 {
+        lon - 360.0
+    } else {
+        lon
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BoundingBoxParseError {
+    #[error(display = "Failed to parse bounding box, expected west,south,east,north")]
+    Format,
+
+    #[error(display = "Invalid bounding box, {}", _0)]
+    Range(#[error(source)] CoordinateRangeError),
+}
+
+/// Parses `west,south,east,north`, the MBTiles metadata bounds format
+impl FromStr for BoundingBox {
+    type Err = BoundingBoxParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| BoundingBoxParseError::Format)?;
+        match vals.as_slice() {
+            [west, south, east, north] => Ok(BoundingBox::new(*west, *south, *east, *north)?),
+            _ => Err(BoundingBoxParseError::Format),
+        }
+    }
+}
+
+impl fmt::Display for BoundingBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.west, self.south, self.east, self.north
+        )
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
 pub struct TileNumber(pub u32);
 
@@ -244,13 +531,137 @@ impl fmt::Display for Daylight {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
+/// Pattern drawn in place of tiles that failed to load
+#[derive(
+    Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Deserialize, Serialize,
+)]
+pub enum TilePlaceholder {
+    /// Solid gray
+    Blank,
+    /// Alternating light and dark gray squares
+    #[default]
+    Checkerboard,
+    /// Light gray with dark gray grid lines
+    Grid,
+}
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse tile placeholder")]
+pub struct TilePlaceholderParseError;
+
+impl FromStr for TilePlaceholder {
+    type Err = TilePlaceholderParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "blank" => Ok(TilePlaceholder::Blank),
+            "checkerboard" => Ok(TilePlaceholder::Checkerboard),
+            "grid" => Ok(TilePlaceholder::Grid),
+            _ => Err(TilePlaceholderParseError),
+        }
+    }
+}
+
+impl fmt::Display for TilePlaceholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TilePlaceholder::Blank => f.write_str("blank"),
+            TilePlaceholder::Checkerboard => f.write_str("checkerboard"),
+            TilePlaceholder::Grid => f.write_str("grid"),
+        }
+    }
+}
+
+/// Which way is up on the map
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Deserialize, Serialize)]
+pub enum MapOrientation {
+    /// North is up, the map doesn't rotate
+    #[default]
+    NorthUp,
+    /// The vehicle heading is up, the map rotates around the vehicle as it turns
+    HeadingUp,
+}
+
+impl MapOrientation {
+    pub fn toggled(&self) -> Self {
+        match self {
+            MapOrientation::NorthUp => MapOrientation::HeadingUp,
+            MapOrientation::HeadingUp => MapOrientation::NorthUp,
+        }
+    }
+
+    /// Clockwise map rotation in degrees for a vehicle heading of `heading`
+    /// degrees clockwise from north
+    pub fn rotation(&self, heading: f64) -> f64 {
+        match self {
+            MapOrientation::NorthUp => 0.0,
+            MapOrientation::HeadingUp => -heading,
+        }
+    }
+}
+
+impl fmt::Display for MapOrientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapOrientation::NorthUp => f.write_str("north-up"),
+            MapOrientation::HeadingUp => f.write_str("heading-up"),
+        }
+    }
+}
+
+/// Point on the screen, or an image, in fractions of its width and height
+/// from the top left corner
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct ScreenAnchor {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl ScreenAnchor {
+    pub const CENTER: ScreenAnchor = ScreenAnchor { x: 0.5, y: 0.5 };
+
+    /// Keeps the anchor on the screen, NaN is clamped to the center
+    pub fn new_clamped(x: f64, y: f64) -> Self {
+        let clamp = |v: f64| if v.is_nan() { 0.5 } else { v.clamp(0.0, 1.0) };
+        ScreenAnchor {
+            x: clamp(x),
+            y: clamp(y),
+        }
+    }
+
+    /// Pixel position on a `width` x `height` screen
+    pub fn to_pixels(&self, width: u32, height: u32) -> (f64, f64) {
+        (self.x * f64::from(width), self.y * f64::from(height))
+    }
+
+    /// The anchor at pixel position (`x`, `y`) on a `width` x `height` screen
+    pub fn from_pixels(x: f64, y: f64, width: u32, height: u32) -> Self {
+        Self::new_clamped(x / f64::from(width), y / f64::from(height))
+    }
+}
+
+impl Default for ScreenAnchor {
+    fn default() -> Self {
+        ScreenAnchor::CENTER
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
 pub struct Zoom(u8);
 
 impl Zoom {
     pub const MIN: Zoom = Zoom(1);
     pub const MAX: Zoom = Zoom(18);
 
+    /// Error if `val` is outside of [`MIN`, `MAX`]
+    pub fn new(val: u8) -> Result<Self, ZoomRangeError> {
+        if (Self::MIN.0..=Self::MAX.0).contains(&val) {
+            Ok(Self(val))
+        } else {
+            Err(ZoomRangeError(val))
+        }
+    }
+
     pub fn new_clamped(val: u8) -> Self {
         Self(val.clamp(Self::MIN.0, Self::MAX.0))
     }
@@ -290,11 +701,31 @@ impl From<Zoom> for u8 {
     }
 }
 
+impl<'de> Deserialize<'de> for Zoom {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Zoom::new(u8::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error(display = "Zoom {} is not between 1 and 18", _0)]
+pub struct ZoomRangeError(pub u8);
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ZoomParseError {
+    #[error(display = "Invalid zoom, {}", _0)]
+    Int(#[error(source)] num::ParseIntError),
+
+    #[error(display = "{}", _0)]
+    Range(#[error(source)] ZoomRangeError),
+}
+
+/// Errors like deserializing does on levels outside of [`MIN`, `MAX`]
 impl FromStr for Zoom {
-    type Err = num::ParseIntError;
+    type Err = ZoomParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Zoom::new_clamped(s.parse::<u8>()?))
+        Ok(Zoom::new(s.parse::<u8>()?)?)
     }
 }
 
@@ -303,3 +734,263 @@ impl fmt::Display for Zoom {
         write!(f, "{}", self.0)
     }
 }
+
+/// Zoom level between whole tile levels, rendered by scaling the tiles of
+/// the nearest `Zoom`
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Serialize)]
+pub struct FractionalZoom(f64);
+
+impl FractionalZoom {
+    pub const MIN: FractionalZoom = FractionalZoom(Zoom::MIN.get() as f64);
+    pub const MAX: FractionalZoom = FractionalZoom(Zoom::MAX.get() as f64);
+
+    /// Error if `val` is NaN or outside of [`MIN`, `MAX`]
+    pub fn new(val: f64) -> Result<Self, FractionalZoomRangeError> {
+        if (Self::MIN.0..=Self::MAX.0).contains(&val) {
+            Ok(Self(val))
+        } else {
+            Err(FractionalZoomRangeError(val))
+        }
+    }
+
+    /// NaN is clamped to `MIN`
+    pub fn new_clamped(val: f64) -> Self {
+        if val.is_nan() {
+            Self::MIN
+        } else {
+            Self(val.clamp(Self::MIN.0, Self::MAX.0))
+        }
+    }
+
+    pub const fn get(&self) -> f64 {
+        self.0
+    }
+
+    /// The nearest whole tile level
+    pub fn tile_zoom(&self) -> Zoom {
+        Zoom::new_clamped(self.0.round() as u8)
+    }
+
+    /// How much the tiles of `tile_zoom` are scaled by, 1.0 at whole levels
+    pub fn tile_scale(&self) -> f64 {
+        2_f64.powf(self.0 - f64::from(self.tile_zoom().get()))
+    }
+
+    pub fn is_whole(&self) -> bool {
+        self.0.fract() == 0.0
+    }
+
+    pub fn saturating_add(&mut self, val: f64) {
+        *self = Self::new_clamped(self.0 + val);
+    }
+
+    pub fn saturating_sub(&mut self, val: f64) {
+        *self = Self::new_clamped(self.0 - val);
+    }
+}
+
+impl<'de> Deserialize<'de> for FractionalZoom {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        FractionalZoom::new(f64::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Error)]
+#[error(display = "Zoom {} is not between 1 and 18", _0)]
+pub struct FractionalZoomRangeError(pub f64);
+
+impl From<Zoom> for FractionalZoom {
+    fn from(z: Zoom) -> Self {
+        FractionalZoom(z.get().into())
+    }
+}
+
+impl From<f64> for FractionalZoom {
+    fn from(z: f64) -> Self {
+        FractionalZoom::new_clamped(z)
+    }
+}
+
+impl From<FractionalZoom> for f64 {
+    fn from(z: FractionalZoom) -> Self {
+        z.0
+    }
+}
+
+impl FromStr for FractionalZoom {
+    type Err = num::ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FractionalZoom::new_clamped(s.parse::<f64>()?))
+    }
+}
+
+impl fmt::Display for FractionalZoom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_latitude_longitude() {
+        assert_eq!(Latitude::new(-90.0).unwrap(), Latitude::MIN);
+        assert_eq!(Longitude::new(180.0).unwrap(), Longitude::MAX);
+        assert!(Latitude::new(90.000001).is_err());
+        assert!(Latitude::new(f64::NAN).is_err());
+        assert!(Longitude::new(-180.5).is_err());
+        assert!(Longitude::new(f64::INFINITY).is_err());
+        assert!(Coordinate::new(0.0, f64::NAN).is_err());
+        assert!(BoundingBox::new(-117.0, 47.0, -116.0, 91.0).is_err());
+
+        // Parsing goes through the checks too
+        assert!("95,200".parse::<Coordinate>().is_err());
+        assert!("-117,47,-116,NaN".parse::<BoundingBox>().is_err());
+        assert_eq!(
+            "-117,47,-116,48".parse::<BoundingBox>().unwrap(),
+            BoundingBox::new(-117.0, 47.0, -116.0, 48.0).unwrap()
+        );
+
+        #[derive(Debug, Deserialize)]
+        struct Position {
+            coordinate: Coordinate,
+        }
+        let parse = |lat: &str, lon: &str| {
+            toml::from_str::<Position>(&format!(
+                "coordinate = {{ latitude = {}, longitude = {} }}",
+                lat, lon
+            ))
+        };
+        let position = parse("47.453551", "-116.788118").unwrap();
+        assert_eq!(
+            position.coordinate,
+            Coordinate::new(47.453551, -116.788118).unwrap()
+        );
+        assert!(parse("91.0", "0.0").is_err());
+        assert!(parse("0.0", "-181.0").is_err());
+        assert!(parse("nan", "0.0").is_err());
+        let err = parse("0.0", "nan").unwrap_err().to_string();
+        assert!(err.contains("Longitude NaN"), "{}", err);
+    }
+
+    #[test]
+    fn checked_zoom() {
+        assert_eq!(Zoom::new(18).unwrap(), Zoom::MAX);
+        assert!(Zoom::new(0).is_err() && Zoom::new(19).is_err());
+        assert_eq!(FractionalZoom::new(1.0).unwrap(), FractionalZoom::MIN);
+        assert!(FractionalZoom::new(18.5).is_err());
+        assert!(FractionalZoom::new(f64::NAN).is_err());
+        assert_eq!("18".parse::<Zoom>().unwrap(), Zoom::MAX);
+        assert!(matches!(
+            "25".parse::<Zoom>(),
+            Err(ZoomParseError::Range(ZoomRangeError(25)))
+        ));
+        assert!(matches!("0".parse::<Zoom>(), Err(ZoomParseError::Range(_))));
+        assert!(matches!("x".parse::<Zoom>(), Err(ZoomParseError::Int(_))));
+
+        #[derive(Debug, Deserialize)]
+        struct View {
+            zoom: Zoom,
+            fractional: FractionalZoom,
+        }
+        let parse = |zoom: &str, fractional: &str| {
+            toml::from_str::<View>(&format!("zoom = {}\nfractional = {}", zoom, fractional))
+        };
+        let view = parse("11", "11.5").unwrap();
+        assert_eq!(view.zoom, Zoom::new_clamped(11));
+        assert_eq!(view.fractional, FractionalZoom::new_clamped(11.5));
+        let err = parse("19", "11.5").unwrap_err().to_string();
+        assert!(err.contains("Zoom 19 is not between 1 and 18"), "{}", err);
+        assert!(parse("11", "0.5").is_err());
+        assert!(parse("11", "nan").is_err());
+    }
+
+    #[test]
+    fn bounding_box_relations() {
+        let bbox = BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap();
+        assert!(bbox.contains(&Coordinate::new(47.453551, -116.788118).unwrap()));
+        assert!(bbox.contains(&Coordinate::new(47.45, -116.80).unwrap()));
+        assert!(!bbox.contains(&Coordinate::new(47.47, -116.788118).unwrap()));
+
+        let east = BoundingBox::new(-116.77, 47.40, -116.70, 47.455).unwrap();
+        let far = BoundingBox::new(-100.0, 47.45, -99.0, 47.46).unwrap();
+        assert!(bbox.intersects(&east) && east.intersects(&bbox));
+        assert!(!bbox.intersects(&far) && !far.intersects(&bbox));
+        assert!(bbox.intersects(&bbox.expand_by_meters(-10.0)));
+
+        let union = bbox.union(&far);
+        assert_eq!(
+            union,
+            BoundingBox::new(-116.80, 47.45, -99.0, 47.46).unwrap()
+        );
+        assert!(union.contains(&bbox.center()) && union.contains(&far.center()));
+    }
+
+    #[test]
+    fn bounding_box_tiles() {
+        let bbox = BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap();
+        let zoom = Zoom::new_clamped(14);
+        let tiles: Vec<TileCoord> = bbox.tiles(zoom).collect();
+        assert_eq!(tiles.len(), 4);
+        assert_eq!(tiles[0].to_string(), "14/2876/5731");
+        assert!(tiles.iter().all(|t| t.bounds().intersects(&bbox)));
+
+        // The east edge of the world is the last column, not the first
+        let world = BoundingBox::new(-180.0, -90.0, 180.0, 90.0).unwrap();
+        let zoom = Zoom::new_clamped(2);
+        assert_eq!(world.tile_ranges(zoom), (vec![0..=3], 0..=3));
+        assert_eq!(world.tiles(zoom).count(), 16);
+
+        // The first and last columns across the antimeridian
+        let wrapped = BoundingBox::new(170.0, -10.0, -170.0, 10.0).unwrap();
+        assert_eq!(wrapped.tile_ranges(zoom), (vec![3..=3, 0..=0], 1..=2));
+        let columns: Vec<u32> = wrapped.tiles(zoom).map(|t| t.x.0).collect();
+        assert_eq!(columns, vec![3, 3, 0, 0]);
+    }
+
+    #[test]
+    fn bounding_box_across_the_antimeridian() {
+        let bbox = BoundingBox::new(179.0, -17.0, -179.0, -16.0).unwrap();
+        assert!(bbox.crosses_antimeridian());
+        assert!((bbox.width() - 2.0).abs() < 1e-9);
+        assert_eq!(bbox.center(), Coordinate::new(-16.5, 180.0).unwrap());
+        assert!(bbox.contains(&Coordinate::new(-16.5, 179.5).unwrap()));
+        assert!(bbox.contains(&Coordinate::new(-16.5, -179.5).unwrap()));
+        assert!(!bbox.contains(&Coordinate::new(-16.5, 0.0).unwrap()));
+
+        let west = BoundingBox::new(178.0, -17.0, 179.5, -16.0).unwrap();
+        let east = BoundingBox::new(-179.5, -17.0, -178.0, -16.0).unwrap();
+        let far = BoundingBox::new(0.0, -17.0, 1.0, -16.0).unwrap();
+        assert!(bbox.intersects(&west) && west.intersects(&bbox));
+        assert!(bbox.intersects(&east) && east.intersects(&bbox));
+        assert!(!bbox.intersects(&far) && !far.intersects(&bbox));
+
+        // Narrower across the antimeridian than around the world
+        assert_eq!(
+            west.union(&east),
+            BoundingBox::new(178.0, -17.0, -178.0, -16.0).unwrap()
+        );
+        assert_eq!(
+            west.union(&bbox),
+            BoundingBox::new(178.0, -17.0, -179.0, -16.0).unwrap()
+        );
+        let world = BoundingBox::new(-180.0, -90.0, 180.0, 90.0).unwrap();
+        assert_eq!(bbox.union(&world), world);
+
+        // A corridor around a track up to the antimeridian continues past it
+        let track = BoundingBox::new(179.98, -17.0, 179.99, -16.0).unwrap();
+        let corridor = track.expand_by_meters(5_000.0);
+        assert!(corridor.crosses_antimeridian(), "{}", corridor);
+        assert!(corridor.contains(&Coordinate::new(-16.5, -179.99).unwrap()));
+        assert!(corridor.contains(&Coordinate::new(-16.5, 179.95).unwrap()));
+        assert!(!corridor.contains(&Coordinate::new(-16.5, 0.0).unwrap()));
+        let wide = BoundingBox::new(-170.0, -1.0, 170.0, 1.0)
+            .unwrap()
+            .expand_by_meters(2_000_000.0);
+        assert_eq!((wide.west, wide.east), (Longitude::MIN, Longitude::MAX));
+        assert_eq!(track.expand_by_meters(0.0), track);
+    }
+}
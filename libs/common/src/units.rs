@@ -0,0 +1,666 @@
+//! Physical quantities, stored in SI units and converted to the metric,
+//! imperial or nautical units they're shown in
+
+use err_derive::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const METERS_PER_FOOT: f64 = 0.3048;
+pub const METERS_PER_MILE: f64 = 1609.344;
+pub const METERS_PER_NAUTICAL_MILE: f64 = 1852.0;
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+/// Units quantities are shown in
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Deserialize, Serialize)]
+pub enum UnitSystem {
+    /// Kilometers, meters and km/h
+    #[default]
+    Metric,
+    /// Miles, feet and mph
+    Imperial,
+    /// Nautical miles, feet and knots
+    Nautical,
+}
+
+#[derive(Debug, Error)]
+#[error(display = "Failed to parse unit system, expected Metric, Imperial or Nautical")]
+pub struct UnitSystemParseError;
+
+impl FromStr for UnitSystem {
+    type Err = UnitSystemParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "metric" => Ok(UnitSystem::Metric),
+            "imperial" => Ok(UnitSystem::Imperial),
+            "nautical" => Ok(UnitSystem::Nautical),
+            _ => Err(UnitSystemParseError),
+        }
+    }
+}
+
+impl fmt::Display for UnitSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            UnitSystem::Metric => "Metric",
+            UnitSystem::Imperial => "Imperial",
+            UnitSystem::Nautical => "Nautical",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A quantity converted to a display unit. Formats as e.g. `12.5 km`,
+/// `{:.N}` overrides the default number of decimal places.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: &'static str,
+    pub precision: usize,
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(self.precision);
+        write!(f, "{:.*} {}", precision, self.value, self.unit)
+    }
+}
+
+/// Arithmetic shared by the quantities, on their SI value
+macro_rules! impl_quantity_ops {
+    ($t:ident) => {
+        impl Add for $t {
+            type Output = $t;
+
+            fn add(self, rhs: $t) -> $t {
+                $t(self.0 + rhs.0)
+            }
+        }
+
+        impl Sub for $t {
+            type Output = $t;
+
+            fn sub(self, rhs: $t) -> $t {
+                $t(self.0 - rhs.0)
+            }
+        }
+
+        impl AddAssign for $t {
+            fn add_assign(&mut self, rhs: $t) {
+                self.0 += rhs.0;
+            }
+        }
+
+        impl SubAssign for $t {
+            fn sub_assign(&mut self, rhs: $t) {
+                self.0 -= rhs.0;
+            }
+        }
+
+        impl Mul<f64> for $t {
+            type Output = $t;
+
+            fn mul(self, rhs: f64) -> $t {
+                $t(self.0 * rhs)
+            }
+        }
+
+        impl Mul<$t> for f64 {
+            type Output = $t;
+
+            fn mul(self, rhs: $t) -> $t {
+                $t(self * rhs.0)
+            }
+        }
+
+        impl Div<f64> for $t {
+            type Output = $t;
+
+            fn div(self, rhs: f64) -> $t {
+                $t(self.0 / rhs)
+            }
+        }
+
+        /// Ratio of the two
+        impl Div for $t {
+            type Output = f64;
+
+            fn div(self, rhs: $t) -> f64 {
+                self.0 / rhs.0
+            }
+        }
+
+        impl Neg for $t {
+            type Output = $t;
+
+            fn neg(self) -> $t {
+                $t(-self.0)
+            }
+        }
+
+        impl Sum for $t {
+            fn sum<I: Iterator<Item = $t>>(iter: I) -> $t {
+                $t(iter.map(|q| q.0).sum())
+            }
+        }
+    };
+}
+
+/// Meters per second
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, Deserialize, Serialize)]
+pub struct Speed(pub f64);
+
+impl_quantity_ops!(Speed);
+
+impl Speed {
+    pub const ZERO: Speed = Speed(0.0);
+
+    pub fn from_meters_per_second(val: f64) -> Self {
+        Speed(val)
+    }
+
+    pub fn from_kilometers_per_hour(val: f64) -> Self {
+        Speed(val * 1000.0 / SECONDS_PER_HOUR)
+    }
+
+    pub fn from_miles_per_hour(val: f64) -> Self {
+        Speed(val * METERS_PER_MILE / SECONDS_PER_HOUR)
+    }
+
+    pub fn from_knots(val: f64) -> Self {
+        Speed(val * METERS_PER_NAUTICAL_MILE / SECONDS_PER_HOUR)
+    }
+
+    pub fn meters_per_second(&self) -> f64 {
+        self.0
+    }
+
+    pub fn kilometers_per_hour(&self) -> f64 {
+        self.0 * SECONDS_PER_HOUR / 1000.0
+    }
+
+    pub fn miles_per_hour(&self) -> f64 {
+        self.0 * SECONDS_PER_HOUR / METERS_PER_MILE
+    }
+
+    pub fn knots(&self) -> f64 {
+        self.0 * SECONDS_PER_HOUR / METERS_PER_NAUTICAL_MILE
+    }
+
+    /// km/h, mph or knots
+    pub fn display(&self, units: UnitSystem) -> Measurement {
+        let (value, unit) = match units {
+            UnitSystem::Metric => (self.kilometers_per_hour(), "km/h"),
+            UnitSystem::Imperial => (self.miles_per_hour(), "mph"),
+            UnitSystem::Nautical => (self.knots(), "kn"),
+        };
+        Measurement {
+            value,
+            unit,
+            precision: 0,
+        }
+    }
+}
+
+/// Metric
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.display(UnitSystem::Metric), f)
+    }
+}
+
+/// Meters
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, Deserialize, Serialize)]
+pub struct Distance(pub f64);
+
+impl_quantity_ops!(Distance);
+
+impl Distance {
+    pub const ZERO: Distance = Distance(0.0);
+
+    pub fn from_meters(val: f64) -> Self {
+        Distance(val)
+    }
+
+    pub fn from_kilometers(val: f64) -> Self {
+        Distance(val * 1000.0)
+    }
+
+    pub fn from_feet(val: f64) -> Self {
+        Distance(val * METERS_PER_FOOT)
+    }
+
+    pub fn from_miles(val: f64) -> Self {
+        Distance(val * METERS_PER_MILE)
+    }
+
+    pub fn from_nautical_miles(val: f64) -> Self {
+        Distance(val * METERS_PER_NAUTICAL_MILE)
+    }
+
+    pub fn meters(&self) -> f64 {
+        self.0
+    }
+
+    pub fn kilometers(&self) -> f64 {
+        self.0 / 1000.0
+    }
+
+    pub fn feet(&self) -> f64 {
+        self.0 / METERS_PER_FOOT
+    }
+
+    pub fn miles(&self) -> f64 {
+        self.0 / METERS_PER_MILE
+    }
+
+    pub fn nautical_miles(&self) -> f64 {
+        self.0 / METERS_PER_NAUTICAL_MILE
+    }
+
+    /// Meters under a kilometer then kilometers, feet under 1000 ft then
+    /// miles, or nautical miles
+    pub fn display(&self, units: UnitSystem) -> Measurement {
+        let (value, unit, precision) = match units {
+            UnitSystem::Metric if self.0.abs() < 1000.0 => (self.meters(), "m", 0),
+            UnitSystem::Metric => (self.kilometers(), "km", 1),
+            UnitSystem::Imperial if self.feet().abs() < 1000.0 => (self.feet(), "ft", 0),
+            UnitSystem::Imperial => (self.miles(), "mi", 1),
+            UnitSystem::Nautical => (self.nautical_miles(), "NM", 2),
+        };
+        Measurement {
+            value,
+            unit,
+            precision,
+        }
+    }
+}
+
+/// Metric
+impl fmt::Display for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.display(UnitSystem::Metric), f)
+    }
+}
+
+/// Meters above mean sea level
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, Deserialize, Serialize)]
+pub struct Altitude(pub f64);
+
+impl_quantity_ops!(Altitude);
+
+impl Altitude {
+    pub fn from_meters(val: f64) -> Self {
+        Altitude(val)
+    }
+
+    pub fn from_feet(val: f64) -> Self {
+        Altitude(val * METERS_PER_FOOT)
+    }
+
+    pub fn meters(&self) -> f64 {
+        self.0
+    }
+
+    pub fn feet(&self) -> f64 {
+        self.0 / METERS_PER_FOOT
+    }
+
+    /// Meters, or feet for both imperial and nautical
+    pub fn display(&self, units: UnitSystem) -> Measurement {
+        let (value, unit) = match units {
+            UnitSystem::Metric => (self.meters(), "m"),
+            UnitSystem::Imperial | UnitSystem::Nautical => (self.feet(), "ft"),
+        };
+        Measurement {
+            value,
+            unit,
+            precision: 0,
+        }
+    }
+}
+
+/// Metric
+impl fmt::Display for Altitude {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.display(UnitSystem::Metric), f)
+    }
+}
+
+/// Seconds, negative or infinite unlike `std::time::Duration`, e.g. the time
+/// to cover a distance while stopped
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, Deserialize, Serialize)]
+pub struct TravelTime(pub f64);
+
+impl_quantity_ops!(TravelTime);
+
+impl TravelTime {
+    pub fn from_seconds(val: f64) -> Self {
+        TravelTime(val)
+    }
+
+    pub fn from_hours(val: f64) -> Self {
+        TravelTime(val * SECONDS_PER_HOUR)
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.0
+    }
+
+    pub fn hours(&self) -> f64 {
+        self.0 / SECONDS_PER_HOUR
+    }
+
+    /// `None` if negative, NaN or too long for a `Duration`
+    pub fn to_duration(&self) -> Option<Duration> {
+        Duration::try_from_secs_f64(self.0).ok()
+    }
+}
+
+impl From<Duration> for TravelTime {
+    fn from(d: Duration) -> Self {
+        TravelTime(d.as_secs_f64())
+    }
+}
+
+/// `h:mm:ss`, `m:ss` under an hour, or `--:--` if infinite or NaN
+impl fmt::Display for TravelTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.0.is_finite() {
+            return write!(f, "--:--");
+        }
+        let sign = if self.0 < 0.0 { "-" } else { "" };
+        let total = self.0.abs().round() as u64;
+        let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+        if hours > 0 {
+            write!(f, "{}{}:{:02}:{:02}", sign, hours, minutes, seconds)
+        } else {
+            write!(f, "{}{}:{:02}", sign, minutes, seconds)
+        }
+    }
+}
+
+impl Div<Speed> for Distance {
+    type Output = TravelTime;
+
+    fn div(self, rhs: Speed) -> TravelTime {
+        TravelTime(self.0 / rhs.0)
+    }
+}
+
+impl Div<TravelTime> for Distance {
+    type Output = Speed;
+
+    fn div(self, rhs: TravelTime) -> Speed {
+        Speed(self.0 / rhs.0)
+    }
+}
+
+impl Mul<TravelTime> for Speed {
+    type Output = Distance;
+
+    fn mul(self, rhs: TravelTime) -> Distance {
+        Distance(self.0 * rhs.0)
+    }
+}
+
+impl Mul<Speed> for TravelTime {
+    type Output = Distance;
+
+    fn mul(self, rhs: Speed) -> Distance {
+        Distance(self.0 * rhs.0)
+    }
+}
+
+/// Degrees clockwise from north, always in [0, 360)
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, Deserialize, Serialize)]
+#[serde(from = "f64", into = "f64")]
+pub struct Heading(f64);
+
+impl Heading {
+    pub const NORTH: Heading = Heading(0.0);
+    pub const EAST: Heading = Heading(90.0);
+    pub const SOUTH: Heading = Heading(180.0);
+    pub const WEST: Heading = Heading(270.0);
+
+    /// Wraps `degrees` into [0, 360)
+    pub fn new(degrees: f64) -> Self {
+        let wrapped = degrees.rem_euclid(360.0);
+        // rem_euclid of a tiny negative value rounds up to 360
+        Heading(if wrapped >= 360.0 { 0.0 } else { wrapped })
+    }
+
+    pub const fn degrees(&self) -> f64 {
+        self.0
+    }
+
+    pub fn radians(&self) -> f64 {
+        self.0.to_radians()
+    }
+
+    /// Shortest turn from `self` to `to`, positive clockwise, in (-180, 180]
+    pub fn turn_to(&self, to: Heading) -> f64 {
+        let turn = (to.0 - self.0).rem_euclid(360.0);
+        if turn > 180.0 {
+            turn - 360.0
+        } else {
+            turn
+        }
+    }
+
+    /// Reverse direction
+    pub fn reciprocal(&self) -> Self {
+        Heading::new(self.0 + 180.0)
+    }
+
+    /// Nearest of the 16 compass points, e.g. `NNE`
+    pub fn compass_point(&self) -> &'static str {
+        const POINTS: [&str; 16] = [
+            "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW",
+            "NW", "NNW",
+        ];
+        POINTS[(self.0 / 22.5).round() as usize % 16]
+    }
+}
+
+impl From<f64> for Heading {
+    fn from(degrees: f64) -> Self {
+        Heading::new(degrees)
+    }
+}
+
+impl From<Heading> for f64 {
+    fn from(h: Heading) -> Self {
+        h.0
+    }
+}
+
+/// Turns clockwise by the degrees
+impl Add<f64> for Heading {
+    type Output = Heading;
+
+    fn add(self, rhs: f64) -> Heading {
+        Heading::new(self.0 + rhs)
+    }
+}
+
+/// Turns counterclockwise by the degrees
+impl Sub<f64> for Heading {
+    type Output = Heading;
+
+    fn sub(self, rhs: f64) -> Heading {
+        Heading::new(self.0 - rhs)
+    }
+}
+
+/// Shortest turn from `rhs` to `self`, see `turn_to`
+impl Sub for Heading {
+    type Output = f64;
+
+    fn sub(self, rhs: Heading) -> f64 {
+        rhs.turn_to(self)
+    }
+}
+
+/// Three digit degrees, e.g. `045°`, `{:.N}` adds decimal places
+impl fmt::Display for Heading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(0);
+        let scale = 10_f64.powi(precision as i32);
+        // 359.6 rounds to 000, not 360
+        let degrees = Heading::new((self.0 * scale).round() / scale).0;
+        let width = if precision == 0 { 3 } else { precision + 4 };
+        write!(f, "{:0w$.p$}°", degrees, w = width, p = precision)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn conversions() {
+        let speed = Speed::from_knots(10.0);
+        assert!(close(speed.meters_per_second(), 5.144_444_444_444_445));
+        assert!(close(speed.kilometers_per_hour(), 18.52));
+        assert!(close(
+            Speed::from_miles_per_hour(60.0).kilometers_per_hour(),
+            96.56064
+        ));
+        assert!(close(
+            Speed::from_kilometers_per_hour(36.0).meters_per_second(),
+            10.0
+        ));
+
+        assert!(close(Distance::from_miles(1.0).feet(), 5280.0));
+        assert!(close(
+            Distance::from_nautical_miles(1.0).kilometers(),
+            1.852
+        ));
+        assert!(close(Distance::from_feet(3.0).meters(), 0.9144));
+        assert!(close(Altitude::from_feet(1000.0).meters(), 304.8));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let d = Distance::from_kilometers(1.0) + Distance::from_meters(500.0) * 2.0;
+        assert!(close(d.meters(), 2000.0));
+        assert!(close((d - Distance::from_meters(500.0)).meters(), 1500.0));
+        assert!(close(d / Distance::from_meters(500.0), 4.0));
+        let total: Distance = vec![Distance(1.0), Distance(2.5)].into_iter().sum();
+        assert!(close(total.meters(), 3.5));
+
+        let time = Distance::from_kilometers(90.0) / Speed::from_kilometers_per_hour(60.0);
+        assert!(close(time.hours(), 1.5));
+        assert!(close(
+            (Speed::from_meters_per_second(10.0) * time).kilometers(),
+            54.0
+        ));
+        assert!(close(
+            (Distance(100.0) / TravelTime(20.0)).meters_per_second(),
+            5.0
+        ));
+        assert_eq!(
+            TravelTime::from(Duration::from_secs(90)).to_duration(),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!((Distance(1.0) / Speed::ZERO).to_duration(), None);
+        assert_eq!(
+            (Distance(1.0) / Speed::from_meters_per_second(1e-20)).to_duration(),
+            None
+        );
+    }
+
+    #[test]
+    fn heading_wraps() {
+        assert_eq!(Heading::new(-90.0), Heading::WEST);
+        assert_eq!(Heading::new(720.0), Heading::NORTH);
+        assert_eq!(Heading::new(-1e-15), Heading::NORTH);
+        assert_eq!(Heading::new(350.0) + 20.0, Heading::new(10.0));
+        assert_eq!(Heading::new(10.0) - 20.0, Heading::new(350.0));
+        assert!(close(Heading::new(350.0).turn_to(Heading::new(10.0)), 20.0));
+        assert!(close(Heading::new(10.0) - Heading::new(350.0), 20.0));
+        assert!(close(
+            Heading::new(10.0).turn_to(Heading::new(350.0)),
+            -20.0
+        ));
+        assert!(close(Heading::NORTH.turn_to(Heading::SOUTH), 180.0));
+        assert_eq!(Heading::new(100.0).reciprocal(), Heading::new(280.0));
+        assert_eq!(Heading::new(20.0).compass_point(), "NNE");
+        assert_eq!(Heading::new(355.0).compass_point(), "N");
+    }
+
+    #[test]
+    fn formatting() {
+        let speed = Speed::from_kilometers_per_hour(88.0);
+        assert_eq!(speed.display(UnitSystem::Metric).to_string(), "88 km/h");
+        assert_eq!(speed.display(UnitSystem::Imperial).to_string(), "55 mph");
+        assert_eq!(
+            format!("{:.1}", speed.display(UnitSystem::Nautical)),
+            "47.5 kn"
+        );
+
+        assert_eq!(Distance(950.0).to_string(), "950 m");
+        assert_eq!(Distance(12_345.0).to_string(), "12.3 km");
+        assert_eq!(
+            Distance(150.0).display(UnitSystem::Imperial).to_string(),
+            "492 ft"
+        );
+        assert_eq!(
+            Distance(1609.344).display(UnitSystem::Imperial).to_string(),
+            "1.0 mi"
+        );
+        assert_eq!(
+            Distance(926.0).display(UnitSystem::Nautical).to_string(),
+            "0.50 NM"
+        );
+        assert_eq!(
+            Altitude(100.0).display(UnitSystem::Nautical).to_string(),
+            "328 ft"
+        );
+
+        assert_eq!(Heading::new(45.0).to_string(), "045°");
+        assert_eq!(Heading::new(359.7).to_string(), "000°");
+        assert_eq!(format!("{:.1}", Heading::new(5.25)), "005.3°");
+
+        assert_eq!(TravelTime(75.0).to_string(), "1:15");
+        assert_eq!(TravelTime(3725.0).to_string(), "1:02:05");
+        assert_eq!(TravelTime(-30.0).to_string(), "-0:30");
+        assert_eq!(TravelTime(f64::INFINITY).to_string(), "--:--");
+
+        for units in [
+            UnitSystem::Metric,
+            UnitSystem::Imperial,
+            UnitSystem::Nautical,
+        ]
+        .iter()
+        {
+            assert_eq!(units.to_string().parse::<UnitSystem>().unwrap(), *units);
+        }
+    }
+
+    #[test]
+    fn serde() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Fix {
+            speed: Speed,
+            heading: Heading,
+            altitude: Altitude,
+        }
+        let fix: Fix = toml::from_str("speed = 12.5\nheading = -90.0\naltitude = 700.0").unwrap();
+        assert_eq!(fix.speed, Speed(12.5));
+        assert_eq!(fix.heading, Heading::WEST);
+        assert_eq!(fix.altitude, Altitude(700.0));
+        let s = toml::to_string(&fix).unwrap();
+        assert_eq!(s, "speed = 12.5\nheading = 270.0\naltitude = 700.0\n");
+    }
+}
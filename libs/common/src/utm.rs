@@ -0,0 +1,518 @@
+//! Universal Transverse Mercator and the Military Grid Reference System on
+//! the WGS84 ellipsoid. Uses Krüger's series to the sixth order, good to
+//! well under a millimeter inside a zone. The polar regions, where UPS
+//! would take over, are not supported.
+
+use crate::geodesy::{WGS84_FLATTENING, WGS84_SEMI_MAJOR_AXIS_METERS};
+use crate::types::{Coordinate, Latitude, Longitude};
+use err_derive::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Scale factor on the central meridian
+const SCALE_FACTOR: f64 = 0.9996;
+const FALSE_EASTING: f64 = 500_000.0;
+const FALSE_NORTHING_SOUTH: f64 = 10_000_000.0;
+
+/// UTM covers 80°S to 84°N
+pub const MIN_LATITUDE: f64 = -80.0;
+pub const MAX_LATITUDE: f64 = 84.0;
+
+/// Latitude bands from 80°S, 8° each except X which is 12°
+const BANDS: &[u8] = b"CDEFGHJKLMNPQRSTUVWX";
+
+/// MGRS 100 km square column letters, the set repeats every three zones
+const COLUMN_LETTERS: [&[u8]; 3] = [b"ABCDEFGH", b"JKLMNPQR", b"STUVWXYZ"];
+/// MGRS 100 km square row letters, offset in even zones
+const ROW_LETTERS: [&[u8]; 2] = [b"ABCDEFGHJKLMNPQRSTUV", b"FGHJKLMNPQRSTUVABCDE"];
+
+const SQUARE_METERS: f64 = 100_000.0;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "Latitude {} is outside of the UTM range, 80°S to 84°N", _0)]
+    OutOfRange(Latitude),
+
+    #[error(display = "Invalid UTM zone {}, expected 1 to 60", _0)]
+    InvalidZone(u8),
+
+    #[error(display = "Invalid latitude band '{}'", _0)]
+    InvalidBand(char),
+
+    #[error(display = "Failed to parse UTM position, expected e.g. 11T 515972 5255589")]
+    UtmParse,
+
+    #[error(display = "Failed to parse MGRS position, expected e.g. 11T NN 15971 55589")]
+    MgrsParse,
+}
+
+/// UTM grid position
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Utm {
+    /// 1 to 60
+    pub zone: u8,
+    /// Latitude band letter, N and above being the northern hemisphere
+    pub band: char,
+    pub easting: f64,
+    /// From the equator in the northern hemisphere, from 10,000 km south of
+    /// it in the southern
+    pub northing: f64,
+}
+
+impl Utm {
+    /// Grid position of `coord` in its standard zone, including the Norway
+    /// and Svalbard exceptions
+    pub fn from_coordinate(coord: &Coordinate) -> Result<Self, Error> {
+        let lat = coord.latitude.get();
+        if !(MIN_LATITUDE..=MAX_LATITUDE).contains(&lat) {
+            return Err(Error::OutOfRange(coord.latitude));
+        }
+        let lon = (coord.longitude.get() + 540.0).rem_euclid(360.0) - 180.0;
+        let band = band(lat);
+        let mut zone = ((lon + 180.0) / 6.0).floor() as u8 % 60 + 1;
+        match band {
+            'V' if zone == 31 && lon >= 3.0 => zone = 32,
+            'X' if zone == 32 => zone = if lon < 9.0 { 31 } else { 33 },
+            'X' if zone == 34 => zone = if lon < 21.0 { 33 } else { 35 },
+            'X' if zone == 36 => zone = if lon < 33.0 { 35 } else { 37 },
+            _ => (),
+        }
+        let (easting, northing) = project(lat, lon - central_meridian(zone));
+        Ok(Utm {
+            zone,
+            band,
+            easting,
+            northing: if lat < 0.0 {
+                northing + FALSE_NORTHING_SOUTH
+            } else {
+                northing
+            },
+        })
+    }
+
+    pub fn is_northern(&self) -> bool {
+        self.band >= 'N'
+    }
+
+    pub fn to_coordinate(&self) -> Result<Coordinate, Error> {
+        check_zone(self.zone, self.band)?;
+        let northing = if self.is_northern() {
+            self.northing
+        } else {
+            self.northing - FALSE_NORTHING_SOUTH
+        };
+        let (lat, d_lon) = unproject(self.easting, northing);
+        let lon = central_meridian(self.zone) + d_lon;
+        Ok(Coordinate {
+            latitude: Latitude::new_clamped(lat),
+            longitude: Longitude::new_wrapped(lon),
+        })
+    }
+}
+
+/// Rounded to the meter, e.g. `11T 515972 5255589`
+impl fmt::Display for Utm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(0);
+        write!(
+            f,
+            "{}{} {:.*} {:.*}",
+            self.zone, self.band, precision, self.easting, precision, self.northing
+        )
+    }
+}
+
+/// Parses `11T 515972 5255589`, with or without a space before the band
+impl FromStr for Utm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let (zone_band, easting, northing) = match parts.as_slice() {
+            [zone_band, easting, northing] => (zone_band.to_string(), easting, northing),
+            [zone, band, easting, northing] => (format!("{}{}", zone, band), easting, northing),
+            _ => return Err(Error::UtmParse),
+        };
+        let (zone, band) = zone_band_from_str(&zone_band).ok_or(Error::UtmParse)?;
+        let easting: f64 = easting.parse().map_err(|_| Error::UtmParse)?;
+        let northing: f64 = northing.parse().map_err(|_| Error::UtmParse)?;
+        // Inside a zone, and keeps a few degree pairs from passing for grid positions
+        if !(100_000.0..=900_000.0).contains(&easting)
+            || !(0.0..=FALSE_NORTHING_SOUTH).contains(&northing)
+        {
+            return Err(Error::UtmParse);
+        }
+        check_zone(zone, band)?;
+        Ok(Utm {
+            zone,
+            band,
+            easting,
+            northing,
+        })
+    }
+}
+
+/// MGRS grid reference, a UTM position within a lettered 100 km square
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mgrs {
+    pub zone: u8,
+    pub band: char,
+    /// 100 km square column and row letters
+    pub square: (char, char),
+    /// Within the square, 0 to 100 km
+    pub easting: f64,
+    pub northing: f64,
+}
+
+impl Mgrs {
+    pub fn from_coordinate(coord: &Coordinate) -> Result<Self, Error> {
+        Ok(Self::from_utm(&Utm::from_coordinate(coord)?))
+    }
+
+    pub fn from_utm(utm: &Utm) -> Self {
+        let column = (utm.easting / SQUARE_METERS).floor() as usize;
+        let row = (utm.northing / SQUARE_METERS).floor() as usize % 20;
+        let columns = COLUMN_LETTERS[(usize::from(utm.zone) - 1) % 3];
+        let rows = ROW_LETTERS[(usize::from(utm.zone) - 1) % 2];
+        Mgrs {
+            zone: utm.zone,
+            band: utm.band,
+            square: (char::from(columns[(column + 7) % 8]), char::from(rows[row])),
+            easting: utm.easting.rem_euclid(SQUARE_METERS),
+            northing: utm.northing.rem_euclid(SQUARE_METERS),
+        }
+    }
+
+    pub fn to_utm(&self) -> Result<Utm, Error> {
+        check_zone(self.zone, self.band)?;
+        let columns = COLUMN_LETTERS[(usize::from(self.zone) - 1) % 3];
+        let rows = ROW_LETTERS[(usize::from(self.zone) - 1) % 2];
+        let letter_index = |letters: &[u8], c: char| {
+            letters
+                .iter()
+                .position(|l| char::from(*l) == c)
+                .ok_or(Error::MgrsParse)
+        };
+        let column = letter_index(columns, self.square.0)? + 1;
+        let row = letter_index(rows, self.square.1)?;
+        let easting = column as f64 * SQUARE_METERS + self.easting;
+        let mut northing = row as f64 * SQUARE_METERS + self.northing;
+
+        // Row letters repeat every 2,000 km, move up to the band's 2,000 km block
+        let band_index = BANDS
+            .iter()
+            .position(|b| char::from(*b) == self.band)
+            .unwrap_or(0);
+        let band_south = (band_index as f64 - 10.0) * 8.0;
+        let (_, mut band_northing) = project(band_south, 0.0);
+        if band_south < 0.0 {
+            band_northing += FALSE_NORTHING_SOUTH;
+        }
+        let band_northing = (band_northing / SQUARE_METERS).floor() * SQUARE_METERS;
+        while northing < band_northing {
+            northing += 20.0 * SQUARE_METERS;
+        }
+        Ok(Utm {
+            zone: self.zone,
+            band: self.band,
+            easting,
+            northing,
+        })
+    }
+
+    pub fn to_coordinate(&self) -> Result<Coordinate, Error> {
+        self.to_utm()?.to_coordinate()
+    }
+}
+
+/// Full 1 m precision, e.g. `11T NN 15971 55589`. A precision of 1 to 5
+/// digits per axis gives a 10 km to 1 m square, truncated as MGRS requires.
+impl fmt::Display for Mgrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = f.precision().unwrap_or(5).clamp(1, 5);
+        let divisor = 10_f64.powi(5 - digits as i32);
+        write!(
+            f,
+            "{}{} {}{} {:0width$} {:0width$}",
+            self.zone,
+            self.band,
+            self.square.0,
+            self.square.1,
+            (self.easting / divisor).floor() as u32,
+            (self.northing / divisor).floor() as u32,
+            width = digits
+        )
+    }
+}
+
+/// Parses `11T NN 15971 55589` with any or no spaces, and 0 to 5 digits per axis
+impl FromStr for Mgrs {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.split_whitespace().collect();
+        if !s.is_ascii() {
+            return Err(Error::MgrsParse);
+        }
+        let zone_len = s
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or(Error::MgrsParse)?;
+        if s.len() < zone_len + 3 {
+            return Err(Error::MgrsParse);
+        }
+        let (zone, band) = zone_band_from_str(&s[..=zone_len]).ok_or(Error::MgrsParse)?;
+        let mut letters = s[zone_len + 1..zone_len + 3]
+            .chars()
+            .map(|c| c.to_ascii_uppercase());
+        let square = (
+            letters.next().ok_or(Error::MgrsParse)?,
+            letters.next().ok_or(Error::MgrsParse)?,
+        );
+        let digits = &s[zone_len + 3..];
+        if digits.len() % 2 == 1 || digits.len() > 10 || !digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(Error::MgrsParse);
+        }
+        let half = digits.len() / 2;
+        let meters = |d: &str| -> f64 {
+            let val = if d.is_empty() {
+                0.0
+            } else {
+                d.parse().unwrap_or(0.0)
+            };
+            val * 10_f64.powi(5 - d.len() as i32)
+        };
+        let mgrs = Mgrs {
+            zone,
+            band,
+            square,
+            easting: meters(&digits[..half]),
+            northing: meters(&digits[half..]),
+        };
+        // Checks the square letters
+        mgrs.to_utm()?;
+        Ok(mgrs)
+    }
+}
+
+/// Latitude band letter, X stretches to 84°N
+fn band(lat: f64) -> char {
+    let index = ((lat + 80.0) / 8.0).floor().clamp(0.0, 19.0) as usize;
+    char::from(BANDS[index])
+}
+
+fn central_meridian(zone: u8) -> f64 {
+    f64::from(zone) * 6.0 - 183.0
+}
+
+fn check_zone(zone: u8, band: char) -> Result<(), Error> {
+    if !(1..=60).contains(&zone) {
+        return Err(Error::InvalidZone(zone));
+    }
+    if !BANDS.iter().any(|b| char::from(*b) == band) {
+        return Err(Error::InvalidBand(band));
+    }
+    Ok(())
+}
+
+/// `11T` into zone and upper case band letter
+fn zone_band_from_str(s: &str) -> Option<(u8, char)> {
+    let band = s.chars().last()?.to_ascii_uppercase();
+    let zone: u8 = s[..s.len() - band.len_utf8()].parse().ok()?;
+    if !(1..=60).contains(&zone) || !BANDS.iter().any(|b| char::from(*b) == band) {
+        return None;
+    }
+    Some((zone, band))
+}
+
+/// Third flattening
+fn third_flattening() -> f64 {
+    WGS84_FLATTENING / (2.0 - WGS84_FLATTENING)
+}
+
+fn eccentricity() -> f64 {
+    (WGS84_FLATTENING * (2.0 - WGS84_FLATTENING)).sqrt()
+}
+
+/// Radius of the rectifying sphere, scaled to the central meridian
+fn scaled_radius() -> f64 {
+    let n = third_flattening();
+    SCALE_FACTOR * WGS84_SEMI_MAJOR_AXIS_METERS / (1.0 + n)
+        * (1.0 + n.powi(2) / 4.0 + n.powi(4) / 64.0 + n.powi(6) / 256.0)
+}
+
+/// Conformal latitude tangent, τ' from τ
+fn conformal_tan(tau: f64) -> f64 {
+    let e = eccentricity();
+    let sigma = (e * (e * tau / (1.0 + tau * tau).sqrt()).atanh()).sinh();
+    tau * (1.0 + sigma * sigma).sqrt() - sigma * (1.0 + tau * tau).sqrt()
+}
+
+/// Transverse Mercator (x, y) in meters of `lat` at `d_lon` degrees from
+/// the central meridian, before the false easting and northing
+fn project(lat: f64, d_lon: f64) -> (f64, f64) {
+    let n = third_flattening();
+    let alpha = [
+        n / 2.0 - 2.0 / 3.0 * n.powi(2) + 5.0 / 16.0 * n.powi(3) + 41.0 / 180.0 * n.powi(4)
+            - 127.0 / 288.0 * n.powi(5)
+            + 7891.0 / 37800.0 * n.powi(6),
+        13.0 / 48.0 * n.powi(2) - 3.0 / 5.0 * n.powi(3)
+            + 557.0 / 1440.0 * n.powi(4)
+            + 281.0 / 630.0 * n.powi(5)
+            - 1_983_433.0 / 1_935_360.0 * n.powi(6),
+        61.0 / 240.0 * n.powi(3) - 103.0 / 140.0 * n.powi(4)
+            + 15061.0 / 26880.0 * n.powi(5)
+            + 167_603.0 / 181_440.0 * n.powi(6),
+        49561.0 / 161_280.0 * n.powi(4) - 179.0 / 168.0 * n.powi(5)
+            + 6_601_661.0 / 7_257_600.0 * n.powi(6),
+        34729.0 / 80640.0 * n.powi(5) - 3_418_889.0 / 1_995_840.0 * n.powi(6),
+        212_378_941.0 / 319_334_400.0 * n.powi(6),
+    ];
+    let lambda = d_lon.to_radians();
+    let tau_prime = conformal_tan(lat.to_radians().tan());
+    let xi_prime = tau_prime.atan2(lambda.cos());
+    let eta_prime = (lambda.sin() / (tau_prime * tau_prime + lambda.cos().powi(2)).sqrt()).asinh();
+    let (mut xi, mut eta) = (xi_prime, eta_prime);
+    for (j, a) in alpha.iter().enumerate() {
+        let k = 2.0 * (j + 1) as f64;
+        xi += a * (k * xi_prime).sin() * (k * eta_prime).cosh();
+        eta += a * (k * xi_prime).cos() * (k * eta_prime).sinh();
+    }
+    (FALSE_EASTING + scaled_radius() * eta, scaled_radius() * xi)
+}
+
+/// Latitude and degrees from the central meridian of a grid position,
+/// the inverse of `project`
+fn unproject(easting: f64, northing: f64) -> (f64, f64) {
+    let n = third_flattening();
+    let beta = [
+        n / 2.0 - 2.0 / 3.0 * n.powi(2) + 37.0 / 96.0 * n.powi(3)
+            - 1.0 / 360.0 * n.powi(4)
+            - 81.0 / 512.0 * n.powi(5)
+            + 96199.0 / 604_800.0 * n.powi(6),
+        1.0 / 48.0 * n.powi(2) + 1.0 / 15.0 * n.powi(3) - 437.0 / 1440.0 * n.powi(4)
+            + 46.0 / 105.0 * n.powi(5)
+            - 1_118_711.0 / 3_870_720.0 * n.powi(6),
+        17.0 / 480.0 * n.powi(3) - 37.0 / 840.0 * n.powi(4) - 209.0 / 4480.0 * n.powi(5)
+            + 5569.0 / 90720.0 * n.powi(6),
+        4397.0 / 161_280.0 * n.powi(4)
+            - 11.0 / 504.0 * n.powi(5)
+            - 830_251.0 / 7_257_600.0 * n.powi(6),
+        4583.0 / 161_280.0 * n.powi(5) - 108_847.0 / 3_991_680.0 * n.powi(6),
+        20_648_693.0 / 638_668_800.0 * n.powi(6),
+    ];
+    let xi = northing / scaled_radius();
+    let eta = (easting - FALSE_EASTING) / scaled_radius();
+    let (mut xi_prime, mut eta_prime) = (xi, eta);
+    for (j, b) in beta.iter().enumerate() {
+        let k = 2.0 * (j + 1) as f64;
+        xi_prime -= b * (k * xi).sin() * (k * eta).cosh();
+        eta_prime -= b * (k * xi).cos() * (k * eta).sinh();
+    }
+    let tau_prime = xi_prime.sin() / (eta_prime.sinh().powi(2) + xi_prime.cos().powi(2)).sqrt();
+
+    // Newton-Raphson for the geodetic latitude tangent
+    let e2 = eccentricity().powi(2);
+    let mut tau = tau_prime;
+    for _ in 0..10 {
+        let tau_i = conformal_tan(tau);
+        let delta = (tau_prime - tau_i) / (1.0 + tau_i * tau_i).sqrt()
+            * (1.0 + (1.0 - e2) * tau * tau)
+            / ((1.0 - e2) * (1.0 + tau * tau).sqrt());
+        tau += delta;
+        if delta.abs() < 1e-12 {
+            break;
+        }
+    }
+    let lambda = eta_prime.sinh().atan2(xi_prime.cos());
+    (tau.atan().to_degrees(), lambda.to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Coordinate, b: &Coordinate) {
+        assert!(
+            (a.latitude.get() - b.latitude.get()).abs() < 1e-9
+                && (a.longitude.get() - b.longitude.get()).abs() < 1e-9,
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn utm_published_values() {
+        // Eiffel Tower, the example from Movable Type's UTM/MGRS scripts
+        let eiffel = Coordinate::new(48.8582, 2.2945).unwrap();
+        let utm = Utm::from_coordinate(&eiffel).unwrap();
+        assert_eq!(utm.to_string(), "31U 448252 5411933");
+        assert_eq!(Mgrs::from_utm(&utm).to_string(), "31U DQ 48251 11932");
+
+        // Equator on the central meridian
+        let utm = Utm::from_coordinate(&Coordinate::new(0.0, 3.0).unwrap()).unwrap();
+        assert!((utm.easting - 500_000.0).abs() < 1e-6 && utm.northing.abs() < 1e-6);
+        assert_eq!(utm.band, 'N');
+    }
+
+    #[test]
+    fn utm_round_trip() {
+        let coords = [
+            Coordinate::new(47.453551, -116.788118).unwrap(),
+            Coordinate::new(-33.856784, 151.215297).unwrap(),
+            Coordinate::new(-79.9, -179.9).unwrap(),
+            Coordinate::new(83.9, 179.9).unwrap(),
+            Coordinate::new(60.0, 5.0).unwrap(),
+            Coordinate::new(78.0, 20.0).unwrap(),
+        ];
+        for c in coords.iter() {
+            let utm = Utm::from_coordinate(c).unwrap();
+            assert_close(&utm.to_coordinate().unwrap(), c);
+            let parsed: Utm = format!("{:.6}", utm).parse().unwrap();
+            assert_close(&parsed.to_coordinate().unwrap(), c);
+
+            let mgrs = Mgrs::from_utm(&utm);
+            let back = mgrs.to_utm().unwrap();
+            assert_eq!((back.zone, back.band), (utm.zone, utm.band));
+            assert!((back.easting - utm.easting).abs() < 1e-6, "{}", mgrs);
+            assert!((back.northing - utm.northing).abs() < 1e-6, "{}", mgrs);
+            let parsed: Mgrs = mgrs.to_string().parse().unwrap();
+            let back = parsed.to_coordinate().unwrap();
+            // Truncated to the meter
+            assert!(
+                (back.latitude.get() - c.latitude.get()).abs() < 3e-5,
+                "{}",
+                mgrs
+            );
+        }
+    }
+
+    #[test]
+    fn zone_exceptions() {
+        // Bergen is in the widened zone 32V
+        let utm = Utm::from_coordinate(&Coordinate::new(60.39, 5.32).unwrap()).unwrap();
+        assert_eq!((utm.zone, utm.band), (32, 'V'));
+        // Longyearbyen, Svalbard has no zone 32, 34 or 36
+        let utm = Utm::from_coordinate(&Coordinate::new(78.22, 15.65).unwrap()).unwrap();
+        assert_eq!((utm.zone, utm.band), (33, 'X'));
+        assert!(Utm::from_coordinate(&Coordinate::new(84.5, 0.0).unwrap()).is_err());
+        assert!(Utm::from_coordinate(&Coordinate::new(-80.5, 0.0).unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let utm: Utm = "11 t 516012 5255642".parse().unwrap();
+        assert_eq!((utm.zone, utm.band), (11, 'T'));
+        assert!("61T 516012 5255642".parse::<Utm>().is_err());
+        assert!("11I 516012 5255642".parse::<Utm>().is_err());
+        assert!("47N 27 12".parse::<Utm>().is_err());
+
+        let mgrs: Mgrs = "31udq4811".parse().unwrap();
+        assert_eq!(mgrs.square, ('D', 'Q'));
+        assert_eq!((mgrs.easting, mgrs.northing), (48_000.0, 11_000.0));
+        assert_eq!(format!("{:.2}", mgrs), "31U DQ 48 11");
+        assert!("31U DQ 481".parse::<Mgrs>().is_err());
+        assert!("31U DI 48251 11932".parse::<Mgrs>().is_err());
+    }
+}
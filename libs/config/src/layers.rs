@@ -0,0 +1,612 @@
+//! Merging the config from a system file, a user file, `VEHICLE_NAV_*`
+//! environment variables and `section.key=value` overrides, later layers
+//! take precedence over earlier ones
+
+use crate::{validation, Config, LoadError};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+
+/// Where a config value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer {
+    System(PathBuf),
+    User(PathBuf),
+    /// Name of the environment variable
+    Environment(String),
+    /// The `section.key=value` argument
+    CommandLine(String),
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::System(p) => write!(f, "system file {}", p.display()),
+            Layer::User(p) => write!(f, "user file {}", p.display()),
+            Layer::Environment(var) => write!(f, "environment {}", var),
+            Layer::CommandLine(arg) => write!(f, "command line --set {}", arg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    /// Skipped if it doesn't exist and isn't required
+    File {
+        layer: Layer,
+        required: bool,
+    },
+    /// Environment variable name segments, and its value
+    EnvVar {
+        name: String,
+        segments: Vec<String>,
+        value: String,
+    },
+    Override(String),
+}
+
+/// The layers of a config, in order of increasing precedence
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigSources {
+    sources: Vec<Source>,
+}
+
+impl ConfigSources {
+    /// Prefix of the environment variables that set config values
+    pub const ENV_PREFIX: &'static str = "VEHICLE_NAV_";
+
+    /// Separates the section and key in environment variable names,
+    /// e.g. `VEHICLE_NAV_WINDOW__WIDTH`
+    pub const ENV_SEPARATOR: &'static str = "__";
+
+    pub fn new() -> Self {
+        ConfigSources::default()
+    }
+
+    /// System wide config file, skipped if it doesn't exist
+    pub fn with_system_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sources.push(Source::File {
+            layer: Layer::System(path.as_ref().to_path_buf()),
+            required: false,
+        });
+        self
+    }
+
+    /// User config file, it's an error if it doesn't exist
+    pub fn with_user_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.sources.push(Source::File {
+            layer: Layer::User(path.as_ref().to_path_buf()),
+            required: true,
+        });
+        self
+    }
+
+    /// Variables named `VEHICLE_NAV_<SECTION>__<KEY>`, or `VEHICLE_NAV_<KEY>`
+    /// for top level keys, the rest are ignored.
+    /// Names, prefix included, are matched case insensitively and with `_`
+    /// for `-` against the known keys, e.g. `VEHICLE_NAV_STARTUP_DEFAULTS__ZOOM`
+    /// sets `startup-defaults.zoom`. Loading fails on names that don't match one.
+    pub fn with_env_vars<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let prefix_len = Self::ENV_PREFIX.len();
+        for (name, value) in vars.into_iter() {
+            let name = name.into();
+            let has_prefix = name
+                .get(..prefix_len)
+                .map(|p| p.eq_ignore_ascii_case(Self::ENV_PREFIX))
+                == Some(true);
+            let segments: Vec<String> = match &name[if has_prefix { prefix_len } else { 0 }..] {
+                path if has_prefix && !path.is_empty() => path
+                    .split(Self::ENV_SEPARATOR)
+                    .map(str::to_lowercase)
+                    .collect(),
+                _ => continue,
+            };
+            self.sources.push(Source::EnvVar {
+                name,
+                segments,
+                value: value.into(),
+            });
+        }
+        self
+    }
+
+    /// `section.key=value` arguments, values are TOML (`true`, `1024`,
+    /// `{ x = 0.5, y = 0.75 }`) or otherwise taken as a string
+    pub fn with_overrides<I, S>(mut self, overrides: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.sources
+            .extend(overrides.into_iter().map(|o| Source::Override(o.into())));
+        self
+    }
+}
+
+/// The merged config, and the layer each of its values came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayeredConfig {
+    pub config: Config,
+    /// Layer of each value set by one, keyed by TOML path
+    origins: BTreeMap<String, Layer>,
+}
+
+impl LayeredConfig {
+    /// Layer the value at TOML path `path` came from, `None` for defaults
+    pub fn origin(&self, path: &str) -> Option<&Layer> {
+        let mut path = path;
+        loop {
+            if let Some(layer) = self.origins.get(path) {
+                return Some(layer);
+            }
+            path = &path[..path.rfind('.')?];
+        }
+    }
+
+    /// The merged config as TOML, each value commented with the layer it came from
+    pub fn annotated(&self) -> String {
+        let mut out = String::new();
+        // Serializing a valid config can't fail
+        if let Ok(Value::Table(table)) = Value::try_from(&self.config) {
+            self.annotate_table(&mut out, "", &table);
+        }
+        out
+    }
+
+    fn annotate_table(&self, out: &mut String, prefix: &str, table: &Table) {
+        let path = |key: &str| {
+            if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", prefix, key)
+            }
+        };
+
+        for (key, value) in table.iter().filter(|(_, v)| !v.is_table()) {
+            let origin = match self.origin(&path(key)) {
+                Some(layer) => layer.to_string(),
+                None => "default".to_string(),
+            };
+            let _ = writeln!(out, "{} = {}  # {}", toml_key(key), value, origin);
+        }
+        for (key, value) in table.iter() {
+            if let Value::Table(t) = value {
+                let path = path(key);
+                let header = path.split('.').map(toml_key).collect::<Vec<_>>();
+                let _ = writeln!(out, "\n[{}]", header.join("."));
+                self.annotate_table(out, &path, t);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Merges the layers of `sources`, validates and deserializes the result.
+    /// Environment variables and overrides can only set known keys.
+    pub fn load_layered(sources: &ConfigSources) -> Result<LayeredConfig, LoadError> {
+        let mut merged = Value::Table(Table::new());
+        let mut origins = BTreeMap::new();
+        let known = known_keys();
+
+        for source in sources.sources.iter() {
+            match source {
+                Source::File { layer, required } => {
+                    let path = match layer {
+                        Layer::System(p) | Layer::User(p) => p,
+                        _ => continue,
+                    };
+                    if !path.exists() {
+                        if *required {
+                            return Err(LoadError::LoadPath(path.to_path_buf()));
+                        }
+                        log::debug!("Skipping missing config {}", path.display());
+                        continue;
+                    }
+                    let content = fs::read_to_string(fs::canonicalize(path)?)?;
+                    let value: Value = toml::from_str(&content)?;
+                    log::debug!("Loading config {}", path.display());
+                    merge(&mut merged, value, "", layer, &mut origins);
+                }
+                Source::EnvVar {
+                    name,
+                    segments,
+                    value,
+                } => {
+                    let layer = Layer::Environment(name.clone());
+                    resolve_keys(segments, &known)
+                        .and_then(|keys| {
+                            set(&mut merged, &keys, parse_value(value), &layer, &mut origins)
+                        })
+                        .map_err(|e| LoadError::EnvVar(name.clone(), e))?;
+                }
+                Source::Override(arg) => {
+                    let (path, value) = match arg.split_once('=') {
+                        Some((path, value)) => (path.trim(), value.trim()),
+                        None => {
+                            return Err(LoadError::Override(
+                                arg.clone(),
+                                "expected section.key=value".to_string(),
+                            ))
+                        }
+                    };
+                    let keys: Vec<String> = path.split('.').map(str::to_string).collect();
+                    let layer = Layer::CommandLine(arg.clone());
+                    check_known(&keys, &known)
+                        .and_then(|_| {
+                            set(&mut merged, &keys, parse_value(value), &layer, &mut origins)
+                        })
+                        .map_err(|e| LoadError::Override(arg.clone(), e))?;
+                }
+            }
+        }
+
+        let config = merged.try_into()?;
+        validation::validate(&config)?;
+        Ok(LayeredConfig { config, origins })
+    }
+}
+
+/// Merges `value` into `into`, tables are merged key by key and everything
+/// else is replaced
+fn merge(
+    into: &mut Value,
+    value: Value,
+    prefix: &str,
+    layer: &Layer,
+    origins: &mut BTreeMap<String, Layer>,
+) {
+    match (into, value) {
+        (Value::Table(into), Value::Table(table)) => {
+            for (key, value) in table.into_iter() {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                match into.get_mut(&key) {
+                    Some(existing) if existing.is_table() && value.is_table() => {
+                        merge(existing, value, &path, layer, origins)
+                    }
+                    _ => {
+                        replace_origin(origins, &path, layer);
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (into, value) => {
+            replace_origin(origins, prefix, layer);
+            *into = value;
+        }
+    }
+}
+
+/// Sets the value at `keys`, creating the tables leading up to it
+fn set(
+    root: &mut Value,
+    keys: &[String],
+    value: Value,
+    layer: &Layer,
+    origins: &mut BTreeMap<String, Layer>,
+) -> Result<(), String> {
+    if keys.iter().any(|k| k.is_empty()) {
+        return Err("empty key".to_string());
+    }
+    let (last, tables) = match keys.split_last() {
+        Some(split) => split,
+        None => return Err("empty key".to_string()),
+    };
+    let mut table = root;
+    for (i, key) in tables.iter().enumerate() {
+        table = match table {
+            Value::Table(t) => t
+                .entry(key.clone())
+                .or_insert_with(|| Value::Table(Table::new())),
+            _ => return Err(format!("{} is not a table", keys[..i].join("."))),
+        };
+    }
+    match table {
+        Value::Table(t) => {
+            t.insert(last.clone(), value);
+            replace_origin(origins, &keys.join("."), layer);
+            Ok(())
+        }
+        _ => Err(format!("{} is not a table", tables.join("."))),
+    }
+}
+
+/// Values set below `path` by earlier layers are gone once it's replaced
+fn replace_origin(origins: &mut BTreeMap<String, Layer>, path: &str, layer: &Layer) {
+    let nested = format!("{}.", path);
+    origins.retain(|p, _| !p.starts_with(&nested));
+    origins.insert(path.to_string(), layer.clone());
+}
+
+/// Every config key, with the tables of the sample config. Options it
+/// leaves out are filled in.
+fn known_keys() -> Value {
+    let mut config = Config::sample_config();
+    let tiler = &mut config.tiler;
+    tiler.url_template.get_or_insert_with(String::new);
+    tiler.mbtiles_path.get_or_insert_with(PathBuf::new);
+    tiler.tile_directory.get_or_insert_with(PathBuf::new);
+    // Serializing a valid config can't fail
+    Value::try_from(config).unwrap_or_else(|_| Value::Table(Table::new()))
+}
+
+/// Errors unless `keys` is the path of a known value or table
+fn check_known(keys: &[String], known: &Value) -> Result<(), String> {
+    keys.iter()
+        .enumerate()
+        .try_fold(known, |table, (i, key)| {
+            table
+                .get(key)
+                .ok_or_else(|| format!("unknown key {}", keys[..=i].join(".")))
+        })
+        .map(|_| ())
+}
+
+/// Matches lowercased environment variable segments to the `known` keys,
+/// so `startup_defaults` finds `startup-defaults`
+fn resolve_keys(segments: &[String], known: &Value) -> Result<Vec<String>, String> {
+    let mut table = known;
+    let mut keys = Vec::with_capacity(segments.len());
+    for segment in segments.iter() {
+        let (key, value) = table
+            .as_table()
+            .and_then(|t| {
+                t.iter()
+                    .find(|(k, _)| k.replace('-', "_") == segment.replace('-', "_"))
+            })
+            .ok_or_else(|| {
+                let mut path = keys.join(".");
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                format!("unknown key {}{}", path, segment)
+            })?;
+        keys.push(key.clone());
+        table = value;
+    }
+    Ok(keys)
+}
+
+/// TOML values, anything that doesn't parse is a string
+fn parse_value(s: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {}", s))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(s.to_string()))
+}
+
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn sample_path() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .join("sample_config")
+            .join("config.toml")
+    }
+
+    #[test]
+    fn layer_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = dir.path().join("config.toml");
+        fs::write(&user, "[window]\nwidth = 1024\nheight = 768\n").unwrap();
+
+        let sources = ConfigSources::new()
+            .with_system_file(sample_path())
+            .with_user_file(&user)
+            .with_env_vars(vec![
+                ("VEHICLE_NAV_WINDOW__HEIGHT", "900"),
+                ("VEHICLE_NAV_STARTUP_DEFAULTS__ZOOM", "14"),
+                ("HOME", "/root"),
+            ])
+            .with_overrides(vec![
+                "startup-defaults.zoom=15",
+                "tiler.cache.max_size_mb = 1024",
+            ]);
+        let layered = Config::load_layered(&sources).unwrap();
+        let config = &layered.config;
+
+        assert_eq!(config.window.title, "VehicleNAV");
+        assert_eq!(config.window.width, 1024);
+        assert_eq!(config.window.height, 900);
+        assert_eq!(config.startup_defaults.zoom.get(), 15);
+        assert_eq!(config.tiler.cache.as_ref().unwrap().max_size_mb, 1024);
+
+        assert_eq!(
+            layered.origin("window.title"),
+            Some(&Layer::System(sample_path()))
+        );
+        assert_eq!(layered.origin("window.width"), Some(&Layer::User(user)));
+        assert_eq!(
+            layered.origin("window.height"),
+            Some(&Layer::Environment(
+                "VEHICLE_NAV_WINDOW__HEIGHT".to_string()
+            ))
+        );
+        assert_eq!(
+            layered.origin("startup-defaults.zoom"),
+            Some(&Layer::CommandLine("startup-defaults.zoom=15".to_string()))
+        );
+        // Nested values come from the layer that set their table
+        assert_eq!(
+            layered.origin("follow.anchor.x"),
+            Some(&Layer::System(sample_path()))
+        );
+    }
+
+    #[test]
+    fn env_var_names() {
+        let sources = ConfigSources::new()
+            .with_system_file(sample_path())
+            .with_env_vars(vec![
+                ("VEHICLE_NAV_NAME", "from env"),
+                ("VEHICLE_NAV_TILER__KIND", "UrlTemplate"),
+                (
+                    "VEHICLE_NAV_TILER__URL_TEMPLATE",
+                    "http://{s}.example.com/{z}/{x}/{y}.png",
+                ),
+                ("VEHICLE_NAV_FOLLOW__ANCHOR__Y", "0.5"),
+                ("vehicle_nav_window__width", "1024"),
+                ("VEHICLE_NAV_TILER__MBTILES_PATH", "/tmp/tiles.mbtiles"),
+                ("VEHICLE_NAV_", "ignored"),
+                ("VEHICLE_NA", "ignored"),
+            ]);
+        let config = Config::load_layered(&sources).unwrap().config;
+        assert_eq!(config.name, "from env");
+        assert_eq!(config.tiler.kind, crate::TileSourceKind::UrlTemplate);
+        assert_eq!(
+            config.tiler.url_template.as_deref(),
+            Some("http://{s}.example.com/{z}/{x}/{y}.png")
+        );
+        assert_relative_eq!(config.follow.anchor().y, 0.5);
+        assert_relative_eq!(config.follow.anchor().x, 0.5);
+        assert_eq!(config.window.width, 1024);
+        assert_eq!(
+            config.tiler.mbtiles_path,
+            Some(PathBuf::from("/tmp/tiles.mbtiles"))
+        );
+
+        for name in &["VEHICLE_NAV_WINDOW__WIDHT", "VEHICLE_NAV_NAME__FIRST"] {
+            let sources = ConfigSources::new()
+                .with_system_file(sample_path())
+                .with_env_vars(vec![(*name, "1")]);
+            assert!(
+                matches!(Config::load_layered(&sources), Err(LoadError::EnvVar(n, _)) if n == *name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.toml");
+
+        let sources = ConfigSources::new()
+            .with_system_file(&missing)
+            .with_user_file(sample_path());
+        assert_eq!(
+            Config::load_layered(&sources).unwrap().config,
+            Config::sample_config()
+        );
+
+        let sources = ConfigSources::new()
+            .with_system_file(sample_path())
+            .with_user_file(&missing);
+        assert!(matches!(
+            Config::load_layered(&sources),
+            Err(LoadError::LoadPath(p)) if p == missing
+        ));
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        for arg in &[
+            "window.width",
+            "=5",
+            "window..width=5",
+            "name.first=a",
+            "window.widht=5",
+            "units.system.metric=true",
+        ] {
+            let sources = ConfigSources::new()
+                .with_system_file(sample_path())
+                .with_overrides(vec![*arg]);
+            assert!(
+                matches!(Config::load_layered(&sources), Err(LoadError::Override(a, _)) if a == *arg),
+                "{}",
+                arg
+            );
+        }
+
+        // The merged result is validated
+        let sources = ConfigSources::new()
+            .with_system_file(sample_path())
+            .with_overrides(vec!["window.width=0", "pan.step_pixels=0"]);
+        let err = match Config::load_layered(&sources) {
+            Err(LoadError::Validation(e)) => e,
+            res => panic!("{:?}", res),
+        };
+        let paths: Vec<&str> = err.fields.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["window.width", "pan.step_pixels"]);
+
+        // Known keys the sample config leaves out
+        let sources = ConfigSources::new()
+            .with_system_file(sample_path())
+            .with_overrides(vec![
+                "tiler.tile_directory=/tmp/tiles",
+                "tiler.url_template=http://example.com/{z}/{x}/{y}.png",
+            ]);
+        let config = Config::load_layered(&sources).unwrap().config;
+        assert_eq!(
+            config.tiler.tile_directory,
+            Some(PathBuf::from("/tmp/tiles"))
+        );
+    }
+
+    #[test]
+    fn annotated_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = dir.path().join("config.toml");
+        let content = fs::read_to_string(sample_path()).unwrap();
+        fs::write(&system, &content[..content.find("[follow]").unwrap()]).unwrap();
+
+        let sources = ConfigSources::new()
+            .with_system_file(&system)
+            .with_overrides(vec!["window.title=Dash"]);
+        let layered = Config::load_layered(&sources).unwrap();
+        let out = layered.annotated();
+
+        assert!(
+            out.starts_with(&format!(
+                "name = \"sample config\"  # system file {}\n",
+                system.display()
+            )),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("\n[window]\n")
+                && out.contains("\ntitle = \"Dash\"  # command line --set window.title=Dash\n"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("\n[tiler.cache]\n")
+                && out.contains("\n[units]\nsystem = \"Metric\"  # default\n"),
+            "{}",
+            out
+        );
+
+        // The annotated output is a config itself
+        let config: Config = toml::from_str(&out).unwrap();
+        assert_eq!(config, layered.config);
+    }
+}
@@ -1,14 +1,24 @@
 #![deny(warnings)]
 
-use common::{Daylight, Latitude, Longitude, Scale, Zoom};
+use common::{
+    Daylight, Latitude, Longitude, MapOrientation, Scale, ScreenAnchor, TilePlaceholder,
+    UnitSystem, Zoom,
+};
 use err_derive::Error;
+use osm_client::RetryPolicy;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use std::{fs, io};
 use url::Url;
 
-// TODO add a ValidationError
+pub use crate::layers::{ConfigSources, Layer, LayeredConfig};
+pub use crate::validation::{FieldError, ValidationError};
+
+mod layers;
+mod validation;
+
 #[derive(Debug, Error)]
 pub enum LoadError {
     #[error(display = "IO error")]
@@ -19,8 +29,15 @@ pub enum LoadError {
 
     #[error(display = "Config TOML format error")]
     TomlFormat(#[error(source)] toml::de::Error),
-    //#[error(display = "Config validation error")]
-    //Validation(#[error(source)] ValidationError),
+
+    #[error(display = "Config validation error: {}", _0)]
+    Validation(#[error(source)] ValidationError),
+
+    #[error(display = "Invalid config override {:?}, {}", _0, _1)]
+    Override(String, String),
+
+    #[error(display = "Invalid config environment variable {}, {}", _0, _1)]
+    EnvVar(String, String),
 }
 
 // TODO - write to file tests
@@ -44,11 +61,35 @@ pub struct Config {
     pub imu_gps: ImuGps,
     #[serde(rename(serialize = "startup-defaults", deserialize = "startup-defaults"))]
     pub startup_defaults: StartupDefaults,
+    #[serde(default)]
+    pub follow: Follow,
+    #[serde(default)]
+    pub pan: Pan,
+    #[serde(default)]
+    pub units: Units,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Tiler {
+    /// Kind of tile source, defaults to osmscout-server
+    #[serde(default)]
+    pub kind: TileSourceKind,
+    /// osmscout-server base url
     pub url: Url,
+    /// XYZ url template, used by the `UrlTemplate` kind, e.g.
+    /// "http://{s}.example.com/{z}/{x}/{y}{r}.png"
+    /// placeholders: {z}, {x}, {y}, {s}, {r}, {scale} and {daylight}
+    #[serde(default)]
+    pub url_template: Option<String>,
+    /// Substituted for {s} in the url template, defaults to a, b and c
+    #[serde(default)]
+    pub subdomains: Vec<String>,
+    /// Raster (png) MBTiles file, used by the `MbTiles` kind
+    #[serde(default)]
+    pub mbtiles_path: Option<PathBuf>,
+    /// Root of a {z}/{x}/{y}.png tile tree, used by the `Directory` kind
+    #[serde(default)]
+    pub tile_directory: Option<PathBuf>,
     /// Determines tile_size, default is 256 if scale not provided/supported
     /// "1" => 256
     /// "2" => 512
@@ -57,6 +98,97 @@ pub struct Tiler {
     pub scale: Option<Scale>,
     #[serde(default)]
     pub support_daynight: bool,
+    /// Per-request timeout, no timeout if not provided
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Maximum number of attempts per tile request, including the first one.
+    /// Defaults to a single attempt if not provided
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// Base delay of the exponential backoff between attempts
+    #[serde(default)]
+    pub retry_base_delay_ms: Option<u64>,
+    /// The delays between the attempts of a request add up to at most this,
+    /// e.g. long enough for a tile server starting up along with the vehicle.
+    /// Defaults to `RetryPolicy::DEFAULT_MAX_TOTAL_DELAY` if not provided
+    #[serde(default)]
+    pub retry_max_total_delay_ms: Option<u64>,
+    /// Pattern drawn in place of tiles that failed to load
+    #[serde(default)]
+    pub placeholder: TilePlaceholder,
+    /// Memory budget for decoded tiles, disabled if not provided
+    #[serde(default)]
+    pub memory_cache_mb: Option<u64>,
+    /// Tiles that fail to load are filled in from the tiles one level in, or
+    /// else from parent tiles up to this many zoom levels out. Disabled if not
+    /// provided or 0.
+    #[serde(default)]
+    pub fallback_parent_levels: Option<u8>,
+    /// Persistent on-disk tile cache, disabled if not provided
+    #[serde(default)]
+    pub cache: Option<TileCache>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub enum TileSourceKind {
+    /// osmscout-server, z/x/y and the options are query pairs on `url`
+    OsmScout,
+    /// Generic XYZ tile server (tileserver-gl, mbtileserver, a plain file server, etc)
+    /// described by `url_template`
+    UrlTemplate,
+    /// Offline tiles from the local `mbtiles_path` file
+    MbTiles,
+    /// Offline tiles from the local `tile_directory` tree, e.g. an exported tile pack
+    Directory,
+}
+
+impl Default for TileSourceKind {
+    fn default() -> Self {
+        TileSourceKind::OsmScout
+    }
+}
+
+impl Tiler {
+    pub const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 250;
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(
+            self.retry_base_delay_ms
+                .unwrap_or(Self::DEFAULT_RETRY_BASE_DELAY_MS),
+        )
+    }
+
+    pub fn retry_max_total_delay(&self) -> Duration {
+        self.retry_max_total_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(RetryPolicy::DEFAULT_MAX_TOTAL_DELAY)
+    }
+
+    /// `None` if `max_attempts` isn't provided
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.max_attempts.map(|max_attempts| {
+            RetryPolicy::new(max_attempts, self.retry_base_delay())
+                .with_max_total_delay(self.retry_max_total_delay())
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TileCache {
+    /// Cache root directory, created if it doesn't exist
+    pub path: PathBuf,
+    /// Least recently used tiles are evicted once the cache grows beyond this size
+    pub max_size_mb: u64,
+}
+
+impl TileCache {
+    pub fn max_size_bytes(&self) -> u64 {
+        self.max_size_mb.saturating_mul(1024 * 1024)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -78,32 +210,98 @@ pub struct ImuGps {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct StartupDefaults {
     pub daynight: Daylight,
-    // TODO - need to use the new_clamped() methods or do validation checks
     pub zoom: Zoom,
     pub latitude: Latitude,
     pub longitude: Longitude,
+    /// North-up or heading-up, defaults to north-up
+    #[serde(default)]
+    pub orientation: MapOrientation,
+}
+
+/// Following the vehicle, the map moves with it
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct Follow {
+    /// Where the vehicle sits on screen, in fractions of the window width
+    /// and height from the top left. Defaults to the lower third
+    #[serde(default)]
+    pub anchor: Option<ScreenAnchor>,
+    /// The vehicle moves back from the anchor, away from the direction of
+    /// travel, by up to this fraction of the smaller window dimension as speed
+    /// increases. No look-ahead if not provided
+    #[serde(default)]
+    pub max_look_ahead: Option<f64>,
+    /// Speed at which the look-ahead is at its maximum, meters per second
+    #[serde(default)]
+    pub full_look_ahead_speed_mps: Option<f64>,
+}
+
+impl Follow {
+    pub const DEFAULT_ANCHOR: ScreenAnchor = ScreenAnchor { x: 0.5, y: 0.75 };
+    pub const DEFAULT_FULL_LOOK_AHEAD_SPEED_MPS: f64 = 30.0;
+
+    pub fn anchor(&self) -> ScreenAnchor {
+        self.anchor.unwrap_or(Self::DEFAULT_ANCHOR)
+    }
+
+    /// Fraction of the smaller window dimension the vehicle is moved back by
+    /// at `speed_mps` meters per second
+    pub fn look_ahead(&self, speed_mps: f64) -> f64 {
+        let max = match self.max_look_ahead {
+            Some(max) => max,
+            None => return 0.0,
+        };
+        let full_speed = self
+            .full_look_ahead_speed_mps
+            .unwrap_or(Self::DEFAULT_FULL_LOOK_AHEAD_SPEED_MPS);
+        if full_speed <= 0.0 {
+            return max;
+        }
+        max * (speed_mps / full_speed).clamp(0.0, 1.0)
+    }
+}
+
+/// Moving the map around with the arrow keys
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct Pan {
+    /// Screen pixels the map moves per key press, the same at every zoom level
+    #[serde(default)]
+    pub step_pixels: Option<u32>,
+}
+
+impl Pan {
+    pub const DEFAULT_STEP_PIXELS: u32 = 100;
+
+    pub fn step_pixels(&self) -> u32 {
+        self.step_pixels.unwrap_or(Self::DEFAULT_STEP_PIXELS)
+    }
+}
+
+/// Units of the on-screen readouts
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub struct Units {
+    /// Metric, Imperial or Nautical, defaults to metric
+    #[serde(default)]
+    pub system: UnitSystem,
 }
 
 impl FromStr for Config {
     type Err = LoadError;
 
+    /// Deserialization errors have the line number, the range checks after
+    /// it report every invalid field
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let config = toml::from_str(s)?;
+        validation::validate(&config)?;
         Ok(config)
     }
 }
 
 impl Config {
+    /// Just the file at `path`, see `load_layered` for the system and user
+    /// files, the environment and overrides
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
-        if !path.as_ref().exists() {
-            return Err(LoadError::LoadPath(path.as_ref().to_path_buf()));
-        }
-        let p = fs::canonicalize(path)?;
-        let content = fs::read_to_string(&p)?;
-        let config = Config::from_str(&content)?;
-        log::debug!("Loading config {}", p.display());
-        //config.validate()?; TODO
-        Ok(config)
+        let sources = ConfigSources::new().with_user_file(path);
+        Ok(Config::load_layered(&sources)?.config)
     }
 
     pub fn write_to_file(&self, path: &Path) -> Result<(), WriteError> {
@@ -122,9 +320,25 @@ impl Config {
                 target_fps: 60,
             },
             tiler: Tiler {
+                kind: TileSourceKind::OsmScout,
                 url: Url::parse("http://127.0.0.1:8553/v1/tile").unwrap(),
+                url_template: None,
+                subdomains: Vec::new(),
+                mbtiles_path: None,
+                tile_directory: None,
                 scale: Some(Scale::Four),
                 support_daynight: true,
+                request_timeout_ms: Some(2000),
+                max_attempts: Some(4),
+                retry_base_delay_ms: Some(250),
+                retry_max_total_delay_ms: Some(10_000),
+                placeholder: TilePlaceholder::Checkerboard,
+                memory_cache_mb: Some(64),
+                fallback_parent_levels: Some(4),
+                cache: Some(TileCache {
+                    path: PathBuf::from("/var/cache/vehicle-nav/tiles"),
+                    max_size_mb: 512,
+                }),
             },
             imu_gps: ImuGps {
                 mount_location: [0.0; 3],
@@ -132,8 +346,20 @@ impl Config {
             startup_defaults: StartupDefaults {
                 daynight: Daylight::Day,
                 zoom: Zoom::new_clamped(11),
-                latitude: Latitude(47.453551),
-                longitude: Longitude(-116.788118),
+                latitude: Latitude::new_clamped(47.453551),
+                longitude: Longitude::new_clamped(-116.788118),
+                orientation: MapOrientation::NorthUp,
+            },
+            follow: Follow {
+                anchor: Some(Follow::DEFAULT_ANCHOR),
+                max_look_ahead: Some(0.2),
+                full_look_ahead_speed_mps: Some(30.0),
+            },
+            pan: Pan {
+                step_pixels: Some(Pan::DEFAULT_STEP_PIXELS),
+            },
+            units: Units {
+                system: UnitSystem::Metric,
             },
         }
     }
@@ -166,12 +392,37 @@ mod tests {
         assert_eq!(config.window.height, 600);
         assert_eq!(config.window.target_fps, 60);
 
+        assert_eq!(config.tiler.kind, TileSourceKind::OsmScout);
         assert_eq!(
             config.tiler.url,
             Url::parse("http://127.0.0.1:8553/v1/tile").unwrap()
         );
+        assert_eq!(config.tiler.url_template, None);
+        assert!(config.tiler.subdomains.is_empty());
+        assert_eq!(config.tiler.mbtiles_path, None);
+        assert_eq!(config.tiler.tile_directory, None);
         assert_eq!(config.tiler.scale, Some(Scale::Four));
         assert_eq!(config.tiler.support_daynight, true);
+        assert_eq!(
+            config.tiler.request_timeout(),
+            Some(Duration::from_millis(2000))
+        );
+        assert_eq!(config.tiler.max_attempts, Some(4));
+        assert_eq!(config.tiler.retry_base_delay(), Duration::from_millis(250));
+        assert_eq!(
+            config.tiler.retry_policy(),
+            Some(
+                RetryPolicy::new(4, Duration::from_millis(250))
+                    .with_max_total_delay(Duration::from_secs(10))
+            )
+        );
+        assert_eq!(config.tiler.placeholder, TilePlaceholder::Checkerboard);
+        assert_eq!(config.tiler.memory_cache_mb, Some(64));
+        assert_eq!(config.tiler.fallback_parent_levels, Some(4));
+        let cache = config.tiler.cache.as_ref().unwrap();
+        assert_eq!(cache.path, PathBuf::from("/var/cache/vehicle-nav/tiles"));
+        assert_eq!(cache.max_size_mb, 512);
+        assert_eq!(cache.max_size_bytes(), 512 * 1024 * 1024);
 
         assert_relative_eq!(config.imu_gps.mount_location[0], 0.0);
         assert_relative_eq!(config.imu_gps.mount_location[1], 0.0);
@@ -179,9 +430,196 @@ mod tests {
 
         assert_eq!(config.startup_defaults.daynight, Daylight::Day);
         assert_eq!(config.startup_defaults.zoom, Zoom::new_clamped(11));
-        assert_relative_eq!(config.startup_defaults.latitude.0, 47.453551);
-        assert_relative_eq!(config.startup_defaults.longitude.0, -116.788118);
+        assert_relative_eq!(config.startup_defaults.latitude.get(), 47.453551);
+        assert_relative_eq!(config.startup_defaults.longitude.get(), -116.788118);
+        assert_eq!(config.startup_defaults.orientation, MapOrientation::NorthUp);
+
+        assert_eq!(config.follow.anchor(), ScreenAnchor { x: 0.5, y: 0.75 });
+        assert_relative_eq!(config.follow.look_ahead(0.0), 0.0);
+        assert_relative_eq!(config.follow.look_ahead(15.0), 0.1);
+        assert_relative_eq!(config.follow.look_ahead(60.0), 0.2);
+
+        assert_eq!(config.pan.step_pixels(), 100);
+
+        assert_eq!(config.units.system, UnitSystem::Metric);
 
         assert_eq!(config, Config::sample_config());
     }
+
+    #[test]
+    fn url_template_kind() {
+        let mut content = fs::read_to_string(
+            std::env::current_dir()
+                .unwrap()
+                .join("sample_config")
+                .join("config.toml"),
+        )
+        .unwrap();
+        content = content.replace(
+            "kind = \"OsmScout\"\n",
+            "kind = \"UrlTemplate\"\nurl_template = \"http://{s}.example.com/{z}/{x}/{y}{r}.png\"\nsubdomains = [\"t1\", \"t2\"]\n",
+        );
+        let config = Config::from_str(&content).unwrap();
+        assert_eq!(config.tiler.kind, TileSourceKind::UrlTemplate);
+        assert_eq!(
+            config.tiler.url_template.as_deref(),
+            Some("http://{s}.example.com/{z}/{x}/{y}{r}.png")
+        );
+        assert_eq!(config.tiler.subdomains, vec!["t1", "t2"]);
+    }
+
+    #[test]
+    fn mbtiles_kind() {
+        let content = fs::read_to_string(
+            std::env::current_dir()
+                .unwrap()
+                .join("sample_config")
+                .join("config.toml"),
+        )
+        .unwrap()
+        .replace(
+            "kind = \"OsmScout\"\n",
+            "kind = \"MbTiles\"\nmbtiles_path = \"/data/maps/idaho.mbtiles\"\n",
+        );
+        let config = Config::from_str(&content).unwrap();
+        assert_eq!(config.tiler.kind, TileSourceKind::MbTiles);
+        assert_eq!(
+            config.tiler.mbtiles_path,
+            Some(PathBuf::from("/data/maps/idaho.mbtiles"))
+        );
+    }
+
+    #[test]
+    fn directory_kind() {
+        let content = fs::read_to_string(
+            std::env::current_dir()
+                .unwrap()
+                .join("sample_config")
+                .join("config.toml"),
+        )
+        .unwrap()
+        .replace(
+            "kind = \"OsmScout\"\n",
+            "kind = \"Directory\"\ntile_directory = \"/data/maps/idaho\"\n",
+        );
+        let config = Config::from_str(&content).unwrap();
+        assert_eq!(config.tiler.kind, TileSourceKind::Directory);
+        assert_eq!(
+            config.tiler.tile_directory,
+            Some(PathBuf::from("/data/maps/idaho"))
+        );
+    }
+
+    #[test]
+    fn validation_reports_every_field() {
+        let content = fs::read_to_string(
+            std::env::current_dir()
+                .unwrap()
+                .join("sample_config")
+                .join("config.toml"),
+        )
+        .unwrap();
+        let invalid = content
+            .replace("width = 800\n", "width = 0\n")
+            .replace("request_timeout_ms = 2000", "request_timeout_ms = 0")
+            .replace("retry_base_delay_ms = 250", "retry_base_delay_ms = 20000")
+            .replace("memory_cache_mb = 64", "memory_cache_mb = 0")
+            .replace("fallback_parent_levels = 4", "fallback_parent_levels = 20")
+            .replace("max_size_mb = 512", "max_size_mb = 0")
+            .replace(
+                "anchor = { x = 0.5, y = 0.75 }",
+                "anchor = { x = 0.5, y = 1.5 }",
+            )
+            .replace("step_pixels = 100", "step_pixels = 0");
+        let err = match Config::from_str(&invalid) {
+            Err(LoadError::Validation(e)) => e,
+            res => panic!("{:?}", res),
+        };
+        let paths: Vec<&str> = err.fields.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "window.width",
+                "tiler.request_timeout_ms",
+                "tiler.retry_base_delay_ms",
+                "tiler.memory_cache_mb",
+                "tiler.fallback_parent_levels",
+                "tiler.cache.max_size_mb",
+                "follow.anchor.y",
+                "pan.step_pixels",
+            ]
+        );
+        let msg = LoadError::Validation(err).to_string();
+        assert!(
+            msg.contains("window.width: 0 is less than 1; tiler.request_timeout_ms"),
+            "{}",
+            msg
+        );
+
+        // Without a total, base delays are checked against the retry policy's
+        let default_total = RetryPolicy::DEFAULT_MAX_TOTAL_DELAY.as_millis() as u64;
+        let invalid = content
+            .replace("retry_max_total_delay_ms = 10000\n", "")
+            .replace(
+                "retry_base_delay_ms = 250",
+                &format!("retry_base_delay_ms = {}", default_total + 1),
+            );
+        let err = match Config::from_str(&invalid) {
+            Err(LoadError::Validation(e)) => e,
+            res => panic!("{:?}", res),
+        };
+        assert_eq!(err.fields[0].path, "tiler.retry_base_delay_ms");
+
+        // Values the field types can't hold are rejected as they're deserialized
+        for (from, to, expected) in [
+            (
+                "zoom = 11\n",
+                "zoom = 19\n",
+                "Zoom 19 is not between 1 and 18",
+            ),
+            (
+                "latitude = 47.453551\n",
+                "latitude = nan\n",
+                "Latitude NaN is not between -90 and 90 degrees",
+            ),
+            (
+                "longitude = -116.788118\n",
+                "longitude = 200.0\n",
+                "Longitude 200 is not between -180 and 180 degrees",
+            ),
+        ]
+        .iter()
+        {
+            let err = match Config::from_str(&content.replace(from, to)) {
+                Err(LoadError::TomlFormat(e)) => e.to_string(),
+                res => panic!("{:?}", res),
+            };
+            assert!(err.contains(expected), "{}", err);
+            assert!(err.contains("startup-defaults"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn follow_defaults() {
+        let content = fs::read_to_string(
+            std::env::current_dir()
+                .unwrap()
+                .join("sample_config")
+                .join("config.toml"),
+        )
+        .unwrap();
+        let without = &content[..content.find("[follow]").unwrap()];
+        let config = Config::from_str(without).unwrap();
+        assert_eq!(config.follow, Follow::default());
+        assert_eq!(config.follow.anchor(), Follow::DEFAULT_ANCHOR);
+        assert_relative_eq!(config.follow.look_ahead(100.0), 0.0);
+        assert_eq!(config.pan, Pan::default());
+        assert_eq!(config.pan.step_pixels(), Pan::DEFAULT_STEP_PIXELS);
+        assert_eq!(config.units, Units::default());
+        assert_eq!(config.units.system, UnitSystem::Metric);
+
+        let nautical = content.replace("system = \"Metric\"", "system = \"Nautical\"");
+        let config = Config::from_str(&nautical).unwrap();
+        assert_eq!(config.units.system, UnitSystem::Nautical);
+    }
 }
@@ -0,0 +1,255 @@
+//! Range checks on the deserialized config, every invalid field gets
+//! reported at once. Values the field types can't hold at all, e.g. a
+//! latitude of 91 or a zoom of 19, are rejected when deserializing them.
+//!
+//! Every validated table destructures all of its fields, a new field doesn't
+//! build until it's checked or explicitly skipped. Tables with nothing to
+//! check, e.g. `units`, are skipped by `Config`.
+
+use crate::{Config, Follow, ImuGps, Pan, TileCache, Tiler, Window};
+use common::{ScreenAnchor, Zoom};
+use err_derive::Error;
+use std::fmt;
+
+/// A field that failed validation
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    /// TOML path of the field, e.g. `startup-defaults.latitude`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Every invalid field in a config, in the order they're checked
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error(display = "{}", display_fields(self))]
+pub struct ValidationError {
+    pub fields: Vec<FieldError>,
+}
+
+fn display_fields(e: &ValidationError) -> String {
+    e.fields
+        .iter()
+        .map(FieldError::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// A config table whose fields have range checks
+pub(crate) trait Validate {
+    /// Reports the invalid fields to `v`, by their keys in this table
+    fn validate(&self, v: &mut Validator);
+}
+
+pub(crate) fn validate(config: &Config) -> Result<(), ValidationError> {
+    let mut v = Validator::default();
+    config.validate(&mut v);
+    if v.fields.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { fields: v.fields })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Validator {
+    /// Keys of the tables being validated
+    tables: Vec<&'static str>,
+    fields: Vec<FieldError>,
+}
+
+impl Validator {
+    fn table<T: Validate>(&mut self, key: &'static str, table: &T) {
+        self.tables.push(key);
+        table.validate(self);
+        self.tables.pop();
+    }
+
+    fn check(&mut self, key: &str, result: Result<(), String>) {
+        if let Err(message) = result {
+            let mut path = self.tables.join(".");
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(key);
+            self.fields.push(FieldError { path, message });
+        }
+    }
+
+    /// Optional fields are only checked when set
+    fn check_some<T, F>(&mut self, key: &str, value: &Option<T>, check: F)
+    where
+        F: FnOnce(&T) -> Result<(), String>,
+    {
+        if let Some(value) = value {
+            self.check(key, check(value));
+        }
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self, v: &mut Validator) {
+        let Config {
+            name: _,
+            window,
+            tiler,
+            imu_gps,
+            // Zoom, latitude and longitude are checked as they're deserialized
+            startup_defaults: _,
+            follow,
+            pan,
+            units: _,
+        } = self;
+        v.table("window", window);
+        v.table("tiler", tiler);
+        v.table("imu-gps", imu_gps);
+        v.table("follow", follow);
+        v.table("pan", pan);
+    }
+}
+
+impl Validate for Window {
+    fn validate(&self, v: &mut Validator) {
+        let Window {
+            title: _,
+            width,
+            height,
+            target_fps,
+        } = self;
+        v.check("width", at_least(*width, 1));
+        v.check("height", at_least(*height, 1));
+        v.check("target_fps", at_least(*target_fps, 1));
+    }
+}
+
+impl Validate for Tiler {
+    fn validate(&self, v: &mut Validator) {
+        let Tiler {
+            kind: _,
+            url: _,
+            url_template: _,
+            subdomains: _,
+            mbtiles_path: _,
+            tile_directory: _,
+            scale: _,
+            support_daynight: _,
+            request_timeout_ms,
+            max_attempts,
+            retry_base_delay_ms,
+            // Any total is fine, no time for a retry is none
+            retry_max_total_delay_ms: _,
+            placeholder: _,
+            memory_cache_mb,
+            fallback_parent_levels,
+            cache,
+        } = self;
+        v.check_some("request_timeout_ms", request_timeout_ms, |ms| {
+            at_least(*ms, 1)
+        });
+        v.check_some("max_attempts", max_attempts, |n| at_least(*n, 1));
+        // Longer than the total leaves no time for a retry
+        let max_total_delay_ms = self.retry_max_total_delay().as_millis() as u64;
+        v.check_some("retry_base_delay_ms", retry_base_delay_ms, |ms| {
+            at_most(*ms, max_total_delay_ms)
+        });
+        v.check_some("memory_cache_mb", memory_cache_mb, |mb| at_least(*mb, 1));
+        // Parents of the deepest tiles as far out as the first level
+        v.check_some("fallback_parent_levels", fallback_parent_levels, |levels| {
+            at_most(*levels, Zoom::MAX.get() - Zoom::MIN.get())
+        });
+        if let Some(cache) = cache {
+            v.table("cache", cache);
+        }
+    }
+}
+
+impl Validate for TileCache {
+    fn validate(&self, v: &mut Validator) {
+        let TileCache {
+            path: _,
+            max_size_mb,
+        } = self;
+        v.check("max_size_mb", at_least(*max_size_mb, 1));
+    }
+}
+
+impl Validate for ImuGps {
+    fn validate(&self, v: &mut Validator) {
+        let ImuGps { mount_location } = self;
+        if mount_location.iter().any(|m| !m.is_finite()) {
+            v.check(
+                "mount_location",
+                Err(format!("{:?} is not a finite position", mount_location)),
+            );
+        }
+    }
+}
+
+impl Validate for Follow {
+    fn validate(&self, v: &mut Validator) {
+        let Follow {
+            anchor,
+            max_look_ahead,
+            full_look_ahead_speed_mps,
+        } = self;
+        if let Some(anchor) = anchor {
+            v.table("anchor", anchor);
+        }
+        v.check_some("max_look_ahead", max_look_ahead, |f| fraction(*f));
+        v.check_some(
+            "full_look_ahead_speed_mps",
+            full_look_ahead_speed_mps,
+            |speed| {
+                if speed.is_finite() && *speed >= 0.0 {
+                    Ok(())
+                } else {
+                    Err(format!("Speed {} is negative or not a number", speed))
+                }
+            },
+        );
+    }
+}
+
+impl Validate for ScreenAnchor {
+    fn validate(&self, v: &mut Validator) {
+        let ScreenAnchor { x, y } = self;
+        v.check("x", fraction(*x));
+        v.check("y", fraction(*y));
+    }
+}
+
+impl Validate for Pan {
+    fn validate(&self, v: &mut Validator) {
+        let Pan { step_pixels } = self;
+        v.check_some("step_pixels", step_pixels, |px| at_least(*px, 1));
+    }
+}
+
+fn at_least<T: PartialOrd + fmt::Display>(val: T, min: T) -> Result<(), String> {
+    if val >= min {
+        Ok(())
+    } else {
+        Err(format!("{} is less than {}", val, min))
+    }
+}
+
+fn at_most<T: PartialOrd + fmt::Display>(val: T, max: T) -> Result<(), String> {
+    if val <= max {
+        Ok(())
+    } else {
+        Err(format!("{} is more than {}", val, max))
+    }
+}
+
+fn fraction(val: f64) -> Result<(), String> {
+    if (0.0..=1.0).contains(&val) {
+        Ok(())
+    } else {
+        Err(format!("{} is not between 0 and 1", val))
+    }
+}
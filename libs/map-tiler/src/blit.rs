@@ -0,0 +1,212 @@
+use tiny_skia::{Pixmap, PremultipliedColorU8};
+
+/// Copies `src` into `dst` with its top left corner at (`x`, `y`), replacing
+/// the pixels underneath. Parts of `src` outside of `dst` are clipped.
+pub fn copy_pixmap(dst: &mut Pixmap, x: i32, y: i32, src: &Pixmap) {
+    let (dst_width, dst_height) = (i64::from(dst.width()), i64::from(dst.height()));
+    let (src_width, src_height) = (i64::from(src.width()), i64::from(src.height()));
+    let (x, y) = (i64::from(x), i64::from(y));
+    let cols = x.max(0)..(x + src_width).min(dst_width);
+    let rows = y.max(0)..(y + src_height).min(dst_height);
+    if cols.is_empty() || rows.is_empty() {
+        return;
+    }
+
+    let len = 4 * (cols.end - cols.start) as usize;
+    let src_data = src.data();
+    let dst_data = dst.data_mut();
+    for row in rows {
+        let dst_start = 4 * (row * dst_width + cols.start) as usize;
+        let src_start = 4 * ((row - y) * src_width + cols.start - x) as usize;
+        dst_data[dst_start..dst_start + len].copy_from_slice(&src_data[src_start..src_start + len]);
+    }
+}
+
+/// Moves the contents of `pixmap` right by `dx` and down by `dy` pixels,
+/// negative values move left and up. The exposed area is cleared to transparent.
+pub fn shift_pixmap(pixmap: &mut Pixmap, dx: i32, dy: i32) {
+    let width = pixmap.width() as usize;
+    let height = pixmap.height() as usize;
+    let dx_abs = dx.unsigned_abs() as usize;
+    let dy_abs = dy.unsigned_abs() as usize;
+    if dx_abs >= width || dy_abs >= height {
+        pixmap.fill(tiny_skia::Color::TRANSPARENT);
+        return;
+    }
+    if dx == 0 && dy == 0 {
+        return;
+    }
+
+    let stride = 4 * width;
+    let kept = 4 * (width - dx_abs);
+    let data = pixmap.data_mut();
+
+    // Walk the rows against the shift direction so no source row is
+    // overwritten before it's copied
+    let rows: Box<dyn Iterator<Item = usize>> = if dy > 0 {
+        Box::new((0..height).rev())
+    } else {
+        Box::new(0..height)
+    };
+    for row in rows {
+        let dst = row * stride;
+        let src_row = row as i64 - i64::from(dy);
+        if src_row < 0 || src_row >= height as i64 {
+            data[dst..dst + stride].fill(0);
+            continue;
+        }
+        let src = src_row as usize * stride;
+        if dx >= 0 {
+            data.copy_within(src..src + kept, dst + 4 * dx_abs);
+            data[dst..dst + 4 * dx_abs].fill(0);
+        } else {
+            data.copy_within(src + 4 * dx_abs..src + stride, dst);
+            data[dst + kept..dst + stride].fill(0);
+        }
+    }
+}
+
+/// Fills `dst` with `src` scaled by `scale`, `center` being the point in `src`
+/// that ends up in the middle of `dst`. Pixels are filtered bilinearly, past
+/// the edges of `src` its edge pixels are repeated.
+pub fn scale_pixmap(src: &Pixmap, dst: &mut Pixmap, center: (f64, f64), scale: f64) {
+    let (src_width, src_height) = (i64::from(src.width()), i64::from(src.height()));
+    let (half_width, half_height) = (f64::from(dst.width()) / 2.0, f64::from(dst.height()) / 2.0);
+    let stride = dst.width() as usize;
+    let pixels = src.pixels();
+    let at = |x: f64, y: f64| {
+        let x = (x as i64).max(0).min(src_width - 1);
+        let y = (y as i64).max(0).min(src_height - 1);
+        pixels[(y * src_width + x) as usize]
+    };
+
+    for (i, p) in dst.pixels_mut().iter_mut().enumerate() {
+        // Pixel centers are at .5
+        let x = ((i % stride) as f64 + 0.5 - half_width) / scale + center.0 - 0.5;
+        let y = ((i / stride) as f64 + 0.5 - half_height) / scale + center.1 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let corners = [
+            (at(x0, y0), (1.0 - fx) * (1.0 - fy)),
+            (at(x0 + 1.0, y0), fx * (1.0 - fy)),
+            (at(x0, y0 + 1.0), (1.0 - fx) * fy),
+            (at(x0 + 1.0, y0 + 1.0), fx * fy),
+        ];
+        let channel = |c: fn(PremultipliedColorU8) -> u8| {
+            let sum: f64 = corners
+                .iter()
+                .map(|(p, weight)| f64::from(c(*p)) * weight)
+                .sum();
+            sum.round() as u8
+        };
+        // Premultiplied channels never exceed alpha, neither does their weighted sum
+        if let Some(color) = PremultipliedColorU8::from_rgba(
+            channel(PremultipliedColorU8::red),
+            channel(PremultipliedColorU8::green),
+            channel(PremultipliedColorU8::blue),
+            channel(PremultipliedColorU8::alpha),
+        ) {
+            *p = color;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> Pixmap {
+        let mut pixmap = Pixmap::new(width, height).unwrap();
+        for (i, p) in pixmap.pixels_mut().iter_mut().enumerate() {
+            *p = PremultipliedColorU8::from_rgba(i as u8, i as u8, i as u8, 255).unwrap();
+        }
+        pixmap
+    }
+
+    #[test]
+    fn copy_is_clipped() {
+        let src = gradient(4, 3);
+        for x in -5..=8 {
+            for y in -4..=6 {
+                let mut dst = Pixmap::new(6, 5).unwrap();
+                copy_pixmap(&mut dst, x, y, &src);
+                for dst_y in 0..5 {
+                    for dst_x in 0..6 {
+                        let (src_x, src_y) = (dst_x - x, dst_y - y);
+                        let expected = if (0..4).contains(&src_x) && (0..3).contains(&src_y) {
+                            src.pixel(src_x as u32, src_y as u32).unwrap()
+                        } else {
+                            PremultipliedColorU8::from_rgba(0, 0, 0, 0).unwrap()
+                        };
+                        assert_eq!(
+                            dst.pixel(dst_x as u32, dst_y as u32),
+                            Some(expected),
+                            "copy to ({}, {}) at ({}, {})",
+                            x,
+                            y,
+                            dst_x,
+                            dst_y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shift_in_every_direction() {
+        let (width, height) = (7, 5);
+        let original = gradient(width, height);
+        for dx in -8..=8 {
+            for dy in -6..=6 {
+                let mut pixmap = original.clone();
+                shift_pixmap(&mut pixmap, dx, dy);
+                for y in 0..height as i32 {
+                    for x in 0..width as i32 {
+                        let (src_x, src_y) = (x - dx, y - dy);
+                        let expected = if (0..width as i32).contains(&src_x)
+                            && (0..height as i32).contains(&src_y)
+                        {
+                            original.pixel(src_x as u32, src_y as u32).unwrap()
+                        } else {
+                            PremultipliedColorU8::from_rgba(0, 0, 0, 0).unwrap()
+                        };
+                        assert_eq!(
+                            pixmap.pixel(x as u32, y as u32),
+                            Some(expected),
+                            "shift ({}, {}) at ({}, {})",
+                            dx,
+                            dy,
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scale_about_center() {
+        let src = gradient(4, 4);
+        let mut dst = Pixmap::new(4, 4).unwrap();
+
+        // Unscaled and centered is a copy
+        scale_pixmap(&src, &mut dst, (2.0, 2.0), 1.0);
+        assert_eq!(dst.data(), src.data());
+
+        // Doubled about the middle of the image, the gradient is 4y + x at pixel
+        // centers so samples between them are its linear interpolation
+        scale_pixmap(&src, &mut dst, (2.0, 2.0), 2.0);
+        let p = |dst: &Pixmap, x, y| dst.pixel(x, y).unwrap().red();
+        assert_eq!(p(&dst, 0, 0), 4); // 3.75
+        assert_eq!(p(&dst, 1, 1), 6); // 6.25
+        assert_eq!(p(&dst, 3, 1), 7); // 7.25
+        assert_eq!(p(&dst, 2, 2), 9); // 8.75
+
+        // Edge pixels are repeated outside of the source
+        scale_pixmap(&src, &mut dst, (0.0, 0.0), 1.0);
+        assert_eq!(p(&dst, 0, 0), 0);
+        assert_eq!(p(&dst, 3, 3), 5);
+    }
+}
@@ -0,0 +1,547 @@
+//! Persistent on-disk tile cache
+//!
+//! Tiles are stored as the raw bytes returned by the tile server in a
+//! `{root}/{scale}/{daylight}/{z}/{x}/{y}.png` tree.
+//! The total size is bounded, least recently used tiles are evicted first.
+//! Recency is tracked in memory and in the file modification times, which
+//! reads bump, on startup the index is rebuilt from them.
+
+use crate::TileSource;
+use bytes::Bytes;
+use common::{Daylight, Scale, TileCoord, TileNumber, Zoom};
+use err_derive::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use std::{fs, io, process};
+
+/// Temporary files older than this are left over from a crash, removed on open
+const STALE_TMP_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Makes temporary file names unique within the process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "Tile cache IO error: {}", _0)]
+    Io(#[error(source)] io::Error),
+
+    #[error(display = "Tile cache index lock is poisoned")]
+    LockPoisoned,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct TileKey {
+    pub scale: Option<Scale>,
+    pub daylight: Option<Daylight>,
+    pub tile: TileCoord,
+}
+
+impl TileKey {
+    const NO_SCALE_DIR: &'static str = "default";
+    const NO_DAYLIGHT_DIR: &'static str = "any";
+
+    /// Same scale and daylight, for `tile`
+    pub fn with_tile(&self, tile: TileCoord) -> Self {
+        TileKey { tile, ..*self }
+    }
+
+    fn relative_path(&self) -> PathBuf {
+        let scale = self
+            .scale
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| Self::NO_SCALE_DIR.to_string());
+        let daylight = self
+            .daylight
+            .map(|d| d.to_string())
+            .unwrap_or_else(|| Self::NO_DAYLIGHT_DIR.to_string());
+        PathBuf::from(scale)
+            .join(daylight)
+            .join(self.tile.zoom.to_string())
+            .join(self.tile.x.to_string())
+            .join(format!("{}.png", self.tile.y))
+    }
+
+    fn from_relative_path(path: &Path) -> Option<Self> {
+        let parts: Vec<&str> = path.iter().filter_map(|p| p.to_str()).collect();
+        if parts.len() != 5 {
+            return None;
+        }
+        let scale = match parts[0] {
+            Self::NO_SCALE_DIR => None,
+            s => Some(s.parse().ok()?),
+        };
+        let daylight = match parts[1] {
+            Self::NO_DAYLIGHT_DIR => None,
+            d => Some(d.parse().ok()?),
+        };
+        let zoom: u8 = parts[2].parse().ok()?;
+        if !(Zoom::MIN.get()..=Zoom::MAX.get()).contains(&zoom) {
+            return None;
+        }
+        let x: u32 = parts[3].parse().ok()?;
+        let y: u32 = parts[4].strip_suffix(".png")?.parse().ok()?;
+        let tile = TileCoord::new(Zoom::new_clamped(zoom), x, y)?;
+        Some(TileKey {
+            scale,
+            daylight,
+            tile,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<TileKey, Entry>,
+    /// Keys by `Entry::last_used`, least recently used first
+    by_use: BTreeMap<u64, TileKey>,
+    size: u64,
+    tick: u64,
+}
+
+impl Index {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, key: TileKey, size: u64) {
+        let last_used = self.next_tick();
+        if let Some(prev) = self.entries.insert(key, Entry { size, last_used }) {
+            self.size -= prev.size;
+            self.by_use.remove(&prev.last_used);
+        }
+        self.by_use.insert(last_used, key);
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &TileKey) {
+        if let Some(prev) = self.entries.remove(key) {
+            self.size -= prev.size;
+            self.by_use.remove(&prev.last_used);
+        }
+    }
+
+    fn touch(&mut self, key: &TileKey) -> bool {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(e) => {
+                self.by_use.remove(&e.last_used);
+                self.by_use.insert(tick, *key);
+                e.last_used = tick;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the keys that need to be evicted to get under `max_size`,
+    /// least recently used first
+    fn eviction_candidates(&self, max_size: u64) -> Vec<TileKey> {
+        let mut size = self.size;
+        let mut keys = Vec::new();
+        for key in self.by_use.values() {
+            if size <= max_size {
+                break;
+            }
+            size -= self.entries[key].size;
+            keys.push(*key);
+        }
+        keys
+    }
+}
+
+#[derive(Debug)]
+pub struct TileCache {
+    root: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>,
+}
+
+impl TileCache {
+    /// Opens (or creates) the cache rooted at `root`, limited to `max_size` bytes
+    pub fn open<P: AsRef<Path>>(root: P, max_size: u64) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let mut files = Vec::new();
+        Self::scan_dir(&root, &root, &mut files)?;
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut index = Index::default();
+        for (key, size, _) in files.into_iter() {
+            index.insert(key, size);
+        }
+        log::debug!(
+            "Opened TileCache {} with {} tiles, size={}, max_size={}",
+            root.display(),
+            index.entries.len(),
+            index.size,
+            max_size
+        );
+
+        let cache = TileCache {
+            root,
+            max_size,
+            index: Mutex::new(index),
+        };
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Total size in bytes of all the cached tiles
+    pub fn size(&self) -> Result<u64, Error> {
+        Ok(self.index.lock().map_err(|_| Error::LockPoisoned)?.size)
+    }
+
+    /// Number of cached tiles
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self
+            .index
+            .lock()
+            .map_err(|_| Error::LockPoisoned)?
+            .entries
+            .len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn contains(&self, key: &TileKey) -> Result<bool, Error> {
+        Ok(self
+            .index
+            .lock()
+            .map_err(|_| Error::LockPoisoned)?
+            .entries
+            .contains_key(key))
+    }
+
+    pub fn get(&self, key: &TileKey) -> Result<Option<Bytes>, Error> {
+        if !self
+            .index
+            .lock()
+            .map_err(|_| Error::LockPoisoned)?
+            .touch(key)
+        {
+            return Ok(None);
+        }
+        let path = self.root.join(key.relative_path());
+        match fs::read(&path) {
+            Ok(bytes) => {
+                // Keeps the recency across restarts
+                if let Err(e) =
+                    fs::File::open(&path).and_then(|f| f.set_modified(SystemTime::now()))
+                {
+                    log::debug!("Failed to touch {}: {}", path.display(), e);
+                }
+                Ok(Some(bytes.into()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Removed behind our back, forget about it
+                self.index
+                    .lock()
+                    .map_err(|_| Error::LockPoisoned)?
+                    .remove(key);
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn put(&self, key: &TileKey, bytes: &[u8]) -> Result<(), Error> {
+        let path = self.root.join(key.relative_path());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so readers never see a partial tile,
+        // named so other threads and processes sharing the cache don't collide
+        let tmp_path = path.with_extension(format!(
+            "png.{}.{}.tmp",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, &path)?;
+
+        self.index
+            .lock()
+            .map_err(|_| Error::LockPoisoned)?
+            .insert(*key, bytes.len() as u64);
+        self.evict()
+    }
+
+    /// Read-only `TileSource` view of the tiles cached for `scale` and `daylight`
+    pub fn tiles(&self, scale: Option<Scale>, daylight: Option<Daylight>) -> CachedTiles<'_> {
+        CachedTiles {
+            cache: self,
+            scale,
+            daylight,
+        }
+    }
+
+    fn evict(&self) -> Result<(), Error> {
+        let mut index = self.index.lock().map_err(|_| Error::LockPoisoned)?;
+        for key in index.eviction_candidates(self.max_size).into_iter() {
+            log::debug!("Evicting tile {:?}", key);
+            match fs::remove_file(self.root.join(key.relative_path())) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+            index.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn scan_dir(
+        root: &Path,
+        dir: &Path,
+        files: &mut Vec<(TileKey, u64, SystemTime)>,
+    ) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                Self::scan_dir(root, &path, files)?;
+            } else if path.extension() == Some("tmp".as_ref()) {
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|m| m.elapsed().ok())
+                    .unwrap_or_default();
+                if age > STALE_TMP_AGE {
+                    log::debug!("Removing stale temporary file {}", path.display());
+                    match fs::remove_file(&path) {
+                        Ok(()) => (),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            } else if let Some(key) = path
+                .strip_prefix(root)
+                .ok()
+                .and_then(TileKey::from_relative_path)
+            {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((key, metadata.len(), modified));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CachedTiles<'a> {
+    cache: &'a TileCache,
+    scale: Option<Scale>,
+    daylight: Option<Daylight>,
+}
+
+impl<'a> TileSource for CachedTiles<'a> {
+    fn request_tile(
+        &self,
+        x: TileNumber,
+        y: TileNumber,
+        zoom: Zoom,
+    ) -> Result<Bytes, crate::Error> {
+        let key = TileKey {
+            scale: self.scale,
+            daylight: self.daylight,
+            tile: TileCoord::new(zoom, x, y).ok_or(crate::Error::TileNotFound(zoom, x, y))?,
+        };
+        self.cache
+            .get(&key)?
+            .ok_or(crate::Error::TileNotFound(zoom, x, y))
+    }
+
+    fn scale(&self) -> Option<Scale> {
+        self.scale
+    }
+
+    fn daylight(&self) -> Option<Daylight> {
+        self.daylight
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: u32) -> TileKey {
+        TileKey {
+            scale: Some(Scale::Four),
+            daylight: Some(Daylight::Day),
+            tile: TileCoord::new(Zoom::new_clamped(11), x, 7).unwrap(),
+        }
+    }
+
+    #[test]
+    fn key_path_round_trip() {
+        let k = key(3);
+        assert_eq!(k.relative_path(), PathBuf::from("4/day/11/3/7.png"));
+        assert_eq!(TileKey::from_relative_path(&k.relative_path()), Some(k));
+
+        let k = TileKey {
+            scale: None,
+            daylight: None,
+            ..k
+        };
+        assert_eq!(k.relative_path(), PathBuf::from("default/any/11/3/7.png"));
+        assert_eq!(TileKey::from_relative_path(&k.relative_path()), Some(k));
+
+        assert_eq!(TileKey::from_relative_path(Path::new("4/day/11/3")), None);
+        assert_eq!(
+            TileKey::from_relative_path(Path::new("4/day/11/3/7.png.tmp")),
+            None
+        );
+        // Out of range zoom levels and tile numbers are skipped, not clamped
+        assert_eq!(
+            TileKey::from_relative_path(Path::new("4/day/0/0/0.png")),
+            None
+        );
+        assert_eq!(
+            TileKey::from_relative_path(Path::new("4/day/19/3/7.png")),
+            None
+        );
+        assert_eq!(
+            TileKey::from_relative_path(Path::new("4/day/2/4/0.png")),
+            None
+        );
+    }
+
+    #[test]
+    fn put_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::open(dir.path(), 1024).unwrap();
+        assert!(cache.is_empty().unwrap());
+        assert_eq!(cache.get(&key(1)).unwrap(), None);
+
+        cache.put(&key(1), &[1, 2, 3]).unwrap();
+        assert!(cache.contains(&key(1)).unwrap());
+        assert_eq!(
+            cache.get(&key(1)).unwrap(),
+            Some(Bytes::from(vec![1, 2, 3]))
+        );
+        assert_eq!(cache.size().unwrap(), 3);
+
+        cache.put(&key(1), &[4, 5]).unwrap();
+        assert_eq!(cache.get(&key(1)).unwrap(), Some(Bytes::from(vec![4, 5])));
+        assert_eq!(cache.size().unwrap(), 2);
+        assert_eq!(cache.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::open(dir.path(), 10).unwrap();
+        cache.put(&key(1), &[0; 4]).unwrap();
+        cache.put(&key(2), &[0; 4]).unwrap();
+        // Use 1 so 2 becomes the least recently used
+        assert!(cache.get(&key(1)).unwrap().is_some());
+        cache.put(&key(3), &[0; 4]).unwrap();
+
+        assert!(cache.contains(&key(1)).unwrap());
+        assert!(!cache.contains(&key(2)).unwrap());
+        assert!(cache.contains(&key(3)).unwrap());
+        assert_eq!(cache.size().unwrap(), 8);
+        assert!(!dir.path().join(key(2).relative_path()).exists());
+    }
+
+    #[test]
+    fn cached_tiles_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TileCache::open(dir.path(), 1024).unwrap();
+        cache.put(&key(1), &[1]).unwrap();
+
+        let tiles = cache.tiles(Some(Scale::Four), Some(Daylight::Day));
+        let k = key(1);
+        let t = k.tile;
+        assert_eq!(tiles.request_tile(t.x, t.y, t.zoom).unwrap().as_ref(), &[1]);
+        assert!(tiles.request_tile(2.into(), t.y, t.zoom).is_err());
+
+        let tiles = cache.tiles(Some(Scale::Four), Some(Daylight::Night));
+        assert!(tiles.request_tile(t.x, t.y, t.zoom).is_err());
+    }
+
+    #[test]
+    fn reopen_rebuilds_index() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let cache = TileCache::open(dir.path(), 1024).unwrap();
+            cache.put(&key(1), &[0; 4]).unwrap();
+            cache.put(&key(2), &[0; 6]).unwrap();
+        }
+        let cache = TileCache::open(dir.path(), 1024).unwrap();
+        assert_eq!(cache.len().unwrap(), 2);
+        assert_eq!(cache.size().unwrap(), 10);
+        assert_eq!(cache.get(&key(2)).unwrap(), Some(Bytes::from(vec![0; 6])));
+
+        // Shrinking the limit evicts on open
+        let cache = TileCache::open(dir.path(), 6).unwrap();
+        assert_eq!(cache.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn reads_are_remembered_across_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = SystemTime::now() - Duration::from_secs(60);
+        {
+            let cache = TileCache::open(dir.path(), 1024).unwrap();
+            cache.put(&key(1), &[0; 4]).unwrap();
+            cache.put(&key(2), &[0; 4]).unwrap();
+            for k in [key(1), key(2)].iter() {
+                let path = dir.path().join(k.relative_path());
+                fs::File::open(path).unwrap().set_modified(old).unwrap();
+            }
+            // 1 is written first but read last
+            assert!(cache.get(&key(1)).unwrap().is_some());
+        }
+        let cache = TileCache::open(dir.path(), 8).unwrap();
+        cache.put(&key(3), &[0; 4]).unwrap();
+        assert!(cache.contains(&key(1)).unwrap());
+        assert!(!cache.contains(&key(2)).unwrap());
+    }
+
+    #[test]
+    fn stale_temporary_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = dir.path().join("4/day/11/3");
+        fs::create_dir_all(&tiles).unwrap();
+        let stale = tiles.join("7.png.1234.0.tmp");
+        let fresh = tiles.join("8.png.1234.1.tmp");
+        fs::write(&stale, [0; 4]).unwrap();
+        fs::write(&fresh, [0; 4]).unwrap();
+        fs::File::open(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_TMP_AGE)
+            .unwrap();
+
+        let cache = TileCache::open(dir.path(), 1024).unwrap();
+        assert!(cache.is_empty().unwrap());
+        assert!(!stale.exists());
+        // Might still be written by another process
+        assert!(fresh.exists());
+    }
+}
@@ -0,0 +1,128 @@
+//! Tiles in a local `{root}/{z}/{x}/{y}.png` directory tree
+
+use crate::source::png_tile_size;
+use crate::{Error, TileSource};
+use bytes::Bytes;
+use common::{TileNumber, Zoom};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileDirectory {
+    root: PathBuf,
+}
+
+impl TileDirectory {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        TileDirectory {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn tile_path(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> PathBuf {
+        self.root
+            .join(zoom.to_string())
+            .join(x.to_string())
+            .join(format!("{}.png", y))
+    }
+
+    pub fn write_tile(
+        &self,
+        x: TileNumber,
+        y: TileNumber,
+        zoom: Zoom,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        let path = self.tile_path(x, y, zoom);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// The first `{z}/{x}/{y}.png` file found, `None` when there isn't one
+    fn first_tile_path(&self) -> Option<PathBuf> {
+        let sub_dirs = |dir: &Path| {
+            fs::read_dir(dir).into_iter().flatten().filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.is_dir() {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+        };
+        sub_dirs(&self.root)
+            .flat_map(|zoom_dir| sub_dirs(&zoom_dir).collect::<Vec<_>>())
+            .flat_map(|x_dir| fs::read_dir(x_dir).into_iter().flatten())
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .find(|path| path.extension() == Some("png".as_ref()))
+    }
+}
+
+impl TileSource for TileDirectory {
+    fn request_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Bytes, Error> {
+        match fs::read(self.tile_path(x, y, zoom)) {
+            Ok(bytes) => Ok(bytes.into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::TileNotFound(zoom, x, y)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read from the first tile in the tree, all tiles are the same size
+    fn tile_size(&self) -> Option<u32> {
+        let bytes = fs::read(self.first_tile_path()?).ok()?;
+        png_tile_size(&bytes)
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = TileDirectory::new(dir.path());
+        let z = Zoom::new_clamped(4);
+        assert_eq!(
+            tiles.tile_path(3.into(), 5.into(), z),
+            dir.path().join("4").join("3").join("5.png")
+        );
+        assert!(matches!(
+            tiles.request_tile(3.into(), 5.into(), z),
+            Err(Error::TileNotFound(_, _, _))
+        ));
+
+        tiles.write_tile(3.into(), 5.into(), z, &[1, 2]).unwrap();
+        assert_eq!(
+            tiles.request_tile(3.into(), 5.into(), z).unwrap().as_ref(),
+            &[1, 2]
+        );
+    }
+
+    #[test]
+    fn tile_size_from_first_tile() {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = TileDirectory::new(dir.path());
+        assert_eq!(tiles.tile_size(), None);
+
+        let png = tiny_skia::Pixmap::new(16, 16)
+            .unwrap()
+            .encode_png()
+            .unwrap();
+        tiles
+            .write_tile(3.into(), 5.into(), Zoom::new_clamped(4), &png)
+            .unwrap();
+        assert_eq!(tiles.tile_size(), Some(16));
+    }
+}
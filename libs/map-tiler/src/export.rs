@@ -0,0 +1,84 @@
+//! Export tiles for a region into a `{z}/{x}/{y}.png` directory tree
+
+use crate::{Error, TileDirectory, TileRegion, TileSource};
+use common::Zoom;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ExportSummary {
+    /// Number of tiles written
+    pub exported: usize,
+    /// Number of tiles the source didn't have or failed to provide
+    pub missing: usize,
+    /// Total size of the written tiles
+    pub bytes: u64,
+}
+
+/// Copies every tile the source has for the region and the inclusive zoom range
+/// into `dest`, tiles the source doesn't have are skipped
+pub fn export_tiles(
+    source: &dyn TileSource,
+    region: &TileRegion,
+    min_zoom: Zoom,
+    max_zoom: Zoom,
+    dest: &TileDirectory,
+) -> Result<ExportSummary, Error> {
+    if min_zoom > max_zoom {
+        return Err(Error::InvalidZoomRange(min_zoom, max_zoom));
+    }
+    let mut summary = ExportSummary::default();
+    for z in min_zoom.get()..=max_zoom.get() {
+        let zoom = Zoom::new_clamped(z);
+        for tile in region.tiles(zoom) {
+            match source.request_tile(tile.x, tile.y, zoom) {
+                Ok(bytes) => {
+                    dest.write_tile(tile.x, tile.y, zoom, &bytes)?;
+                    summary.exported += 1;
+                    summary.bytes += bytes.len() as u64;
+                }
+                Err(e) => {
+                    log::debug!("Skipping tile {}: {}", tile, e);
+                    summary.missing += 1;
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{BoundingBox, TileNumber};
+
+    #[test]
+    fn export_from_directory() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = TileDirectory::new(src_dir.path());
+        let dst = TileDirectory::new(dst_dir.path());
+        let region =
+            TileRegion::from_bbox(BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap());
+        let z11 = Zoom::new_clamped(11);
+        let z14 = Zoom::new_clamped(14);
+        src.write_tile(359.into(), 716.into(), z11, &[1, 2, 3])
+            .unwrap();
+        src.write_tile(2876.into(), 5731.into(), z14, &[4, 5])
+            .unwrap();
+        // Outside the bbox
+        src.write_tile(100.into(), 100.into(), z11, &[6]).unwrap();
+
+        let summary = export_tiles(&src, &region, z11, z14, &dst).unwrap();
+        assert_eq!(summary.exported, 2);
+        assert_eq!(summary.bytes, 5);
+        // z11: 1, z12: 2, z13: 2, z14: 4 tiles covering the bbox
+        assert_eq!(summary.missing, 7);
+
+        let tile = |x: u32, y: u32, z: Zoom| dst.request_tile(TileNumber(x), TileNumber(y), z);
+        assert_eq!(tile(359, 716, z11).unwrap().as_ref(), &[1, 2, 3]);
+        assert_eq!(tile(2876, 5731, z14).unwrap().as_ref(), &[4, 5]);
+        assert!(tile(100, 100, z11).is_err());
+
+        let res = export_tiles(&src, &region, z14, z11, &dst);
+        assert!(matches!(res, Err(Error::InvalidZoomRange(_, _))));
+    }
+}
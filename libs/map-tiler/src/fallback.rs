@@ -0,0 +1,159 @@
+use crate::cache::TileKey;
+use common::Zoom;
+use tiny_skia::Pixmap;
+
+/// How a missing tile was filled in
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TileSubstitution {
+    /// Upscaled from the part of the ancestor tile at this zoom that covers it
+    Parent(Zoom),
+    /// Downsampled from the four tiles one zoom level in
+    Children,
+}
+
+/// The tile `levels` zoom levels out that covers `key`
+pub(crate) fn ancestor(key: &TileKey, levels: u8) -> Option<TileKey> {
+    key.tile.ancestor(levels).map(|t| key.with_tile(t))
+}
+
+/// The four tiles one zoom level in that cover `key`, in the order
+/// top left, top right, bottom left, bottom right
+pub(crate) fn children(key: &TileKey) -> Option<[TileKey; 4]> {
+    let [top_left, top_right, bottom_left, bottom_right] = key.tile.children()?;
+    Some([
+        key.with_tile(top_left),
+        key.with_tile(top_right),
+        key.with_tile(bottom_left),
+        key.with_tile(bottom_right),
+    ])
+}
+
+/// Scales the part of `parent` covering tile (`x`, `y`), `levels` zoom levels
+/// in, up to the size of `parent`. Nearest neighbour, so nothing gets blurred
+/// and every pixel is a copy.
+pub(crate) fn upscale(parent: &Pixmap, levels: u8, x: u32, y: u32) -> Option<Pixmap> {
+    let size = parent.width();
+    let factor = 1u64.checked_shl(levels.into())?;
+    let part = (u64::from(size) / factor) as u32;
+    if part == 0 || parent.height() != size {
+        return None;
+    }
+    let left = (u64::from(x) % factor) as u32 * part;
+    let top = (u64::from(y) % factor) as u32 * part;
+
+    let mut image = Pixmap::new(size, size)?;
+    let src = parent.pixels();
+    let stride = size as usize;
+    for (i, p) in image.pixels_mut().iter_mut().enumerate() {
+        let src_x = left + (i % stride) as u32 * part / size;
+        let src_y = top + (i / stride) as u32 * part / size;
+        *p = src[src_y as usize * stride + src_x as usize];
+    }
+    Some(image)
+}
+
+/// Combines four tiles, ordered as returned by `children`, into one of the same
+/// size, averaging every 2x2 block of pixels
+pub(crate) fn downsample(children: [&Pixmap; 4]) -> Option<Pixmap> {
+    let size = children[0].width();
+    let half = size / 2;
+    if 2 * half != size
+        || children
+            .iter()
+            .any(|c| c.width() != size || c.height() != size)
+    {
+        return None;
+    }
+    let stride = 4 * size as usize;
+
+    let mut image = Pixmap::new(size, size)?;
+    let data = image.data_mut();
+    for (i, child) in children.iter().enumerate() {
+        let left = (i % 2) * half as usize;
+        let top = (i / 2) * half as usize;
+        let pixels = child.data();
+        for y in 0..half as usize {
+            for x in 0..half as usize {
+                let dst = (top + y) * stride + 4 * (left + x);
+                let src = 2 * y * stride + 8 * x;
+                for c in 0..4 {
+                    let sum: u32 = [src, src + 4, src + stride, src + stride + 4]
+                        .iter()
+                        .map(|at| u32::from(pixels[at + c]))
+                        .sum();
+                    data[dst + c] = ((sum + 2) / 4) as u8;
+                }
+            }
+        }
+    }
+    Some(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::TileCoord;
+    use tiny_skia::Color;
+
+    fn key(zoom: u8, x: u32, y: u32) -> TileKey {
+        TileKey {
+            scale: None,
+            daylight: None,
+            tile: TileCoord::new(Zoom::new_clamped(zoom), x, y).unwrap(),
+        }
+    }
+
+    fn solid(size: u32, c: u8) -> Pixmap {
+        let mut pixmap = Pixmap::new(size, size).unwrap();
+        pixmap.fill(Color::from_rgba8(c, c, c, 255));
+        pixmap
+    }
+
+    #[test]
+    fn ancestors_and_children() {
+        assert_eq!(ancestor(&key(5, 13, 6), 1), Some(key(4, 6, 3)));
+        assert_eq!(ancestor(&key(5, 13, 6), 3), Some(key(2, 1, 0)));
+        assert_eq!(ancestor(&key(2, 3, 3), 1), Some(key(1, 1, 1)));
+        assert_eq!(ancestor(&key(2, 3, 3), 2), None);
+        assert_eq!(
+            children(&key(4, 6, 3)),
+            Some([key(5, 12, 6), key(5, 13, 6), key(5, 12, 7), key(5, 13, 7)])
+        );
+        assert_eq!(children(&key(18, 0, 0)), None);
+    }
+
+    #[test]
+    fn upscale_quadrant() {
+        // Four 4x4 quadrants with their own color
+        let mut parent = Pixmap::new(8, 8).unwrap();
+        for (i, c) in [10u8, 20, 30, 40].iter().enumerate() {
+            let quadrant = solid(4, *c);
+            crate::copy_pixmap(
+                &mut parent,
+                (i as i32 % 2) * 4,
+                (i as i32 / 2) * 4,
+                &quadrant,
+            );
+        }
+        // Odd x, even y is the top right quadrant one level in
+        let image = upscale(&parent, 1, 7, 4).unwrap();
+        assert_eq!(image.data(), solid(8, 20).data());
+        // Two levels in, tile (3, 2) is the top right 2x2 of the bottom right quadrant
+        let image = upscale(&parent, 2, 3, 2).unwrap();
+        assert_eq!(image.data(), solid(8, 40).data());
+        assert!(upscale(&parent, 4, 0, 0).is_none());
+    }
+
+    #[test]
+    fn downsample_children() {
+        let children = [solid(4, 10), solid(4, 20), solid(4, 30), solid(4, 41)];
+        let image = downsample([&children[0], &children[1], &children[2], &children[3]]).unwrap();
+        let expected = [(0, 0, 10), (3, 0, 20), (0, 3, 30), (3, 3, 41)];
+        for (x, y, c) in expected.iter() {
+            let p = image.pixel(*x, *y).unwrap();
+            assert_eq!((p.red(), p.alpha()), (*c, 255));
+        }
+        let odd = solid(3, 0);
+        assert!(downsample([&odd, &odd, &odd, &odd]).is_none());
+    }
+}
@@ -3,11 +3,37 @@
 #![deny(warnings)]
 
 use bytes::Bytes;
-use common::{util::*, Coordinate, Zoom};
+use common::{
+    Coordinate, FractionalZoom, GridTile, ScreenAnchor, TileCoord, TileGrid, TileNumber,
+    TilePlaceholder, Viewport, Zoom,
+};
 use err_derive::Error;
-use osm_client::OsmClient;
+use lru::LruCache;
 use rayon::prelude::*;
-use tiny_skia::{Pixmap, PixmapPaint, Transform};
+use std::collections::HashMap;
+use tiny_skia::Pixmap;
+
+pub use crate::blit::{copy_pixmap, scale_pixmap, shift_pixmap};
+pub use crate::cache::{CachedTiles, TileCache, TileKey};
+pub use crate::directory::TileDirectory;
+pub use crate::export::{export_tiles, ExportSummary};
+pub use crate::fallback::TileSubstitution;
+pub use crate::mbtiles::MbTiles;
+pub use crate::prefetch::{prefetch_tiles, PrefetchStatus, PrefetchSummary};
+pub use crate::region::TileRegion;
+pub use crate::source::TileSource;
+
+mod blit;
+pub mod cache;
+mod directory;
+mod export;
+mod fallback;
+pub mod mbtiles;
+pub mod overlay;
+mod placeholder;
+mod prefetch;
+mod region;
+mod source;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -19,6 +45,37 @@ pub enum Error {
 
     #[error(display = "PNG decode error: {}", _0)]
     TileDecodeError(#[error(source)] png::DecodingError),
+
+    #[error(display = "{}", _0)]
+    Cache(#[error(source)] cache::Error),
+
+    #[error(display = "MBTiles error: {}", _0)]
+    MbTiles(#[error(source)] rusqlite::Error),
+
+    #[error(display = "Unsupported tile format {}", _0)]
+    UnsupportedTileFormat(String),
+
+    #[error(display = "Tile {}/{}/{} not found", _0, _1, _2)]
+    TileNotFound(Zoom, TileNumber, TileNumber),
+
+    #[error(display = "Zoom {} is outside the tile source's zoom range", _0)]
+    ZoomOutOfRange(Zoom),
+
+    #[error(
+        display = "Minimum zoom {} is greater than the maximum zoom {}",
+        _0,
+        _1
+    )]
+    InvalidZoomRange(Zoom, Zoom),
+
+    #[error(display = "Tile source lock is poisoned")]
+    SourceLockPoisoned,
+
+    #[error(display = "IO error: {}", _0)]
+    Io(#[error(source)] std::io::Error),
+
+    #[error(display = "Failed to build the worker thread pool")]
+    ThreadPool(#[error(source)] rayon::ThreadPoolBuildError),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -26,121 +83,1152 @@ pub struct Config {
     pub width: u32,
     pub height: u32,
     pub tile_size: u32,
+    /// Number of decoded tiles kept in memory, 0 disables the in-memory cache
+    pub memory_cache_tiles: usize,
+    /// Drawn in place of tiles that failed to load
+    pub placeholder: TilePlaceholder,
+    /// Tiles that failed to load are substituted with the four downsampled
+    /// tiles one level in, or else an upscaled ancestor tile up to this many
+    /// zoom levels out. 0 disables substitution.
+    pub fallback_parent_levels: u8,
+}
+
+impl Config {
+    /// Number of decoded RGBA tiles that fit in a budget of `bytes`
+    pub fn tiles_for_memory_budget(tile_size: u32, bytes: u64) -> usize {
+        let tile_bytes = 4 * u64::from(tile_size) * u64::from(tile_size);
+        (bytes / tile_bytes.max(1)) as usize
+    }
+
+    /// Side of the square image that covers a `width` x `height` viewport
+    /// rotated by any angle around `anchor`, twice the distance to the
+    /// farthest corner. The diagonal for an anchor at the center.
+    pub fn rotated_size(width: u32, height: u32, anchor: ScreenAnchor) -> u32 {
+        let (x, y) = anchor.to_pixels(width, height);
+        let (width, height) = (f64::from(width), f64::from(height));
+        let farthest = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)]
+            .iter()
+            .map(|(cx, cy)| (cx - x).hypot(cy - y))
+            .fold(0.0, f64::max);
+        (2.0 * farthest).ceil() as u32
+    }
 }
 
-// TODO - refactor, use stuff in common lib
 impl Config {
-    pub(crate) fn x_to_px(&self, x_center: f64, x: f64) -> f64 {
-        let px = (x - x_center) * self.tile_size as f64 + self.width as f64 / 2f64;
-        px.round()
+    pub fn viewport(&self) -> Viewport {
+        Viewport::new(self.width, self.height, self.tile_size)
     }
 
-    pub(crate) fn y_to_px(&self, y_center: f64, y: f64) -> f64 {
-        let px = (y - y_center) * self.tile_size as f64 + self.height as f64 / 2f64;
-        px.round()
+    /// Size of the image drawn at the nearest whole level, which is then
+    /// scaled to `width` x `height` at `zoom`. Has a pixel to spare on
+    /// every side for the filtering.
+    pub fn tile_level_size(width: u32, height: u32, zoom: FractionalZoom) -> (u32, u32) {
+        // Only the sizes are needed
+        let viewport = Viewport::new(width, height, 1).tile_level(zoom);
+        (viewport.width, viewport.height)
     }
 }
 
-// TODO - refactor
+/// What the image currently holds
+#[derive(Debug)]
+struct RenderedView {
+    zoom: Zoom,
+    origin: (i64, i64),
+    /// Tiles drawn with the placeholder or a substitute, (x, y) as in `GridTile`
+    incomplete: Vec<(i32, i32)>,
+}
+
+/// A tile fetched for the image
+struct FetchedTile<'a> {
+    tile: &'a GridTile,
+    pixmap: Result<Pixmap, Error>,
+}
+
+/// A decoded tile in the memory cache
+#[derive(Debug)]
+struct MemoryTile {
+    pixmap: Pixmap,
+    /// A stand-in for a tile that failed to load, the real tile is tried
+    /// again next time and the stand-in is used if it fails again
+    substitution: Option<TileSubstitution>,
+}
+
+/// Tiles looked up to make substitutes during a request, `None` when they
+/// aren't available, so each is only read and decoded once
+type DecodedTiles = HashMap<TileKey, Option<Pixmap>>;
+
+/// A tile that couldn't be fetched or decoded
 #[derive(Debug)]
-struct Coord {
-    tile_x: u32,
-    tile_y: u32,
-    x: i32,
-    y: i32,
+pub struct TileFailure {
+    pub key: TileKey,
+    pub error: Error,
+}
+
+/// A tile that couldn't be fetched or decoded and was drawn with a
+/// substitute made from other tiles
+#[derive(Debug)]
+pub struct SubstitutedTile {
+    pub key: TileKey,
+    pub substitution: TileSubstitution,
+    pub error: Error,
+}
+
+/// A tile and where it goes in the stitched image
+#[derive(Debug)]
+pub struct PlacedTile<'a> {
+    pub key: TileKey,
+    /// Image pixel offset of the tile's top left corner, can be negative
+    /// for tiles partially outside the image
+    pub x: i32,
+    pub y: i32,
+    pub image: &'a Pixmap,
+    /// The tile failed to load, `image` is the placeholder pattern
+    pub failed: bool,
+    /// The tile failed to load, `image` is made from other tiles
+    pub substitution: Option<TileSubstitution>,
+}
+
+/// The stitched map image, tiles that failed are drawn with a substitute
+/// and listed in `substituted_tiles` when possible, otherwise they're drawn
+/// with the placeholder pattern and listed in `failed_tiles`
+#[derive(Debug)]
+pub struct StitchedMap<'a> {
+    pub image: &'a Pixmap,
+    pub failed_tiles: Vec<TileFailure>,
+    pub substituted_tiles: Vec<SubstitutedTile>,
+    /// Tiles kept from the previous image instead of being drawn again
+    pub reused_tiles: usize,
 }
 
 #[derive(Debug)]
 pub struct MapTiler {
-    client: OsmClient,
+    source: Box<dyn TileSource>,
+    cache: Option<TileCache>,
+    memory_cache: Option<LruCache<TileKey, MemoryTile>>,
+    /// Drawn at a whole zoom level, the output unless zoomed between levels
     image: Pixmap,
+    /// `image` scaled to a zoom between levels
+    scaled: Pixmap,
+    placeholder: Pixmap,
     config: Config,
-    tiles: Vec<Coord>,
-    tile_image_results: Vec<Result<Bytes, osm_client::Error>>,
+    /// Where the requested center goes in the viewport
+    anchor: ScreenAnchor,
+    /// Covers the viewport at any rotation around the anchor
+    rotatable: bool,
+    /// Leaves the scaling between whole zoom levels to the caller
+    unscaled: bool,
+    tiles: Vec<GridTile>,
+    view: Option<RenderedView>,
 }
 
 impl MapTiler {
-    pub fn new(client: OsmClient, config: Config) -> Result<Self, Error> {
+    pub fn new(source: Box<dyn TileSource>, config: Config) -> Result<Self, Error> {
         let image = Pixmap::new(config.width, config.height)
             .ok_or(Error::ImageSize(config.width, config.height))?;
+        let scaled = image.clone();
+        let placeholder = placeholder::render(config.placeholder, config.tile_size)
+            .ok_or(Error::ImageSize(config.tile_size, config.tile_size))?;
         log::debug!(
-            "Created new MapTiler w={}, h={}, tile_size={}",
+            "Created new MapTiler w={}, h={}, tile_size={}, memory_cache_tiles={}",
             config.width,
             config.height,
-            config.tile_size
+            config.tile_size,
+            config.memory_cache_tiles
         );
+        let memory_cache = if config.memory_cache_tiles != 0 {
+            Some(LruCache::new(config.memory_cache_tiles))
+        } else {
+            None
+        };
         Ok(MapTiler {
-            client,
+            source,
+            cache: None,
+            memory_cache,
             image,
+            scaled,
+            placeholder,
             config,
+            anchor: ScreenAnchor::CENTER,
+            rotatable: false,
+            unscaled: false,
             tiles: Vec::with_capacity(8),
-            tile_image_results: Vec::with_capacity(8),
+            view: None,
         })
     }
 
-    pub fn request_tiles(&mut self, center: Coordinate, zoom: Zoom) -> Result<&Pixmap, Error> {
-        let x_center = lon_to_x(center.longitude, zoom);
-        let y_center = lat_to_y(center.latitude, zoom);
+    pub fn with_cache(mut self, cache: TileCache) -> Self {
+        self.set_cache(Some(cache));
+        self
+    }
 
-        let x_min = (x_center - (0.5 * self.config.width as f64 / self.config.tile_size as f64))
-            .floor() as i32;
-        let y_min = (y_center - (0.5 * self.config.height as f64 / self.config.tile_size as f64))
-            .floor() as i32;
-        let x_max = (x_center + (0.5 * self.config.width as f64 / self.config.tile_size as f64))
-            .ceil() as i32;
-        let y_max = (y_center + (0.5 * self.config.height as f64 / self.config.tile_size as f64))
-            .ceil() as i32;
+    pub fn set_cache<T: Into<Option<TileCache>>>(&mut self, cache: T) {
+        self.cache = cache.into();
+    }
 
-        let max_tile: i32 = 2i32.pow(zoom.get() as u32);
+    pub fn cache(&self) -> Option<&TileCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Places the requested center at `anchor` in the `width` x `height`
+    /// viewport instead of its center
+    pub fn with_anchor(mut self, anchor: ScreenAnchor) -> Self {
+        self.set_anchor(anchor);
+        self
+    }
+
+    pub fn set_anchor(&mut self, anchor: ScreenAnchor) {
+        self.anchor = anchor;
+    }
+
+    /// Renders a square image big enough to cover the `width` x `height`
+    /// viewport rotated by any angle around the anchor, see `Config::rotated_size`.
+    /// The requested center is in the middle of the image.
+    pub fn with_rotatable(mut self, rotatable: bool) -> Self {
+        self.set_rotatable(rotatable);
+        self
+    }
+
+    pub fn set_rotatable(&mut self, rotatable: bool) {
+        self.rotatable = rotatable;
+    }
+
+    /// Between whole zoom levels, returns the image drawn at the nearest
+    /// whole level instead of scaling it, sized to cover the viewport once
+    /// scaled by `FractionalZoom::tile_scale`, see `Config::tile_level_size`.
+    /// Tiles are then streamed and images shifted like at whole levels, for
+    /// callers that scale the image as they draw it, e.g. as a texture.
+    pub fn with_unscaled(mut self, unscaled: bool) -> Self {
+        self.set_unscaled(unscaled);
+        self
+    }
+
+    pub fn set_unscaled(&mut self, unscaled: bool) {
+        self.unscaled = unscaled;
+    }
+
+    /// The config of the returned images
+    fn output_config(&self) -> Config {
+        if self.rotatable {
+            let size = Config::rotated_size(self.config.width, self.config.height, self.anchor);
+            Config {
+                width: size,
+                height: size,
+                ..self.config
+            }
+        } else {
+            self.config
+        }
+    }
+
+    /// When the view at `center` and `zoom` overlaps the previously rendered
+    /// image, returns how far its pixels move, (x, y) with positive being
+    /// right and down. `request_tiles` then shifts the image by that much
+    /// and only draws the tiles that come into view.
+    /// Always `None` between whole zoom levels, unless unscaled.
+    pub fn view_shift<Z>(&self, center: Coordinate, zoom: Z) -> Option<(i32, i32)>
+    where
+        Z: Into<FractionalZoom>,
+    {
+        let zoom = zoom.into();
+        if !zoom.is_whole() && !self.unscaled {
+            return None;
+        }
+        let output = self.output_config();
+        self.shift_at(&self.output_grid(&output, center, zoom))
+    }
+
+    /// Where the tiles go in the image drawn at the nearest whole zoom level,
+    /// with the requested center at the anchor, or the middle when rotatable
+    fn output_grid(&self, output: &Config, center: Coordinate, zoom: FractionalZoom) -> TileGrid {
+        TileGrid::for_view(output.viewport(), zoom, &center, self.output_anchor(output))
+    }
+
+    fn output_anchor(&self, output: &Config) -> (f64, f64) {
+        if self.rotatable {
+            output.viewport().middle()
+        } else {
+            self.anchor.to_pixels(output.width, output.height)
+        }
+    }
+
+    fn shift_at(&self, grid: &TileGrid) -> Option<(i32, i32)> {
+        let viewport = grid.viewport();
+        if (self.image.width(), self.image.height()) != (viewport.width, viewport.height) {
+            return None;
+        }
+        let view = self.view.as_ref().filter(|v| v.zoom == grid.zoom())?;
+        let origin = grid.origin();
+        let dx = origin.0 - view.origin.0;
+        let dy = origin.1 - view.origin.1;
+        if dx.abs() < i64::from(viewport.width) && dy.abs() < i64::from(viewport.height) {
+            Some((dx as i32, dy as i32))
+        } else {
+            None
+        }
+    }
+
+    /// Individual tile failures don't fail the request, they're reported
+    /// in the returned `StitchedMap`
+    pub fn request_tiles<Z>(
+        &mut self,
+        center: Coordinate,
+        zoom: Z,
+    ) -> Result<StitchedMap<'_>, Error>
+    where
+        Z: Into<FractionalZoom>,
+    {
+        self.request_tiles_with(center, zoom, |_| ())
+    }
+
+    /// Same as `request_tiles`, and `on_tile` is called with every tile as soon
+    /// as it's ready, before the stitched image is complete.
+    /// Tiles already in memory are handed over first, on the calling thread,
+    /// the rest from the fetching threads as they complete.
+    /// Tiles kept from the previous image (see `view_shift`) aren't passed
+    /// to `on_tile`, and neither are any tiles between whole zoom levels,
+    /// where only the complete image gets scaled, unless unscaled.
+    pub fn request_tiles_with<Z, F>(
+        &mut self,
+        center: Coordinate,
+        zoom: Z,
+        on_tile: F,
+    ) -> Result<StitchedMap<'_>, Error>
+    where
+        Z: Into<FractionalZoom>,
+        F: Fn(PlacedTile<'_>) + Sync,
+    {
+        let zoom = zoom.into();
+        let output = self.output_config();
+        let grid = self.output_grid(&output, center, zoom);
+        if zoom.is_whole() || self.unscaled {
+            return self.render_tiles(grid, on_tile);
+        }
+        if (self.scaled.width(), self.scaled.height()) != (output.width, output.height) {
+            self.scaled = Pixmap::new(output.width, output.height)
+                .ok_or(Error::ImageSize(output.width, output.height))?;
+        }
+
+        let StitchedMap {
+            failed_tiles,
+            substituted_tiles,
+            reused_tiles,
+            ..
+        } = self.render_tiles(grid, |_| ())?;
+
+        // Where the middle ended up in the whole level image, tiles are
+        // placed on whole pixels
+        let image_center = TileGrid::image_center(
+            output.viewport(),
+            zoom,
+            &center,
+            self.output_anchor(&output),
+        );
+        let center_px = grid.tile_to_px(image_center.0, image_center.1);
+        scale_pixmap(&self.image, &mut self.scaled, center_px, zoom.tile_scale());
+        Ok(StitchedMap {
+            image: &self.scaled,
+            failed_tiles,
+            substituted_tiles,
+            reused_tiles,
+        })
+    }
+
+    /// Draws the tiles of `grid` into `image`, sized to its viewport
+    fn render_tiles<F>(&mut self, grid: TileGrid, on_tile: F) -> Result<StitchedMap<'_>, Error>
+    where
+        F: Fn(PlacedTile<'_>) + Sync,
+    {
+        let viewport = grid.viewport();
+        let zoom = grid.zoom();
+        if (self.image.width(), self.image.height()) != (viewport.width, viewport.height) {
+            self.image = Pixmap::new(viewport.width, viewport.height)
+                .ok_or(Error::ImageSize(viewport.width, viewport.height))?;
+            self.view = None;
+        }
+
+        let shift = self.shift_at(&grid);
+        let previous_view = self.view.take();
 
         self.tiles.clear();
-        for x in x_min..x_max {
-            for y in y_min..y_max {
-                let tile_x: i32 = (x + max_tile) % max_tile;
-                let tile_y: i32 = (y + max_tile) % max_tile;
-                self.tiles.push(Coord {
-                    tile_x: tile_x as u32,
-                    tile_y: tile_y as u32,
+        self.tiles.extend(grid.tiles());
+
+        // Pixels still in view are moved rather than drawn again, only tiles
+        // that are at least partially outside of what's kept get drawn
+        let mut reused_tiles = 0;
+        let mut tiles: Vec<&GridTile> = self.tiles.iter().collect();
+        if let (Some((dx, dy)), Some(previous_view)) = (shift, previous_view.as_ref()) {
+            log::trace!("Shifting the map image by ({}, {})", dx, dy);
+            shift_pixmap(&mut self.image, dx, dy);
+            let (width, height) = (viewport.width as i32, viewport.height as i32);
+            let kept_x = dx.max(0)..width + dx.min(0);
+            let kept_y = dy.max(0)..height + dy.min(0);
+            let tile_size = viewport.tile_size as i32;
+            tiles.retain(|c| {
+                let (x, y) = grid.tile_offset(c.x, c.y);
+                let kept = x.max(0) >= kept_x.start
+                    && (x + tile_size).min(width) <= kept_x.end
+                    && y.max(0) >= kept_y.start
+                    && (y + tile_size).min(height) <= kept_y.end
+                    && !previous_view.incomplete.contains(&(c.x, c.y));
+                !kept
+            });
+            reused_tiles = self.tiles.len() - tiles.len();
+        }
+
+        // Decoded tiles already in memory don't need to be fetched or decoded
+        // again, substitutes do
+        let (hits, misses): (Vec<&GridTile>, Vec<&GridTile>) = tiles.into_iter().partition(|c| {
+            self.memory_cache
+                .as_ref()
+                .and_then(|m| m.peek(&self.tile_key(c, zoom)))
+                .map(|t| t.substitution.is_none())
+                .unwrap_or(false)
+        });
+
+        // Draw the hits before fetching and inserting the misses so nothing
+        // gets evicted before it's drawn
+        for tile in hits.into_iter() {
+            let key = self.tile_key(tile, zoom);
+            if let Some(cached) = self.memory_cache.as_mut().and_then(|m| m.get(&key)) {
+                let (x, y) = grid.tile_offset(tile.x, tile.y);
+                Self::draw_tile(&mut self.image, x, y, &cached.pixmap);
+                on_tile(PlacedTile {
+                    key,
                     x,
                     y,
+                    image: &cached.pixmap,
+                    failed: false,
+                    substitution: None,
                 });
             }
         }
 
-        self.tile_image_results.clear();
-        self.tile_image_results = self
-            .tiles
-            .par_iter()
-            .map(|c| self.client.request_tile(c.tile_x, c.tile_y, zoom))
+        // Substitutes are made afterwards from the tiles at hand, only the
+        // tiles themselves are fetched in parallel
+        let fetched: Vec<FetchedTile> = misses
+            .into_par_iter()
+            .map(|c| {
+                let key = self.tile_key(c, zoom);
+                let pixmap = Self::fetch_tile(self.source.as_ref(), self.cache.as_ref(), &key)
+                    .and_then(|bytes| Ok(Pixmap::decode_png(&bytes)?));
+                if let Ok(image) = &pixmap {
+                    let (x, y) = grid.tile_offset(c.x, c.y);
+                    on_tile(PlacedTile {
+                        key,
+                        x,
+                        y,
+                        image,
+                        failed: false,
+                        substitution: None,
+                    });
+                }
+                FetchedTile { tile: c, pixmap }
+            })
             .collect();
 
-        for (tile, tile_image_result) in self.tiles.iter().zip(self.tile_image_results.drain(..)) {
-            let tile_image = tile_image_result?;
-            let (x, y) = (tile.x, tile.y);
-            let (x_px, y_px) = (
-                self.config.x_to_px(x_center, x.into()),
-                self.config.y_to_px(y_center, y.into()),
-            );
+        let mut failed_tiles = Vec::new();
+        let mut substituted_tiles = Vec::new();
+        let mut incomplete = Vec::new();
+        let mut decoded = DecodedTiles::new();
+        for fetched_tile in fetched.into_iter() {
+            let tile = fetched_tile.tile;
+            let key = self.tile_key(tile, zoom);
+            let (x, y) = grid.tile_offset(tile.x, tile.y);
+            let error = match fetched_tile.pixmap {
+                Ok(pixmap) => {
+                    Self::draw_tile(&mut self.image, x, y, &pixmap);
+                    if let Some(m) = self.memory_cache.as_mut() {
+                        m.put(
+                            key,
+                            MemoryTile {
+                                pixmap,
+                                substitution: None,
+                            },
+                        );
+                    }
+                    continue;
+                }
+                Err(error) => error,
+            };
+            incomplete.push((tile.x, tile.y));
+            match self.substitute_tile(&key, &mut decoded) {
+                Some((pixmap, substitution)) => {
+                    log::debug!(
+                        "Failed to load tile {:?}, using {:?}: {}",
+                        key,
+                        substitution,
+                        error
+                    );
+                    Self::draw_tile(&mut self.image, x, y, &pixmap);
+                    on_tile(PlacedTile {
+                        key,
+                        x,
+                        y,
+                        image: &pixmap,
+                        failed: false,
+                        substitution: Some(substitution),
+                    });
+                    substituted_tiles.push(SubstitutedTile {
+                        key,
+                        substitution,
+                        error,
+                    });
+                    if let Some(m) = self.memory_cache.as_mut() {
+                        m.put(
+                            key,
+                            MemoryTile {
+                                pixmap,
+                                substitution: Some(substitution),
+                            },
+                        );
+                    }
+                }
+                None => {
+                    log::warn!("Failed to load tile {:?}: {}", key, error);
+                    Self::draw_tile(&mut self.image, x, y, &self.placeholder);
+                    on_tile(PlacedTile {
+                        key,
+                        x,
+                        y,
+                        image: &self.placeholder,
+                        failed: true,
+                        substitution: None,
+                    });
+                    failed_tiles.push(TileFailure { key, error });
+                }
+            }
+        }
 
-            let pixmap = Pixmap::decode_png(&tile_image)?;
-            self.image.draw_pixmap(
-                x_px as i32,
-                y_px as i32,
-                pixmap.as_ref(),
-                &PixmapPaint::default(),
-                Transform::default(),
-                None,
-            );
+        self.view = Some(RenderedView {
+            zoom,
+            origin: grid.origin(),
+            incomplete,
+        });
+        Ok(StitchedMap {
+            image: &self.image,
+            failed_tiles,
+            substituted_tiles,
+            reused_tiles,
+        })
+    }
+
+    /// Makes a stand-in for the tile at `key` from the four tiles one zoom
+    /// level in, or else the closest ancestor tile. The one made for an
+    /// earlier request is reused when it's still in the memory cache.
+    fn substitute_tile(
+        &self,
+        key: &TileKey,
+        decoded: &mut DecodedTiles,
+    ) -> Option<(Pixmap, TileSubstitution)> {
+        if self.config.fallback_parent_levels == 0 {
+            return None;
+        }
+        let cached = self.memory_cache.as_ref().and_then(|m| m.peek(key));
+        if let Some(MemoryTile {
+            pixmap,
+            substitution: Some(substitution),
+        }) = cached
+        {
+            return Some((pixmap.clone(), *substitution));
+        }
+
+        if let Some(children) = fallback::children(key) {
+            // Stops at the first one missing
+            let available = children
+                .iter()
+                .take_while(|child| self.load_tile(child, decoded))
+                .count();
+            if available == children.len() {
+                let pixmap = |i: usize| decoded[&children[i]].as_ref();
+                if let (Some(top_left), Some(top_right), Some(bottom_left), Some(bottom_right)) =
+                    (pixmap(0), pixmap(1), pixmap(2), pixmap(3))
+                {
+                    if let Some(image) =
+                        fallback::downsample([top_left, top_right, bottom_left, bottom_right])
+                    {
+                        return Some((image, TileSubstitution::Children));
+                    }
+                }
+            }
         }
 
-        Ok(&self.image)
+        for levels in 1..=self.config.fallback_parent_levels {
+            let parent = fallback::ancestor(key, levels)?;
+            if self.load_tile(&parent, decoded) {
+                let pixmap = decoded[&parent].as_ref()?;
+                return fallback::upscale(pixmap, levels, key.tile.x.0, key.tile.y.0)
+                    .map(|image| (image, TileSubstitution::Parent(parent.tile.zoom)));
+            }
+        }
+        None
+    }
+
+    /// Puts the decoded tile at `key` into `decoded`, unless it's there
+    /// already, returns whether it's available. Tiles come from the memory
+    /// or disk cache, or a local source, they're never requested over the
+    /// network.
+    fn load_tile(&self, key: &TileKey, decoded: &mut DecodedTiles) -> bool {
+        if let Some(pixmap) = decoded.get(key) {
+            return pixmap.is_some();
+        }
+        let cached = self
+            .memory_cache
+            .as_ref()
+            .and_then(|m| m.peek(key))
+            .filter(|t| t.substitution.is_none());
+        let pixmap = match cached {
+            Some(tile) => Some(tile.pixmap.clone()),
+            None => {
+                let bytes = if self.source.is_cacheable() {
+                    self.cache.as_ref().and_then(|cache| cache.get(key).ok()?)
+                } else {
+                    Self::fetch_tile(self.source.as_ref(), None, key).ok()
+                };
+                bytes.and_then(|bytes| Pixmap::decode_png(&bytes).ok())
+            }
+        };
+        let available = pixmap.is_some();
+        decoded.insert(*key, pixmap);
+        available
+    }
+
+    fn tile_key(&self, c: &GridTile, zoom: Zoom) -> TileKey {
+        TileKey {
+            scale: self.source.scale(),
+            daylight: self.source.daylight(),
+            tile: TileCoord {
+                zoom,
+                x: c.tile_x,
+                y: c.tile_y,
+            },
+        }
+    }
+
+    fn draw_tile(image: &mut Pixmap, x: i32, y: i32, pixmap: &Pixmap) {
+        // Not draw_pixmap, it bleeds a pixel past tiles at negative offsets
+        // which a shifted image doesn't paint over again
+        copy_pixmap(image, x, y, pixmap);
+    }
+
+    /// Cached tiles are used when available, otherwise the tile is requested
+    /// from the source and written back to the cache
+    fn fetch_tile(
+        source: &dyn TileSource,
+        cache: Option<&TileCache>,
+        key: &TileKey,
+    ) -> Result<Bytes, Error> {
+        if let Some((min, max)) = source.zoom_range() {
+            if key.tile.zoom < min || key.tile.zoom > max {
+                return Err(Error::ZoomOutOfRange(key.tile.zoom));
+            }
+        }
+        let cache = cache.filter(|_| source.is_cacheable());
+        if let Some(cache) = cache {
+            match cache.get(key) {
+                Ok(Some(bytes)) => return Ok(bytes),
+                Ok(None) => (),
+                Err(e) => log::warn!("Failed to read tile {:?} from the cache: {}", key, e),
+            }
+        }
+        let bytes = source.request_tile(key.tile.x, key.tile.y, key.tile.zoom)?;
+        if let Some(cache) = cache {
+            if let Err(e) = cache.put(key, &bytes) {
+                log::warn!("Failed to write tile {:?} to the cache: {}", key, e);
+            }
+        }
+        Ok(bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use common::util::{x_to_lon, y_to_lat};
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     #[test]
     fn todo() {
         assert_eq!(2 + 2, 4);
     }
+
+    /// Every zoom 3 tile, colored (x * 30, y * 30, 100), in a directory
+    /// removed on drop
+    struct TestTiler {
+        _dir: tempfile::TempDir,
+        tiles: TileDirectory,
+        config: Config,
+        map_tiler: MapTiler,
+    }
+
+    impl TestTiler {
+        /// Another tiler of the same tiles
+        fn tiler(&self, config: Config) -> MapTiler {
+            MapTiler::new(Box::new(self.tiles.clone()), config).unwrap()
+        }
+    }
+
+    const TEST_ZOOM: u8 = 3;
+
+    fn test_tiler(width: u32, height: u32) -> TestTiler {
+        let dir = tempfile::tempdir().unwrap();
+        let tiles = TileDirectory::new(dir.path());
+        for x in 0..8 {
+            for y in 0..8 {
+                write_test_tile(&tiles, TEST_ZOOM, x, y, (x * 30, y * 30, 100));
+            }
+        }
+        let config = Config {
+            width,
+            height,
+            tile_size: 16,
+            memory_cache_tiles: 0,
+            placeholder: TilePlaceholder::Blank,
+            fallback_parent_levels: 0,
+        };
+        TestTiler {
+            _dir: dir,
+            map_tiler: MapTiler::new(Box::new(tiles.clone()), config).unwrap(),
+            tiles,
+            config,
+        }
+    }
+
+    fn write_test_tile(tiles: &TileDirectory, zoom: u8, x: u8, y: u8, (r, g, b): (u8, u8, u8)) {
+        let mut pixmap = Pixmap::new(16, 16).unwrap();
+        pixmap.fill(tiny_skia::Color::from_rgba8(r, g, b, 255));
+        tiles
+            .write_tile(
+                TileNumber(x.into()),
+                TileNumber(y.into()),
+                Zoom::new_clamped(zoom),
+                &pixmap.encode_png().unwrap(),
+            )
+            .unwrap();
+    }
+
+    /// Coordinate at tile position (x, y) at the test zoom
+    fn test_coord(x: f64, y: f64) -> Coordinate {
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        Coordinate {
+            latitude: y_to_lat(y, zoom),
+            longitude: x_to_lon(x, zoom),
+        }
+    }
+
+    /// Counts the tiles requested from `tiles`
+    #[derive(Debug)]
+    struct CountingSource {
+        tiles: TileDirectory,
+        requests: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl TileSource for CountingSource {
+        fn request_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Bytes, Error> {
+            self.requests
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.tiles.request_tile(x, y, zoom)
+        }
+
+        fn is_cacheable(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn memory_cache_skips_decoding() {
+        use std::sync::Arc;
+
+        let t = test_tiler(32, 32);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        let requested_after_pan = |memory_cache_tiles| {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let source = CountingSource {
+                tiles: t.tiles.clone(),
+                requests: requests.clone(),
+            };
+            let mut map_tiler = MapTiler::new(
+                Box::new(source),
+                Config {
+                    memory_cache_tiles,
+                    ..t.config
+                },
+            )
+            .unwrap();
+            // Tiles 3..=4 in x
+            map_tiler.request_tiles(test_coord(4.0, 4.0), zoom).unwrap();
+            // Another level in between so the image isn't shifted
+            map_tiler
+                .request_tiles(test_coord(4.0, 4.0), Zoom::new_clamped(4))
+                .unwrap();
+            let before = requests.load(Ordering::SeqCst);
+            // One tile to the right, tiles 4..=5 in x
+            let map = map_tiler.request_tiles(test_coord(5.0, 4.0), zoom).unwrap();
+            assert_eq!(map.reused_tiles, 0);
+            assert!(map.failed_tiles.is_empty());
+            requests.load(Ordering::SeqCst) - before
+        };
+
+        // Only the column coming into view is fetched and decoded
+        assert_eq!(requested_after_pan(8), 2);
+        assert_eq!(requested_after_pan(0), 4);
+    }
+
+    #[test]
+    fn streamed_tiles_match_stitched_image() {
+        let t = test_tiler(32, 32);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        fs::remove_file(t.tiles.tile_path(4.into(), 4.into(), zoom)).unwrap();
+        let mut map_tiler = t.tiler(Config {
+            memory_cache_tiles: 8,
+            ..t.config
+        });
+
+        // Tiles 3..=4 cover the 32x32 image
+        let center = test_coord(4.0, 4.0);
+        for _ in 0..2 {
+            // Another zoom first so the second pass doesn't reuse the first
+            // image and gets every tile from the memory cache
+            map_tiler
+                .request_tiles(center, Zoom::new_clamped(2))
+                .unwrap();
+            let placed = std::sync::Mutex::new(Vec::new());
+            let map = map_tiler
+                .request_tiles_with(center, zoom, |t| {
+                    placed.lock().unwrap().push((
+                        t.key.tile.x.0,
+                        t.key.tile.y.0,
+                        t.x,
+                        t.y,
+                        t.image.pixel(0, 0),
+                        t.failed,
+                    ));
+                })
+                .unwrap();
+            assert_eq!(map.failed_tiles.len(), 1);
+            let mut placed = placed.into_inner().unwrap();
+            placed.sort_by_key(|p| (p.0, p.1));
+            let offsets: Vec<_> = placed.iter().map(|p| (p.0, p.1, p.2, p.3, p.5)).collect();
+            assert_eq!(
+                offsets,
+                vec![
+                    (3, 3, 0, 0, false),
+                    (3, 4, 0, 16, false),
+                    (4, 3, 16, 0, false),
+                    (4, 4, 16, 16, true),
+                ]
+            );
+            for p in placed.iter() {
+                assert_eq!(map.image.pixel(p.2 as u32 + 1, p.3 as u32 + 1), p.4);
+            }
+        }
+    }
+
+    #[test]
+    fn panned_image_matches_full_render() {
+        let mut t = test_tiler(40, 24);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        t.map_tiler
+            .request_tiles(test_coord(4.0, 4.0), zoom)
+            .unwrap();
+        // Moves less than a tile, and more than the image in x
+        for (x, y) in [(4.3, 3.8), (4.1, 4.4), (6.9, 4.4)].iter() {
+            let center = test_coord(*x, *y);
+            let drawn = AtomicUsize::new(0);
+            let map = t
+                .map_tiler
+                .request_tiles_with(center, zoom, |_| {
+                    drawn.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            let reused = map.reused_tiles;
+            let drawn = drawn.into_inner();
+            let image = map.image.clone();
+
+            // A fresh tiler draws every tile
+            let mut full = t.tiler(t.config);
+            let all = AtomicUsize::new(0);
+            let expected = full
+                .request_tiles_with(center, zoom, |_| {
+                    all.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            assert_eq!(reused + drawn, all.into_inner());
+            assert_eq!(reused == 0, *x > 6.0);
+            let diff = image
+                .data()
+                .iter()
+                .zip(expected.image.data())
+                .position(|(a, b)| a != b);
+            assert_eq!(diff, None, "center {}, {}", x, y);
+        }
+        assert_eq!(
+            t.map_tiler.view_shift(test_coord(6.9, 4.4), zoom),
+            Some((0, 0))
+        );
+        assert_eq!(
+            t.map_tiler
+                .view_shift(test_coord(6.9, 4.4), Zoom::new_clamped(4)),
+            None
+        );
+    }
+
+    #[test]
+    fn fractional_zoom_scales_tiles() {
+        let mut t = test_tiler(40, 24);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        let center = test_coord(4.0, 4.0);
+
+        // Zoomed in between levels, tiles are drawn at zoom 3 and scaled up
+        let fractional = FractionalZoom::new_clamped(3.3);
+        assert_eq!(fractional.tile_zoom(), zoom);
+        let tile_px = 16.0 * fractional.tile_scale();
+        let map = t.map_tiler.request_tiles(center, fractional).unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (40, 24));
+        for (tile_x, tile_y) in [(3u8, 3u8), (4, 3), (3, 4), (4, 4)].iter() {
+            // Middle of the tile, a tile away from the edges of the others
+            let x = (f64::from(*tile_x) - 3.5) * tile_px + 20.0;
+            let y = (f64::from(*tile_y) - 3.5) * tile_px + 12.0;
+            let p = map.image.pixel(x as u32, y as u32).unwrap();
+            assert_eq!(
+                (p.red(), p.green(), p.blue()),
+                (tile_x * 30, tile_y * 30, 100),
+                "tile {}, {}",
+                tile_x,
+                tile_y
+            );
+        }
+        assert_eq!(t.map_tiler.view_shift(center, fractional), None);
+
+        // Back at a whole level the image is drawn directly again
+        let map = t.map_tiler.request_tiles(center, zoom).unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (40, 24));
+        let p = map.image.pixel(20 - 8, 12 - 8).unwrap();
+        assert_eq!((p.red(), p.green()), (90, 90));
+    }
+
+    #[test]
+    fn unscaled_fractional_zoom() {
+        let t = test_tiler(40, 24);
+        let mut unscaled = t.tiler(t.config).with_unscaled(true);
+        let fractional = FractionalZoom::new_clamped(3.3);
+        let center = test_coord(4.0, 4.0);
+
+        // The zoom 3 image, big enough to cover the viewport once scaled up
+        let size = Config::tile_level_size(40, 24, fractional);
+        assert_eq!(size, (35, 22));
+        let streamed = AtomicUsize::new(0);
+        let map = unscaled
+            .request_tiles_with(center, fractional, |_| {
+                streamed.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        assert_eq!((map.image.width(), map.image.height()), size);
+        assert_eq!(streamed.into_inner(), 4 * 2);
+        // Tile (4, 4) starts at the middle
+        let p = map.image.pixel(18, 11).unwrap();
+        assert_eq!((p.red(), p.green()), (120, 120));
+        let p = map.image.pixel(16, 9).unwrap();
+        assert_eq!((p.red(), p.green()), (90, 90));
+
+        // Panning at the same zoom shifts the image
+        let panned = test_coord(4.25, 4.0);
+        assert_eq!(unscaled.view_shift(panned, fractional), Some((-4, 0)));
+        let map = unscaled.request_tiles(panned, fractional).unwrap();
+        assert!(map.reused_tiles > 0);
+        assert_eq!(
+            unscaled.view_shift(panned, FractionalZoom::new_clamped(3.4)),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_tiles_are_substituted() {
+        let t = test_tiler(32, 32);
+        // 3/3/3 only exists as its parent 2/1/1
+        fs::remove_file(t.tiles.tile_path(3.into(), 3.into(), Zoom::new_clamped(3))).unwrap();
+        write_test_tile(&t.tiles, 2, 1, 1, (200, 200, 200));
+        let config = Config {
+            fallback_parent_levels: 2,
+            ..t.config
+        };
+        let render = |config: Config, zoom: u8| {
+            let mut map_tiler = t.tiler(config);
+            let map = map_tiler
+                .request_tiles(Coordinate::new(0.0, 0.0).unwrap(), Zoom::new_clamped(zoom))
+                .unwrap();
+            let mut substituted: Vec<_> = map
+                .substituted_tiles
+                .iter()
+                .map(|t| (t.key.tile.x.0, t.key.tile.y.0, t.substitution))
+                .collect();
+            substituted.sort_by_key(|t| (t.0, t.1));
+            // One pixel in each of the four tiles
+            let colors: Vec<(u8, u8)> = [(8, 8), (24, 8), (8, 24), (24, 24)]
+                .iter()
+                .map(|(x, y)| {
+                    let p = map.image.pixel(*x, *y).unwrap();
+                    (p.red(), p.green())
+                })
+                .collect();
+            (colors, substituted, map.failed_tiles.len())
+        };
+
+        // The four tiles one level in, pixel (8, 8) of each tile is in its
+        // bottom right child
+        let (colors, substituted, failed) = render(config, 2);
+        assert_eq!(colors, vec![(200, 200), (150, 90), (90, 150), (150, 150)]);
+        let children = TileSubstitution::Children;
+        assert_eq!(
+            substituted,
+            vec![(1, 2, children), (2, 1, children), (2, 2, children)]
+        );
+        assert_eq!(failed, 0);
+
+        let (colors, substituted, failed) = render(config, 3);
+        assert_eq!(colors, vec![(200, 200), (120, 90), (90, 120), (120, 120)]);
+        assert_eq!(
+            substituted,
+            vec![(3, 3, TileSubstitution::Parent(Zoom::new_clamped(2)))]
+        );
+        assert_eq!(failed, 0);
+
+        // Two levels out for 4/7/7
+        let (colors, substituted, failed) = render(config, 4);
+        assert_eq!(colors, vec![(200, 200), (120, 90), (90, 120), (120, 120)]);
+        assert_eq!(substituted.len(), 4);
+        assert_eq!(
+            substituted[0],
+            (7, 7, TileSubstitution::Parent(Zoom::new_clamped(2)))
+        );
+        assert_eq!(
+            substituted[3],
+            (8, 8, TileSubstitution::Parent(Zoom::new_clamped(3)))
+        );
+        assert_eq!(failed, 0);
+
+        let config = Config {
+            fallback_parent_levels: 1,
+            ..config
+        };
+        let (_, substituted, failed) = render(config, 4);
+        assert_eq!(substituted.len(), 3);
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn substitutes_are_made_once() {
+        use std::sync::Arc;
+
+        let t = test_tiler(32, 32);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let source = CountingSource {
+            tiles: t.tiles.clone(),
+            requests: requests.clone(),
+        };
+        let mut map_tiler = MapTiler::new(
+            Box::new(source),
+            Config {
+                memory_cache_tiles: 16,
+                fallback_parent_levels: 1,
+                ..t.config
+            },
+        )
+        .unwrap();
+        let requested = |map_tiler: &mut MapTiler, zoom: u8| {
+            let before = requests.load(Ordering::SeqCst);
+            let map = map_tiler
+                .request_tiles(test_coord(4.5, 4.5), Zoom::new_clamped(zoom))
+                .unwrap();
+            assert!(map.failed_tiles.is_empty());
+            let substituted = map.substituted_tiles.len();
+            (requests.load(Ordering::SeqCst) - before, substituted)
+        };
+
+        // Zoom 4 tiles 8..=9 are missing, all four share the parent 3/4/4.
+        // Each is requested, then its first child, then the parent once.
+        assert_eq!(requested(&mut map_tiler, 4), (4 + 4 + 1, 4));
+        requested(&mut map_tiler, 3);
+        // The real tiles are tried again, the substitutes come from memory
+        assert_eq!(requested(&mut map_tiler, 4), (4, 4));
+    }
+
+    #[test]
+    fn rotatable_image_covers_viewport() {
+        let mut t = test_tiler(30, 20);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        let size = Config::rotated_size(30, 20, ScreenAnchor::CENTER);
+        assert_eq!(size, 37);
+        let center = test_coord(3.9, 4.2);
+
+        // Same as an unrotatable tiler with a square viewport of the diagonal
+        let mut square = t.tiler(Config {
+            width: size,
+            height: size,
+            ..t.config
+        });
+        let map_tiler = &mut t.map_tiler;
+        map_tiler.request_tiles(center, zoom).unwrap();
+        map_tiler.set_rotatable(true);
+        // The previous image is a different size, nothing to shift
+        assert_eq!(map_tiler.view_shift(center, zoom), None);
+        let map = map_tiler.request_tiles(center, zoom).unwrap();
+        let expected = square.request_tiles(center, zoom).unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (size, size));
+        assert_eq!(map.image.data(), expected.image.data());
+        assert_eq!(map_tiler.view_shift(center, zoom), Some((0, 0)));
+
+        let map = map_tiler
+            .request_tiles(center, FractionalZoom::new_clamped(2.8))
+            .unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (size, size));
+        map_tiler.set_rotatable(false);
+        let map = map_tiler.request_tiles(center, zoom).unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (30, 20));
+    }
+
+    #[test]
+    fn anchored_center() {
+        let t = test_tiler(30, 20);
+        let zoom = Zoom::new_clamped(TEST_ZOOM);
+        let lower = ScreenAnchor::new_clamped(0.5, 0.75);
+        let mut anchored = t.tiler(t.config).with_anchor(lower);
+        let mut centered = t.tiler(t.config);
+
+        // The corner of tile (4, 4) at (15, 15) instead of (15, 10), the same
+        // as centering 5 pixels further up
+        let map = anchored.request_tiles(test_coord(4.0, 4.0), zoom).unwrap();
+        let p = map.image.pixel(15, 15).unwrap();
+        assert_eq!((p.red(), p.green()), (120, 120));
+        let p = map.image.pixel(14, 14).unwrap();
+        assert_eq!((p.red(), p.green()), (90, 90));
+        let image = map.image.clone();
+        let expected = centered
+            .request_tiles(test_coord(4.0, 4.0 - 5.0 / 16.0), zoom)
+            .unwrap();
+        assert_eq!(image.data(), expected.image.data());
+
+        // Rotating around the anchor takes a larger square, centered on it
+        assert_eq!(Config::rotated_size(30, 20, lower), 43);
+        anchored.set_rotatable(true);
+        let map = anchored.request_tiles(test_coord(4.0, 4.0), zoom).unwrap();
+        assert_eq!((map.image.width(), map.image.height()), (43, 43));
+        // The odd size puts the corner at 21.5, tiles are placed on whole pixels
+        let p = map.image.pixel(20, 20).unwrap();
+        assert_eq!((p.red(), p.green()), (90, 90));
+        let p = map.image.pixel(22, 22).unwrap();
+        assert_eq!((p.red(), p.green()), (120, 120));
+    }
+
+    #[test]
+    fn memory_budget_tiles() {
+        assert_eq!(Config::tiles_for_memory_budget(256, 0), 0);
+        assert_eq!(Config::tiles_for_memory_budget(256, 256 * 256 * 4), 1);
+        assert_eq!(Config::tiles_for_memory_budget(1024, 64 * 1024 * 1024), 16);
+        assert_eq!(Config::tiles_for_memory_budget(0, 10), 10);
+    }
 }
@@ -0,0 +1,259 @@
+//! Offline raster tiles from an MBTiles SQLite file
+//!
+//! https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+//!
+//! Tiles are stored in the TMS scheme, rows are flipped relative to XYZ.
+
+use crate::source::png_tile_size;
+use crate::{Error, TileSource};
+use bytes::Bytes;
+use common::{BoundingBox, TileCoord, TileNumber, Zoom};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, NO_PARAMS};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    pub name: Option<String>,
+    pub format: Option<String>,
+    pub min_zoom: Option<Zoom>,
+    pub max_zoom: Option<Zoom>,
+    pub bounds: Option<BoundingBox>,
+    /// Not part of the spec, but written by some tools
+    pub tile_size: Option<u32>,
+}
+
+impl Metadata {
+    fn read(conn: &Connection) -> Result<Self, Error> {
+        let mut stmt = conn.prepare("SELECT name, value FROM metadata")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut metadata = Metadata::default();
+        for row in rows {
+            let (name, value) = row?;
+            match name.as_str() {
+                "name" => metadata.name = Some(value),
+                "format" => metadata.format = Some(value),
+                // Commonly starts at level 0, which the map doesn't go down to
+                "minzoom" => metadata.min_zoom = value.parse().ok().map(Zoom::new_clamped),
+                "maxzoom" => metadata.max_zoom = value.parse().ok().map(Zoom::new_clamped),
+                "bounds" => metadata.bounds = value.parse().ok(),
+                "tilesize" => metadata.tile_size = value.parse().ok(),
+                _ => (),
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+#[derive(Debug)]
+pub struct MbTiles {
+    path: PathBuf,
+    metadata: Metadata,
+    tile_size: Option<u32>,
+    conn: Mutex<Connection>,
+}
+
+impl MbTiles {
+    const SUPPORTED_FORMAT: &'static str = "png";
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let conn = Connection::open_with_flags(
+            &path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let metadata = Metadata::read(&conn)?;
+        if let Some(format) = &metadata.format {
+            if format != Self::SUPPORTED_FORMAT {
+                return Err(Error::UnsupportedTileFormat(format.clone()));
+            }
+        }
+        let tile_size = match metadata.tile_size {
+            Some(size) => Some(size),
+            None => Self::first_tile_size(&conn)?,
+        };
+        log::debug!(
+            "Opened MBTiles {} {:?}, tile size {:?}",
+            path.display(),
+            metadata,
+            tile_size
+        );
+        Ok(MbTiles {
+            path,
+            metadata,
+            tile_size,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// All tiles of a file are the same size, so the first one will do
+    fn first_tile_size(conn: &Connection) -> Result<Option<u32>, Error> {
+        let tile: Option<Vec<u8>> = conn
+            .query_row("SELECT tile_data FROM tiles LIMIT 1", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(tile.and_then(|bytes| png_tile_size(&bytes)))
+    }
+
+    /// The tile if the file can have it, tiles outside of the metadata
+    /// bounds aren't looked up
+    fn covered_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Option<TileCoord> {
+        let tile = TileCoord::new(zoom, x, y)?;
+        match &self.metadata.bounds {
+            Some(bounds) if !bounds.intersects(&tile.bounds()) => None,
+            _ => Some(tile),
+        }
+    }
+}
+
+impl TileSource for MbTiles {
+    fn request_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Bytes, Error> {
+        let tile = self
+            .covered_tile(x, y, zoom)
+            .ok_or(Error::TileNotFound(zoom, x, y))?;
+        let conn = self.conn.lock().map_err(|_| Error::SourceLockPoisoned)?;
+        let tile: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                // MBTiles rows count up from the bottom
+                [zoom.get() as u32, tile.x.0, tile.tms_y().0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tile.map(Bytes::from).ok_or(Error::TileNotFound(zoom, x, y))
+    }
+
+    fn tile_size(&self) -> Option<u32> {
+        self.tile_size
+    }
+
+    fn zoom_range(&self) -> Option<(Zoom, Zoom)> {
+        match (self.metadata.min_zoom, self.metadata.max_zoom) {
+            (Some(min), Some(max)) => Some((min, max)),
+            _ => None,
+        }
+    }
+
+    fn is_cacheable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiny_skia::Pixmap;
+
+    fn create(path: &Path, format: &str) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE metadata (name text, value text);
+             CREATE TABLE tiles (zoom_level integer, tile_column integer, tile_row integer, tile_data blob);",
+        )
+        .unwrap();
+        for (name, value) in [
+            ("name", "test"),
+            ("format", format),
+            ("minzoom", "0"),
+            ("maxzoom", "14"),
+            ("bounds", "-117.0,47.0,-116.0,48.0"),
+        ]
+        .iter()
+        {
+            conn.execute(
+                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+                [name, value],
+            )
+            .unwrap();
+        }
+        // XYZ (z=3, x=1, y=2) is TMS row 5
+        conn.execute(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (3, 1, 5, ?1)",
+            [vec![1_u8, 2, 3]],
+        )
+        .unwrap();
+        // Outside of the bounds, (z=3, x=5, y=2)
+        conn.execute(
+            "INSERT INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (3, 5, 5, ?1)",
+            [vec![4_u8, 5, 6]],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn read_metadata_and_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.mbtiles");
+        create(&path, "png");
+
+        let mbtiles = MbTiles::open(&path).unwrap();
+        let metadata = mbtiles.metadata();
+        assert_eq!(metadata.name.as_deref(), Some("test"));
+        assert_eq!(metadata.format.as_deref(), Some("png"));
+        assert_eq!(
+            mbtiles.zoom_range(),
+            Some((Zoom::MIN, Zoom::new_clamped(14)))
+        );
+        assert_eq!(
+            metadata.bounds,
+            Some(BoundingBox::new(-117.0, 47.0, -116.0, 48.0).unwrap())
+        );
+        assert!(!mbtiles.is_cacheable());
+
+        let tile = mbtiles
+            .request_tile(1.into(), 2.into(), Zoom::new_clamped(3))
+            .unwrap();
+        assert_eq!(tile.as_ref(), &[1, 2, 3]);
+
+        let res = mbtiles.request_tile(1.into(), 5.into(), Zoom::new_clamped(3));
+        assert!(matches!(res, Err(Error::TileNotFound(_, _, _))));
+        let res = mbtiles.request_tile(5.into(), 2.into(), Zoom::new_clamped(3));
+        assert!(matches!(res, Err(Error::TileNotFound(_, _, _))));
+        let res = mbtiles.request_tile(8.into(), 2.into(), Zoom::new_clamped(3));
+        assert!(matches!(res, Err(Error::TileNotFound(_, _, _))));
+    }
+
+    #[test]
+    fn tile_size_from_metadata_or_first_tile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.mbtiles");
+        create(&path, "png");
+        // The test tiles aren't PNGs
+        assert_eq!(MbTiles::open(&path).unwrap().tile_size(), None);
+
+        let conn = Connection::open(&path).unwrap();
+        let png = Pixmap::new(16, 16).unwrap().encode_png().unwrap();
+        conn.execute("UPDATE tiles SET tile_data = ?1", [png])
+            .unwrap();
+        assert_eq!(MbTiles::open(&path).unwrap().tile_size(), Some(16));
+
+        conn.execute(
+            "INSERT INTO metadata (name, value) VALUES ('tilesize', '512')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        let mbtiles = MbTiles::open(&path).unwrap();
+        assert_eq!(mbtiles.metadata().tile_size, Some(512));
+        assert_eq!(mbtiles.tile_size(), Some(512));
+    }
+
+    #[test]
+    fn unsupported_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.mbtiles");
+        create(&path, "pbf");
+        let res = MbTiles::open(&path);
+        assert!(matches!(res, Err(Error::UnsupportedTileFormat(f)) if f == "pbf"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Routes and markers drawn on top of a map image, positions are in image pixels
+
+use tiny_skia::{
+    Color, FillRule, LineCap, LineJoin, Paint, PathBuilder, Pixmap, Stroke, Transform,
+};
+
+/// Polyline through the points
+pub fn draw_route(image: &mut Pixmap, points: &[(f64, f64)], color: Color, width: f32) {
+    let mut pb = PathBuilder::new();
+    for (i, (x, y)) in points.iter().enumerate() {
+        if i == 0 {
+            pb.move_to(*x as f32, *y as f32);
+        } else {
+            pb.line_to(*x as f32, *y as f32);
+        }
+    }
+    let path = match pb.finish() {
+        Some(p) => p,
+        // Less than two points
+        None => return,
+    };
+    let mut paint = Paint {
+        anti_alias: true,
+        ..Default::default()
+    };
+    paint.set_color(color);
+    let stroke = Stroke {
+        width,
+        line_cap: LineCap::Round,
+        line_join: LineJoin::Round,
+        ..Default::default()
+    };
+    image.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+}
+
+/// Filled circle with a white outline
+pub fn draw_marker(image: &mut Pixmap, point: (f64, f64), color: Color, radius: f32) {
+    let path = match PathBuilder::from_circle(point.0 as f32, point.1 as f32, radius) {
+        Some(p) => p,
+        None => return,
+    };
+    let mut paint = Paint {
+        anti_alias: true,
+        ..Default::default()
+    };
+    paint.set_color(color);
+    image.fill_path(
+        &path,
+        &paint,
+        FillRule::Winding,
+        Transform::identity(),
+        None,
+    );
+    paint.set_color(Color::WHITE);
+    let stroke = Stroke {
+        width: (radius / 4.0).max(1.0),
+        ..Default::default()
+    };
+    image.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(image: &Pixmap, x: u32, y: u32) -> [u8; 4] {
+        let i = 4 * (y * image.width() + x) as usize;
+        let d = image.data();
+        [d[i], d[i + 1], d[i + 2], d[i + 3]]
+    }
+
+    #[test]
+    fn route_and_marker() {
+        let red = Color::from_rgba8(255, 0, 0, 255);
+        let blue = Color::from_rgba8(0, 0, 255, 255);
+        let mut image = Pixmap::new(32, 32).unwrap();
+
+        draw_route(
+            &mut image,
+            &[(2.0, 4.0), (30.0, 4.0), (30.0, 28.0)],
+            red,
+            3.0,
+        );
+        assert_eq!(pixel(&image, 16, 4), [255, 0, 0, 255]);
+        assert_eq!(pixel(&image, 30, 16), [255, 0, 0, 255]);
+        assert_eq!(pixel(&image, 16, 16), [0, 0, 0, 0]);
+
+        draw_marker(&mut image, (12.0, 20.0), blue, 6.0);
+        assert_eq!(pixel(&image, 12, 20), [0, 0, 255, 255]);
+        assert_eq!(pixel(&image, 2, 28), [0, 0, 0, 0]);
+
+        // Nothing to draw
+        let before = image.clone();
+        draw_route(&mut image, &[(1.0, 1.0)], red, 3.0);
+        draw_route(&mut image, &[], red, 3.0);
+        assert_eq!(image, before);
+    }
+}
@@ -0,0 +1,71 @@
+//! Placeholder tiles drawn in place of tiles that failed to load
+
+use common::TilePlaceholder;
+use tiny_skia::Pixmap;
+
+const LIGHT: [u8; 4] = [0xD0, 0xD0, 0xD0, 0xFF];
+const DARK: [u8; 4] = [0xA0, 0xA0, 0xA0, 0xFF];
+
+/// Number of squares/cells along each edge of the tile
+const CELLS: u32 = 8;
+
+pub(crate) fn render(pattern: TilePlaceholder, tile_size: u32) -> Option<Pixmap> {
+    let mut pixmap = Pixmap::new(tile_size, tile_size)?;
+    let cell_size = (tile_size / CELLS).max(1);
+    let line_width = (cell_size / 16).max(1);
+    for (i, px) in pixmap.data_mut().chunks_exact_mut(4).enumerate() {
+        let x = i as u32 % tile_size;
+        let y = i as u32 / tile_size;
+        let color = match pattern {
+            TilePlaceholder::Blank => LIGHT,
+            TilePlaceholder::Checkerboard => {
+                if (x / cell_size + y / cell_size) & 1 == 0 {
+                    LIGHT
+                } else {
+                    DARK
+                }
+            }
+            TilePlaceholder::Grid => {
+                if x % cell_size < line_width || y % cell_size < line_width {
+                    DARK
+                } else {
+                    LIGHT
+                }
+            }
+        };
+        px.copy_from_slice(&color);
+    }
+    Some(pixmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(pixmap: &Pixmap, x: u32, y: u32) -> [u8; 4] {
+        let i = 4 * (y * pixmap.width() + x) as usize;
+        let mut px = [0; 4];
+        px.copy_from_slice(&pixmap.data()[i..i + 4]);
+        px
+    }
+
+    #[test]
+    fn patterns() {
+        let p = render(TilePlaceholder::Blank, 256).unwrap();
+        assert_eq!(pixel(&p, 0, 0), LIGHT);
+        assert_eq!(pixel(&p, 255, 255), LIGHT);
+
+        let p = render(TilePlaceholder::Checkerboard, 256).unwrap();
+        assert_eq!(pixel(&p, 0, 0), LIGHT);
+        assert_eq!(pixel(&p, 32, 0), DARK);
+        assert_eq!(pixel(&p, 32, 32), LIGHT);
+
+        let p = render(TilePlaceholder::Grid, 256).unwrap();
+        assert_eq!(pixel(&p, 0, 10), DARK);
+        assert_eq!(pixel(&p, 10, 10), LIGHT);
+        assert_eq!(pixel(&p, 33, 10), DARK);
+        assert_eq!(pixel(&p, 34, 10), LIGHT);
+
+        assert!(render(TilePlaceholder::Blank, 0).is_none());
+    }
+}
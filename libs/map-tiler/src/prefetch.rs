@@ -0,0 +1,210 @@
+//! Seed the tile cache with every tile covering a region
+
+use crate::{Error, TileCache, TileKey, TileRegion, TileSource};
+use common::Zoom;
+use rayon::prelude::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PrefetchStatus {
+    /// Downloaded and written to the cache, size in bytes
+    Fetched(u64),
+    /// Already in the cache, e.g. from an earlier interrupted run
+    Cached,
+    /// The source failed to provide the tile
+    Failed,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct PrefetchSummary {
+    pub fetched: usize,
+    pub cached: usize,
+    pub failed: usize,
+    /// Total size of the fetched tiles
+    pub bytes: u64,
+}
+
+/// Fetches every tile covering the region over the inclusive zoom range into
+/// the cache, at most `concurrency` requests at a time.
+///
+/// Tiles already in the cache are skipped, cache writes are atomic so an
+/// interrupted prefetch picks up where it left off when run again.
+/// `on_tile` is called once for every tile as it completes.
+pub fn prefetch_tiles<F>(
+    source: &dyn TileSource,
+    cache: &TileCache,
+    region: &TileRegion,
+    min_zoom: Zoom,
+    max_zoom: Zoom,
+    concurrency: usize,
+    on_tile: F,
+) -> Result<PrefetchSummary, Error>
+where
+    F: Fn(&TileKey, PrefetchStatus) + Sync,
+{
+    if min_zoom > max_zoom {
+        return Err(Error::InvalidZoomRange(min_zoom, max_zoom));
+    }
+    // Keys are generated as the workers need them, regions can have a lot of tiles
+    let (scale, daylight) = (source.scale(), source.daylight());
+    let keys = (min_zoom.get()..=max_zoom.get())
+        .map(Zoom::new_clamped)
+        .flat_map(|zoom| {
+            region.tiles(zoom).map(move |tile| TileKey {
+                scale,
+                daylight,
+                tile,
+            })
+        });
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrency.max(1))
+        .build()?;
+    pool.install(|| {
+        keys.par_bridge()
+            .map(|key| {
+                let status = prefetch_tile(source, cache, &key)?;
+                on_tile(&key, status);
+                Ok(PrefetchSummary::from(status))
+            })
+            .try_reduce(PrefetchSummary::default, |a, b| Ok(a + b))
+    })
+}
+
+impl From<PrefetchStatus> for PrefetchSummary {
+    fn from(status: PrefetchStatus) -> Self {
+        let mut summary = PrefetchSummary::default();
+        match status {
+            PrefetchStatus::Fetched(bytes) => {
+                summary.fetched = 1;
+                summary.bytes = bytes;
+            }
+            PrefetchStatus::Cached => summary.cached = 1,
+            PrefetchStatus::Failed => summary.failed = 1,
+        }
+        summary
+    }
+}
+
+impl std::ops::Add for PrefetchSummary {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        PrefetchSummary {
+            fetched: self.fetched + other.fetched,
+            cached: self.cached + other.cached,
+            failed: self.failed + other.failed,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// Source failures are reported in the status, cache errors are fatal
+fn prefetch_tile(
+    source: &dyn TileSource,
+    cache: &TileCache,
+    key: &TileKey,
+) -> Result<PrefetchStatus, Error> {
+    if cache.contains(key)? {
+        return Ok(PrefetchStatus::Cached);
+    }
+    let tile = key.tile;
+    match source.request_tile(tile.x, tile.y, tile.zoom) {
+        Ok(bytes) => {
+            cache.put(key, &bytes)?;
+            Ok(PrefetchStatus::Fetched(bytes.len() as u64))
+        }
+        Err(e) => {
+            log::warn!("Failed to prefetch tile {}: {}", tile, e);
+            Ok(PrefetchStatus::Failed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TileDirectory;
+    use common::{BoundingBox, TileCoord};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn prefetch_and_resume() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let src = TileDirectory::new(src_dir.path());
+        let cache = TileCache::open(cache_dir.path(), 1024 * 1024).unwrap();
+        let region =
+            TileRegion::from_bbox(BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap());
+        let z11 = Zoom::new_clamped(11);
+        let z12 = Zoom::new_clamped(12);
+        src.write_tile(359.into(), 716.into(), z11, &[1, 2, 3])
+            .unwrap();
+        src.write_tile(719.into(), 1432.into(), z12, &[4, 5])
+            .unwrap();
+        src.write_tile(719.into(), 1433.into(), z12, &[6]).unwrap();
+        assert_eq!(region.tile_count(z11, z12), 3);
+
+        // Interrupted run, only z11 made it
+        let summary = prefetch_tiles(&src, &cache, &region, z11, z11, 2, |_, _| ()).unwrap();
+        assert_eq!(
+            summary,
+            PrefetchSummary {
+                fetched: 1,
+                cached: 0,
+                failed: 0,
+                bytes: 3
+            }
+        );
+
+        let calls = AtomicUsize::new(0);
+        let summary = prefetch_tiles(&src, &cache, &region, z11, z12, 2, |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            summary,
+            PrefetchSummary {
+                fetched: 2,
+                cached: 1,
+                failed: 0,
+                bytes: 3
+            }
+        );
+        assert_eq!(cache.len().unwrap(), 3);
+        let key = TileKey {
+            scale: None,
+            daylight: None,
+            tile: TileCoord::new(z12, 719, 1433).unwrap(),
+        };
+        assert_eq!(cache.get(&key).unwrap().unwrap().as_ref(), &[6]);
+    }
+
+    #[test]
+    fn source_failures_are_counted() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let src = TileDirectory::new(src_dir.path());
+        let cache = TileCache::open(cache_dir.path(), 1024).unwrap();
+        let region =
+            TileRegion::from_bbox(BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap());
+        let z = Zoom::new_clamped(14);
+        let summary = prefetch_tiles(&src, &cache, &region, z, z, 4, |_, status| {
+            assert_eq!(status, PrefetchStatus::Failed)
+        })
+        .unwrap();
+        assert_eq!(summary.failed, 4);
+        assert!(cache.is_empty().unwrap());
+
+        let res = prefetch_tiles(
+            &src,
+            &cache,
+            &region,
+            z,
+            Zoom::new_clamped(13),
+            4,
+            |_, _| (),
+        );
+        assert!(matches!(res, Err(Error::InvalidZoomRange(_, _))));
+    }
+}
@@ -0,0 +1,276 @@
+//! Sets of tiles covering a geographic region
+//!
+//! Regions can cover a lot of tiles at the higher zoom levels, they're
+//! produced a column at a time instead of all at once.
+
+use common::geodesy::haversine_distance;
+use common::{BoundingBox, Coordinate, Latitude, Longitude, TileCoord, TileNumber, Zoom};
+use std::ops::RangeInclusive;
+
+/// Union of bounding boxes, e.g. a single area or a corridor around a track
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TileRegion {
+    areas: Vec<BoundingBox>,
+}
+
+impl TileRegion {
+    /// Track segments are split into pieces at least this long
+    const MIN_PIECE_METERS: f64 = 50.0;
+
+    pub fn from_bbox(bbox: BoundingBox) -> Self {
+        TileRegion { areas: vec![bbox] }
+    }
+
+    /// Corridor of `buffer_meters` on each side of the track. Segments are
+    /// split into pieces about as long as the buffer, one box per piece, so
+    /// the corridor follows diagonal segments instead of covering their
+    /// whole bounding box.
+    pub fn from_track(track: &[Coordinate], buffer_meters: f64) -> Self {
+        let piece_meters = buffer_meters.max(Self::MIN_PIECE_METERS);
+        let pieces: Vec<(Coordinate, Coordinate)> = match track {
+            [] => Vec::new(),
+            [c] => vec![(*c, *c)],
+            _ => track
+                .windows(2)
+                .flat_map(|seg| {
+                    let (from, to) = (seg[0], seg[1]);
+                    let n = (haversine_distance(&from, &to) / piece_meters)
+                        .ceil()
+                        .max(1.0) as u32;
+                    (0..n).map(move |i| {
+                        (
+                            interpolate(&from, &to, f64::from(i) / f64::from(n)),
+                            interpolate(&from, &to, f64::from(i + 1) / f64::from(n)),
+                        )
+                    })
+                })
+                .collect(),
+        };
+        TileRegion {
+            areas: pieces
+                .into_iter()
+                .filter_map(|(from, to)| BoundingBox::from_coordinates(vec![from, to]))
+                .map(|bbox| bbox.expand_by_meters(buffer_meters))
+                .collect(),
+        }
+    }
+
+    pub fn areas(&self) -> &[BoundingBox] {
+        &self.areas
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+
+    /// Distinct tiles covering the region at `zoom`, ordered by x then y
+    pub fn tiles(&self, zoom: Zoom) -> impl Iterator<Item = TileCoord> + '_ {
+        self.columns(zoom).flat_map(move |(x, y_ranges)| {
+            y_ranges.into_iter().flatten().map(move |y| TileCoord {
+                zoom,
+                x: TileNumber(x),
+                y: TileNumber(y),
+            })
+        })
+    }
+
+    /// Number of distinct tiles covering the region over the inclusive zoom
+    /// range, zero when `min_zoom` is greater than `max_zoom`
+    pub fn tile_count(&self, min_zoom: Zoom, max_zoom: Zoom) -> usize {
+        (min_zoom.get()..=max_zoom.get())
+            .flat_map(|z| self.columns(Zoom::new_clamped(z)))
+            .flat_map(|(_, y_ranges)| y_ranges)
+            .map(|y| (y.end() - y.start()) as usize + 1)
+            .sum()
+    }
+
+    fn columns(&self, zoom: Zoom) -> Columns {
+        let mut pending: Vec<TileRanges> = self
+            .areas
+            .iter()
+            .flat_map(|bbox| {
+                // Either side of the antimeridian
+                let (x_ranges, y_range) = bbox.tile_ranges(zoom);
+                x_ranges.into_iter().map(move |xs| (xs, y_range.clone()))
+            })
+            .collect();
+        // Last to start at the front, the next area to reach is popped off the back
+        pending.sort_by(|a, b| b.0.start().cmp(a.0.start()));
+        Columns {
+            pending,
+            active: Vec::new(),
+            next_x: 0,
+        }
+    }
+}
+
+/// Inclusive x and y tile number ranges of an area
+type TileRanges = (RangeInclusive<u32>, RangeInclusive<u32>);
+
+/// Sweeps the areas left to right, yielding the merged y ranges of every
+/// column that has tiles
+#[derive(Debug)]
+struct Columns {
+    /// Areas the sweep hasn't reached yet, by descending first column
+    pending: Vec<TileRanges>,
+    /// Areas covering the current column
+    active: Vec<TileRanges>,
+    next_x: u32,
+}
+
+impl Iterator for Columns {
+    type Item = (u32, Vec<RangeInclusive<u32>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut x = self.next_x;
+        self.active.retain(|(xs, _)| *xs.end() >= x);
+        if self.active.is_empty() {
+            // Skip ahead over the empty columns
+            x = x.max(*self.pending.last()?.0.start());
+        }
+        while self.pending.last().map(|(xs, _)| *xs.start() <= x) == Some(true) {
+            self.active.extend(self.pending.pop());
+        }
+        self.next_x = x + 1;
+
+        let mut y_ranges: Vec<RangeInclusive<u32>> =
+            self.active.iter().map(|(_, ys)| ys.clone()).collect();
+        y_ranges.sort_by_key(|ys| *ys.start());
+        let mut merged: Vec<RangeInclusive<u32>> = Vec::with_capacity(y_ranges.len());
+        for ys in y_ranges.into_iter() {
+            match merged.last_mut() {
+                Some(last) if *ys.start() <= last.end() + 1 => {
+                    let end = *ys.end().max(last.end());
+                    *last = *last.start()..=end;
+                }
+                _ => merged.push(ys),
+            }
+        }
+        Some((x, merged))
+    }
+}
+
+/// Straight line in latitude and longitude, `t` from 0 at `from` to 1 at `to`
+fn interpolate(from: &Coordinate, to: &Coordinate, t: f64) -> Coordinate {
+    Coordinate {
+        latitude: Latitude::new_clamped(
+            from.latitude.get() + (to.latitude.get() - from.latitude.get()) * t,
+        ),
+        longitude: Longitude::new_clamped(
+            from.longitude.get() + (to.longitude.get() - from.longitude.get()) * t,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bbox_tile_range() {
+        let bbox = BoundingBox::new(-180.0, -85.0, 179.9, 85.0).unwrap();
+        assert_eq!(bbox.tile_ranges(Zoom::new_clamped(1)), (vec![0..=1], 0..=1));
+
+        // Coeur d'Alene, ID
+        let bbox = BoundingBox::new(-116.80, 47.45, -116.77, 47.46).unwrap();
+        assert_eq!(
+            bbox.tile_ranges(Zoom::new_clamped(11)),
+            (vec![359..=359], 716..=716)
+        );
+        assert_eq!(
+            bbox.tile_ranges(Zoom::new_clamped(14)),
+            (vec![2876..=2877], 5731..=5732)
+        );
+    }
+
+    #[test]
+    fn track_corridor() {
+        let track = [
+            Coordinate::new(47.4535, -116.7881).unwrap(),
+            Coordinate::new(47.4566, -116.7832).unwrap(),
+            Coordinate::new(47.4700, -116.7000).unwrap(),
+        ];
+        let region = TileRegion::from_track(&track, 100.0);
+        // One box per 100 m piece of the 0.5 km and 6.5 km segments
+        assert_eq!(region.areas().len(), 6 + 65);
+        for c in track.iter() {
+            assert!(region
+                .areas()
+                .iter()
+                .any(|b| b.west.get() < c.longitude.get()
+                    && b.east.get() > c.longitude.get()
+                    && b.south.get() < c.latitude.get()
+                    && b.north.get() > c.latitude.get()));
+        }
+
+        let z = Zoom::new_clamped(14);
+        let tiles: Vec<_> = region.tiles(z).collect();
+        // Distinct even though the segment boxes overlap
+        let mut deduped = tiles.clone();
+        deduped.dedup();
+        assert_eq!(tiles, deduped);
+        assert!(tiles.contains(&TileCoord::new(z, 2876, 5732).unwrap()));
+
+        let bbox_tiles = TileRegion::from_bbox(
+            BoundingBox::from_coordinates(track.iter().copied())
+                .unwrap()
+                .expand_by_meters(100.0),
+        )
+        .tiles(z)
+        .collect::<Vec<_>>();
+        assert!(tiles.len() <= bbox_tiles.len());
+        assert!(tiles.iter().all(|t| bbox_tiles.contains(t)));
+
+        let single = TileRegion::from_track(&track[..1], 0.0);
+        assert_eq!(single.tile_count(z, z), 1);
+        assert!(TileRegion::from_track(&[], 100.0).is_empty());
+    }
+
+    #[test]
+    fn diagonal_corridor_follows_the_track() {
+        let track = [
+            Coordinate::new(47.0, -117.0).unwrap(),
+            Coordinate::new(47.5, -116.3).unwrap(),
+        ];
+        let z = Zoom::new_clamped(14);
+        let corridor = TileRegion::from_track(&track, 100.0);
+        let segment_bbox = TileRegion::from_bbox(
+            BoundingBox::from_coordinates(track.iter().copied())
+                .unwrap()
+                .expand_by_meters(100.0),
+        );
+        let count = corridor.tile_count(z, z);
+        assert_eq!(count, corridor.tiles(z).count());
+        assert!(count * 10 < segment_bbox.tile_count(z, z));
+        for t in 0..=10 {
+            let c = interpolate(&track[0], &track[1], f64::from(t) / 10.0);
+            let tile = TileCoord::from_coordinate(&c, z);
+            assert!(corridor.tiles(z).any(|t| t == tile));
+        }
+    }
+
+    #[test]
+    fn overlapping_areas_are_merged() {
+        let z = Zoom::new_clamped(11);
+        let region = TileRegion {
+            areas: vec![
+                BoundingBox::new(-116.0, 47.0, -115.0, 47.5).unwrap(),
+                BoundingBox::new(-117.0, 47.2, -115.5, 47.3).unwrap(),
+                BoundingBox::new(-100.0, 47.0, -100.0, 47.0).unwrap(),
+            ],
+        };
+        let tiles: Vec<_> = region.tiles(z).collect();
+        let mut sorted = tiles.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(tiles, sorted);
+        let expected: std::collections::BTreeSet<_> = region
+            .areas()
+            .iter()
+            .flat_map(|bbox| bbox.tiles(z))
+            .collect();
+        assert_eq!(tiles, expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(region.tile_count(z, z), tiles.len());
+        assert_eq!(region.tile_count(Zoom::new_clamped(12), z), 0);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::Error;
+use bytes::Bytes;
+use common::{Daylight, Scale, TileNumber, Zoom};
+use osm_client::OsmClient;
+use std::fmt;
+
+/// Provides encoded (PNG) raster tiles to the `MapTiler`
+pub trait TileSource: fmt::Debug + Send + Sync {
+    fn request_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Bytes, Error>;
+
+    /// Part of the tile cache key
+    fn scale(&self) -> Option<Scale> {
+        None
+    }
+
+    /// Part of the tile cache key
+    fn daylight(&self) -> Option<Daylight> {
+        None
+    }
+
+    /// Width and height of the tiles in pixels, if known
+    fn tile_size(&self) -> Option<u32> {
+        self.scale().map(|s| s.tile_size())
+    }
+
+    /// Inclusive range of the zoom levels this source has tiles for, if known
+    fn zoom_range(&self) -> Option<(Zoom, Zoom)> {
+        None
+    }
+
+    /// Whether tiles from this source should be written to the tile cache,
+    /// no point in caching tiles that are already on local disk
+    fn is_cacheable(&self) -> bool {
+        true
+    }
+}
+
+/// Width of an encoded tile, read from the PNG header without decoding the pixels
+pub(crate) fn png_tile_size(bytes: &[u8]) -> Option<u32> {
+    let (info, _) = png::Decoder::new(bytes).read_info().ok()?;
+    Some(info.width)
+}
+
+impl TileSource for OsmClient {
+    fn request_tile(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Bytes, Error> {
+        Ok(OsmClient::request_tile(self, x, y, zoom)?)
+    }
+
+    fn scale(&self) -> Option<Scale> {
+        OsmClient::scale(self)
+    }
+
+    fn daylight(&self) -> Option<Daylight> {
+        OsmClient::daylight(self)
+    }
+}
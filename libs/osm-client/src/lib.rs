@@ -1,4 +1,4 @@
-//! TODO docs
+//! HTTP raster tile client for osmscout-server and XYZ url template servers
 //!
 //! zoom: 1..=18
 //! http://localhost:8080/{z}/{x}/{y}.png
@@ -15,9 +15,15 @@ use common::{Daylight, Scale, TileNumber, Zoom};
 use err_derive::Error;
 use reqwest::blocking::Client;
 use std::time::Duration;
+use std::{error::Error as StdError, io, thread};
 use url::Url;
 
-// TODO - timeout variant
+pub use crate::retry::RetryPolicy;
+pub use crate::template::UrlTemplate;
+
+mod retry;
+mod template;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(display = "Failed to parse request url: {}", _0)]
@@ -28,29 +34,114 @@ pub enum Error {
 
     #[error(display = "Failed to parse response as bytes: {}", _0)]
     ParseResponseBytes(reqwest::Error),
+
+    #[error(display = "Request timed out: {}", _0)]
+    Timeout(reqwest::Error),
+
+    #[error(display = "Connection refused: {}", _0)]
+    ConnectionRefused(reqwest::Error),
+
+    #[error(display = "Server responded with HTTP status {}", _0)]
+    HttpStatus(u16),
+
+    #[error(display = "URL template is missing the {} placeholder", _0)]
+    TemplatePlaceholder(&'static str),
+}
+
+impl Error {
+    /// Whether the request might succeed if tried again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Timeout(_) | Error::ConnectionRefused(_) => true,
+            Error::Request(e) => e.is_connect(),
+            Error::HttpStatus(status) => *status == 429 || (500..600).contains(status),
+            Error::UrlParse(_) | Error::ParseResponseBytes(_) | Error::TemplatePlaceholder(_) => {
+                false
+            }
+        }
+    }
+
+    fn from_request(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Error::Timeout(e)
+        } else if e.is_connect() && Self::is_connection_refused(&e) {
+            Error::ConnectionRefused(e)
+        } else {
+            Error::Request(e)
+        }
+    }
+
+    fn from_response_bytes(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Error::Timeout(e)
+        } else {
+            Error::ParseResponseBytes(e)
+        }
+    }
+
+    fn is_connection_refused(e: &reqwest::Error) -> bool {
+        let mut source = e.source();
+        while let Some(err) = source {
+            if let Some(io_err) = err.downcast_ref::<io::Error>() {
+                if io_err.kind() == io::ErrorKind::ConnectionRefused {
+                    return true;
+                }
+            }
+            source = err.source();
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Endpoint {
+    /// osmscout-server, z/x/y and the options are query pairs on the base url
+    OsmScout(Url),
+    Template(UrlTemplate),
 }
 
 #[derive(Debug)]
 pub struct OsmClient {
     client: Client,
-    server_url: Url,
+    endpoint: Endpoint,
     timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
     scale: Option<Scale>,
     daylight: Option<Daylight>,
 }
 
 impl OsmClient {
+    /// Client for an osmscout-server style endpoint
     pub fn new(server_url: Url) -> Self {
         log::debug!("Created new OsmClient {}", server_url);
+        Self::with_endpoint(Endpoint::OsmScout(server_url))
+    }
+
+    /// Client for a generic XYZ tile server
+    pub fn from_template(template: UrlTemplate) -> Self {
+        log::debug!("Created new OsmClient {}", template.as_str());
+        Self::with_endpoint(Endpoint::Template(template))
+    }
+
+    fn with_endpoint(endpoint: Endpoint) -> Self {
         OsmClient {
             client: Client::new(),
-            server_url,
+            endpoint,
             timeout: None,
+            retry_policy: RetryPolicy::NONE,
             scale: None,
             daylight: None,
         }
     }
 
+    /// The base url, or the template for template clients
+    pub fn server_url(&self) -> &str {
+        match &self.endpoint {
+            Endpoint::OsmScout(url) => url.as_str(),
+            Endpoint::Template(t) => t.as_str(),
+        }
+    }
+
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.set_timeout(Some(timeout));
         self
@@ -60,6 +151,23 @@ impl OsmClient {
         self.timeout = timeout.into();
     }
 
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.set_retry_policy(retry_policy);
+        self
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
     pub fn with_scale(mut self, scale: Scale) -> Self {
         self.set_scale(Some(scale));
         self
@@ -70,6 +178,10 @@ impl OsmClient {
         self.update_base_url_query_pairs();
     }
 
+    pub fn scale(&self) -> Option<Scale> {
+        self.scale
+    }
+
     pub fn with_daylight(mut self, daylight: Daylight) -> Self {
         self.set_daylight(Some(daylight));
         self
@@ -80,43 +192,89 @@ impl OsmClient {
         self.update_base_url_query_pairs();
     }
 
+    pub fn daylight(&self) -> Option<Daylight> {
+        self.daylight
+    }
+
+    /// Retryable failures are retried according to the client's `RetryPolicy`,
+    /// sleeping on the calling thread in between
     pub fn request_tile<T: Into<TileNumber>, Z: Into<Zoom>>(
         &self,
         x: T,
         y: T,
         zoom: Z,
     ) -> Result<Bytes, Error> {
-        let z = zoom.into().to_string();
-        let x = x.into().to_string();
-        let y = y.into().to_string();
-        let mut base = self.server_url.clone();
-        let url = base
-            .query_pairs_mut()
-            .append_pair("z", &z)
-            .append_pair("x", &x)
-            .append_pair("y", &y)
-            .finish();
+        let url = self.tile_url(x.into(), y.into(), zoom.into())?;
+
+        let mut attempt = 1;
+        let mut waited = Duration::from_millis(0);
+        loop {
+            let e = match self.request_once(&url) {
+                Ok(bytes) => return Ok(bytes),
+                Err(e) if e.is_retryable() => e,
+                Err(e) => return Err(e),
+            };
+            let delay = match self.retry_policy.next_delay(attempt, waited) {
+                Some(delay) => delay,
+                None => return Err(e),
+            };
+            log::debug!(
+                "Tile request attempt {} failed ({}), retrying in {:?}",
+                attempt,
+                e,
+                delay
+            );
+            thread::sleep(delay);
+            waited += delay;
+            attempt += 1;
+        }
+    }
+
+    fn tile_url(&self, x: TileNumber, y: TileNumber, zoom: Zoom) -> Result<Url, Error> {
+        match &self.endpoint {
+            Endpoint::OsmScout(base) => {
+                let mut url = base.clone();
+                url.query_pairs_mut()
+                    .append_pair("z", &zoom.to_string())
+                    .append_pair("x", &x.to_string())
+                    .append_pair("y", &y.to_string());
+                Ok(url)
+            }
+            Endpoint::Template(t) => t.expand(x, y, zoom, self.scale, self.daylight),
+        }
+    }
+
+    fn request_once(&self, url: &Url) -> Result<Bytes, Error> {
         let req = if let Some(timeout) = self.timeout {
             self.client.get(url.as_str()).timeout(timeout)
         } else {
             self.client.get(url.as_str())
         };
         log::debug!("Sending tile request {:?}", req);
-        let resp = req.send().map_err(Error::Request)?;
+        let resp = req.send().map_err(Error::from_request)?;
         log::debug!("Received tile response {:?}", resp);
-        let bytes = resp.bytes().map_err(Error::ParseResponseBytes)?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(Error::HttpStatus(status.as_u16()));
+        }
+        let bytes = resp.bytes().map_err(Error::from_response_bytes)?;
         Ok(bytes)
     }
 
+    /// Templates pick up the options when expanded
     fn update_base_url_query_pairs(&mut self) {
-        self.server_url.query_pairs_mut().clear();
+        let server_url = match &mut self.endpoint {
+            Endpoint::OsmScout(url) => url,
+            Endpoint::Template(_) => return,
+        };
+        server_url.query_pairs_mut().clear();
         if let Some(daylight) = &self.daylight {
             let qp = daylight.url_query_pair();
-            self.server_url.query_pairs_mut().append_pair(qp.0, qp.1);
+            server_url.query_pairs_mut().append_pair(qp.0, qp.1);
         }
         if let Some(scale) = &self.scale {
             let qp = scale.url_query_pair();
-            self.server_url.query_pairs_mut().append_pair(qp.0, qp.1);
+            server_url.query_pairs_mut().append_pair(qp.0, qp.1);
         }
     }
 }
@@ -124,49 +282,161 @@ impl OsmClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves one canned HTTP response per status code, in order
+    fn serve(statuses: Vec<u16>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/v1/tile",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        thread::spawn(move || {
+            for status in statuses.into_iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).unwrap();
+                let body = b"tile";
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn template_requests() {
+        let base = serve(vec![200]);
+        let template = UrlTemplate::new(format!(
+            "http://{}:{}/{{z}}/{{x}}/{{y}}{{r}}.png",
+            base.host_str().unwrap(),
+            base.port().unwrap()
+        ))
+        .unwrap();
+        let client = OsmClient::from_template(template).with_scale(Scale::Two);
+        assert_eq!(
+            client
+                .tile_url(1.into(), 2.into(), Zoom::new_clamped(3))
+                .unwrap()
+                .path(),
+            "/3/1/2@2x.png"
+        );
+        assert_eq!(client.request_tile(1, 2, 3).unwrap().as_ref(), b"tile");
+    }
+
+    #[test]
+    fn http_status_error() {
+        let client = OsmClient::new(serve(vec![404]));
+        let res = client.request_tile(1, 2, 3);
+        assert!(matches!(res, Err(Error::HttpStatus(404))));
+    }
+
+    #[test]
+    fn connection_refused_error() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/v1/tile", addr)).unwrap();
+        let client = OsmClient::new(url);
+        let err = client.request_tile(1, 2, 3).unwrap_err();
+        assert!(matches!(err, Error::ConnectionRefused(_)), "{:?}", err);
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn timeout_error() {
+        // Connections are queued up by the OS but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/v1/tile",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let client = OsmClient::new(url).with_timeout(Duration::from_millis(100));
+        let err = client.request_tile(1, 2, 3).unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{:?}", err);
+        assert!(err.is_retryable());
+        drop(listener);
+    }
+
+    #[test]
+    fn retry_delays_are_capped() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/v1/tile", addr)).unwrap();
+        let client = OsmClient::new(url).with_retry_policy(
+            RetryPolicy::new(5, Duration::from_secs(5))
+                .with_max_total_delay(Duration::from_millis(10)),
+        );
+        let start = std::time::Instant::now();
+        let err = client.request_tile(1, 2, 3).unwrap_err();
+        assert!(matches!(err, Error::ConnectionRefused(_)), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn retries_retryable_errors() {
+        let client = OsmClient::new(serve(vec![503, 500, 200]))
+            .with_retry_policy(RetryPolicy::new(3, Duration::from_millis(1)));
+        let bytes = client.request_tile(1, 2, 3).unwrap();
+        assert_eq!(bytes.as_ref(), b"tile");
+
+        let client = OsmClient::new(serve(vec![503, 200]))
+            .with_retry_policy(RetryPolicy::new(1, Duration::from_millis(1)));
+        let res = client.request_tile(1, 2, 3);
+        assert!(matches!(res, Err(Error::HttpStatus(503))));
+
+        // Not retryable
+        let client = OsmClient::new(serve(vec![404, 200]))
+            .with_retry_policy(RetryPolicy::new(3, Duration::from_millis(1)));
+        let res = client.request_tile(1, 2, 3);
+        assert!(matches!(res, Err(Error::HttpStatus(404))));
+    }
 
     #[test]
     fn base_url_manipulation() {
         let url = Url::parse("http://127.0.0.1:8553/v1/tile").unwrap();
 
         let client = OsmClient::new(url.clone());
-        assert_eq!(client.server_url.as_str(), "http://127.0.0.1:8553/v1/tile");
+        assert_eq!(client.server_url(), "http://127.0.0.1:8553/v1/tile");
         let client = client.with_timeout(Duration::from_secs(1));
-        assert_eq!(client.server_url.as_str(), "http://127.0.0.1:8553/v1/tile");
+        assert_eq!(client.server_url(), "http://127.0.0.1:8553/v1/tile");
 
         let client = client.with_daylight(Daylight::Day);
         assert_eq!(
-            client.server_url.as_str(),
+            client.server_url(),
             "http://127.0.0.1:8553/v1/tile?daylight=1"
         );
         let client = client.with_daylight(Daylight::Night);
         assert_eq!(
-            client.server_url.as_str(),
+            client.server_url(),
             "http://127.0.0.1:8553/v1/tile?daylight=0"
         );
 
         let client = OsmClient::new(url.clone());
         let client = client.with_scale(Scale::One);
-        assert_eq!(
-            client.server_url.as_str(),
-            "http://127.0.0.1:8553/v1/tile?scale=1"
-        );
+        assert_eq!(client.server_url(), "http://127.0.0.1:8553/v1/tile?scale=1");
         let client = client.with_scale(Scale::Two);
-        assert_eq!(
-            client.server_url.as_str(),
-            "http://127.0.0.1:8553/v1/tile?scale=2"
-        );
+        assert_eq!(client.server_url(), "http://127.0.0.1:8553/v1/tile?scale=2");
         let client = client.with_scale(Scale::Four);
-        assert_eq!(
-            client.server_url.as_str(),
-            "http://127.0.0.1:8553/v1/tile?scale=4"
-        );
+        assert_eq!(client.server_url(), "http://127.0.0.1:8553/v1/tile?scale=4");
 
         let client = OsmClient::new(url)
             .with_daylight(Daylight::Day)
             .with_scale(Scale::Four);
         assert_eq!(
-            client.server_url.as_str(),
+            client.server_url(),
             "http://127.0.0.1:8553/v1/tile?daylight=1&scale=4"
         );
     }
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+/// Exponential backoff with jitter
+///
+/// The delay before attempt `n + 1` is `base_delay * 2^(n - 1)`, capped at
+/// `max_delay`, and then randomized to somewhere between half and all of it
+/// so a fleet of clients doesn't retry in lock step.
+/// The delays of a request add up to at most `max_total_delay`, requests
+/// are made from shared worker threads which sit idle while waiting.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// No more attempts once the next delay would take the total past this
+    pub max_total_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::NONE
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, no retries
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        base_delay: Duration::from_millis(0),
+        max_delay: Duration::from_millis(0),
+        max_total_delay: Duration::from_millis(0),
+    };
+
+    pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+    pub const DEFAULT_MAX_TOTAL_DELAY: Duration = Duration::from_secs(2);
+
+    pub fn new(max_attempts: u32, base_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay: Self::DEFAULT_MAX_DELAY.max(base_delay),
+            max_total_delay: Self::DEFAULT_MAX_TOTAL_DELAY,
+        }
+    }
+
+    pub fn with_max_total_delay(mut self, max_total_delay: Duration) -> Self {
+        self.max_total_delay = max_total_delay;
+        self
+    }
+
+    /// Delay before the next attempt after `attempt` (starting at 1) failed,
+    /// `jitter` is in the range [0, 1)
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .base_delay
+            .checked_mul(1 << exp)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        backoff.mul_f64(0.5 + 0.5 * jitter.clamp(0.0, 1.0))
+    }
+
+    /// Randomized delay before the next attempt after `attempt` failed,
+    /// having already waited `waited`. `None` when out of attempts or time.
+    pub(crate) fn next_delay(&self, attempt: u32, waited: Duration) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let delay = self.delay(attempt, rand::random::<f64>());
+        if waited + delay > self.max_total_delay {
+            None
+        } else {
+            Some(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let p = RetryPolicy::new(5, Duration::from_millis(100));
+        assert_eq!(p.delay(1, 1.0), Duration::from_millis(100));
+        assert_eq!(p.delay(2, 1.0), Duration::from_millis(200));
+        assert_eq!(p.delay(3, 1.0), Duration::from_millis(400));
+        assert_eq!(p.delay(3, 0.0), Duration::from_millis(200));
+        assert_eq!(p.delay(3, 0.5), Duration::from_millis(300));
+        assert_eq!(p.delay(10, 1.0), RetryPolicy::DEFAULT_MAX_DELAY);
+        assert_eq!(p.delay(1000, 1.0), RetryPolicy::DEFAULT_MAX_DELAY);
+    }
+
+    #[test]
+    fn total_delay_is_capped() {
+        let p = RetryPolicy::new(10, Duration::from_millis(100))
+            .with_max_total_delay(Duration::from_millis(250));
+        assert!(p.next_delay(1, Duration::from_millis(0)).is_some());
+        // At least 100 ms more
+        assert!(p.next_delay(2, Duration::from_millis(160)).is_none());
+        assert!(p.next_delay(10, Duration::from_millis(0)).is_none());
+        assert_eq!(
+            RetryPolicy::NONE.next_delay(1, Duration::from_millis(0)),
+            None
+        );
+    }
+
+    #[test]
+    fn at_least_one_attempt() {
+        assert_eq!(RetryPolicy::new(0, Duration::from_secs(1)).max_attempts, 1);
+        assert_eq!(RetryPolicy::default().max_attempts, 1);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::Error;
+use common::{Daylight, Scale, TileNumber, Zoom};
+use url::Url;
+
+/// XYZ tile URL template, e.g. `http://{s}.tile.example.com/{z}/{x}/{y}{r}.png`
+///
+/// Supported placeholders:
+/// * `{z}`, `{x}`, `{y}`: tile zoom and numbers, required
+/// * `{s}`: subdomain, picked from the subdomains list by tile number
+/// * `{r}`: retina suffix, empty for scale 1, `@2x` for scale 2, `@4x` for scale 4
+/// * `{scale}`: 1, 2 or 4
+/// * `{daylight}`: 1 for day, 0 for night
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrlTemplate {
+    template: String,
+    subdomains: Vec<String>,
+}
+
+impl UrlTemplate {
+    const REQUIRED: [&'static str; 3] = ["{z}", "{x}", "{y}"];
+
+    pub fn new<S: Into<String>>(template: S) -> Result<Self, Error> {
+        let template = UrlTemplate {
+            template: template.into(),
+            subdomains: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        };
+        for p in Self::REQUIRED.iter() {
+            if !template.template.contains(p) {
+                return Err(Error::TemplatePlaceholder(p));
+            }
+        }
+        // Make sure the expanded template is a valid url
+        let _ = template.expand(0, 0, Zoom::MIN, None, None)?;
+        Ok(template)
+    }
+
+    /// Subdomains substituted for `{s}`, defaults to `a`, `b` and `c`
+    pub fn with_subdomains(mut self, subdomains: Vec<String>) -> Self {
+        if !subdomains.is_empty() {
+            self.subdomains = subdomains;
+        }
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    pub fn expand<T: Into<TileNumber>>(
+        &self,
+        x: T,
+        y: T,
+        zoom: Zoom,
+        scale: Option<Scale>,
+        daylight: Option<Daylight>,
+    ) -> Result<Url, Error> {
+        let (x, y) = (x.into().0, y.into().0);
+        let subdomain = &self.subdomains[(x as usize + y as usize) % self.subdomains.len()];
+        let scale = scale.unwrap_or_default();
+        let retina = match scale {
+            Scale::One => "",
+            Scale::Two => "@2x",
+            Scale::Four => "@4x",
+        };
+        let daylight = daylight.unwrap_or(Daylight::Day).url_query_pair().1;
+        let url = self
+            .template
+            .replace("{z}", &zoom.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string())
+            .replace("{s}", subdomain)
+            .replace("{r}", retina)
+            .replace("{scale}", &scale.to_string())
+            .replace("{daylight}", daylight);
+        Url::parse(&url).map_err(Error::UrlParse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_placeholders() {
+        let t = UrlTemplate::new("http://{s}.example.com/{z}/{x}/{y}{r}.png").unwrap();
+        assert_eq!(
+            t.expand(3, 4, Zoom::new_clamped(5), None, None)
+                .unwrap()
+                .as_str(),
+            "http://b.example.com/5/3/4.png"
+        );
+        assert_eq!(
+            t.expand(3, 3, Zoom::new_clamped(5), Some(Scale::Two), None)
+                .unwrap()
+                .as_str(),
+            "http://a.example.com/5/3/3@2x.png"
+        );
+
+        let t = UrlTemplate::new(
+            "http://localhost:8553/v1/tile?daylight={daylight}&scale={scale}&z={z}&x={x}&y={y}",
+        )
+        .unwrap();
+        assert_eq!(
+            t.expand(
+                1,
+                2,
+                Zoom::new_clamped(11),
+                Some(Scale::Four),
+                Some(Daylight::Night)
+            )
+            .unwrap()
+            .as_str(),
+            "http://localhost:8553/v1/tile?daylight=0&scale=4&z=11&x=1&y=2"
+        );
+    }
+
+    #[test]
+    fn subdomains() {
+        let t = UrlTemplate::new("http://{s}.example.com/{z}/{x}/{y}.png")
+            .unwrap()
+            .with_subdomains(vec!["tiles1".to_string(), "tiles2".to_string()]);
+        let z = Zoom::new_clamped(1);
+        assert_eq!(
+            t.expand(0, 0, z, None, None).unwrap().host_str(),
+            Some("tiles1.example.com")
+        );
+        assert_eq!(
+            t.expand(0, 1, z, None, None).unwrap().host_str(),
+            Some("tiles2.example.com")
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(matches!(
+            UrlTemplate::new("http://localhost:8080/{z}/{x}.png"),
+            Err(Error::TemplatePlaceholder("{y}"))
+        ));
+        assert!(matches!(
+            UrlTemplate::new("{z}/{x}/{y}.png"),
+            Err(Error::UrlParse(_))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::core::{RaylibHandle, RaylibThread};
 use crate::ffi;
+use std::ffi::CString;
 
 impl RaylibHandle {
     /// Setup canvas (framebuffer) to start drawing
@@ -54,6 +55,30 @@ pub trait RaylibDraw {
         }
     }
 
+    /// Draws the `source_rec` part of a `texture` into `dest_rec`, scaled to fit.
+    /// `rotation` is in degrees around `origin`, relative to the top left of `dest_rec`.
+    #[inline]
+    fn draw_texture_pro(
+        &mut self,
+        texture: impl AsRef<ffi::Texture2D>,
+        source_rec: ffi::Rectangle,
+        dest_rec: ffi::Rectangle,
+        origin: ffi::Vector2,
+        rotation: f32,
+        tint: impl Into<ffi::Color>,
+    ) {
+        unsafe {
+            ffi::DrawTexturePro(
+                *texture.as_ref(),
+                source_rec,
+                dest_rec,
+                origin,
+                rotation,
+                tint.into(),
+            );
+        }
+    }
+
     /// Draws a line.
     #[inline]
     fn draw_line(
@@ -95,6 +120,22 @@ pub trait RaylibDraw {
         }
     }
 
+    /// Draws text (using default font).
+    #[inline]
+    fn draw_text(
+        &mut self,
+        text: &str,
+        x: i32,
+        y: i32,
+        font_size: i32,
+        color: impl Into<ffi::Color>,
+    ) {
+        let c_text = CString::new(text).unwrap();
+        unsafe {
+            ffi::DrawText(c_text.as_ptr(), x, y, font_size, color.into());
+        }
+    }
+
     /// Shows current FPS.
     #[inline]
     fn draw_fps(&mut self, x: i32, y: i32) {
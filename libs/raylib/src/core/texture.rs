@@ -70,6 +70,29 @@ impl From<&Pixmap> for Image {
     }
 }
 
+impl Texture2D {
+    /// Replaces the texture pixels (GPU memory) with the pixmap pixels,
+    /// the sizes must match and the texture must be R8G8B8A8, like the
+    /// ones loaded from a `Pixmap` image
+    pub fn update_from_pixmap(&mut self, pixmap: &Pixmap) -> Result<(), Error> {
+        if pixmap.width() as i32 != self.0.width || pixmap.height() as i32 != self.0.height {
+            return Err(Error::TextureSizeMismatch(
+                pixmap.width(),
+                pixmap.height(),
+                self.0.width,
+                self.0.height,
+            ));
+        }
+        if self.0.format != ffi::PixelFormat::PIXELFORMAT_PIXELFORMAT_UNCOMPRESSED_R8G8B8A8 as i32 {
+            return Err(Error::TextureFormat(self.0.format));
+        }
+        unsafe {
+            ffi::UpdateTexture(self.0, pixmap.data().as_ptr() as *const _);
+        }
+        Ok(())
+    }
+}
+
 impl RaylibHandle {
     /// Loads texture from file into GPU memory (VRAM).
     pub fn load_texture(&mut self, _: &RaylibThread, filename: &str) -> Result<Texture2D, Error> {
@@ -4,4 +4,16 @@ use err_derive::Error;
 pub enum Error {
     #[error(display = "Failed to load texture from {}", _0)]
     TextureLoadFromFile(String),
+
+    #[error(
+        display = "Pixmap size {}x{} doesn't match the texture size {}x{}",
+        _0,
+        _1,
+        _2,
+        _3
+    )]
+    TextureSizeMismatch(u32, u32, i32, i32),
+
+    #[error(display = "Texture pixel format {} isn't R8G8B8A8", _0)]
+    TextureFormat(i32),
 }
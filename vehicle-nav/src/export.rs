@@ -0,0 +1,51 @@
+use crate::gpx;
+use crate::opts::ExportOpts;
+use crate::tile_source;
+use config::Config;
+use err_derive::Error;
+use map_tiler::TileDirectory;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "{}", _0)]
+    MapTiler(#[error(source)] map_tiler::Error),
+
+    #[error(display = "{}", _0)]
+    TileSource(#[error(source)] tile_source::Error),
+
+    #[error(display = "{}", _0)]
+    Gpx(#[error(source)] gpx::Error),
+
+    #[error(display = "Missing config item {}", _0)]
+    MissingConfig(&'static str),
+}
+
+pub fn run(config: &Config, opts: &ExportOpts) -> Result<(), Error> {
+    let region = opts.region.tile_region()?;
+    let (min_zoom, max_zoom) = (opts.region.min_zoom, opts.region.max_zoom);
+    let dest = TileDirectory::new(&opts.output);
+    log::info!(
+        "Exporting {} tiles, zoom {}..={} to {}",
+        region.tile_count(min_zoom, max_zoom),
+        min_zoom,
+        max_zoom,
+        dest.root().display()
+    );
+    let summary = if opts.from_cache {
+        let cache =
+            tile_source::cache_from_config(config)?.ok_or(Error::MissingConfig("tiler.cache"))?;
+        // Cached tiles are keyed by the scale and daylight of the source that
+        // fetched them, the source itself isn't needed
+        let (scale, daylight) = tile_source::cache_key_from_config(config);
+        let tiles = cache.tiles(scale, daylight);
+        map_tiler::export_tiles(&tiles, &region, min_zoom, max_zoom, &dest)?
+    } else {
+        let source = tile_source::from_config(config)?;
+        map_tiler::export_tiles(source.as_ref(), &region, min_zoom, max_zoom, &dest)?
+    };
+    println!(
+        "Exported {} tiles ({} bytes), {} missing",
+        summary.exported, summary.bytes, summary.missing
+    );
+    Ok(())
+}
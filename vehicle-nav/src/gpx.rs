@@ -0,0 +1,113 @@
+//! Minimal GPX reader, only the track and route point coordinates are used
+
+use common::{Coordinate, Latitude, Longitude};
+use err_derive::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "IO error")]
+    Io(#[error(source)] io::Error),
+
+    #[error(display = "GPX XML error: {}", _0)]
+    Xml(#[error(source)] xml::reader::Error),
+
+    #[error(display = "Missing or invalid '{}' attribute on a {} element", _0, _1)]
+    InvalidAttribute(&'static str, String),
+
+    #[error(display = "No track or route points found")]
+    Empty,
+}
+
+pub fn read_track<P: AsRef<Path>>(path: P) -> Result<Vec<Coordinate>, Error> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+/// Coordinates of every track (`trkpt`) and route (`rtept`) point, in document order
+pub fn parse<R: Read>(reader: R) -> Result<Vec<Coordinate>, Error> {
+    let mut coords = Vec::new();
+    for event in EventReader::new(reader) {
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = event?
+        {
+            if name.local_name != "trkpt" && name.local_name != "rtept" {
+                continue;
+            }
+            let attr = |attr_name: &'static str| {
+                attributes
+                    .iter()
+                    .find(|a| a.name.local_name == attr_name)
+                    .and_then(|a| a.value.trim().parse::<f64>().ok())
+            };
+            let invalid = |attr_name| Error::InvalidAttribute(attr_name, name.local_name.clone());
+            coords.push(Coordinate {
+                latitude: attr("lat")
+                    .and_then(|lat| Latitude::new(lat).ok())
+                    .ok_or_else(|| invalid("lat"))?,
+                longitude: attr("lon")
+                    .and_then(|lon| Longitude::new(lon).ok())
+                    .ok_or_else(|| invalid("lon"))?,
+            });
+        }
+    }
+    if coords.is_empty() {
+        Err(Error::Empty)
+    } else {
+        Ok(coords)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_track_and_route_points() {
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <wpt lat="1.0" lon="2.0"><name>ignored</name></wpt>
+  <trk><trkseg>
+    <trkpt lat="47.453551" lon="-116.788118"><ele>650.0</ele></trkpt>
+    <trkpt lat="47.453358" lon="-116.787340"/>
+  </trkseg></trk>
+  <rte><rtept lat="47.456655" lon="-116.783225"/></rte>
+</gpx>"#;
+        let coords = parse(gpx.as_bytes()).unwrap();
+        assert_eq!(
+            coords,
+            vec![
+                Coordinate::new(47.453551, -116.788118).unwrap(),
+                Coordinate::new(47.453358, -116.787340).unwrap(),
+                Coordinate::new(47.456655, -116.783225).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_gpx() {
+        assert!(matches!(
+            parse(r#"<gpx><trk><trkseg></trkseg></trk></gpx>"#.as_bytes()),
+            Err(Error::Empty)
+        ));
+        assert!(matches!(
+            parse(r#"<gpx><trk><trkseg><trkpt lat="x" lon="1"/></trkseg></trk></gpx>"#.as_bytes()),
+            Err(Error::InvalidAttribute("lat", _))
+        ));
+        assert!(matches!(
+            parse(r#"<gpx><rte><rtept lat="NaN" lon="1"/></rte></gpx>"#.as_bytes()),
+            Err(Error::InvalidAttribute("lat", _))
+        ));
+        assert!(matches!(
+            parse(r#"<gpx><rte><rtept lat="1" lon="200"/></rte></gpx>"#.as_bytes()),
+            Err(Error::InvalidAttribute("lon", _))
+        ));
+        assert!(matches!(
+            parse(r#"<gpx><trk>"#.as_bytes()),
+            Err(Error::Xml(_))
+        ));
+    }
+}
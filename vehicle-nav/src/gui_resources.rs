@@ -37,6 +37,13 @@ impl GuiResources {
         a: 255,
     };
 
+    pub const READOUT_COLOR: ffi::Color = ffi::Color {
+        r: 0,
+        g: 158,
+        b: 47,
+        a: 255,
+    };
+
     pub fn load(
         rh: &mut RaylibHandle,
         rl_t: &RaylibThread,
@@ -1,11 +1,12 @@
 //#![deny(warnings)]
 
 use crate::gui_resources::GuiResources;
-use crate::map_tile_service::MapTileService;
-use crate::opts::Opts;
+use crate::map_tile_service::{GetTilesResponse, MapTileService};
+use crate::map_view::{CanvasView, MapView};
+use crate::opts::{Command, Opts};
+use crate::pan::{PanDirection, Panner};
 use crate::route_transform_service::RouteTransformService;
-use crate::zoom_delta_map::ZoomDeltaMap;
-use common::Coordinate;
+use common::{geodesy, Coordinate, Heading, MapOrientation, ScreenAnchor, Speed};
 use config::Config;
 use raylib::prelude::*;
 use std::process;
@@ -14,17 +15,24 @@ use std::sync::{
     Arc,
 };
 use structopt::StructOpt;
+use tiny_skia::Pixmap;
 
 //use osm_client::{Daylight, OsmClient, Scale};
 //use map_tiler::{Config as MapTilerConfig, MapTiler};
 //use std::time::Duration;
 
+mod export;
+mod gpx;
 mod gui_resources;
 mod map_tile_service;
+mod map_view;
 mod opts;
+mod pan;
+mod prefetch;
+mod render;
 mod route_transform_service;
 mod thread;
-mod zoom_delta_map;
+mod tile_source;
 
 // links for the README
 // https://wiki.openstreetmap.org/wiki/Slippy_map_tilenames
@@ -36,9 +44,7 @@ mod zoom_delta_map;
 // - config crate, toml file
 //   * use the newtypes from the other crates for basic sanity checking
 //   * max_rendered_route_waypoints/lines
-//   * add client timeout
 //   * line color(s)
-// - cli opts accept env vars
 // - rm/cleanup all the log::debug's
 //
 // - need to tune/revisit the bounded channel sizes/capacity, add config items
@@ -85,6 +91,12 @@ mod zoom_delta_map;
 // probably knows about zoom, tolerance filter to reduce nearby points, omit offscreen points, etc
 // support multiple routes, RouteId, colored/etc
 
+/// Zoom levels per mouse wheel notch
+const WHEEL_ZOOM_STEP: f64 = 0.5;
+
+/// Seconds between the mock route fixes, for the vehicle speed
+const ROUTE_FIX_INTERVAL_SECS: f64 = 10.0;
+
 fn main() {
     match do_main() {
         Ok(()) => (),
@@ -106,6 +118,22 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    let layered = Config::load_layered(&opts.config_sources())?;
+    if opts.print_config {
+        print!("{}", layered.annotated());
+        return Ok(());
+    }
+    let config = layered.config;
+
+    if let Some(cmd) = &opts.command {
+        match cmd {
+            Command::Export(export_opts) => export::run(&config, export_opts)?,
+            Command::Prefetch(prefetch_opts) => prefetch::run(&config, prefetch_opts)?,
+            Command::Render(render_opts) => render::run(&config, render_opts)?,
+        }
+        return Ok(());
+    }
+
     let running = Arc::new(AtomicUsize::new(0));
     let r = running.clone();
     ctrlc::set_handler(move || {
@@ -118,13 +146,11 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })?;
 
-    let config = Config::load(&opts.config)?;
-
-    let zoom_delta_map = ZoomDeltaMap::new(&config);
+    let panner = Panner::new(&config);
 
     let (map_client, map_shutdown_handle) = MapTileService::start(config.clone())?;
     let (route_transform_client, route_transform_shutdown_handle) =
-        RouteTransformService::start(config.clone())?;
+        RouteTransformService::start(config.clone(), map_client.tile_size())?;
 
     let screen_width = config.window.width.into();
     let screen_height = config.window.height.into();
@@ -136,92 +162,306 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         .build();
 
     rl.set_target_fps(config.window.target_fps.into());
-    // rl.get_frame_time()
 
-    let mut zoom = config.startup_defaults.zoom;
-    let mut center_coord = Coordinate::from((
-        config.startup_defaults.latitude,
-        config.startup_defaults.longitude,
-    ));
+    let mut view = MapView::new(
+        Coordinate::from((
+            config.startup_defaults.latitude,
+            config.startup_defaults.longitude,
+        )),
+        config.startup_defaults.zoom,
+        map_client.tile_size(),
+        screen_width as u32,
+        screen_height as u32,
+    );
     let mut resources = GuiResources::load(&mut rl, &rl_t)?;
 
     // these would come from the sensor service
-    let route_coords: Vec<Coordinate> = vec![
-        Coordinate::new(47.453551, -116.788118),
-        Coordinate::new(47.453358, -116.787340),
-        Coordinate::new(47.454036, -116.787275),
-        Coordinate::new(47.454054, -116.787093),
-        Coordinate::new(47.453927, -116.786878),
-        Coordinate::new(47.453561, -116.786750),
-        Coordinate::new(47.454326, -116.786530),
-        Coordinate::new(47.454243, -116.785156),
-        Coordinate::new(47.455712, -116.784239),
-        Coordinate::new(47.456655, -116.783225),
-    ];
+    let route_coords: Vec<Coordinate> = [
+        (47.453551, -116.788118),
+        (47.453358, -116.787340),
+        (47.454036, -116.787275),
+        (47.454054, -116.787093),
+        (47.453927, -116.786878),
+        (47.453561, -116.786750),
+        (47.454326, -116.786530),
+        (47.454243, -116.785156),
+        (47.455712, -116.784239),
+        (47.456655, -116.783225),
+    ]
+    .iter()
+    .map(|(lat, lon)| Coordinate::new(*lat, *lon).unwrap())
+    .collect();
 
     // TODO - manage this somewhere
     let mut route_points: Vec<ffi::Vector2> = Vec::with_capacity(route_coords.len());
 
+    // Latest position and direction of travel
+    let vehicle = route_coords.last().copied();
+    let vehicle_heading = match route_coords.as_slice() {
+        [.., from, to] => geodesy::initial_bearing(from, to),
+        _ => 0.0,
+    };
+    let vehicle_speed = match route_coords.as_slice() {
+        [.., from, to] => Speed::from_meters_per_second(
+            geodesy::haversine_distance(from, to) / ROUTE_FIX_INTERVAL_SECS,
+        ),
+        _ => Speed::ZERO,
+    };
+    let mut orientation = config.startup_defaults.orientation;
+    // Following keeps the vehicle at the follow anchor, heading-up always follows
+    let mut following = orientation == MapOrientation::HeadingUp;
+
     for c in route_coords.into_iter() {
         route_transform_client.push_coordinate(c)?;
     }
 
+    let mut map_generation = None;
+    // View of the latest map request
+    let mut map_request_view: Option<CanvasView> = None;
+    let mut map_canvas =
+        Pixmap::new(screen_width as u32, screen_height as u32).ok_or("Invalid window size")?;
+    // Generation of the view currently in the canvas, and of the last
+    // complete map drawn into it
+    let mut canvas_generation = None;
+    let mut complete_generation = None;
+    // View the canvas was rendered for, it's scaled and moved to match
+    // the view until the next map arrives
+    let mut canvas_view: Option<CanvasView> = None;
+    let mut map_canvas_dirty = false;
+
     loop {
         let should_close = running.load(Ordering::SeqCst) != 0 || rl.window_should_close();
         if should_close {
             break;
         }
 
-        // coord shift is a function of zoom, constant distince in pixels,
-        // put that dist in the config
-        if rl.is_key_pressed(ffi::KeyboardKey::KEY_M) {
-            map_client.request(center_coord, zoom)?;
+        // A new map is requested once the view settles, in between the
+        // current one is scaled
+        let mut request_map = view.update(rl.get_frame_time());
+
+        if rl.is_key_pressed(ffi::KeyboardKey::KEY_H) {
+            orientation = orientation.toggled();
+            log::debug!("Map orientation {}", orientation);
+            // Heading-up rotates around the vehicle
+            following |= orientation == MapOrientation::HeadingUp;
+            request_map = true;
         }
-        if rl.is_key_pressed(ffi::KeyboardKey::KEY_UP) {
-            let (d_lat, _) = zoom_delta_map.get(zoom);
-            center_coord.latitude.saturating_add(d_lat);
-            map_client.request(center_coord, zoom)?;
+        if rl.is_key_pressed(ffi::KeyboardKey::KEY_F) {
+            following = !following || orientation == MapOrientation::HeadingUp;
+            log::debug!("Following {}", following);
+            request_map = true;
         }
-        if rl.is_key_pressed(ffi::KeyboardKey::KEY_DOWN) {
-            let (d_lat, _) = zoom_delta_map.get(zoom);
-            center_coord.latitude.saturating_sub(d_lat);
-            map_client.request(center_coord, zoom)?;
+        view.set_rotation(orientation.rotation(vehicle_heading));
+
+        // Panning moves the view away from the vehicle, heading-up always follows it
+        let pan_keys = [
+            (ffi::KeyboardKey::KEY_UP, PanDirection::Up),
+            (ffi::KeyboardKey::KEY_DOWN, PanDirection::Down),
+            (ffi::KeyboardKey::KEY_LEFT, PanDirection::Left),
+            (ffi::KeyboardKey::KEY_RIGHT, PanDirection::Right),
+        ];
+        for (key, direction) in pan_keys.iter() {
+            if !rl.is_key_pressed(*key) {
+                continue;
+            }
+            if orientation == MapOrientation::HeadingUp {
+                log::debug!("Not panning heading-up, the map follows the vehicle");
+                continue;
+            }
+            following = false;
+            panner.pan(&mut view, *direction);
+            request_map = true;
         }
-        if rl.is_key_pressed(ffi::KeyboardKey::KEY_RIGHT) {
-            let (_, d_lon) = zoom_delta_map.get(zoom);
-            center_coord.longitude.saturating_add(d_lon);
-            map_client.request(center_coord, zoom)?;
+
+        match (following, vehicle) {
+            (true, Some(vehicle)) => {
+                view.follow(vehicle);
+                // Direction of travel on the screen, clockwise from the top
+                let screen_heading = vehicle_heading + view.rotation();
+                view.set_anchor(map_view::follow_anchor(
+                    &config.follow,
+                    screen_heading,
+                    vehicle_speed.meters_per_second(),
+                    screen_width as u32,
+                    screen_height as u32,
+                ));
+            }
+            _ => view.set_anchor(ScreenAnchor::CENTER),
         }
-        if rl.is_key_pressed(ffi::KeyboardKey::KEY_LEFT) {
-            let (_, d_lon) = zoom_delta_map.get(zoom);
-            center_coord.longitude.saturating_sub(d_lon);
-            map_client.request(center_coord, zoom)?;
+
+        if rl.is_key_pressed(ffi::KeyboardKey::KEY_M) {
+            request_map = true;
         }
         if rl.is_key_pressed(ffi::KeyboardKey::KEY_I) {
-            zoom.increment();
-            map_client.request(center_coord, zoom)?;
+            let mut zoom = view.target_zoom();
+            zoom.saturating_add(1.0);
+            view.zoom_to(zoom, None);
         }
         if rl.is_key_pressed(ffi::KeyboardKey::KEY_O) {
-            zoom.decrement();
-            map_client.request(center_coord, zoom)?;
+            let mut zoom = view.target_zoom();
+            zoom.saturating_sub(1.0);
+            view.zoom_to(zoom, None);
+        }
+        let wheel = rl.get_mouse_wheel_move();
+        if wheel != 0.0 {
+            // Zooms in on the map under the cursor, unless the view is
+            // pinned to the vehicle
+            let mut zoom = view.target_zoom();
+            zoom.saturating_add(f64::from(wheel) * WHEEL_ZOOM_STEP);
+            let cursor = (f64::from(rl.get_mouse_x()), f64::from(rl.get_mouse_y()));
+            view.zoom_to(zoom, if following { None } else { Some(cursor) });
         }
 
-        if let Some(map_pixmap) = map_client.try_recv()? {
-            log::debug!("Got pixmap");
-
-            route_points.clear();
-            route_transform_client.get_route(center_coord, zoom)?;
+        if request_map {
+            let request_view = CanvasView {
+                center: view.center(),
+                zoom: view.zoom(),
+                anchor: view.anchor(),
+                orientation,
+            };
+            map_generation = Some(map_client.request(
+                request_view.center,
+                request_view.zoom,
+                request_view.orientation,
+                request_view.anchor,
+            )?);
+            map_request_view = Some(request_view);
+        }
 
-            let mut map_image = Image::from(&map_pixmap);
-            map_image.resize(screen_width, screen_height);
-            let _ = resources
-                .map_texture
-                .replace(rl.load_texture_from_image(&rl_t, &map_image)?);
+        // Tiles are composited into the canvas as they arrive, the texture
+        // is updated at most once per frame
+        while let Some(map_resp) = map_client.try_recv()? {
+            // Responses to anything but the latest request are for a view that's gone
+            let generation = map_resp.generation();
+            if Some(generation) != map_generation {
+                log::debug!("Discarding stale map generation {}", generation.0);
+                continue;
+            }
+            if Some(generation) != canvas_generation {
+                // First response for a new view, the canvas is reused when
+                // it holds the complete map the new one is shifted from
+                canvas_generation = Some(generation);
+                match &map_resp {
+                    GetTilesResponse::Shift(shift)
+                        if Some(shift.previous) == complete_generation =>
+                    {
+                        map_tiler::shift_pixmap(&mut map_canvas, shift.dx, shift.dy)
+                    }
+                    _ => {
+                        // Heading-up maps are rendered big enough to cover
+                        // the screen at any rotation around the anchor, and
+                        // maps between whole zoom levels at the nearest one
+                        let (width, height) = match map_request_view {
+                            Some(request) => {
+                                let viewport = request
+                                    .tile_grid(
+                                        screen_width as u32,
+                                        screen_height as u32,
+                                        map_client.tile_size(),
+                                    )
+                                    .viewport();
+                                (viewport.width, viewport.height)
+                            }
+                            None => (screen_width as u32, screen_height as u32),
+                        };
+                        // The previous map stays under the incoming tiles
+                        // until the complete map replaces it
+                        let mut canvas = Pixmap::new(width, height).ok_or("Invalid map size")?;
+                        if let (Some(from), Some(to)) = (canvas_view, map_request_view) {
+                            let p = view.canvas_placement(
+                                &from,
+                                (map_canvas.width(), map_canvas.height()),
+                                &to,
+                                (width, height),
+                            );
+                            canvas.draw_pixmap(
+                                0,
+                                0,
+                                map_canvas.as_ref(),
+                                &tiny_skia::PixmapPaint {
+                                    quality: tiny_skia::FilterQuality::Bilinear,
+                                    ..tiny_skia::PixmapPaint::default()
+                                },
+                                tiny_skia::Transform::from_row(
+                                    p.scale as f32,
+                                    0.0,
+                                    0.0,
+                                    p.scale as f32,
+                                    p.x as f32,
+                                    p.y as f32,
+                                ),
+                                None,
+                            );
+                        }
+                        map_canvas = canvas;
+                    }
+                }
+                complete_generation = None;
+                canvas_view = map_request_view;
+                route_points.clear();
+                if let Some(canvas) = canvas_view {
+                    route_transform_client.get_route(canvas)?;
+                }
+            }
+            match map_resp {
+                // Already applied above
+                GetTilesResponse::Shift(_) => (),
+                GetTilesResponse::Tile(tile) => {
+                    if tile.failed {
+                        log::debug!("Tile at ({}, {}) failed to load", tile.x, tile.y);
+                    } else if let Some(substitution) = tile.substitution {
+                        log::debug!(
+                            "Tile at ({}, {}) substituted with {:?}",
+                            tile.x,
+                            tile.y,
+                            substitution
+                        );
+                    }
+                    map_tiler::copy_pixmap(&mut map_canvas, tile.x, tile.y, &tile.image);
+                }
+                GetTilesResponse::Map(map) => {
+                    log::debug!("Got pixmap");
+                    if !map.failed_tiles.is_empty() {
+                        log::warn!(
+                            "Map rendered with {} failed tile(s)",
+                            map.failed_tiles.len()
+                        );
+                    }
+                    if !map.substituted_tiles.is_empty() {
+                        log::debug!(
+                            "Map rendered with {} substituted tile(s)",
+                            map.substituted_tiles.len()
+                        );
+                    }
+                    map_canvas = map.image;
+                    complete_generation = Some(generation);
+                }
+            }
+            map_canvas_dirty = true;
+        }
+        if map_canvas_dirty {
+            map_canvas_dirty = false;
+            let size = (map_canvas.width() as i32, map_canvas.height() as i32);
+            match resources.map_texture.as_mut() {
+                Some(map_texture) if (map_texture.width, map_texture.height) == size => {
+                    map_texture.update_from_pixmap(&map_canvas)?
+                }
+                _ => {
+                    let map_image = Image::from(&map_canvas);
+                    resources.map_texture = Some(rl.load_texture_from_image(&rl_t, &map_image)?);
+                }
+            }
         }
 
-        if let Some(mut route) = route_transform_client.try_recv()? {
+        // Like the map responses, routes for anything but the current canvas
+        // would be drawn on the wrong map image
+        while let Some(route) = route_transform_client.try_recv()? {
+            if Some(route.map_view) != canvas_view {
+                log::debug!("Discarding route for a stale map view");
+                continue;
+            }
             log::debug!("Got route len={}", route.route_chunk.len());
-            route_points = route.route_chunk.drain(..).collect();
+            route_points = route.route_chunk;
         }
 
         let mut dh = rl.begin_drawing(&rl_t);
@@ -229,27 +469,91 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         // TODO - consider clearing ealier on, route stuff gets messed up on quick changes
         dh.clear_background(GuiResources::BG_COLOR);
 
-        let foreground_texture = match &resources.map_texture {
-            Some(map_texture) => map_texture,
-            None => &resources.background_texture,
+        // The map image and the route points on its tiles are placed the same way
+        let placement = match (&resources.map_texture, canvas_view) {
+            (Some(map_texture), Some(canvas)) => Some(view.image_placement(
+                &canvas,
+                (map_texture.width as u32, map_texture.height as u32),
+            )),
+            _ => None,
         };
-
-        dh.draw_texture(
-            foreground_texture,
-            screen_width / 2 - foreground_texture.width / 2,
-            screen_height / 2 - foreground_texture.height / 2,
-            GuiResources::MAP_TEXTURE_COLOR,
-        );
+        match (&resources.map_texture, placement) {
+            (Some(map_texture), Some(p)) => {
+                let (x, y) = p.pivot;
+                let (width, height) = (map_texture.width as f32, map_texture.height as f32);
+                let scale = p.scale as f32;
+                dh.draw_texture_pro(
+                    map_texture,
+                    ffi::Rectangle {
+                        x: 0.0,
+                        y: 0.0,
+                        width,
+                        height,
+                    },
+                    ffi::Rectangle {
+                        x: x as f32,
+                        y: y as f32,
+                        width: width * scale,
+                        height: height * scale,
+                    },
+                    ffi::Vector2 {
+                        x: width * scale / 2.0,
+                        y: height * scale / 2.0,
+                    },
+                    p.rotation as f32,
+                    GuiResources::MAP_TEXTURE_COLOR,
+                );
+            }
+            _ => {
+                let texture = &resources.background_texture;
+                dh.draw_texture(
+                    texture,
+                    screen_width / 2 - texture.width / 2,
+                    screen_height / 2 - texture.height / 2,
+                    GuiResources::MAP_TEXTURE_COLOR,
+                );
+            }
+        }
 
         // works but no line thickness
         //dh.draw_line_strip(&route_points, ROUTE_COLOR);
 
+        let place = |v: ffi::Vector2| match placement {
+            Some(p) => {
+                let (x, y) = p.apply(v.x.into(), v.y.into());
+                ffi::Vector2 {
+                    x: x as f32,
+                    y: y as f32,
+                }
+            }
+            None => v,
+        };
+
         // line size should be a function of zoom
         for pair in route_points.windows(2) {
-            dh.draw_line_ex(pair[0], pair[1], 2.0, GuiResources::ROUTE_COLOR);
+            dh.draw_line_ex(
+                place(pair[0]),
+                place(pair[1]),
+                2.0,
+                GuiResources::ROUTE_COLOR,
+            );
         }
 
         dh.draw_fps(25, 25);
+
+        // Readouts are in the configured units
+        let readout = format!(
+            "{}  {}",
+            vehicle_speed.display(config.units.system),
+            Heading::new(vehicle_heading)
+        );
+        dh.draw_text(
+            &readout,
+            25,
+            screen_height - 45,
+            20,
+            GuiResources::READOUT_COLOR,
+        );
     }
 
     map_shutdown_handle.blocking_shutdown()?;
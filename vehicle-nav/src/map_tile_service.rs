@@ -1,11 +1,17 @@
 use crate::thread::{SendRecvError, ShutdownHandle, ShutdownHandlingThread};
-use common::{Coordinate, Scale, Zoom};
+use crate::tile_source;
+use common::{Coordinate, FractionalZoom, MapOrientation, ScreenAnchor};
 use config::Config;
-use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError, TrySendError};
 use err_derive::Error;
-use map_tiler::{Config as MapTilerConfig, MapTiler};
-use osm_client::OsmClient;
+use map_tiler::{
+    Config as MapTilerConfig, MapTiler, SubstitutedTile, TileFailure, TileSubstitution,
+};
 use std::io;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use tiny_skia::Pixmap;
 
 // https://docs.rs/crossbeam/0.8.0/crossbeam/channel/index.html
@@ -20,20 +26,82 @@ pub enum Error {
     #[error(display = "{}", _0)]
     MapTilerError(#[error(source)] map_tiler::Error),
 
+    #[error(display = "{}", _0)]
+    TileSource(#[error(source)] tile_source::Error),
+
     #[error(display = "{}", _0)]
     SendRecv(#[error(source)] SendRecvError),
 }
 
+/// Identifies a request and its response, increases with every request
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Generation(pub u64);
+
 #[derive(Debug)]
 pub struct GetTilesRequest {
+    pub generation: Generation,
     pub center: Coordinate,
-    pub zoom: Zoom,
+    pub zoom: FractionalZoom,
+    /// Heading-up maps are rendered big enough to be rotated
+    pub orientation: MapOrientation,
+    /// Where `center` goes on the screen
+    pub anchor: ScreenAnchor,
 }
 
 // TODO image: Image, once it has Send
+/// When the new view overlaps the previous one, a `Shift` response comes
+/// first and only the tiles coming into view are streamed, otherwise every
+/// tile of a request is streamed as a `Tile` response as soon as it's ready.
+/// Either way a single `Map` response with the complete image comes last.
+#[derive(Debug)]
+pub enum GetTilesResponse {
+    Shift(ShiftResponse),
+    Tile(TileResponse),
+    Map(MapResponse),
+}
+
+impl GetTilesResponse {
+    /// Generation of the request this is a response to
+    pub fn generation(&self) -> Generation {
+        match self {
+            GetTilesResponse::Shift(s) => s.generation,
+            GetTilesResponse::Tile(t) => t.generation,
+            GetTilesResponse::Map(m) => m.generation,
+        }
+    }
+}
+
+/// The map image of generation `previous` moved by (`dx`, `dy`) pixels,
+/// positive is right and down
+#[derive(Debug)]
+pub struct ShiftResponse {
+    pub generation: Generation,
+    pub previous: Generation,
+    pub dx: i32,
+    pub dy: i32,
+}
+
+#[derive(Debug)]
+pub struct TileResponse {
+    pub generation: Generation,
+    /// Map image pixel offset of the tile's top left corner, can be negative
+    pub x: i32,
+    pub y: i32,
+    pub image: Pixmap,
+    /// The tile failed to load, `image` is the placeholder pattern
+    pub failed: bool,
+    /// The tile failed to load, `image` is made from other tiles
+    pub substitution: Option<TileSubstitution>,
+}
+
 #[derive(Debug)]
-pub struct GetTilesResponse {
+pub struct MapResponse {
+    pub generation: Generation,
     pub image: Pixmap,
+    /// Tiles drawn with the placeholder pattern
+    pub failed_tiles: Vec<TileFailure>,
+    /// Tiles drawn with a substitute made from other tiles
+    pub substituted_tiles: Vec<SubstitutedTile>,
 }
 
 // TODO MapTileServiceConfigClient or just tack on some Option fields in the request
@@ -41,33 +109,62 @@ pub struct GetTilesResponse {
 pub struct MapTileServiceClient {
     req_sender: Sender<GetTilesRequest>,
     resp_recvr: Receiver<GetTilesResponse>,
+    next_generation: Arc<AtomicU64>,
+    tile_size: u32,
 }
 
 impl MapTileServiceClient {
-    fn new(req_sender: Sender<GetTilesRequest>, resp_recvr: Receiver<GetTilesResponse>) -> Self {
+    fn new(
+        req_sender: Sender<GetTilesRequest>,
+        resp_recvr: Receiver<GetTilesResponse>,
+        tile_size: u32,
+    ) -> Self {
         MapTileServiceClient {
             req_sender,
             resp_recvr,
+            next_generation: Arc::new(AtomicU64::new(0)),
+            tile_size,
         }
     }
 
+    /// Size of the tiles of the configured source, in pixels
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+    }
+
+    /// Returns the generation of the request, responses to older requests are stale
     // TODO consider the try_send with timeout
-    pub fn request(&self, center: Coordinate, zoom: Zoom) -> Result<(), Error> {
+    pub fn request(
+        &self,
+        center: Coordinate,
+        zoom: FractionalZoom,
+        orientation: MapOrientation,
+        anchor: ScreenAnchor,
+    ) -> Result<Generation, Error> {
+        let generation = Generation(self.next_generation.fetch_add(1, Ordering::SeqCst));
         log::debug!(
-            "Request tiles {}, {}, {}",
+            "Request tiles {}, {}, {}, {}, generation {}",
             center.latitude,
             center.longitude,
-            zoom
+            zoom,
+            orientation,
+            generation.0
         );
         self.req_sender
-            .send(GetTilesRequest { center, zoom })
+            .send(GetTilesRequest {
+                generation,
+                center,
+                zoom,
+                orientation,
+                anchor,
+            })
             .map_err(SendRecvError::from)?;
-        Ok(())
+        Ok(generation)
     }
 
-    pub fn try_recv(&self) -> Result<Option<Pixmap>, Error> {
+    pub fn try_recv(&self) -> Result<Option<GetTilesResponse>, Error> {
         match self.resp_recvr.try_recv() {
-            Ok(resp) => Ok(Some(resp.image)),
+            Ok(resp) => Ok(Some(resp)),
             Err(e) => match e {
                 TryRecvError::Empty => Ok(None),
                 TryRecvError::Disconnected => Err(SendRecvError::RecvChannelDisconnected.into()),
@@ -80,50 +177,112 @@ impl MapTileServiceClient {
 pub struct MapTileService {
     map_tiler: MapTiler,
     resp_sender: Sender<GetTilesResponse>,
+    /// Generation of the last rendered map
+    rendered: Option<Generation>,
 }
 
 impl MapTileService {
     fn new(config: Config, resp_sender: Sender<GetTilesResponse>) -> Result<Self, Error> {
-        let mut client = OsmClient::new(config.tiler.url);
-        if config.tiler.support_daynight {
-            client.set_daylight(config.startup_defaults.daynight);
-        }
-        if let Some(scale) = config.tiler.scale {
-            client.set_scale(scale);
-        }
-        let tile_size = config
+        let source = tile_source::from_config(&config)?;
+        let tile_size = tile_source::tile_size(source.as_ref());
+        let memory_cache_tiles = config
             .tiler
-            .scale
-            .map(|s| s.tile_size())
-            .unwrap_or_else(|| Scale::default().tile_size());
-        let map_tiler = MapTiler::new(
-            client,
+            .memory_cache_mb
+            .map(|mb| {
+                MapTilerConfig::tiles_for_memory_budget(tile_size, mb.saturating_mul(1024 * 1024))
+            })
+            .unwrap_or(0);
+        let mut map_tiler = MapTiler::new(
+            source,
             MapTilerConfig {
                 width: config.window.width.into(),
                 height: config.window.height.into(),
                 tile_size,
+                memory_cache_tiles,
+                placeholder: config.tiler.placeholder,
+                fallback_parent_levels: config.tiler.fallback_parent_levels.unwrap_or(0),
             },
         )?;
+        map_tiler.set_cache(tile_source::cache_from_config(&config)?);
+        // Maps between whole zoom levels are scaled when they're drawn
+        map_tiler.set_unscaled(true);
         Ok(MapTileService {
             map_tiler,
             resp_sender,
+            rendered: None,
         })
     }
 
+    /// Room for the requests queued up while a map is being rendered,
+    /// they're coalesced into a single render
+    const REQUEST_CHANNEL_CAPACITY: usize = 16;
+
+    /// The main loop drains the streamed tiles once per frame
+    const RESPONSE_CHANNEL_CAPACITY: usize = 16;
+
     pub fn start(config: Config) -> Result<(MapTileServiceClient, ShutdownHandle), Error> {
-        let (tile_req_sender, tile_req_recvr) = channel::bounded(2);
-        let (tile_resp_sender, tile_resp_recvr) = channel::bounded(2);
+        let (tile_req_sender, tile_req_recvr) = channel::bounded(Self::REQUEST_CHANNEL_CAPACITY);
+        let (tile_resp_sender, tile_resp_recvr) = channel::bounded(Self::RESPONSE_CHANNEL_CAPACITY);
         let service = MapTileService::new(config, tile_resp_sender)?;
+        let tile_size = service.map_tiler.config().tile_size;
         let shutdown_handle = service.spawn("MapTileService".to_string(), tile_req_recvr)?;
         Ok((
-            MapTileServiceClient::new(tile_req_sender, tile_resp_recvr),
+            MapTileServiceClient::new(tile_req_sender, tile_resp_recvr, tile_size),
             shutdown_handle,
         ))
     }
 
     fn process_tile_request(&mut self, req: GetTilesRequest) -> Result<GetTilesResponse, Error> {
-        let image = self.map_tiler.request_tiles(req.center, req.zoom)?.clone();
-        Ok(GetTilesResponse { image })
+        let resp_sender = &self.resp_sender;
+        let generation = req.generation;
+        self.map_tiler
+            .set_rotatable(req.orientation == MapOrientation::HeadingUp);
+        self.map_tiler.set_anchor(req.anchor);
+        let shift = self.map_tiler.view_shift(req.center, req.zoom);
+        if let (Some((dx, dy)), Some(previous)) = (shift, self.rendered) {
+            // Blocks unlike the tiles, they're drawn on top of the shifted
+            // image. A disconnected channel is dealt with when the map
+            // response is sent
+            let _ = resp_sender.send(GetTilesResponse::Shift(ShiftResponse {
+                generation,
+                previous,
+                dx,
+                dy,
+            }));
+        }
+        // Whatever happens next, the tiler's image is no longer the last response's
+        self.rendered = None;
+        let map = self
+            .map_tiler
+            .request_tiles_with(req.center, req.zoom, |tile| {
+                // Never block the fetching threads on a slow consumer, a dropped
+                // tile still shows up in the map response. A disconnected
+                // channel is dealt with when the map response is sent
+                let resp = GetTilesResponse::Tile(TileResponse {
+                    generation,
+                    x: tile.x,
+                    y: tile.y,
+                    image: tile.image.clone(),
+                    failed: tile.failed,
+                    substitution: tile.substitution,
+                });
+                if let Err(TrySendError::Full(_)) = resp_sender.try_send(resp) {
+                    log::debug!(
+                        "Response channel full, dropped streamed tile {:?}",
+                        tile.key
+                    );
+                }
+            })?;
+        self.rendered = Some(generation);
+        if map.reused_tiles != 0 {
+            log::debug!("Reused {} tile(s) of the previous map", map.reused_tiles);
+        }
+        Ok(GetTilesResponse::Map(MapResponse {
+            generation,
+            image: map.image.clone(),
+            failed_tiles: map.failed_tiles,
+            substituted_tiles: map.substituted_tiles,
+        }))
     }
 }
 
@@ -133,16 +292,56 @@ impl ShutdownHandlingThread for MapTileService {
     type ShutdownError = Error;
 
     fn handle_requests(&mut self, requests: Vec<Self::Msg>) -> Result<(), Self::ShutdownError> {
-        for req in requests.into_iter() {
-            // TODO - consider revising this to not shutdown on all errors, some things are
-            // tolerable
-            // - server timeout/not up yet stuff is ok, just retry
-            // - put a result in the response
-            let resp = self.process_tile_request(req)?;
-            self.resp_sender
-                .send(resp)
-                .map_err(|_| SendRecvError::SendChannelDisconnected)?;
+        // Latest wins, anything older is already stale
+        let num_requests = requests.len();
+        let req = match latest_request(requests) {
+            Some(req) => req,
+            None => return Ok(()),
+        };
+        if num_requests > 1 {
+            log::debug!(
+                "Skipping {} stale tile request(s), rendering generation {}",
+                num_requests - 1,
+                req.generation.0
+            );
         }
+        // Individual tile failures are reported in the response, anything else
+        // is logged and the service keeps going, only a disconnected
+        // response channel shuts it down
+        let resp = match self.process_tile_request(req) {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::error!("Failed to process tile request: {}", e);
+                return Ok(());
+            }
+        };
+        self.resp_sender
+            .send(resp)
+            .map_err(|_| SendRecvError::SendChannelDisconnected)?;
         Ok(())
     }
 }
+
+fn latest_request(requests: Vec<GetTilesRequest>) -> Option<GetTilesRequest> {
+    requests.into_iter().max_by_key(|req| req.generation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_request_wins() {
+        let req = |generation: u64, zoom: f64| GetTilesRequest {
+            generation: Generation(generation),
+            center: Coordinate::new(47.453551, -116.788118).unwrap(),
+            zoom: FractionalZoom::new_clamped(zoom),
+            orientation: MapOrientation::NorthUp,
+            anchor: ScreenAnchor::CENTER,
+        };
+        assert!(latest_request(Vec::new()).is_none());
+        let latest = latest_request(vec![req(3, 10.0), req(5, 12.5), req(4, 11.0)]).unwrap();
+        assert_eq!(latest.generation, Generation(5));
+        assert_eq!(latest.zoom, FractionalZoom::new_clamped(12.5));
+    }
+}
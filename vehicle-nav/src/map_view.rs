@@ -0,0 +1,567 @@
+use common::{
+    util::*, Coordinate, FractionalZoom, MapOrientation, ScreenAnchor, TileGrid, Viewport,
+};
+use config::Follow;
+
+/// How long a zoom change is animated for, in seconds
+const ZOOM_DURATION: f32 = 0.25;
+
+/// Center, zoom and rotation of the map on screen, zoom changes are
+/// animated over a few frames. The center is at the anchor, the middle of
+/// the screen unless following the vehicle.
+#[derive(Debug, Clone)]
+pub struct MapView {
+    center: Coordinate,
+    zoom: FractionalZoom,
+    anchor: ScreenAnchor,
+    /// Degrees clockwise around the anchor
+    rotation: f64,
+    tile_size: u32,
+    width: u32,
+    height: u32,
+    animation: Option<ZoomAnimation>,
+}
+
+#[derive(Debug, Clone)]
+struct ZoomAnimation {
+    from: FractionalZoom,
+    to: FractionalZoom,
+    /// Stays at the same screen position while zooming
+    anchor: Option<ZoomAnchor>,
+    elapsed: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ZoomAnchor {
+    coord: Coordinate,
+    /// Unrotated screen pixels from the view's anchor
+    offset: (f64, f64),
+}
+
+/// What a map image was rendered for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CanvasView {
+    pub center: Coordinate,
+    pub zoom: FractionalZoom,
+    pub anchor: ScreenAnchor,
+    pub orientation: MapOrientation,
+}
+
+impl CanvasView {
+    /// Screen position the middle of the image is drawn at. Screen sized
+    /// images go around the screen center, heading-up ones, which can be
+    /// larger than the screen, around the anchor they were rendered for.
+    pub fn image_middle(&self, width: u32, height: u32) -> (f64, f64) {
+        match self.orientation {
+            MapOrientation::HeadingUp => self.anchor.to_pixels(width, height),
+            MapOrientation::NorthUp => (f64::from(width) / 2.0, f64::from(height) / 2.0),
+        }
+    }
+
+    /// Where the tiles go in the image rendered for this view on a `width` x
+    /// `height` screen, the grid the map service draws them on. Heading-up
+    /// images are squares that cover the screen at any rotation, with the
+    /// center in the middle.
+    pub fn tile_grid(&self, width: u32, height: u32, tile_size: u32) -> TileGrid {
+        let (viewport, anchor) = match self.orientation {
+            MapOrientation::HeadingUp => {
+                let size = map_tiler::Config::rotated_size(width, height, self.anchor);
+                let viewport = Viewport::new(size, size, tile_size);
+                (viewport, viewport.middle())
+            }
+            MapOrientation::NorthUp => (
+                Viewport::new(width, height, tile_size),
+                self.anchor.to_pixels(width, height),
+            ),
+        };
+        TileGrid::for_view(viewport, self.zoom, &self.center, anchor)
+    }
+}
+
+/// Where an image rendered for one view goes on the screen showing another,
+/// image pixel (u, v) goes to (`x + u * scale`, `y + v * scale`), which is then
+/// rotated clockwise by `rotation` degrees around `pivot`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Placement {
+    pub x: f64,
+    pub y: f64,
+    pub scale: f64,
+    pub rotation: f64,
+    pub pivot: (f64, f64),
+}
+
+impl Placement {
+    pub fn apply(&self, u: f64, v: f64) -> (f64, f64) {
+        rotate(
+            (self.x + u * self.scale, self.y + v * self.scale),
+            self.pivot,
+            self.rotation,
+        )
+    }
+}
+
+impl MapView {
+    /// On a `width` x `height` screen
+    pub fn new(
+        center: Coordinate,
+        zoom: impl Into<FractionalZoom>,
+        tile_size: u32,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        MapView {
+            center,
+            zoom: zoom.into(),
+            anchor: ScreenAnchor::CENTER,
+            rotation: 0.0,
+            tile_size,
+            width,
+            height,
+            animation: None,
+        }
+    }
+
+    pub fn center(&self) -> Coordinate {
+        self.center
+    }
+
+    pub fn zoom(&self) -> FractionalZoom {
+        self.zoom
+    }
+
+    pub fn anchor(&self) -> ScreenAnchor {
+        self.anchor
+    }
+
+    /// Puts the center at `anchor` on the screen
+    pub fn set_anchor(&mut self, anchor: ScreenAnchor) {
+        self.anchor = anchor;
+    }
+
+    pub fn rotation(&self) -> f64 {
+        self.rotation
+    }
+
+    /// Rotates the map clockwise by `degrees` around the anchor
+    pub fn set_rotation(&mut self, degrees: f64) {
+        self.rotation = degrees;
+    }
+
+    /// The zoom the view ends up at once the animation is over
+    pub fn target_zoom(&self) -> FractionalZoom {
+        self.animation.as_ref().map(|a| a.to).unwrap_or(self.zoom)
+    }
+
+    /// Keeps the view on a moving `center`, a running zoom animation carries
+    /// on around it instead of any screen position it was anchored to
+    pub fn follow(&mut self, center: Coordinate) {
+        if let Some(animation) = self.animation.as_mut() {
+            animation.anchor = None;
+        }
+        self.center = center;
+    }
+
+    /// Moves the center by (`dx`, `dy`) screen pixels, positive being right
+    /// and down on the rotated screen. Skips to the end of any zoom animation
+    /// first, which would otherwise put the center back.
+    pub fn pan_by(&mut self, dx: f64, dy: f64) {
+        self.finish_animation();
+        let (dx, dy) = rotate((dx, dy), (0.0, 0.0), -self.rotation);
+        self.center = pan(&self.center, dx, dy, self.zoom, self.tile_size);
+    }
+
+    /// Starts animating towards `zoom`. With an `anchor`, a screen position
+    /// in pixels, the map under it stays in place.
+    pub fn zoom_to(&mut self, zoom: FractionalZoom, anchor: Option<(f64, f64)>) {
+        let anchor = anchor.map(|(x, y)| {
+            // Relative to the view's anchor, on the unrotated map
+            let pivot = self.anchor_px();
+            let (x, y) = rotate((x, y), pivot, -self.rotation);
+            let offset = (x - pivot.0, y - pivot.1);
+            ZoomAnchor {
+                coord: self.offset_to_coordinate(offset),
+                offset,
+            }
+        });
+        self.animation = Some(ZoomAnimation {
+            from: self.zoom,
+            to: zoom,
+            anchor,
+            elapsed: 0.0,
+        });
+    }
+
+    /// Advances the animation by `dt` seconds, returns true when it's done
+    /// and the view has settled
+    pub fn update(&mut self, dt: f32) -> bool {
+        let animation = match self.animation.as_mut() {
+            Some(a) => a,
+            None => return false,
+        };
+        animation.elapsed += dt;
+        let t = f64::from((animation.elapsed / ZOOM_DURATION).min(1.0));
+        // Ease out, fast at first and slowing down towards the target
+        let eased = t * (2.0 - t);
+        let zoom = animation.from.get() + (animation.to.get() - animation.from.get()) * eased;
+        let anchor = animation.anchor;
+        let done = t >= 1.0;
+        if done {
+            self.animation = None;
+        }
+        self.set_zoom(FractionalZoom::new_clamped(zoom), anchor);
+        done
+    }
+
+    /// Placement of an unrotated, screen sized, image rendered for `canvas`
+    /// on the screen showing this view
+    pub fn placement(&self, canvas: &CanvasView) -> Placement {
+        let scale = 2_f64.powf(self.zoom.get() - canvas.zoom.get());
+        let tile_size = f64::from(self.tile_size);
+        let dx = (lon_to_x(canvas.center.longitude, self.zoom)
+            - lon_to_x(self.center.longitude, self.zoom))
+            * tile_size;
+        let dy = (lat_to_y(canvas.center.latitude, self.zoom)
+            - lat_to_y(self.center.latitude, self.zoom))
+            * tile_size;
+        let (x, y) = self.anchor_px();
+        let (canvas_x, canvas_y) = canvas.anchor.to_pixels(self.width, self.height);
+        Placement {
+            x: x + dx - canvas_x * scale,
+            y: y + dy - canvas_y * scale,
+            scale,
+            rotation: self.rotation,
+            pivot: (x, y),
+        }
+    }
+
+    /// Placement of the `image_size` map image rendered for `canvas`, drawn
+    /// around `CanvasView::image_middle` and scaled from the nearest whole
+    /// zoom level, on the screen showing this view. Anything drawn on the
+    /// image's `CanvasView::tile_grid` lines up with it.
+    pub fn image_placement(&self, canvas: &CanvasView, image_size: (u32, u32)) -> Placement {
+        let p = self.placement(canvas);
+        let (middle_x, middle_y) = canvas.image_middle(self.width, self.height);
+        let (x, y) = p.apply(middle_x, middle_y);
+        let scale = p.scale * canvas.zoom.tile_scale();
+        Placement {
+            x: x - f64::from(image_size.0) / 2.0 * scale,
+            y: y - f64::from(image_size.1) / 2.0 * scale,
+            scale,
+            rotation: p.rotation,
+            pivot: (x, y),
+        }
+    }
+
+    /// Placement of the `from_size` image rendered for `from` in the `to_size`
+    /// image rendered for `to`. Map images are north up, so it's only moved
+    /// and scaled. Images are drawn at the nearest whole zoom level of their
+    /// view, see `FractionalZoom::tile_scale`.
+    pub fn canvas_placement(
+        &self,
+        from: &CanvasView,
+        from_size: (u32, u32),
+        to: &CanvasView,
+        to_size: (u32, u32),
+    ) -> Placement {
+        let view = MapView {
+            center: to.center,
+            zoom: to.zoom,
+            anchor: to.anchor,
+            rotation: 0.0,
+            animation: None,
+            ..self.clone()
+        };
+        let p = view.placement(from);
+        let (from_scale, to_scale) = (from.zoom.tile_scale(), to.zoom.tile_scale());
+        // Images are drawn around their middle
+        let (from_x, from_y) = from.image_middle(self.width, self.height);
+        let (to_x, to_y) = to.image_middle(self.width, self.height);
+        let offset = |p_x: f64, from_x: f64, from_len: u32, to_x: f64, to_len: u32| {
+            (p_x + p.scale * (from_x - f64::from(from_len) / 2.0 * from_scale) - to_x) / to_scale
+                + f64::from(to_len) / 2.0
+        };
+        Placement {
+            x: offset(p.x, from_x, from_size.0, to_x, to_size.0),
+            y: offset(p.y, from_y, from_size.1, to_y, to_size.1),
+            scale: p.scale * from_scale / to_scale,
+            rotation: 0.0,
+            pivot: (0.0, 0.0),
+        }
+    }
+
+    fn anchor_px(&self) -> (f64, f64) {
+        self.anchor.to_pixels(self.width, self.height)
+    }
+
+    fn finish_animation(&mut self) {
+        if let Some(animation) = self.animation.take() {
+            self.set_zoom(animation.to, animation.anchor);
+        }
+    }
+
+    fn set_zoom(&mut self, zoom: FractionalZoom, anchor: Option<ZoomAnchor>) {
+        self.zoom = zoom;
+        if let Some(anchor) = anchor {
+            let tile_size = f64::from(self.tile_size);
+            let x = lon_to_x(anchor.coord.longitude, zoom) - anchor.offset.0 / tile_size;
+            let y = lat_to_y(anchor.coord.latitude, zoom) - anchor.offset.1 / tile_size;
+            self.center = Coordinate::from((y_to_lat(y, zoom), x_to_lon(x, zoom)));
+        }
+    }
+
+    fn offset_to_coordinate(&self, offset: (f64, f64)) -> Coordinate {
+        let tile_size = f64::from(self.tile_size);
+        let x = lon_to_x(self.center.longitude, self.zoom) + offset.0 / tile_size;
+        let y = lat_to_y(self.center.latitude, self.zoom) + offset.1 / tile_size;
+        Coordinate::from((y_to_lat(y, self.zoom), x_to_lon(x, self.zoom)))
+    }
+}
+
+/// Screen anchor of the followed vehicle, moved back from the configured one,
+/// against the direction of travel, the faster it goes. `heading` is in degrees
+/// clockwise from the top of the screen.
+pub fn follow_anchor(
+    follow: &Follow,
+    heading: f64,
+    speed_mps: f64,
+    width: u32,
+    height: u32,
+) -> ScreenAnchor {
+    let (x, y) = follow.anchor().to_pixels(width, height);
+    let distance = follow.look_ahead(speed_mps) * f64::from(width.min(height));
+    let (sin, cos) = heading.to_radians().sin_cos();
+    ScreenAnchor::from_pixels(x - distance * sin, y + distance * cos, width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{CoordinateTransform, Scale};
+
+    #[test]
+    fn anchored_zoom_animation() {
+        let start = Coordinate::new(47.453551, -116.788118).unwrap();
+        let mut view = MapView::new(start, FractionalZoom::new_clamped(15.0), 256, 800, 600);
+        let anchor = (200.0, -100.0);
+        let anchored = view.offset_to_coordinate(anchor);
+
+        view.zoom_to(
+            FractionalZoom::new_clamped(16.0),
+            Some((400.0 + anchor.0, 300.0 + anchor.1)),
+        );
+        assert_eq!(view.target_zoom(), FractionalZoom::new_clamped(16.0));
+        let mut frames = 0;
+        while !view.update(1.0 / 60.0) {
+            frames += 1;
+            let z = view.zoom().get();
+            assert!(z > 15.0 && z < 16.0, "zoom {}", z);
+            // The map under the anchor doesn't move
+            let c = view.offset_to_coordinate(anchor);
+            assert!((c.latitude.get() - anchored.latitude.get()).abs() < 1e-9);
+            assert!((c.longitude.get() - anchored.longitude.get()).abs() < 1e-9);
+        }
+        assert!(frames > 5);
+        assert!(view.animation.is_none());
+        assert_eq!(view.zoom(), FractionalZoom::new_clamped(16.0));
+        assert!(!view.update(1.0 / 60.0));
+
+        // An image of the starting view is twice the size, with the anchor in place
+        let start_view = CanvasView {
+            center: start,
+            zoom: FractionalZoom::new_clamped(15.0),
+            anchor: ScreenAnchor::CENTER,
+            orientation: MapOrientation::NorthUp,
+        };
+        let p = view.placement(&start_view);
+        assert!((p.scale - 2.0).abs() < 1e-9);
+        let (x, y) = p.apply(400.0 + anchor.0, 300.0 + anchor.1);
+        assert!((x - (400.0 + anchor.0)).abs() < 1e-6, "x={}", x);
+        assert!((y - (300.0 + anchor.1)).abs() < 1e-6, "y={}", y);
+
+        // Rotated a quarter turn around the screen center, right becomes down
+        view.set_rotation(90.0);
+        let mut canvas = CanvasView {
+            center: view.center(),
+            zoom: view.zoom(),
+            anchor: view.anchor(),
+            orientation: MapOrientation::HeadingUp,
+        };
+        let p = view.placement(&canvas);
+        let (x, y) = p.apply(500.0, 300.0);
+        assert!((x - 400.0).abs() < 1e-6 && (y - 400.0).abs() < 1e-6);
+
+        // Same around the anchor, for an image rendered centered
+        view.set_anchor(ScreenAnchor::new_clamped(0.5, 0.75));
+        canvas.anchor = ScreenAnchor::CENTER;
+        let p = view.placement(&canvas);
+        let (x, y) = p.apply(400.0, 300.0);
+        assert!((x - 400.0).abs() < 1e-6 && (y - 450.0).abs() < 1e-6);
+        let (x, y) = p.apply(500.0, 300.0);
+        assert!((x - 400.0).abs() < 1e-6 && (y - 550.0).abs() < 1e-6);
+
+        // Zooming at a rotated screen position keeps the map under it there
+        let cursor = (600.0, 200.0);
+        let under = view.offset_to_coordinate({
+            let (x, y) = rotate(cursor, (400.0, 450.0), -90.0);
+            (x - 400.0, y - 450.0)
+        });
+        view.zoom_to(FractionalZoom::new_clamped(15.5), Some(cursor));
+        while !view.update(0.1) {}
+        let t = CoordinateTransform::new(&view.center(), Scale::One, view.zoom(), 800, 600)
+            .with_anchor(view.anchor())
+            .with_rotation(90.0);
+        let (x, y) = t.coordinate_to_pixel(&under);
+        assert!(
+            (x - cursor.0).abs() <= 1.0 && (y - cursor.1).abs() <= 1.0,
+            "{}, {}",
+            x,
+            y
+        );
+    }
+
+    #[test]
+    fn previous_canvas_placement() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        let view = MapView::new(center, FractionalZoom::new_clamped(15.0), 256, 800, 600);
+        let from = CanvasView {
+            center,
+            zoom: FractionalZoom::new_clamped(15.0),
+            anchor: ScreenAnchor::CENTER,
+            orientation: MapOrientation::NorthUp,
+        };
+        let p = view.canvas_placement(&from, (800, 600), &from, (800, 600));
+        assert_eq!(p.apply(10.0, 20.0), (10.0, 20.0));
+
+        // Zoomed in on the center
+        let to = CanvasView {
+            zoom: FractionalZoom::new_clamped(16.0),
+            ..from
+        };
+        let p = view.canvas_placement(&from, (800, 600), &to, (800, 600));
+        assert!((p.scale - 2.0).abs() < 1e-9);
+        assert_eq!(p.apply(400.0, 300.0), (400.0, 300.0));
+        assert_eq!(p.apply(500.0, 300.0), (600.0, 300.0));
+
+        // Into a larger heading-up image, centered on the same coordinate
+        let to = CanvasView {
+            anchor: ScreenAnchor::new_clamped(0.5, 0.75),
+            orientation: MapOrientation::HeadingUp,
+            ..from
+        };
+        let p = view.canvas_placement(&from, (800, 600), &to, (1000, 1000));
+        assert_eq!(p.apply(400.0, 300.0), (500.0, 500.0));
+        // And back
+        let p = view.canvas_placement(&to, (1000, 1000), &from, (800, 600));
+        assert_eq!(p.apply(500.0, 500.0), (400.0, 300.0));
+
+        // Half way to zoom 16 the image is drawn at 16, the same scale
+        let half = CanvasView {
+            zoom: FractionalZoom::new_clamped(15.5),
+            ..from
+        };
+        let zoomed = CanvasView {
+            zoom: FractionalZoom::new_clamped(16.0),
+            ..from
+        };
+        let size = (566, 426);
+        let p = view.canvas_placement(&half, size, &zoomed, (800, 600));
+        assert!((p.scale - 1.0).abs() < 1e-9);
+        let (x, y) = p.apply(283.0, 213.0);
+        assert!((x - 400.0).abs() < 1e-9 && (y - 300.0).abs() < 1e-9);
+        let p = view.canvas_placement(&from, (800, 600), &half, size);
+        assert!((p.scale - 2.0).abs() < 1e-9);
+        let (x, y) = p.apply(400.0, 300.0);
+        assert!((x - 283.0).abs() < 1e-9 && (y - 213.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn image_placement() {
+        let center = Coordinate::new(47.453551, -116.788118).unwrap();
+        for (zoom, orientation) in [
+            (15.0, MapOrientation::NorthUp),
+            (15.3, MapOrientation::NorthUp),
+            (15.0, MapOrientation::HeadingUp),
+            (14.6, MapOrientation::HeadingUp),
+        ]
+        .iter()
+        {
+            let mut view = MapView::new(center, FractionalZoom::new_clamped(*zoom), 256, 800, 600);
+            let anchor = ScreenAnchor::new_clamped(0.5, 0.75);
+            view.set_anchor(anchor);
+            if *orientation == MapOrientation::HeadingUp {
+                view.set_rotation(30.0);
+            }
+            let canvas = CanvasView {
+                center,
+                zoom: view.zoom(),
+                anchor,
+                orientation: *orientation,
+            };
+            let grid = canvas.tile_grid(800, 600, 256);
+            let viewport = grid.viewport();
+            let p = view.image_placement(&canvas, (viewport.width, viewport.height));
+            // The center, on the tiles, is at the anchor on the screen
+            let tile_zoom = grid.zoom();
+            let (u, v) = grid.tile_to_px(
+                lon_to_x(center.longitude, tile_zoom),
+                lat_to_y(center.latitude, tile_zoom),
+            );
+            let (x, y) = p.apply(u, v);
+            assert!((x - 400.0).abs() <= 1.0, "{} x={}", zoom, x);
+            assert!((y - 450.0).abs() <= 1.0, "{} y={}", zoom, y);
+        }
+    }
+
+    #[test]
+    fn panning_ends_zoom() {
+        let start = Coordinate::new(47.453551, -116.788118).unwrap();
+        let mut view = MapView::new(start, FractionalZoom::new_clamped(15.0), 256, 800, 600);
+        view.zoom_to(FractionalZoom::new_clamped(16.0), Some((600.0, 200.0)));
+        view.update(1.0 / 60.0);
+        let mut zoomed = view.clone();
+        zoomed.finish_animation();
+        view.pan_by(100.0, 0.0);
+        assert_eq!(view.zoom(), FractionalZoom::new_clamped(16.0));
+        let panned = view.center();
+        assert_eq!(panned, zoomed.offset_to_coordinate((100.0, 0.0)));
+        // Nothing left to undo the pan
+        assert!(!view.update(1.0 / 60.0));
+        assert_eq!(view.center(), panned);
+    }
+
+    #[test]
+    fn following_keeps_zooming() {
+        let start = Coordinate::new(47.453551, -116.788118).unwrap();
+        let vehicle = Coordinate::new(47.456655, -116.783225).unwrap();
+        let mut view = MapView::new(start, FractionalZoom::new_clamped(15.0), 256, 800, 600);
+        view.zoom_to(FractionalZoom::new_clamped(16.0), Some((600.0, 200.0)));
+        let mut frames = 0;
+        loop {
+            view.follow(vehicle);
+            if view.update(1.0 / 60.0) {
+                break;
+            }
+            frames += 1;
+        }
+        assert!(frames > 5);
+        assert_eq!(view.center(), vehicle);
+        assert_eq!(view.zoom(), FractionalZoom::new_clamped(16.0));
+    }
+
+    #[test]
+    fn look_ahead() {
+        let follow = Follow {
+            anchor: Some(ScreenAnchor::new_clamped(0.5, 0.75)),
+            max_look_ahead: Some(0.2),
+            full_look_ahead_speed_mps: Some(30.0),
+        };
+        let at = |heading, speed| follow_anchor(&follow, heading, speed, 800, 600);
+        assert_eq!(at(0.0, 0.0), ScreenAnchor::new_clamped(0.5, 0.75));
+        // Heading up the screen at full speed, 0.2 * 600 pixels further down
+        let a = at(0.0, 40.0);
+        assert!((a.x - 0.5).abs() < 1e-9 && (a.y - 0.95).abs() < 1e-9);
+        // Heading right at half speed, 60 pixels to the left
+        let a = at(90.0, 15.0);
+        assert!((a.x - 340.0 / 800.0).abs() < 1e-9 && (a.y - 0.75).abs() < 1e-9);
+    }
+}
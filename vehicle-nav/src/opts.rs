@@ -1,7 +1,13 @@
+use crate::gpx;
+use common::{BoundingBox, Coordinate, Zoom};
+use config::ConfigSources;
+use map_tiler::TileRegion;
+use std::env;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
 pub const CONFIG_SYS_PATH: &str = "/etc/vehicle-nav/config.toml";
+pub const CONFIG_ENV_VAR: &str = "VEHICLE_NAV_CONFIG";
 
 #[derive(Debug, Clone, PartialEq, StructOpt)]
 pub struct Opts {
@@ -9,7 +15,161 @@ pub struct Opts {
     #[structopt(long, name = "path")]
     pub write_default_config: Option<PathBuf>,
 
-    /// Configuration file path
-    #[structopt(long, short = "c", default_value = CONFIG_SYS_PATH)]
-    pub config: PathBuf,
+    /// User configuration file path, merged over the system file
+    /// /etc/vehicle-nav/config.toml.
+    /// Defaults to $XDG_CONFIG_HOME/vehicle-nav/config.toml if it exists
+    #[structopt(long, short = "c", env = CONFIG_ENV_VAR)]
+    pub config: Option<PathBuf>,
+
+    /// Set a config value, "section.key=value", can be repeated.
+    /// Takes precedence over the config files and VEHICLE_NAV_<SECTION>__<KEY>
+    /// environment variables
+    #[structopt(long = "set", name = "section.key=value", number_of_values = 1)]
+    pub overrides: Vec<String>,
+
+    /// Print the merged configuration, and where each value came from, and exit
+    #[structopt(long)]
+    pub print_config: bool,
+
+    /// Runs the GUI if no command is provided
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub enum Command {
+    /// Export the tiles for a region into a {z}/{x}/{y}.png directory tree
+    Export(ExportOpts),
+
+    /// Download the tiles for a region into the tile cache
+    Prefetch(PrefetchOpts),
+
+    /// Render a map image with an optional route and markers to a PNG file
+    Render(RenderOpts),
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct ExportOpts {
+    #[structopt(flatten)]
+    pub region: RegionOpts,
+
+    /// Only export tiles already in the tile cache instead of requesting
+    /// them from the configured tile source
+    #[structopt(long)]
+    pub from_cache: bool,
+
+    /// Output directory, created if it doesn't exist
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct PrefetchOpts {
+    #[structopt(flatten)]
+    pub region: RegionOpts,
+
+    /// Maximum number of concurrent tile requests
+    #[structopt(long, default_value = "4")]
+    pub concurrency: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct RenderOpts {
+    /// Map center, "latitude,longitude" in degrees, or DMS, DDM, UTM or MGRS.
+    /// Defaults to fitting the GPX track, or the startup defaults
+    #[structopt(long, allow_hyphen_values = true)]
+    pub center: Option<Coordinate>,
+
+    /// Defaults to fitting the GPX track, or the startup defaults
+    #[structopt(long)]
+    pub zoom: Option<Zoom>,
+
+    /// Image width, defaults to the window width
+    #[structopt(long)]
+    pub width: Option<u32>,
+
+    /// Image height, defaults to the window height
+    #[structopt(long)]
+    pub height: Option<u32>,
+
+    /// GPX file, its track and route points are drawn as a route
+    #[structopt(long)]
+    pub gpx: Option<PathBuf>,
+
+    /// Marker at "latitude,longitude", or any format of --center, can be repeated
+    #[structopt(long = "marker", number_of_values = 1, allow_hyphen_values = true)]
+    pub markers: Vec<Coordinate>,
+
+    /// Output PNG file path
+    pub output: PathBuf,
+}
+
+impl Opts {
+    /// The system file, the user file, the environment and the --set overrides
+    pub fn config_sources(&self) -> ConfigSources {
+        let mut sources = ConfigSources::new().with_system_file(CONFIG_SYS_PATH);
+        if let Some(path) = self
+            .config
+            .clone()
+            .or_else(|| user_config_path().filter(|p| p.exists()))
+        {
+            sources = sources.with_user_file(path);
+        }
+        sources
+            .with_env_vars(
+                env::vars_os()
+                    // Config names and values are UTF-8, others aren't for us
+                    .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+                    .filter(|(k, _)| k != CONFIG_ENV_VAR),
+            )
+            .with_overrides(self.overrides.iter().cloned())
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("vehicle-nav").join("config.toml"))
+}
+
+/// A bounding box or a GPX track, and a zoom range
+#[derive(Debug, Clone, PartialEq, StructOpt)]
+pub struct RegionOpts {
+    /// Region bounding box, "west,south,east,north" in degrees. West east
+    /// of east crosses the antimeridian.
+    #[structopt(
+        long,
+        allow_hyphen_values = true,
+        required_unless = "gpx",
+        conflicts_with = "gpx"
+    )]
+    pub bbox: Option<BoundingBox>,
+
+    /// GPX file, the region is a corridor around its track and route points
+    #[structopt(long)]
+    pub gpx: Option<PathBuf>,
+
+    /// Width of the corridor on each side of the GPX track, in meters
+    #[structopt(long, default_value = "500")]
+    pub buffer: f64,
+
+    #[structopt(long)]
+    pub min_zoom: Zoom,
+
+    #[structopt(long)]
+    pub max_zoom: Zoom,
+}
+
+impl RegionOpts {
+    pub fn tile_region(&self) -> Result<TileRegion, gpx::Error> {
+        match (&self.bbox, &self.gpx) {
+            (Some(bbox), _) => Ok(TileRegion::from_bbox(*bbox)),
+            (None, Some(path)) => {
+                let track = gpx::read_track(path)?;
+                Ok(TileRegion::from_track(&track, self.buffer))
+            }
+            // Enforced by the arg parser
+            (None, None) => Ok(TileRegion::default()),
+        }
+    }
 }
@@ -0,0 +1,123 @@
+use crate::map_view::MapView;
+use config::Config;
+
+/// Which way the view moves on screen, the map content moves the other way
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PanDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl PanDirection {
+    /// Unit screen offset, positive being right and down
+    fn unit(&self) -> (f64, f64) {
+        match self {
+            PanDirection::Up => (0.0, -1.0),
+            PanDirection::Down => (0.0, 1.0),
+            PanDirection::Left => (-1.0, 0.0),
+            PanDirection::Right => (1.0, 0.0),
+        }
+    }
+}
+
+/// Moves the view by a constant number of screen pixels per step, whatever
+/// the zoom level, latitude or rotation
+#[derive(Debug, Clone)]
+pub struct Panner {
+    step_pixels: f64,
+}
+
+impl Panner {
+    pub fn new(config: &Config) -> Self {
+        Panner {
+            step_pixels: config.pan.step_pixels().into(),
+        }
+    }
+
+    pub fn pan(&self, view: &mut MapView, direction: PanDirection) {
+        let (x, y) = direction.unit();
+        view.pan_by(x * self.step_pixels, y * self.step_pixels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{Coordinate, CoordinateTransform, FractionalZoom, Scale};
+
+    fn panner(step_pixels: u32) -> Panner {
+        let mut config = Config::sample_config();
+        config.pan.step_pixels = Some(step_pixels);
+        Panner::new(&config)
+    }
+
+    fn screen_position(view: &MapView, coord: &Coordinate) -> (f64, f64) {
+        CoordinateTransform::new(&view.center(), Scale::One, view.zoom(), 800, 600)
+            .with_anchor(view.anchor())
+            .with_rotation(view.rotation())
+            .coordinate_to_pixel(coord)
+    }
+
+    #[test]
+    fn constant_screen_distance() {
+        let start = Coordinate::new(47.453551, -116.788118).unwrap();
+        let panner = panner(100);
+        for zoom in [3.0, 11.0, 15.5, 18.0].iter() {
+            let mut view = MapView::new(start, FractionalZoom::new_clamped(*zoom), 256, 800, 600);
+            panner.pan(&mut view, PanDirection::Right);
+            let (x, y) = screen_position(&view, &start);
+            assert!(
+                (x - 300.0).abs() <= 1.0 && (y - 300.0).abs() <= 1.0,
+                "{}, {}",
+                x,
+                y
+            );
+            panner.pan(&mut view, PanDirection::Up);
+            let (x, y) = screen_position(&view, &start);
+            assert!(
+                (x - 300.0).abs() <= 1.0 && (y - 400.0).abs() <= 1.0,
+                "{}, {}",
+                x,
+                y
+            );
+            panner.pan(&mut view, PanDirection::Left);
+            panner.pan(&mut view, PanDirection::Down);
+            assert!((view.center().latitude.get() - start.latitude.get()).abs() < 1e-9);
+            assert!((view.center().longitude.get() - start.longitude.get()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn rotated_view() {
+        // Heading east, up on the screen is east on the map
+        let start = Coordinate::new(47.453551, -116.788118).unwrap();
+        let mut view = MapView::new(start, FractionalZoom::new_clamped(14.0), 256, 800, 600);
+        view.set_rotation(-90.0);
+        panner(50).pan(&mut view, PanDirection::Up);
+        assert!(view.center().longitude.get() > start.longitude.get());
+        assert!((view.center().latitude.get() - start.latitude.get()).abs() < 1e-9);
+        let (x, y) = screen_position(&view, &start);
+        assert!(
+            (x - 400.0).abs() <= 1.0 && (y - 350.0).abs() <= 1.0,
+            "{}, {}",
+            x,
+            y
+        );
+    }
+
+    #[test]
+    fn wraps_and_clamps() {
+        let panner = panner(200);
+        let mut view = MapView::new(Coordinate::new(0.0, 179.9).unwrap(), 2.0, 256, 800, 600);
+        panner.pan(&mut view, PanDirection::Right);
+        assert!(view.center().longitude.get() < 0.0, "{}", view.center());
+
+        let mut view = MapView::new(Coordinate::new(80.0, 0.0).unwrap(), 2.0, 256, 800, 600);
+        for _ in 0..10 {
+            panner.pan(&mut view, PanDirection::Up);
+        }
+        assert_eq!(view.center().latitude, common::Latitude::MERCATOR_MAX);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::gpx;
+use crate::opts::PrefetchOpts;
+use crate::tile_source;
+use config::Config;
+use err_derive::Error;
+use indicatif::{ProgressBar, ProgressStyle};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "{}", _0)]
+    MapTiler(#[error(source)] map_tiler::Error),
+
+    #[error(display = "{}", _0)]
+    TileSource(#[error(source)] tile_source::Error),
+
+    #[error(display = "{}", _0)]
+    Gpx(#[error(source)] gpx::Error),
+
+    #[error(display = "Missing config item {}", _0)]
+    MissingConfig(&'static str),
+
+    #[error(display = "The configured tile source is already local, nothing to prefetch")]
+    LocalTileSource,
+}
+
+pub fn run(config: &Config, opts: &PrefetchOpts) -> Result<(), Error> {
+    let source = tile_source::from_config(config)?;
+    if !source.is_cacheable() {
+        return Err(Error::LocalTileSource);
+    }
+    let cache =
+        tile_source::cache_from_config(config)?.ok_or(Error::MissingConfig("tiler.cache"))?;
+
+    let region = opts.region.tile_region()?;
+    let (min_zoom, max_zoom) = (opts.region.min_zoom, opts.region.max_zoom);
+    let progress = ProgressBar::new(region.tile_count(min_zoom, max_zoom) as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("{elapsed_precise} [{bar:40}] {pos}/{len} tiles ({eta})")
+            .progress_chars("=> "),
+    );
+
+    let summary = map_tiler::prefetch_tiles(
+        source.as_ref(),
+        &cache,
+        &region,
+        min_zoom,
+        max_zoom,
+        opts.concurrency,
+        |_, _| progress.inc(1),
+    )?;
+    progress.finish_and_clear();
+
+    if summary.bytes > cache.max_size() {
+        log::warn!(
+            "Prefetched {} bytes but the tile cache max size is {} bytes, some tiles were evicted",
+            summary.bytes,
+            cache.max_size()
+        );
+    }
+    println!(
+        "Prefetched {} tiles ({} bytes), {} already cached, {} failed",
+        summary.fetched, summary.bytes, summary.cached, summary.failed
+    );
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::gpx;
+use crate::opts::RenderOpts;
+use crate::tile_source;
+use common::{util::fit_bounds, BoundingBox, Coordinate, CoordinateTransform, Viewport};
+use config::Config;
+use err_derive::Error;
+use map_tiler::{overlay, Config as MapTilerConfig, MapTiler};
+use tiny_skia::Color;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "{}", _0)]
+    MapTiler(#[error(source)] map_tiler::Error),
+
+    #[error(display = "{}", _0)]
+    TileSource(#[error(source)] tile_source::Error),
+
+    #[error(display = "{}", _0)]
+    Gpx(#[error(source)] gpx::Error),
+
+    #[error(display = "PNG encoding error: {}", _0)]
+    PngEncode(#[error(source)] png::EncodingError),
+}
+
+/// Space kept between a fitted GPX track and the image edges
+const FIT_PADDING: u32 = 32;
+
+const ROUTE_WIDTH: f32 = 3.0;
+const MARKER_RADIUS: f32 = 8.0;
+
+pub fn run(config: &Config, opts: &RenderOpts) -> Result<(), Error> {
+    let source = tile_source::from_config(config)?;
+    let tile_size = tile_source::tile_size(source.as_ref());
+    let width = opts.width.unwrap_or_else(|| config.window.width.into());
+    let height = opts.height.unwrap_or_else(|| config.window.height.into());
+    let track = opts.gpx.as_ref().map(gpx::read_track).transpose()?;
+
+    let (center, zoom) = match (opts.center, &track) {
+        (Some(center), _) => (center, opts.zoom.unwrap_or(config.startup_defaults.zoom)),
+        (None, Some(track)) => {
+            let bbox =
+                BoundingBox::from_coordinates(track.iter().copied()).ok_or(gpx::Error::Empty)?;
+            let (center, zoom) = fit_bounds(
+                &bbox,
+                width.saturating_sub(2 * FIT_PADDING),
+                height.saturating_sub(2 * FIT_PADDING),
+                tile_size,
+            );
+            (center, opts.zoom.unwrap_or(zoom))
+        }
+        (None, None) => (
+            Coordinate::from((
+                config.startup_defaults.latitude,
+                config.startup_defaults.longitude,
+            )),
+            opts.zoom.unwrap_or(config.startup_defaults.zoom),
+        ),
+    };
+    log::info!(
+        "Rendering {}x{} map at {}, zoom {}",
+        width,
+        height,
+        center,
+        zoom
+    );
+
+    let mut map_tiler = MapTiler::new(
+        source,
+        MapTilerConfig {
+            width,
+            height,
+            tile_size,
+            memory_cache_tiles: 0,
+            placeholder: config.tiler.placeholder,
+            fallback_parent_levels: config.tiler.fallback_parent_levels.unwrap_or(0),
+        },
+    )?;
+    map_tiler.set_cache(tile_source::cache_from_config(config)?);
+    let map = map_tiler.request_tiles(center, zoom)?;
+    if !map.failed_tiles.is_empty() {
+        log::warn!(
+            "Map rendered with {} failed tile(s)",
+            map.failed_tiles.len()
+        );
+    }
+    if !map.substituted_tiles.is_empty() {
+        log::info!(
+            "Map rendered with {} substituted tile(s)",
+            map.substituted_tiles.len()
+        );
+    }
+    let mut image = map.image.clone();
+
+    let transform =
+        CoordinateTransform::from_viewport(&center, Viewport::new(width, height, tile_size), zoom);
+    if let Some(track) = &track {
+        let points: Vec<(f64, f64)> = track
+            .iter()
+            .map(|c| transform.coordinate_to_pixel(c))
+            .collect();
+        overlay::draw_route(
+            &mut image,
+            &points,
+            Color::from_rgba8(255, 0, 0, 255),
+            ROUTE_WIDTH,
+        );
+    }
+    for marker in opts.markers.iter() {
+        overlay::draw_marker(
+            &mut image,
+            transform.coordinate_to_pixel(marker),
+            Color::from_rgba8(0, 90, 255, 255),
+            MARKER_RADIUS,
+        );
+    }
+
+    image.save_png(&opts.output)?;
+    println!("Wrote {}", opts.output.display());
+    Ok(())
+}
@@ -1,5 +1,6 @@
+use crate::map_view::CanvasView;
 use crate::thread::{SendRecvError, ShutdownHandle, ShutdownHandlingThread};
-use common::{Coordinate, CoordinateTransform, Zoom};
+use common::{util::*, Coordinate};
 use config::Config;
 use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
 use err_derive::Error;
@@ -31,8 +32,7 @@ pub struct AddRouteCoordinateRequest {
 pub struct GetRouteRequest {
     // TODO - RouteId type
     // For now, there is only a single route
-    map_center: Coordinate,
-    map_zoom: Zoom,
+    map_view: CanvasView,
 }
 
 #[derive(Debug)]
@@ -40,6 +40,8 @@ pub struct GetRouteResponse {
     // TODO - RouteId type
     // probably don't need to provide offset, but nice to have
     pub offset: usize,
+    /// The map image the route points are pixels of
+    pub map_view: CanvasView,
     pub route_chunk: Vec<ffi::Vector2>, // TODO - config, chunk_size
 }
 
@@ -63,12 +65,14 @@ impl RouteTransformServiceClient {
         Ok(())
     }
 
-    pub fn get_route(&self, map_center: Coordinate, map_zoom: Zoom) -> Result<(), Error> {
-        log::debug!("Request route center {}, zoom {}", map_center, map_zoom);
-        let req = Request::GetRoute(GetRouteRequest {
-            map_center,
-            map_zoom,
-        });
+    /// Route points are pixels of the map image rendered for `map_view`
+    pub fn get_route(&self, map_view: CanvasView) -> Result<(), Error> {
+        log::debug!(
+            "Request route center {}, zoom {}",
+            map_view.center,
+            map_view.zoom
+        );
+        let req = Request::GetRoute(GetRouteRequest { map_view });
         self.req_sender.send(req).map_err(SendRecvError::from)?;
         Ok(())
     }
@@ -86,35 +90,36 @@ impl RouteTransformServiceClient {
 
 #[derive(Debug)]
 pub struct RouteTransformService {
-    transform: CoordinateTransform,
+    width: u32,
+    height: u32,
+    tile_size: u32,
     route: Vec<Coordinate>, // TODO ring buffer instead of vec
     resp_sender: Sender<GetRouteResponse>,
 }
 
 impl RouteTransformService {
-    fn new(config: Config, resp_sender: Sender<GetRouteResponse>) -> Result<Self, Error> {
-        let center_coord = Coordinate::from((
-            config.startup_defaults.latitude,
-            config.startup_defaults.longitude,
-        ));
-        let transform = CoordinateTransform::new(
-            &center_coord,
-            config.tiler.scale.unwrap_or_default(),
-            config.startup_defaults.zoom,
-            config.window.width.into(),
-            config.window.height.into(),
-        );
+    fn new(
+        config: Config,
+        tile_size: u32,
+        resp_sender: Sender<GetRouteResponse>,
+    ) -> Result<Self, Error> {
         Ok(RouteTransformService {
-            transform,
+            width: config.window.width.into(),
+            height: config.window.height.into(),
+            tile_size,
             route: Vec::with_capacity(256), // TODO - config
             resp_sender,
         })
     }
 
-    pub fn start(config: Config) -> Result<(RouteTransformServiceClient, ShutdownHandle), Error> {
+    /// Routes are placed on tiles of `tile_size` pixels, the map's
+    pub fn start(
+        config: Config,
+        tile_size: u32,
+    ) -> Result<(RouteTransformServiceClient, ShutdownHandle), Error> {
         let (req_sender, req_recvr) = channel::bounded(32);
         let (route_resp_sender, route_resp_recvr) = channel::unbounded();
-        let service = RouteTransformService::new(config, route_resp_sender)?;
+        let service = RouteTransformService::new(config, tile_size, route_resp_sender)?;
         let shutdown_handle = service.spawn("RouteTransformService".to_string(), req_recvr)?;
         Ok((
             RouteTransformServiceClient::new(req_sender, route_resp_recvr),
@@ -129,12 +134,17 @@ impl RouteTransformService {
     }
 
     fn process_route_request(&mut self, req: GetRouteRequest) -> Result<GetRouteResponse, Error> {
-        self.transform.update(&req.map_center, req.map_zoom);
+        // On the tiles of the map image
+        let grid = req
+            .map_view
+            .tile_grid(self.width, self.height, self.tile_size);
+        let zoom = grid.zoom();
         let route_chunk = self
             .route
             .iter()
             .map(|c| {
-                let (x, y) = self.transform.coordinate_to_pixel(c);
+                let (x, y) =
+                    grid.tile_to_px(lon_to_x(c.longitude, zoom), lat_to_y(c.latitude, zoom));
                 ffi::Vector2 {
                     x: x as _,
                     y: y as _,
@@ -143,6 +153,7 @@ impl RouteTransformService {
             .collect();
         Ok(GetRouteResponse {
             offset: 0,
+            map_view: req.map_view,
             route_chunk,
         })
     }
@@ -0,0 +1,100 @@
+use common::{Daylight, Scale};
+use config::{Config, TileSourceKind};
+use err_derive::Error;
+use map_tiler::{MbTiles, TileCache, TileDirectory, TileSource};
+use osm_client::{OsmClient, UrlTemplate};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(display = "{}", _0)]
+    MapTiler(#[error(source)] map_tiler::Error),
+
+    #[error(display = "{}", _0)]
+    OsmClient(#[error(source)] osm_client::Error),
+
+    #[error(display = "Missing config item {}", _0)]
+    MissingConfig(&'static str),
+}
+
+/// Builds the tile source described by the `[tiler]` config section
+pub fn from_config(config: &Config) -> Result<Box<dyn TileSource>, Error> {
+    match config.tiler.kind {
+        TileSourceKind::OsmScout => {
+            let client = OsmClient::new(config.tiler.url.clone());
+            Ok(Box::new(configure_client(client, config)))
+        }
+        TileSourceKind::UrlTemplate => {
+            let template = config
+                .tiler
+                .url_template
+                .as_ref()
+                .ok_or(Error::MissingConfig("tiler.url_template"))?;
+            let client = OsmClient::from_template(
+                UrlTemplate::new(template.as_str())?
+                    .with_subdomains(config.tiler.subdomains.clone()),
+            );
+            Ok(Box::new(configure_client(client, config)))
+        }
+        TileSourceKind::MbTiles => {
+            let path = config
+                .tiler
+                .mbtiles_path
+                .as_ref()
+                .ok_or(Error::MissingConfig("tiler.mbtiles_path"))?;
+            let mbtiles = MbTiles::open(path)?;
+            Ok(Box::new(mbtiles))
+        }
+        TileSourceKind::Directory => {
+            let root = config
+                .tiler
+                .tile_directory
+                .as_ref()
+                .ok_or(Error::MissingConfig("tiler.tile_directory"))?;
+            Ok(Box::new(TileDirectory::new(root)))
+        }
+    }
+}
+
+/// Scale and daylight the configured network source keys its cached tiles by
+pub fn cache_key_from_config(config: &Config) -> (Option<Scale>, Option<Daylight>) {
+    let daylight = if config.tiler.support_daynight {
+        Some(config.startup_defaults.daynight)
+    } else {
+        None
+    };
+    (config.tiler.scale, daylight)
+}
+
+/// Size of the tiles of `source`, the default scale's when it doesn't know
+pub fn tile_size(source: &dyn TileSource) -> u32 {
+    source
+        .tile_size()
+        .unwrap_or_else(|| Scale::default().tile_size())
+}
+
+/// Opens the tile cache if one is configured
+pub fn cache_from_config(config: &Config) -> Result<Option<TileCache>, Error> {
+    match &config.tiler.cache {
+        Some(cache_config) => {
+            let cache = TileCache::open(&cache_config.path, cache_config.max_size_bytes())
+                .map_err(map_tiler::Error::from)?;
+            Ok(Some(cache))
+        }
+        None => Ok(None),
+    }
+}
+
+fn configure_client(mut client: OsmClient, config: &Config) -> OsmClient {
+    client.set_timeout(config.tiler.request_timeout());
+    if let Some(retry_policy) = config.tiler.retry_policy() {
+        client.set_retry_policy(retry_policy);
+    }
+    let (scale, daylight) = cache_key_from_config(config);
+    if let Some(daylight) = daylight {
+        client.set_daylight(daylight);
+    }
+    if let Some(scale) = scale {
+        client.set_scale(scale);
+    }
+    client
+}